@@ -12,7 +12,7 @@ use clap::Parser;
 use fake_user_agent::get_chrome_rua;
 use iori::{
     cache::IoriCache,
-    dash::archive::CommonDashArchiveSource,
+    dash::live::CommonDashLiveSource,
     download::ParallelDownloader,
     hls::{CommonM3u8ArchiveSource, HlsLiveSource, SegmentRange},
     merge::IoriMerger,
@@ -251,6 +251,7 @@ impl MinyamiArgs {
                     !self.keep,
                     target_file,
                     std::env::var("RE_LIVE_PIPE_OPTIONS").ok(),
+                    2,
                 )
             } else {
                 IoriMerger::auto(target_file, self.keep)
@@ -318,16 +319,11 @@ impl MinyamiArgs {
         }
 
         match (self.dash, self.live) {
-            // DASH Live
-            (true, true) => unimplemented!(),
-            // DASH Archive
-            (true, false) => {
-                let source = CommonDashArchiveSource::new(
-                    client,
-                    self.m3u8.clone(),
-                    self.key.as_deref(),
-                    self.shaka_packager.clone(),
-                )?;
+            // DASH Live & Archive
+            (true, _) => {
+                let source =
+                    CommonDashLiveSource::new(client, self.m3u8.parse()?, self.key.as_deref())?
+                        .with_shaka_packager(self.shaka_packager.clone());
                 self.download(source, cache).await?;
             }
             // HLS Live
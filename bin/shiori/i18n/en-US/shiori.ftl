@@ -2,6 +2,7 @@ shiori-about = Yet another m3u8 downloader
 
 download-wait = Wait for stream to start when no stream is detected
 download-url = URL to download
+download-report = Write a JSON report of the merge result to the file, or to stdout if it is `-`
 
 download-http-headers = Additional HTTP headers for all HTTP requests, format is key: value
 download-http-cookies =
@@ -16,6 +17,13 @@ download-concurrency = Threads limit
 download-segment-retries = Segment retry limit
 # download-segment-retry-delay = Set retry delay after download fails in seconds
 download-manifest-retries = Manifest retry limit
+download-skip-ads = Skip segments in ad breaks marked by EXT-X-CUE-OUT/EXT-X-CUE-IN, EXT-X-DATERANGE with SCTE35-OUT or SCTE-35 EventStreams
+download-ad-url-pattern = Skip segments whose URL matches this regular expression. Can be specified multiple times.
+download-ad-period-pattern = Skip DASH periods whose id matches this regular expression. Can be specified multiple times.
+download-skip-ads-dry-run = Only list segments which would be skipped as ads, without skipping them
+download-no-validate = Do not check downloaded segments for truncated data, HTML error pages or broken MPEG-TS and MP4 structure before caching them. Segments are not checked with --defer-decryption.
+download-segment-format = Use this format, like `ts`, `mp4` or `aac`, for all media segments instead of detecting it from their data. Use it when the detected format is wrong.
+download-decrypt-defer-decryption = Cache segments without decrypting them, and record their keys in the cache directory. Decrypt them later with `shiori decrypt`, with another key if the key was wrong. It can not be used with in-memory cache, output or merging options.
 
 download-cache-in-menory-cache = Use in-memory cache and do not write cache to disk while downloading
 download-cache-temp-dir =
@@ -31,8 +39,19 @@ download-cache-cache-dir =
     If specified, the cache will be stored in this directory directly without creating a subdirectory.
 
 download-output-no-merge = Do not merge stream
+download-output-playlist = Do not merge stream, but write an index.m3u8 playlist of downloaded segments in the cache directory
 download-output-concat = Merge files using concat
 download-output-output = Output filename
 download-output-pipe = Pipe to stdout
 download-output-pipe-mux = Mux with ffmpeg. Only works when `--pipe` is set.
 download-output-pipe-to = Pipe to a file
+
+download-merger-subtitle-format = Output format of subtitles extracted from fMP4 tracks. Supports vtt, srt and ass.
+download-merger-progressive-mp4 = Rewrite merged fMP4 tracks into a progressive MP4 with a single moov box.
+download-merger-remux = Remux MPEG-TS streams into a single MP4 file without external tools.
+download-merger-incremental = Append segments to per-track files while downloading, leaving only the final mux after downloading.
+download-merger-on-failure = What to do when some segments failed to download: skip, gaps, split, or the max missing percentage like 5%
+download-merger-archive = Also merge segments into this file, for example to archive a stream while piping it to a player.
+download-merger-serve = Serve the stream over HTTP at this address, like 0.0.0.0:8080, as a live HLS playlist at /index.m3u8 for players on the network.
+download-merger-serve-window = Number of latest segments kept in the served playlist and the cache. Keep all segments by default.
+download-merger-timed-metadata = Extract timed metadata, like ID3 tags and emsg boxes, with their timestamps into this JSON-lines file while downloading.
@@ -2,6 +2,7 @@ shiori-about = 又一个直播下载器
 
 download-wait = 当未检测到直播流时，是否等待直播流开始
 download-url = 视频地址
+download-report = 将 JSON 格式的合并结果报告写入文件，为 `-` 时输出到标准输出
 
 download-http-headers = 设置 HTTP header，格式为 key: value
 download-http-cookies =
@@ -16,6 +17,13 @@ download-concurrency = 并发数
 download-segment-retries = 分块下载重试次数
 # download-segment-retry-delay = 设置下载失败后重试的延迟，单位为秒
 download-manifest-retries = manifest 下载重试次数
+download-skip-ads = 跳过由 EXT-X-CUE-OUT/EXT-X-CUE-IN、带 SCTE35-OUT 的 EXT-X-DATERANGE 或 SCTE-35 EventStream 标记的广告分片
+download-ad-url-pattern = 跳过 URL 匹配此正则表达式的分片，可多次指定
+download-ad-period-pattern = 跳过 id 匹配此正则表达式的 DASH Period，可多次指定
+download-skip-ads-dry-run = 仅列出将被作为广告跳过的分片，不实际跳过
+download-no-validate = 缓存前不检查下载的分片是否被截断、是否为 HTML 错误页面或 MPEG-TS 与 MP4 结构是否损坏。使用 --defer-decryption 时不检查分片
+download-segment-format = 对所有媒体分片使用指定的格式（如 `ts`、`mp4` 或 `aac`），而不是根据分片数据检测格式。用于检测结果错误的情况
+download-decrypt-defer-decryption = 缓存分片时不进行解密，并在缓存目录中记录分片的密钥。之后可使用 `shiori decrypt` 解密，密钥错误时可指定新的密钥。不能与内存缓存、输出或合并选项同时使用
 
 download-cache-in-menory-cache = 使用内存缓存，下载时不将缓存写入磁盘
 download-cache-temp-dir =
@@ -30,8 +38,19 @@ download-cache-cache-dir =
   文件会直接存储在该目录下，而不会创建子目录。为安全起见，请自行创建子目录。
 
 download-output-no-merge = 跳过合并
+download-output-playlist = 跳过合并，在缓存目录中写入引用已下载分片的 index.m3u8 播放列表
 download-output-concat = 使用 Concat 合并文件
 download-output-output = 输出文件名
 download-output-pipe = 输出到标准输出
 download-output-pipe-mux = 使用 FFmpeg 混流，仅在 `--pipe` 生效时有效
 download-output-pipe-to = 使用 Pipe 输出到指定路径
+
+download-merger-subtitle-format = 从 fMP4 轨道提取的字幕的输出格式，支持 vtt、srt 和 ass
+download-merger-progressive-mp4 = 将合并后的 fMP4 轨道重写为仅含单个 moov 的普通 MP4
+download-merger-remux = 不依赖外部工具，将 MPEG-TS 流重新封装为单个 MP4 文件
+download-merger-incremental = 下载时即将分片追加到各轨道文件，下载完成后仅需最终封装
+download-merger-on-failure = 部分分片下载失败时的处理方式：skip（跳过合并）、gaps（保留缺口合并）、split（在缺口处分段）或最大缺失比例（如 5%）
+download-merger-archive = 同时将分片合并到此文件，例如在管道输出到播放器的同时存档
+download-merger-serve = 在此地址（如 0.0.0.0:8080）通过 HTTP 提供直播 HLS 播放列表 /index.m3u8，供网络中的播放器观看
+download-merger-serve-window = 提供的播放列表与缓存中保留的最新分片数量，默认保留全部分片
+download-merger-timed-metadata = 下载时将 ID3 标签、emsg 等定时元数据及其时间戳提取到此 JSON-lines 文件
@@ -3,6 +3,7 @@ use clap_handler::Handler;
 
 use crate::ll;
 
+pub mod decrypt;
 pub mod download;
 pub mod inspect;
 pub mod merge;
@@ -33,5 +34,6 @@ pub enum ShioriCommand {
     #[clap(after_help = inspect::get_default_external_inspector().help())]
     Inspect(inspect::InspectCommand),
     Merge(merge::MergeCommand),
+    Decrypt(decrypt::DecryptCommand),
     Update(update::UpdateCommand),
 }
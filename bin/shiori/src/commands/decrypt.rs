@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use clap_handler::handler;
+use iori::decrypt::deferred::decrypt_cache_dir;
+
+#[derive(Parser, Clone, Default, Debug)]
+#[clap(name = "decrypt")]
+/// Decrypt segments downloaded with `--defer-decryption`
+pub struct DecryptCommand {
+    /// Key to use instead of the recorded ones. A hex key for AES-128 and SAMPLE-AES, or
+    /// `<kid>:<key>` pairs separated by `;` for CENC
+    #[clap(long)]
+    key: Option<String>,
+
+    #[clap(long = "shaka-packager", visible_alias = "shaka")]
+    shaka_packager_command: Option<PathBuf>,
+
+    /// Directory to write decrypted segments to. Defaults to `decrypted` in the cache directory
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// Cache directory of the download
+    cache_dir: PathBuf,
+}
+
+#[handler(DecryptCommand)]
+pub async fn decrypt_command(me: DecryptCommand) -> anyhow::Result<()> {
+    let output = me.output.unwrap_or_else(|| me.cache_dir.join("decrypted"));
+    let count = decrypt_cache_dir(
+        &me.cache_dir,
+        &output,
+        me.key.as_deref(),
+        me.shaka_packager_command,
+    )
+    .await?;
+
+    log::info!(
+        "Decrypted {count} segments into {}. Use `shiori merge` to merge them.",
+        output.display()
+    );
+    Ok(())
+}
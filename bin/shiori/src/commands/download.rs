@@ -8,6 +8,7 @@ use clap::{Args, Parser};
 use clap_handler::handler;
 use fake_user_agent::get_chrome_rua;
 use iori::{
+    ad::AdFilter,
     cache::{
         opendal::{services, Operator},
         IoriCache,
@@ -15,16 +16,24 @@ use iori::{
     dash::live::CommonDashLiveSource,
     download::ParallelDownloader,
     hls::HlsLiveSource,
-    merge::IoriMerger,
+    merge::{
+        AutoMerger, ConcatAfterMerger, FailurePolicy, IncrementalMerger, IoriMerger, RemuxMerger,
+        ServeMerger, TeeMerger,
+    },
+    metadata::Metadata,
+    processor::{SegmentValidator, TimedMetadataProcessor},
     raw::{HttpFileSource, RawDataSource},
+    subtitle::SubtitleFormat,
     utils::{detect_manifest_type, DuplicateOutputFileNamer},
-    HttpClient, PlaylistType,
+    HttpClient, PlaylistType, SegmentFormat,
 };
+use regex::Regex;
 use reqwest::{
     header::{HeaderMap, HeaderName, HeaderValue},
     Client, IntoUrl,
 };
 use std::{
+    net::SocketAddr,
     num::NonZeroU32,
     path::PathBuf,
     str::FromStr,
@@ -49,6 +58,9 @@ where
     #[clap(flatten)]
     pub output: OutputOptions,
 
+    #[clap(flatten)]
+    pub merger: MergerOptions,
+
     #[clap(flatten)]
     pub decrypt: DecryptOptions,
 
@@ -59,6 +71,10 @@ where
     #[clap(about_ll = "download-wait")]
     pub wait: bool,
 
+    #[clap(long)]
+    #[clap(about_ll = "download-report")]
+    pub report: Option<PathBuf>,
+
     #[clap(flatten)]
     pub inspector_options: I,
 
@@ -86,13 +102,32 @@ where
                 })?,
         };
 
-        let downloader = ParallelDownloader::builder()
+        let ad_filter = self.download.ad_filter();
+        let defer_decryption = self.decrypt.defer_decryption;
+        let merger = if defer_decryption {
+            IoriMerger::deferred_decryption()
+        } else {
+            self.output
+                .into_merger(&self.merger)?
+                .with_metadata(self.extra.metadata)
+        };
+        let mut downloader = ParallelDownloader::builder()
             .concurrency(self.download.concurrency)
             .retries(self.download.segment_retries)
             .cache(self.cache.into_cache()?)
-            .merger(self.output.into_merger());
+            .merger(merger);
+        // encrypted segments can not be checked before they are decrypted
+        if !self.download.no_validate && !defer_decryption {
+            downloader = downloader.processor(SegmentValidator::new());
+        }
+        if let Some(timed_metadata) = self.merger.timed_metadata {
+            downloader = downloader.processor(TimedMetadataProcessor::new(timed_metadata));
+        }
+        if let Some(format) = self.download.segment_format {
+            downloader = downloader.segment_format(format);
+        }
 
-        match playlist_type {
+        let report = match playlist_type {
             PlaylistType::HLS | PlaylistType::Unknown => {
                 if matches!(playlist_type, PlaylistType::Unknown) {
                     log::warn!(
@@ -106,33 +141,51 @@ where
                     self.decrypt.key.as_deref(),
                     self.decrypt.shaka_packager_command,
                 )
-                .with_retry(self.download.manifest_retries);
-                downloader.download(source).await?;
+                .with_retry(self.download.manifest_retries)
+                .with_deferred_decryption(defer_decryption)
+                .with_ad_filter(ad_filter);
+                downloader.download(source).await?
             }
             PlaylistType::DASH => {
                 let source = CommonDashLiveSource::new(
                     client,
                     self.url.parse()?,
                     self.decrypt.key.as_deref(),
-                    // self.decrypt.shaka_packager_command.clone(),
-                )?;
-                downloader.download(source).await?;
+                )?
+                .with_shaka_packager(self.decrypt.shaka_packager_command)
+                .with_deferred_decryption(defer_decryption)
+                .with_ad_filter(ad_filter);
+                downloader.download(source).await?
             }
             PlaylistType::Raw(ext) => {
                 if self.url.starts_with("http") {
                     let source = HttpFileSource::new(client, self.url, ext);
-                    downloader.download(source).await?;
+                    downloader.download(source).await?
                 } else {
                     let source = RawDataSource::new(self.url, ext);
-                    downloader.download(source).await?;
+                    downloader.download(source).await?
                 }
             }
+        };
+
+        if let Some(path) = self.report {
+            let report = serde_json::to_string_pretty(&report)?;
+            if path.as_os_str() == "-" {
+                println!("{report}");
+            } else {
+                tokio::fs::write(path, report).await?;
+            }
         }
 
         Ok(())
     }
 
     fn merge(mut self, from: Self) -> Self {
+        self.extra.metadata = from.extra.metadata;
+        if !self.extra.metadata.is_empty() && self.extra.metadata.source_url.is_none() {
+            self.extra.metadata.source_url = Some(self.url.clone());
+        }
+
         self.url = from.url;
         self.http.headers.extend(from.http.headers);
         self.http.cookies.extend(from.http.cookies);
@@ -142,6 +195,9 @@ where
         if self.output.output.is_none() {
             self.output.output = from.output.output;
         }
+        if self.output.pipe_mux_streams.is_none() {
+            self.output.pipe_mux_streams = from.output.pipe_mux_streams;
+        }
         self.extra.playlist_type = from.extra.playlist_type;
 
         self
@@ -218,6 +274,30 @@ pub struct DownloadOptions {
     #[clap(long, default_value = "3")]
     #[clap(about_ll = "download-manifest-retries")]
     pub manifest_retries: u32,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-skip-ads")]
+    pub skip_ads: bool,
+
+    #[clap(long = "ad-url-pattern", value_parser = parse_regex)]
+    #[clap(about_ll = "download-ad-url-pattern")]
+    pub ad_url_patterns: Vec<Regex>,
+
+    #[clap(long = "ad-period-pattern", value_parser = parse_regex)]
+    #[clap(about_ll = "download-ad-period-pattern")]
+    pub ad_period_patterns: Vec<Regex>,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-skip-ads-dry-run")]
+    pub skip_ads_dry_run: bool,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-no-validate")]
+    pub no_validate: bool,
+
+    #[clap(long, value_parser = parse_segment_format)]
+    #[clap(about_ll = "download-segment-format")]
+    pub segment_format: Option<SegmentFormat>,
 }
 
 impl Default for DownloadOptions {
@@ -226,10 +306,34 @@ impl Default for DownloadOptions {
             concurrency: NonZeroU32::new(5).unwrap(),
             segment_retries: 5,
             manifest_retries: 3,
+            skip_ads: false,
+            ad_url_patterns: Vec::new(),
+            ad_period_patterns: Vec::new(),
+            skip_ads_dry_run: false,
+            no_validate: false,
+            segment_format: None,
         }
     }
 }
 
+impl DownloadOptions {
+    fn ad_filter(&self) -> AdFilter {
+        AdFilter::default()
+            .with_cue_tags(self.skip_ads || self.skip_ads_dry_run)
+            .with_url_patterns(self.ad_url_patterns.clone())
+            .with_period_patterns(self.ad_period_patterns.clone())
+            .with_dry_run(self.skip_ads_dry_run)
+    }
+}
+
+fn parse_regex(input: &str) -> Result<Regex, String> {
+    Regex::new(input).map_err(|e| e.to_string())
+}
+
+fn parse_segment_format(input: &str) -> Result<SegmentFormat, String> {
+    input.parse().map_err(|e: iori::IoriError| e.to_string())
+}
+
 #[derive(Args, Clone, Debug, Default)]
 pub struct CacheOptions {
     #[clap(short = 'm', long)]
@@ -283,12 +387,36 @@ pub struct DecryptOptions {
 
     #[clap(long = "shaka-packager", visible_alias = "shaka")]
     pub shaka_packager_command: Option<PathBuf>,
+
+    // deferred segments are only cached, so output and merging options would be ignored
+    #[clap(long, conflicts_with_all = [
+        "in_memory_cache",
+        "no_merge",
+        "playlist",
+        "concat",
+        "output",
+        "pipe",
+        "pipe_mux",
+        "pipe_to",
+        "progressive_mp4",
+        "remux",
+        "incremental",
+        "on_failure",
+        "archive",
+        "serve",
+        "serve_window",
+    ])]
+    #[clap(about_ll = "download-decrypt-defer-decryption")]
+    pub defer_decryption: bool,
 }
 
 #[derive(Clone, Default)]
 pub struct ExtraOptions {
     /// Force Dash mode
     pub playlist_type: Option<PlaylistType>,
+
+    /// Metadata of the inspected resource, written into the output file
+    pub metadata: Metadata,
 }
 
 #[derive(Args, Clone, Debug, Default)]
@@ -298,6 +426,10 @@ pub struct OutputOptions {
     #[clap(about_ll = "download-output-no-merge")]
     pub no_merge: bool,
 
+    #[clap(long)]
+    #[clap(about_ll = "download-output-playlist")]
+    pub playlist: bool,
+
     #[clap(long)]
     #[clap(about_ll = "download-output-concat")]
     pub concat: bool,
@@ -317,15 +449,108 @@ pub struct OutputOptions {
     #[clap(long)]
     #[clap(about_ll = "download-output-pipe-to")]
     pub pipe_to: Option<PathBuf>,
+
+    /// Number of streams to mux with `pipe_mux`, as reported by the inspector. Only the first
+    /// stream is muxed if unknown.
+    #[clap(skip)]
+    pub pipe_mux_streams: Option<usize>,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct MergerOptions {
+    #[clap(long, default_value = "vtt", value_parser = parse_subtitle_format)]
+    #[clap(about_ll = "download-merger-subtitle-format")]
+    pub subtitle_format: SubtitleFormat,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-merger-progressive-mp4")]
+    pub progressive_mp4: bool,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-merger-remux")]
+    pub remux: bool,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-merger-incremental")]
+    pub incremental: bool,
+
+    #[clap(long, value_parser = parse_failure_policy)]
+    #[clap(about_ll = "download-merger-on-failure")]
+    pub on_failure: Option<FailurePolicy>,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-merger-archive")]
+    pub archive: Option<PathBuf>,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-merger-serve")]
+    pub serve: Option<SocketAddr>,
+
+    #[clap(long, requires = "serve")]
+    #[clap(about_ll = "download-merger-serve-window")]
+    pub serve_window: Option<usize>,
+
+    #[clap(long)]
+    #[clap(about_ll = "download-merger-timed-metadata")]
+    pub timed_metadata: Option<PathBuf>,
+}
+
+impl MergerOptions {
+    fn auto_merger(&self, output: PathBuf) -> IoriMerger {
+        let mut auto = AutoMerger::new(output, false)
+            .with_subtitle_format(self.subtitle_format)
+            .with_progressive_mp4(self.progressive_mp4);
+        if let Some(policy) = self.on_failure {
+            auto = auto.with_failure_policy(policy);
+        }
+        IoriMerger::Auto(auto)
+    }
+}
+
+fn parse_subtitle_format(input: &str) -> Result<SubtitleFormat, String> {
+    input.parse().map_err(|e: iori::IoriError| e.to_string())
+}
+
+fn parse_failure_policy(input: &str) -> Result<FailurePolicy, String> {
+    input.parse().map_err(|e: iori::IoriError| e.to_string())
 }
 
 impl OutputOptions {
-    pub fn into_merger(self) -> IoriMerger {
+    pub fn into_merger(self, merger: &MergerOptions) -> anyhow::Result<IoriMerger> {
+        let mut mergers = Vec::new();
+        if let Some(addr) = merger.serve {
+            let mut serve = ServeMerger::new(addr, false)?;
+            if let Some(window) = merger.serve_window {
+                serve = serve.with_window(window);
+            }
+            mergers.push(IoriMerger::Serve(serve));
+        }
+        let primary = mergers.len();
+        mergers.push(self.into_output_merger(merger));
+        if let Some(archive) = &merger.archive {
+            mergers.push(merger.auto_merger(archive.clone()));
+        }
+
+        Ok(if mergers.len() == 1 {
+            mergers.pop().unwrap()
+        } else {
+            IoriMerger::Tee(TeeMerger::new(mergers).with_primary(primary))
+        })
+    }
+
+    fn into_output_merger(self, merger: &MergerOptions) -> IoriMerger {
         if self.no_merge {
             IoriMerger::skip()
+        } else if self.playlist {
+            IoriMerger::playlist()
         } else if self.pipe || self.pipe_mux || self.pipe_to.is_some() {
             if self.pipe_mux {
-                IoriMerger::pipe_mux(true, self.pipe_to.unwrap_or("-".into()), None)
+                IoriMerger::pipe_mux(
+                    true,
+                    self.pipe_to.unwrap_or("-".into()),
+                    None,
+                    self.pipe_mux_streams.unwrap_or(1),
+                )
             } else if let Some(file) = self.pipe_to {
                 IoriMerger::pipe_to_file(true, file)
             } else {
@@ -343,9 +568,26 @@ impl OutputOptions {
             }
 
             if self.concat {
-                IoriMerger::concat(output, false)
+                let mut concat = ConcatAfterMerger::new(output, false);
+                if let Some(policy) = merger.on_failure {
+                    concat = concat.with_failure_policy(policy);
+                }
+                IoriMerger::Concat(concat)
+            } else if merger.remux {
+                let mut remux = RemuxMerger::new(output, false);
+                if let Some(policy) = merger.on_failure {
+                    remux = remux.with_failure_policy(policy);
+                }
+                IoriMerger::Remux(remux)
+            } else if merger.incremental {
+                let mut incremental = IncrementalMerger::new(output, false)
+                    .with_subtitle_format(merger.subtitle_format);
+                if let Some(policy) = merger.on_failure {
+                    incremental = incremental.with_failure_policy(policy);
+                }
+                IoriMerger::Incremental(incremental)
             } else {
-                IoriMerger::auto(output, false)
+                merger.auto_merger(output)
             }
         } else {
             unreachable!()
@@ -407,6 +649,7 @@ where
             },
             extra: ExtraOptions {
                 playlist_type: Some(data.playlist_type),
+                metadata: data.metadata,
             },
             output: OutputOptions {
                 output: data.title.map(|title| {
@@ -435,6 +678,7 @@ where
                     filename.into()
                 }),
                 pipe_mux: data.streams_hint.unwrap_or(1) > 1,
+                pipe_mux_streams: data.streams_hint.map(|streams| streams as usize),
                 ..Default::default()
             },
             url: data.playlist_url,
@@ -1,20 +1,29 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use clap::Parser;
 use clap_handler::handler;
 use iori::{
-    cache::CacheSource,
+    cache::{
+        file::{read_index, INDEX_FILE},
+        CacheSource,
+    },
+    decrypt::deferred::SEGMENTS_FILE,
+    hls::m3u8_rs::{self, AlternativeMediaType, Playlist},
     merge::{IoriMerger, Merger},
-    SegmentFormat, SegmentInfo,
+    SegmentFormat, SegmentInfo, SegmentType, SNIFF_SIZE,
 };
 use tokio::{
     fs::{read_dir, File},
-    io::BufReader,
+    io::{AsyncReadExt, BufReader},
     sync::Mutex,
 };
 
 struct ExistingLocalCache {
-    files: Mutex<HashMap<u64, PathBuf>>,
+    files: Mutex<HashMap<(u64, u64), PathBuf>>,
 }
 
 impl ExistingLocalCache {
@@ -25,7 +34,10 @@ impl ExistingLocalCache {
     }
 
     async fn add_file(&self, segment: &SegmentInfo, file: PathBuf) {
-        self.files.lock().await.insert(segment.sequence, file);
+        self.files
+            .lock()
+            .await
+            .insert((segment.stream_id, segment.sequence), file);
     }
 }
 
@@ -42,14 +54,18 @@ impl CacheSource for ExistingLocalCache {
         segment: &iori::SegmentInfo,
     ) -> iori::IoriResult<iori::cache::CacheSourceReader> {
         let lock = self.files.lock().await;
-        let file = lock.get(&segment.sequence).unwrap();
+        let file = lock.get(&(segment.stream_id, segment.sequence)).unwrap();
         let file = File::open(file).await?;
         let file = BufReader::new(file);
         Ok(Box::new(file))
     }
 
     async fn segment_path(&self, segment: &SegmentInfo) -> Option<PathBuf> {
-        self.files.lock().await.get(&segment.sequence).cloned()
+        self.files
+            .lock()
+            .await
+            .get(&(segment.stream_id, segment.sequence))
+            .cloned()
     }
 
     async fn invalidate(&self, _segment: &iori::SegmentInfo) -> iori::IoriResult<()> {
@@ -67,6 +83,9 @@ pub struct MergeCommand {
     #[clap(long)]
     pub concat: bool,
 
+    #[clap(long)]
+    pub remux: bool,
+
     #[clap(long, default_value = "ts")]
     pub format: SegmentFormat,
 
@@ -78,42 +97,59 @@ pub struct MergeCommand {
 
 #[handler(MergeCommand)]
 pub async fn merge_command(me: MergeCommand) -> anyhow::Result<()> {
-    eprintln!("{:#?}", me);
-
     let cache = Arc::new(ExistingLocalCache::new());
     let mut merger = if me.concat {
         IoriMerger::concat(me.output, true)
+    } else if me.remux {
+        IoriMerger::remux(me.output, true)
     } else {
         IoriMerger::auto(me.output, true)
     };
 
-    let files = if me.inputs.len() == 1 && me.inputs[0].is_dir() {
-        // read all files in directory and merge
-        let mut dir = read_dir(&me.inputs[0]).await?;
-        let mut files = Vec::new();
-        while let Some(entry) = dir.next_entry().await? {
-            let path = entry.path();
-            if path.ends_with(".DS_Store") {
-                continue;
-            }
+    let is_playlist =
+        me.inputs.len() == 1 && me.inputs[0].extension().is_some_and(|ext| ext == "m3u8");
+    let is_indexed = me.inputs.len() == 1 && me.inputs[0].join(INDEX_FILE).is_file();
+    let segments = if is_playlist {
+        load_playlist(&me.inputs[0]).await?
+    } else if is_indexed {
+        load_index(&me.inputs[0]).await?
+    } else {
+        let files = if me.inputs.len() == 1 && me.inputs[0].is_dir() {
+            // read all files in directory and merge
+            let mut dir = read_dir(&me.inputs[0]).await?;
+            let mut files = Vec::new();
+            while let Some(entry) = dir.next_entry().await? {
+                let path = entry.path();
+                // skip the list of segments downloaded with deferred decryption
+                if path.ends_with(".DS_Store") || path.ends_with(SEGMENTS_FILE) {
+                    continue;
+                }
 
-            if path.is_file() {
-                files.push(path);
+                if path.is_file() {
+                    files.push(path);
+                }
             }
-        }
-        files.sort();
+            files.sort();
+            files
+        } else {
+            me.inputs
+        };
+
         files
-    } else {
-        me.inputs
+            .into_iter()
+            .enumerate()
+            .map(|(sequence, input)| {
+                let segment = iori::SegmentInfo {
+                    sequence: sequence as u64,
+                    format: me.format.clone(),
+                    ..Default::default()
+                };
+                (segment, input)
+            })
+            .collect()
     };
 
-    eprintln!("{:#?}", files);
-    for (sequence, input) in files.into_iter().enumerate() {
-        let segment = iori::SegmentInfo {
-            sequence: sequence as u64,
-            format: me.format.clone(),
-            ..Default::default()
-        };
+    for (segment, input) in segments {
         cache.add_file(&segment, input).await;
         merger.update(segment, cache.clone()).await?;
     }
@@ -122,3 +158,99 @@ pub async fn merge_command(me: MergeCommand) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Load segments from a playlist written by `shiori download --playlist`.
+///
+/// Each media playlist is a stream. Segments are read as whole files, which include their
+/// initialization segments, so byte ranges in the playlist are ignored.
+async fn load_playlist(path: &Path) -> anyhow::Result<Vec<(SegmentInfo, PathBuf)>> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let streams = match parse_playlist(path).await? {
+        Playlist::MasterPlaylist(master) => {
+            let variants = master
+                .variants
+                .into_iter()
+                .map(|v| (v.uri, SegmentType::Video));
+            let alternatives = master.alternatives.into_iter().filter_map(|a| {
+                let r#type = match a.media_type {
+                    AlternativeMediaType::Audio => SegmentType::Audio,
+                    AlternativeMediaType::Subtitles => SegmentType::Subtitle,
+                    _ => SegmentType::Video,
+                };
+                a.uri.map(|uri| (uri, r#type))
+            });
+
+            let mut streams = Vec::new();
+            for (uri, r#type) in variants.chain(alternatives) {
+                let Playlist::MediaPlaylist(playlist) = parse_playlist(&dir.join(uri)).await?
+                else {
+                    anyhow::bail!("Nested master playlist is not supported");
+                };
+                streams.push((playlist, r#type));
+            }
+            streams
+        }
+        Playlist::MediaPlaylist(playlist) => vec![(playlist, SegmentType::Video)],
+    };
+
+    let mut segments = Vec::new();
+    for (stream_id, (playlist, r#type)) in streams.into_iter().enumerate() {
+        for (sequence, segment) in playlist.segments.into_iter().enumerate() {
+            let file = dir.join(&segment.uri);
+            let info = SegmentInfo {
+                stream_id: stream_id as u64,
+                sequence: sequence as u64,
+                file_name: file
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or(segment.uri.clone()),
+                r#type,
+                format: sniff_format(&file, SegmentFormat::from_filename(&segment.uri)).await,
+                duration: Some(segment.duration as f64),
+                ..Default::default()
+            };
+            segments.push((info, file));
+        }
+    }
+    Ok(segments)
+}
+
+/// Load segments from the index of a cache directory, with the formats detected while
+/// downloading.
+async fn load_index(dir: &Path) -> anyhow::Result<Vec<(SegmentInfo, PathBuf)>> {
+    let segments = read_index(dir)
+        .await?
+        .into_iter()
+        .map(|segment| {
+            let info = SegmentInfo {
+                stream_id: segment.stream_id,
+                sequence: segment.sequence,
+                file_name: segment.file_name.clone(),
+                r#type: segment.r#type,
+                format: segment.segment_format(),
+                ..Default::default()
+            };
+            (info, dir.join(&segment.file_name))
+        })
+        .collect();
+    Ok(segments)
+}
+
+/// Correct the format from the file name with the first bytes of the file.
+async fn sniff_format(file: &Path, format: SegmentFormat) -> SegmentFormat {
+    let Ok(file) = File::open(file).await else {
+        return format;
+    };
+    let mut head = Vec::with_capacity(SNIFF_SIZE);
+    match file.take(SNIFF_SIZE as u64).read_to_end(&mut head).await {
+        Ok(_) => format.refine(&head),
+        Err(_) => format,
+    }
+}
+
+async fn parse_playlist(path: &Path) -> anyhow::Result<Playlist> {
+    let data = tokio::fs::read(path).await?;
+    m3u8_rs::parse_playlist_res(&data)
+        .map_err(|e| anyhow::anyhow!("Failed to parse {}: {e:?}", path.display()))
+}
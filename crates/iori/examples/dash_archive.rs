@@ -1,8 +1,8 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use iori::{
-    cache::file::FileCacheSource, dash::archive::CommonDashArchiveSource,
-    download::SequencialDownloader, merge::SkipMerger,
+    cache::file::FileCacheSource, dash::live::CommonDashLiveSource, download::SequencialDownloader,
+    merge::SkipMerger,
 };
 
 #[tokio::main]
@@ -21,8 +21,8 @@ async fn main() -> anyhow::Result<()> {
     let started_at = started_at.duration_since(UNIX_EPOCH).unwrap().as_millis();
     let output_dir = std::env::temp_dir().join(format!("iori_save_{}", started_at));
 
-    let source = CommonDashArchiveSource::new(Default::default(), url, key.as_deref(), None)?;
-    let merger = SkipMerger;
+    let source = CommonDashLiveSource::new(Default::default(), url.parse()?, key.as_deref())?;
+    let merger = SkipMerger::new();
     let cache = FileCacheSource::new(output_dir)?;
 
     let mut downloader = SequencialDownloader::new(source, merger, cache);
@@ -0,0 +1,219 @@
+//! Skipping of server-side inserted ads.
+//!
+//! An [AdFilter] is an opt-in filter of HLS and DASH sources. Segments inside detected ad
+//! breaks are dropped before they reach the downloader. Ad breaks are detected by:
+//!
+//! - Cue tags: `EXT-X-CUE-OUT`/`EXT-X-CUE-IN` or `EXT-X-DATERANGE` with `SCTE35-OUT` in HLS
+//!   playlists, and SCTE-35 `EventStream`s in DASH manifests. A break without a duration
+//!   lasts until the cue-in tag or the next discontinuity.
+//! - URL patterns matching segment URLs, for ads served from another host or path.
+//! - Period id patterns matching DASH periods, for ads inserted as separate periods.
+use std::fmt::Display;
+
+use regex::Regex;
+
+use crate::chapter::{SegmentEvent, SegmentEventKind};
+
+#[derive(Debug, Clone, Default)]
+pub struct AdFilter {
+    /// Detect ad breaks from cue tags and SCTE-35 events.
+    cue_tags: bool,
+    /// Segments with URLs matching any of the patterns are ads.
+    url_patterns: Vec<Regex>,
+    /// DASH periods with ids matching any of the patterns are ads.
+    period_patterns: Vec<Regex>,
+    /// Only log segments which would be skipped.
+    dry_run: bool,
+}
+
+/// Why a segment is considered as an ad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdReason {
+    CueTag,
+    UrlPattern,
+    PeriodPattern,
+}
+
+impl Display for AdReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdReason::CueTag => write!(f, "cue tag"),
+            AdReason::UrlPattern => write!(f, "URL pattern"),
+            AdReason::PeriodPattern => write!(f, "period id pattern"),
+        }
+    }
+}
+
+impl AdFilter {
+    pub fn with_cue_tags(mut self, cue_tags: bool) -> Self {
+        self.cue_tags = cue_tags;
+        self
+    }
+
+    pub fn with_url_patterns(mut self, url_patterns: Vec<Regex>) -> Self {
+        self.url_patterns = url_patterns;
+        self
+    }
+
+    pub fn with_period_patterns(mut self, period_patterns: Vec<Regex>) -> Self {
+        self.period_patterns = period_patterns;
+        self
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Whether any heuristic is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.cue_tags || !self.url_patterns.is_empty() || !self.period_patterns.is_empty()
+    }
+
+    /// Check a segment, given whether it is inside an ad break marked by cue tags.
+    pub fn check_segment(&self, url: &str, in_cue_break: bool) -> Option<AdReason> {
+        if self.cue_tags && in_cue_break {
+            Some(AdReason::CueTag)
+        } else if self.url_patterns.iter().any(|p| p.is_match(url)) {
+            Some(AdReason::UrlPattern)
+        } else {
+            None
+        }
+    }
+
+    /// Check a DASH period by its id.
+    pub fn check_period(&self, id: Option<&str>) -> Option<AdReason> {
+        let id = id?;
+        self.period_patterns
+            .iter()
+            .any(|p| p.is_match(id))
+            .then_some(AdReason::PeriodPattern)
+    }
+
+    /// Whether to drop an ad. In dry-run mode, the ad is only logged and kept.
+    pub(crate) fn should_drop(&self, target: impl Display, reason: AdReason) -> bool {
+        if self.dry_run {
+            tracing::info!("[dry run] Would skip ad {target} ({reason})");
+            false
+        } else {
+            tracing::debug!("Skipping ad {target} ({reason})");
+            true
+        }
+    }
+}
+
+/// State of cue tag ad breaks across segments of a stream.
+#[derive(Debug, Default)]
+pub(crate) struct AdBreakState {
+    /// Remaining seconds of the current ad break. Infinite if the duration is unknown.
+    remaining: Option<f64>,
+}
+
+impl AdBreakState {
+    /// Update the state with a segment in order, returning whether the segment is inside
+    /// an ad break.
+    pub(crate) fn update(
+        &mut self,
+        events: &[SegmentEvent],
+        duration: f64,
+        discontinuity: bool,
+    ) -> bool {
+        let mut starts_here = false;
+        for event in events {
+            match event.kind {
+                SegmentEventKind::AdStart => {
+                    self.remaining = Some(event.duration.unwrap_or(f64::INFINITY));
+                    starts_here = true;
+                }
+                SegmentEventKind::AdEnd => self.remaining = None,
+                SegmentEventKind::Marker => {}
+            }
+        }
+        // an open-ended break ends at the next discontinuity
+        if !starts_here && discontinuity && self.remaining == Some(f64::INFINITY) {
+            self.remaining = None;
+        }
+
+        match self.remaining {
+            // tolerate rounding of segment durations
+            Some(remaining) if remaining > 0.1 => {
+                self.remaining = Some(remaining - duration);
+                true
+            }
+            _ => {
+                self.remaining = None;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ad_break_state() {
+        let cue_out =
+            |duration| vec![SegmentEvent::new(SegmentEventKind::AdStart).with_duration(duration)];
+        let cue_in = vec![SegmentEvent::new(SegmentEventKind::AdEnd)];
+
+        let mut state = AdBreakState::default();
+        assert!(!state.update(&[], 6., false));
+        // 12 seconds of ads in 6.006 second segments
+        assert!(state.update(&cue_out(Some(12.)), 6.006, true));
+        assert!(state.update(&[], 6.006, false));
+        assert!(!state.update(&[], 6.006, true));
+
+        // until cue-in
+        assert!(state.update(&cue_out(None), 6., true));
+        assert!(state.update(&[], 6., false));
+        assert!(!state.update(&cue_in, 6., false));
+
+        // until discontinuity
+        assert!(state.update(&cue_out(None), 6., true));
+        assert!(state.update(&[], 6., false));
+        assert!(!state.update(&[], 6., true));
+    }
+
+    #[test]
+    fn test_ad_filter() {
+        let filter = AdFilter::default();
+        assert!(!filter.is_enabled());
+        assert_eq!(
+            filter.check_segment("https://ads.example.com/0.ts", true),
+            None
+        );
+
+        let filter = AdFilter::default()
+            .with_cue_tags(true)
+            .with_url_patterns(vec![Regex::new(r"//ads\.").unwrap()])
+            .with_period_patterns(vec![Regex::new(r"^ad-").unwrap()]);
+        assert!(filter.is_enabled());
+        assert_eq!(
+            filter.check_segment("https://cdn.example.com/0.ts", true),
+            Some(AdReason::CueTag)
+        );
+        assert_eq!(
+            filter.check_segment("https://ads.example.com/0.ts", false),
+            Some(AdReason::UrlPattern)
+        );
+        assert_eq!(
+            filter.check_segment("https://cdn.example.com/0.ts", false),
+            None
+        );
+        assert_eq!(
+            filter.check_period(Some("ad-1")),
+            Some(AdReason::PeriodPattern)
+        );
+        assert_eq!(filter.check_period(Some("main")), None);
+        assert_eq!(filter.check_period(None), None);
+
+        assert!(filter.should_drop("segment", AdReason::CueTag));
+        assert!(
+            !filter
+                .with_dry_run(true)
+                .should_drop("segment", AdReason::CueTag)
+        );
+    }
+}
@@ -4,6 +4,7 @@ pub mod memory;
 pub mod opendal;
 
 use crate::{error::IoriResult, SegmentInfo};
+use futures::future::BoxFuture;
 use std::{future::Future, path::PathBuf, sync::Arc};
 use tokio::io::{AsyncRead, AsyncWrite};
 
@@ -35,6 +36,22 @@ pub trait CacheSource: Send + Sync + 'static {
     /// Clear the cache source.
     fn clear(&self) -> impl Future<Output = IoriResult<()>> + Send;
 
+    /// Keep segments after they are read, until they are invalidated or cleared.
+    ///
+    /// Cache sources which drop a segment once it is read, like
+    /// [MemoryCacheSource](memory::MemoryCacheSource), must be told so when a segment is read
+    /// more than once.
+    fn retain_segments(&self) {}
+
+    /// Record a downloaded segment with its detected format, so that the cache can be merged
+    /// later without the source.
+    fn record_segment(
+        &self,
+        _segment: &SegmentInfo,
+    ) -> impl Future<Output = IoriResult<()>> + Send {
+        async { Ok(()) }
+    }
+
     /// Hint a location for the cached segments.
     fn location_hint(&self) -> Option<String> {
         None
@@ -71,11 +88,87 @@ where
         self.as_ref().clear()
     }
 
+    fn retain_segments(&self) {
+        self.as_ref().retain_segments()
+    }
+
+    fn record_segment(&self, segment: &SegmentInfo) -> impl Future<Output = IoriResult<()>> + Send {
+        self.as_ref().record_segment(segment)
+    }
+
     fn location_hint(&self) -> Option<String> {
         self.as_ref().location_hint()
     }
 }
 
+/// Object-safe version of [CacheSource], for mergers which keep a cache of any type.
+pub(crate) trait DynCacheSource: Send + Sync {
+    fn open_writer<'a>(
+        &'a self,
+        segment: &'a SegmentInfo,
+    ) -> BoxFuture<'a, IoriResult<Option<CacheSourceWriter>>>;
+
+    fn open_reader<'a>(
+        &'a self,
+        segment: &'a SegmentInfo,
+    ) -> BoxFuture<'a, IoriResult<CacheSourceReader>>;
+
+    fn segment_path<'a>(&'a self, segment: &'a SegmentInfo) -> BoxFuture<'a, Option<PathBuf>>;
+
+    fn invalidate<'a>(&'a self, segment: &'a SegmentInfo) -> BoxFuture<'a, IoriResult<()>>;
+
+    fn clear(&self) -> BoxFuture<'_, IoriResult<()>>;
+
+    fn retain_segments(&self);
+
+    fn record_segment<'a>(&'a self, segment: &'a SegmentInfo) -> BoxFuture<'a, IoriResult<()>>;
+
+    fn location_hint(&self) -> Option<String>;
+}
+
+impl<C> DynCacheSource for C
+where
+    C: CacheSource,
+{
+    fn open_writer<'a>(
+        &'a self,
+        segment: &'a SegmentInfo,
+    ) -> BoxFuture<'a, IoriResult<Option<CacheSourceWriter>>> {
+        Box::pin(CacheSource::open_writer(self, segment))
+    }
+
+    fn open_reader<'a>(
+        &'a self,
+        segment: &'a SegmentInfo,
+    ) -> BoxFuture<'a, IoriResult<CacheSourceReader>> {
+        Box::pin(CacheSource::open_reader(self, segment))
+    }
+
+    fn segment_path<'a>(&'a self, segment: &'a SegmentInfo) -> BoxFuture<'a, Option<PathBuf>> {
+        Box::pin(CacheSource::segment_path(self, segment))
+    }
+
+    fn invalidate<'a>(&'a self, segment: &'a SegmentInfo) -> BoxFuture<'a, IoriResult<()>> {
+        Box::pin(CacheSource::invalidate(self, segment))
+    }
+
+    fn clear(&self) -> BoxFuture<'_, IoriResult<()>> {
+        Box::pin(CacheSource::clear(self))
+    }
+
+    fn retain_segments(&self) {
+        CacheSource::retain_segments(self)
+    }
+
+    fn record_segment<'a>(&'a self, segment: &'a SegmentInfo) -> BoxFuture<'a, IoriResult<()>> {
+        Box::pin(CacheSource::record_segment(self, segment))
+    }
+
+    fn location_hint(&self) -> Option<String> {
+        CacheSource::location_hint(self)
+    }
+}
+
 pub enum IoriCache {
     Memory(memory::MemoryCacheSource),
     File(file::FileCacheSource),
@@ -154,6 +247,24 @@ impl CacheSource for IoriCache {
         }
     }
 
+    fn retain_segments(&self) {
+        match self {
+            IoriCache::Memory(cache) => cache.retain_segments(),
+            IoriCache::File(cache) => cache.retain_segments(),
+            #[cfg(feature = "opendal")]
+            IoriCache::Opendal(cache) => cache.retain_segments(),
+        }
+    }
+
+    async fn record_segment(&self, segment: &SegmentInfo) -> IoriResult<()> {
+        match self {
+            IoriCache::Memory(cache) => cache.record_segment(segment).await,
+            IoriCache::File(cache) => cache.record_segment(segment).await,
+            #[cfg(feature = "opendal")]
+            IoriCache::Opendal(cache) => cache.record_segment(segment).await,
+        }
+    }
+
     fn location_hint(&self) -> Option<String> {
         match self {
             IoriCache::Memory(cache) => cache.location_hint(),
@@ -1,10 +1,69 @@
 use super::{CacheSource, CacheSourceReader, CacheSourceWriter};
-use crate::{error::IoriResult, IoriError};
-use std::path::PathBuf;
-use tokio::fs::File;
+use crate::{error::IoriResult, IoriError, SegmentFormat, SegmentInfo, SegmentType};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+    task::JoinHandle,
+};
+
+/// Name of the JSON-lines file in the cache directory, which records downloaded segments
+/// with their detected formats.
+pub const INDEX_FILE: &str = "index.jsonl";
+
+/// A segment recorded in [INDEX_FILE].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexedSegment {
+    pub stream_id: u64,
+    pub sequence: u64,
+    /// Name of the cached file in the cache directory.
+    pub file_name: String,
+    pub r#type: SegmentType,
+    /// Extension of the detected format, like `ts` or `mp4`.
+    pub format: String,
+}
+
+impl IndexedSegment {
+    pub fn segment_format(&self) -> SegmentFormat {
+        self.format.parse().unwrap_or_default()
+    }
+}
+
+/// Read segments recorded in [INDEX_FILE] of the cache directory, ordered by stream id and
+/// sequence.
+///
+/// The latest record of each segment is used, and segments whose files were removed are
+/// skipped.
+pub async fn read_index(cache_dir: &Path) -> IoriResult<Vec<IndexedSegment>> {
+    let file = File::open(cache_dir.join(INDEX_FILE)).await?;
+    let mut lines = tokio::io::BufReader::new(file).lines();
+
+    let mut segments = BTreeMap::new();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let segment: IndexedSegment = serde_json::from_str(&line)?;
+        segments.insert((segment.stream_id, segment.sequence), segment);
+    }
+    Ok(segments
+        .into_values()
+        .filter(|segment| cache_dir.join(&segment.file_name).is_file())
+        .collect())
+}
 
 pub struct FileCacheSource {
     cache_dir: PathBuf,
+    /// Writer of [INDEX_FILE], opened when the first segment is recorded.
+    index: tokio::sync::Mutex<Option<File>>,
 }
 
 impl FileCacheSource {
@@ -13,7 +72,10 @@ impl FileCacheSource {
             return Err(IoriError::CacheDirExists(cache_dir));
         }
 
-        Ok(Self { cache_dir })
+        Ok(Self {
+            cache_dir,
+            index: Default::default(),
+        })
     }
 
     async fn ensure_cache_dir(&self) -> IoriResult<()> {
@@ -31,6 +93,13 @@ impl FileCacheSource {
         let filename = format!("{stream_id:02}_{sequence:06}_{filename}");
         self.cache_dir.join(filename)
     }
+
+    /// Path of the segment while it is being written.
+    fn part_path(path: &std::path::Path) -> PathBuf {
+        let mut part_path = path.as_os_str().to_owned();
+        part_path.push(".part");
+        PathBuf::from(part_path)
+    }
 }
 
 impl CacheSource for FileCacheSource {
@@ -40,18 +109,27 @@ impl CacheSource for FileCacheSource {
     ) -> IoriResult<Option<CacheSourceWriter>> {
         self.ensure_cache_dir().await?;
 
+        // A segment file only exists after it is completely written, so a non-empty file is
+        // kept, even if it was written by a previous run. Partial files left by an
+        // interrupted download, and empty files, are written again.
         let path = self.segment_path(segment);
-        if path
-            .metadata()
-            .map(|p| p.is_file() && p.len() > 0)
-            .unwrap_or_default()
-        {
-            tracing::warn!("File {} already exists, ignoring.", path.display());
-            return Ok(None);
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            if metadata.is_file() && metadata.len() > 0 {
+                tracing::warn!("File {} already exists, ignoring.", path.display());
+                return Ok(None);
+            }
+            tracing::warn!("File {} is incomplete, downloading again.", path.display());
         }
 
-        let tmp_file: File = File::create(path).await?;
-        Ok(Some(Box::new(tmp_file)))
+        let part_path = Self::part_path(&path);
+        let file = File::create(&part_path).await?;
+        Ok(Some(Box::new(FileCacheWriter {
+            file,
+            part_path,
+            path,
+            rename: None,
+            renamed: false,
+        })))
     }
 
     async fn open_reader(&self, segment: &crate::SegmentInfo) -> IoriResult<CacheSourceReader> {
@@ -66,8 +144,10 @@ impl CacheSource for FileCacheSource {
 
     async fn invalidate(&self, segment: &crate::SegmentInfo) -> IoriResult<()> {
         let path = self.segment_path(segment);
-        if path.exists() {
-            tokio::fs::remove_file(path).await?;
+        for path in [Self::part_path(&path), path] {
+            if path.exists() {
+                tokio::fs::remove_file(path).await?;
+            }
         }
         Ok(())
     }
@@ -85,11 +165,196 @@ impl CacheSource for FileCacheSource {
             }
         }
 
+        *self.index.lock().await = None;
         tokio::fs::remove_dir_all(&self.cache_dir).await?;
         Ok(())
     }
 
+    async fn record_segment(&self, segment: &SegmentInfo) -> IoriResult<()> {
+        let path = self.segment_path(segment);
+        let Some(file_name) = path.file_name() else {
+            return Ok(());
+        };
+        let entry = IndexedSegment {
+            stream_id: segment.stream_id,
+            sequence: segment.sequence,
+            file_name: file_name.to_string_lossy().to_string(),
+            r#type: segment.r#type,
+            format: segment.format.as_ext().to_string(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let mut index = self.index.lock().await;
+        if index.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.cache_dir.join(INDEX_FILE))
+                .await?;
+            *index = Some(file);
+        }
+        let file = index.as_mut().unwrap();
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
     fn location_hint(&self) -> Option<String> {
         Some(self.cache_dir.display().to_string())
     }
 }
+
+/// Writer of a segment file, which is written to a `.part` file first and renamed to the
+/// segment path on shutdown.
+struct FileCacheWriter {
+    file: File,
+    part_path: PathBuf,
+    path: PathBuf,
+    /// Renaming task started on shutdown, which does not block the runtime.
+    rename: Option<JoinHandle<io::Result<()>>>,
+    renamed: bool,
+}
+
+impl AsyncWrite for FileCacheWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.file).poll_shutdown(cx))?;
+        if this.renamed {
+            return Poll::Ready(Ok(()));
+        }
+
+        let rename = this.rename.get_or_insert_with(|| {
+            let part_path = this.part_path.clone();
+            let path = this.path.clone();
+            tokio::task::spawn_blocking(move || std::fs::rename(part_path, path))
+        });
+        let result = ready!(Pin::new(rename).poll(cx)).map_err(io::Error::other);
+        this.rename = None;
+        result??;
+
+        this.renamed = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SegmentInfo;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_partial_segment() -> IoriResult<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = FileCacheSource::new(dir.path().join("cache"))?;
+        let segment = SegmentInfo {
+            file_name: "0.ts".to_string(),
+            ..Default::default()
+        };
+
+        // interrupted before shutdown
+        let mut writer = cache.open_writer(&segment).await?.unwrap();
+        writer.write_all(b"partial").await?;
+        drop(writer);
+
+        let mut writer = cache.open_writer(&segment).await?.unwrap();
+        writer.write_all(b"complete").await?;
+        writer.shutdown().await?;
+        drop(writer);
+        assert!(cache.open_writer(&segment).await?.is_none());
+
+        let mut data = String::new();
+        cache
+            .open_reader(&segment)
+            .await?
+            .read_to_string(&mut data)
+            .await?;
+        assert_eq!(data, "complete");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_segment() -> IoriResult<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = FileCacheSource::new(dir.path().join("cache"))?;
+        let segment = SegmentInfo {
+            file_name: "0.ts".to_string(),
+            ..Default::default()
+        };
+
+        // empty file
+        let mut writer = cache.open_writer(&segment).await?.unwrap();
+        writer.shutdown().await?;
+        drop(writer);
+        let mut writer = cache.open_writer(&segment).await?.unwrap();
+        writer.write_all(b"complete").await?;
+        writer.shutdown().await?;
+        drop(writer);
+        assert!(cache.open_writer(&segment).await?.is_none());
+
+        // written by a previous run
+        let segment = SegmentInfo {
+            sequence: 1,
+            file_name: "1.ts".to_string(),
+            ..Default::default()
+        };
+        tokio::fs::write(cache.segment_path(&segment), b"complete").await?;
+        assert!(cache.open_writer(&segment).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_segment_index() -> IoriResult<()> {
+        let dir = tempfile::tempdir()?;
+        let cache_dir = dir.path().join("cache");
+        let cache = FileCacheSource::new(cache_dir.clone())?;
+
+        for (stream_id, format) in [(1, SegmentFormat::Mp4), (0, SegmentFormat::Mpeg2TS)] {
+            let segment = SegmentInfo {
+                stream_id,
+                file_name: "segment".to_string(),
+                format,
+                ..Default::default()
+            };
+            let mut writer = cache.open_writer(&segment).await?.unwrap();
+            writer.write_all(b"data").await?;
+            writer.shutdown().await?;
+            drop(writer);
+            cache.record_segment(&segment).await?;
+        }
+
+        // the latest record is used, and invalidated segments are skipped
+        let segment = SegmentInfo {
+            stream_id: 1,
+            file_name: "segment".to_string(),
+            format: SegmentFormat::Cmfa,
+            ..Default::default()
+        };
+        cache.record_segment(&segment).await?;
+        let segments = read_index(&cache_dir).await?;
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].segment_format(), SegmentFormat::Mpeg2TS);
+        assert_eq!(segments[1].segment_format(), SegmentFormat::Cmfa);
+        assert_eq!(segments[1].file_name, "01_000000_segment");
+
+        cache.invalidate(&segment).await?;
+        assert_eq!(read_index(&cache_dir).await?.len(), 1);
+
+        Ok(())
+    }
+}
@@ -5,13 +5,18 @@ use std::{
     io::{self, Cursor},
     mem,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     task::Poll,
 };
 
 #[derive(Default)]
 pub struct MemoryCacheSource {
     cache: Arc<Mutex<HashMap<(u64, u64), MemoryEntry>>>,
+    /// Keep segments after they are read. See [CacheSource::retain_segments].
+    retain: AtomicBool,
 }
 
 impl MemoryCacheSource {
@@ -47,24 +52,30 @@ impl CacheSource for MemoryCacheSource {
     }
 
     async fn open_reader(&self, segment: &crate::SegmentInfo) -> IoriResult<CacheSourceReader> {
-        let data = self
-            .cache
-            .lock()
-            .unwrap()
-            .remove(&(segment.sequence, segment.stream_id))
-            .unwrap_or_default();
+        let key = (segment.sequence, segment.stream_id);
+        let mut cache = self.cache.lock().unwrap();
+        let data = if self.retain.load(Ordering::Relaxed) {
+            match cache.get(&key) {
+                Some(MemoryEntry::Data(data)) => Some(data.clone()),
+                _ => None,
+            }
+        } else {
+            match cache.remove(&key) {
+                Some(MemoryEntry::Data(data)) => Some(data),
+                Some(MemoryEntry::Pending) => {
+                    cache.insert(key, MemoryEntry::Pending);
+                    None
+                }
+                None => None,
+            }
+        };
+
         match data {
-            MemoryEntry::Pending => Err(IoriError::IOError(std::io::Error::new(
+            Some(data) => Ok(Box::new(Cursor::new(data))),
+            None => Err(IoriError::IOError(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
-                format!(
-                    "Cache for {:?} not found",
-                    (segment.sequence, segment.stream_id)
-                ),
+                format!("Cache for {:?} not found", key),
             ))),
-            MemoryEntry::Data(data) => {
-                let reader = Cursor::new(data);
-                Ok(Box::new(reader))
-            }
         }
     }
 
@@ -81,6 +92,10 @@ impl CacheSource for MemoryCacheSource {
         cache.clear();
         Ok(())
     }
+
+    fn retain_segments(&self) {
+        self.retain.store(true, Ordering::Relaxed);
+    }
 }
 
 #[derive(Debug, Default)]
@@ -159,4 +174,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_memory_cache_retain_segments() -> IoriResult<()> {
+        let cache = MemoryCacheSource::new();
+        cache.retain_segments();
+        let segment: RawSegment = RawSegment::new("".to_string(), "ts".to_string());
+        let segment_info = SegmentInfo::from(&segment);
+
+        let mut writer = cache.open_writer(&segment_info).await?.unwrap();
+        writer.write_all(b"hello").await?;
+        writer.shutdown().await?;
+        drop(writer);
+
+        for _ in 0..2 {
+            let mut reader = cache.open_reader(&segment_info).await?;
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await?;
+            assert_eq!(data, b"hello");
+        }
+
+        cache.invalidate(&segment_info).await?;
+        let result = cache.open_reader(&segment_info).await;
+        assert!(result.is_err());
+
+        Ok(())
+    }
 }
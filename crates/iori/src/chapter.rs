@@ -0,0 +1,358 @@
+//! Chapter markers from timed events.
+//!
+//! Sources attach [SegmentEvent]s to segments while loading playlists: HLS
+//! `EXT-X-DATERANGE` and `EXT-X-CUE-OUT`/`EXT-X-CUE-IN` tags, or DASH `EventStream`s.
+//! SCTE-35 `emsg` boxes in fragmented MP4 segments are read when merging. Events are
+//! converted to a list of [Chapter]s, which is written into the output by mergers.
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::mp4::{Mp4BoxIter, parse_emsg, segment_start_time, write_full_box};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentEventKind {
+    /// Start of an ad break, like `EXT-X-CUE-OUT` or a SCTE-35 splice out.
+    AdStart,
+    /// End of an ad break, like `EXT-X-CUE-IN` or a SCTE-35 splice in.
+    AdEnd,
+    /// Start of a named range or program, like `EXT-X-DATERANGE`.
+    Marker,
+}
+
+/// A timed event attached to a segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentEvent {
+    pub kind: SegmentEventKind,
+    pub title: Option<String>,
+    /// Start of the event in seconds, relative to the start of the segment.
+    pub offset: f64,
+    /// Duration of the event in seconds, if known.
+    pub duration: Option<f64>,
+}
+
+impl SegmentEvent {
+    pub fn new(kind: SegmentEventKind) -> Self {
+        Self {
+            kind,
+            title: None,
+            offset: 0.,
+            duration: None,
+        }
+    }
+
+    pub fn with_title(mut self, title: Option<String>) -> Self {
+        self.title = title;
+        self
+    }
+
+    pub fn with_offset(mut self, offset: f64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn with_duration(mut self, duration: Option<f64>) -> Self {
+        self.duration = duration;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chapter {
+    /// Start time in seconds.
+    pub start: f64,
+    /// End time in seconds.
+    pub end: f64,
+    pub title: String,
+}
+
+const PROGRAM_TITLE: &str = "Program";
+const AD_BREAK_TITLE: &str = "Ad break";
+
+/// Build chapters from events with their absolute start times in seconds.
+///
+/// Ad breaks become chapters of their own, and the rest of the timeline becomes program
+/// chapters. Markers start a new chapter with their title, which lasts for the duration of
+/// the marker or until the next event. Nothing is returned if there is no event.
+pub fn build_chapters(events: Vec<(f64, SegmentEvent)>, duration: f64) -> Vec<Chapter> {
+    if events.is_empty() {
+        return Vec::new();
+    }
+
+    // (time, title) of chapter boundaries
+    let mut boundaries = vec![(0., PROGRAM_TITLE.to_string())];
+    for (time, event) in events {
+        let time = time.clamp(0., duration);
+        let title = match event.kind {
+            SegmentEventKind::AdStart => AD_BREAK_TITLE.to_string(),
+            SegmentEventKind::AdEnd => PROGRAM_TITLE.to_string(),
+            SegmentEventKind::Marker => event.title.unwrap_or_else(|| PROGRAM_TITLE.to_string()),
+        };
+        boundaries.push((time, title));
+        if let Some(event_duration) = event.duration.filter(|d| *d > 0.) {
+            boundaries.push((
+                (time + event_duration).min(duration),
+                PROGRAM_TITLE.to_string(),
+            ));
+        }
+    }
+    // a stable sort keeps the order of events at the same time
+    boundaries.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut chapters: Vec<Chapter> = Vec::new();
+    for (time, title) in boundaries {
+        if let Some(last) = chapters.last_mut() {
+            if last.start == time {
+                // the later event at the same time wins
+                last.title = title;
+                continue;
+            }
+            last.end = time;
+        }
+        chapters.push(Chapter {
+            start: time,
+            end: duration,
+            title,
+        });
+    }
+
+    // merge adjacent chapters with the same title
+    chapters.dedup_by(|next, previous| {
+        if next.title == previous.title {
+            previous.end = next.end;
+            true
+        } else {
+            false
+        }
+    });
+    chapters.retain(|chapter| chapter.end > chapter.start);
+    chapters
+}
+
+/// Read SCTE-35 events from `emsg` boxes of a fragmented MP4 segment.
+///
+/// Splice events with a zero duration end an ad break, and other events start one.
+/// Events of other schemes, like ID3, are ignored.
+pub(crate) fn read_emsg_events(data: &[u8]) -> Vec<SegmentEvent> {
+    let segment_start = segment_start_time(data);
+
+    let mut events = Vec::new();
+    for emsg in Mp4BoxIter::new(data).filter(|b| &b.r#type == b"emsg") {
+        let Some(message) = parse_emsg(emsg.data) else {
+            continue;
+        };
+        if !message.scheme_id_uri.contains("scte35") {
+            continue;
+        }
+
+        let event = match message.event_duration {
+            0 => SegmentEvent::new(SegmentEventKind::AdEnd),
+            u32::MAX => SegmentEvent::new(SegmentEventKind::AdStart),
+            duration => SegmentEvent::new(SegmentEventKind::AdStart)
+                .with_duration(Some(duration as f64 / message.timescale.max(1) as f64)),
+        };
+        events.push(event.with_offset(message.offset(segment_start)));
+    }
+    events
+}
+
+fn format_time(seconds: f64) -> String {
+    let millis = (seconds * 1000.).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Chapters in the simple OGM format accepted by `mkvmerge --chapters`.
+pub(crate) fn to_ogm_chapters(chapters: &[Chapter]) -> String {
+    let mut output = String::new();
+    for (index, chapter) in chapters.iter().enumerate() {
+        let number = index + 1;
+        writeln!(output, "CHAPTER{number:02}={}", format_time(chapter.start)).unwrap();
+        writeln!(output, "CHAPTER{number:02}NAME={}", chapter.title).unwrap();
+    }
+    output
+}
+
+/// A Nero `chpl` box, which is read by most players from `moov/udta`.
+///
+/// It holds at most 255 chapters.
+pub(crate) fn to_mp4_chpl(chapters: &[Chapter]) -> Vec<u8> {
+    // reserved
+    let mut payload = vec![0; 4];
+    let chapters = &chapters[..chapters.len().min(u8::MAX as usize)];
+    payload.push(chapters.len() as u8);
+    for chapter in chapters {
+        // start time in 100ns units
+        let start = (chapter.start * 10_000_000.).round() as u64;
+        payload.extend_from_slice(&start.to_be_bytes());
+
+        let mut title = chapter.title.as_str();
+        while title.len() > u8::MAX as usize {
+            let mut end = title.len() - 1;
+            while !title.is_char_boundary(end) {
+                end -= 1;
+            }
+            title = &title[..end];
+        }
+        payload.push(title.len() as u8);
+        payload.extend_from_slice(title.as_bytes());
+    }
+
+    let mut chpl = Vec::new();
+    write_full_box(&mut chpl, b"chpl", 1, 0, &payload);
+    chpl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::mp4::{find_box, read_u64, write_box};
+
+    fn titles(chapters: &[Chapter]) -> Vec<(f64, f64, &str)> {
+        chapters
+            .iter()
+            .map(|c| (c.start, c.end, c.title.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_build_chapters() {
+        assert!(build_chapters(Vec::new(), 60.).is_empty());
+
+        let events = vec![
+            (
+                10.,
+                SegmentEvent::new(SegmentEventKind::AdStart).with_duration(Some(5.)),
+            ),
+            (
+                30.,
+                SegmentEvent::new(SegmentEventKind::Marker).with_title(Some("Talk".to_string())),
+            ),
+            (40., SegmentEvent::new(SegmentEventKind::AdStart)),
+            (50., SegmentEvent::new(SegmentEventKind::AdEnd)),
+        ];
+        let chapters = build_chapters(events, 60.);
+        assert_eq!(
+            titles(&chapters),
+            [
+                (0., 10., "Program"),
+                (10., 15., "Ad break"),
+                (15., 30., "Program"),
+                (30., 40., "Talk"),
+                (40., 50., "Ad break"),
+                (50., 60., "Program"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_chapters_at_start() {
+        let events = vec![
+            (0., SegmentEvent::new(SegmentEventKind::AdStart)),
+            (20., SegmentEvent::new(SegmentEventKind::AdEnd)),
+            (
+                20.,
+                SegmentEvent::new(SegmentEventKind::AdEnd).with_duration(Some(1.)),
+            ),
+        ];
+        let chapters = build_chapters(events, 30.);
+        assert_eq!(
+            titles(&chapters),
+            [(0., 20., "Ad break"), (20., 30., "Program")]
+        );
+    }
+
+    #[test]
+    fn test_chapter_formats() {
+        let chapters = vec![
+            Chapter {
+                start: 0.,
+                end: 61.5,
+                title: "Program".to_string(),
+            },
+            Chapter {
+                start: 3661.5,
+                end: 3700.,
+                title: "Ad break".to_string(),
+            },
+        ];
+        assert_eq!(
+            to_ogm_chapters(&chapters),
+            "CHAPTER01=00:00:00.000\nCHAPTER01NAME=Program\nCHAPTER02=01:01:01.500\nCHAPTER02NAME=Ad break\n"
+        );
+
+        let chpl = to_mp4_chpl(&chapters);
+        let chpl = find_box(&chpl, b"chpl").unwrap();
+        assert_eq!(chpl.data[0], 1);
+        assert_eq!(chpl.data[8], 2);
+        assert_eq!(read_u64(chpl.data, 9), Some(0));
+        assert_eq!(chpl.data[17], 7);
+        assert_eq!(&chpl.data[18..25], b"Program");
+        assert_eq!(read_u64(chpl.data, 25), Some(36_615_000_000));
+    }
+
+    #[test]
+    fn test_read_emsg_events() {
+        let mut data = Vec::new();
+
+        // version 0, splice out at 2s for 30s
+        let mut emsg = vec![0; 4];
+        emsg.extend_from_slice(b"urn:scte:scte35:2013:bin\0\0");
+        for value in [90000u32, 180000, 2700000, 1] {
+            emsg.extend_from_slice(&value.to_be_bytes());
+        }
+        write_box(&mut data, b"emsg", &emsg);
+
+        // version 1, splice in at 104s of a fragment starting at 100s
+        let mut emsg = vec![1, 0, 0, 0];
+        emsg.extend_from_slice(&1000u32.to_be_bytes());
+        emsg.extend_from_slice(&104_000u64.to_be_bytes());
+        emsg.extend_from_slice(&0u32.to_be_bytes());
+        emsg.extend_from_slice(&2u32.to_be_bytes());
+        emsg.extend_from_slice(b"urn:scte:scte35:2013:bin\0\0");
+        write_box(&mut data, b"emsg", &emsg);
+
+        // ID3 is ignored
+        let mut emsg = vec![0; 4];
+        emsg.extend_from_slice(b"https://aomedia.org/emsg/ID3\0\0");
+        for value in [1000u32, 0, 0, 3] {
+            emsg.extend_from_slice(&value.to_be_bytes());
+        }
+        write_box(&mut data, b"emsg", &emsg);
+
+        let mut tfdt = vec![0; 4];
+        tfdt.extend_from_slice(&9_000_000u32.to_be_bytes());
+        let mut traf = Vec::new();
+        write_box(&mut traf, b"tfdt", &tfdt);
+        let mut moof = Vec::new();
+        write_box(&mut moof, b"traf", &traf);
+        write_box(&mut data, b"moof", &moof);
+
+        let mut mdhd = vec![0; 12];
+        mdhd.extend_from_slice(&90000u32.to_be_bytes());
+        let mut mdia = Vec::new();
+        write_box(&mut mdia, b"mdhd", &mdhd);
+        let mut trak = Vec::new();
+        write_box(&mut trak, b"mdia", &mdia);
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"trak", &trak);
+        let mut init = Vec::new();
+        write_box(&mut init, b"moov", &moov);
+        init.extend_from_slice(&data);
+
+        assert_eq!(
+            read_emsg_events(&init),
+            [
+                SegmentEvent::new(SegmentEventKind::AdStart)
+                    .with_offset(2.)
+                    .with_duration(Some(30.)),
+                SegmentEvent::new(SegmentEventKind::AdEnd).with_offset(4.),
+            ]
+        );
+    }
+}
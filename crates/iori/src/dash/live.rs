@@ -3,8 +3,12 @@ mod selector;
 mod timeline;
 
 use super::segment::DashSegment;
-use crate::{decrypt::IoriKey, fetch::fetch_segment, HttpClient, IoriResult, StreamingSource};
+use crate::{
+    ad::AdFilter, decrypt::IoriKey, fetch::fetch_segment, HttpClient, IoriResult, StreamingSource,
+};
 use std::{
+    collections::HashMap,
+    path::PathBuf,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
@@ -20,6 +24,9 @@ pub struct CommonDashLiveSource {
     mpd_url: Url,
     key: Option<Arc<IoriKey>>,
     timeline: Arc<Mutex<Option<MPDTimeline>>>,
+    shaka_packager_command: Option<PathBuf>,
+    defer_decryption: bool,
+    ad_filter: AdFilter,
 }
 
 impl CommonDashLiveSource {
@@ -31,8 +38,28 @@ impl CommonDashLiveSource {
             mpd_url,
             key,
             timeline: Arc::new(Mutex::new(None)),
+            shaka_packager_command: None,
+            defer_decryption: false,
+            ad_filter: AdFilter::default(),
         })
     }
+
+    pub fn with_shaka_packager(mut self, shaka_packager_command: Option<PathBuf>) -> Self {
+        self.shaka_packager_command = shaka_packager_command;
+        self
+    }
+
+    /// Cache segments without decrypting them, to be decrypted after downloading.
+    pub fn with_deferred_decryption(mut self, defer_decryption: bool) -> Self {
+        self.defer_decryption = defer_decryption;
+        self
+    }
+
+    /// Skip segments in ad breaks detected by the filter.
+    pub fn with_ad_filter(mut self, ad_filter: AdFilter) -> Self {
+        self.ad_filter = ad_filter;
+        self
+    }
 }
 
 impl StreamingSource for CommonDashLiveSource {
@@ -55,12 +82,25 @@ impl StreamingSource for CommonDashLiveSource {
         let sequence_number = Arc::new(AtomicU64::new(0));
 
         let minimum_update_period = mpd.minimumUpdatePeriod.unwrap_or(Duration::from_secs(2));
-        let timeline = MPDTimeline::from_mpd(mpd, Some(&self.mpd_url), self.client.clone()).await?;
+        let timeline = MPDTimeline::from_mpd(mpd, Some(&self.mpd_url), self.client.clone())
+            .await?
+            .with_ad_filter(self.ad_filter.clone());
 
         let (mut segments, mut last_update) =
             timeline.segments_since(None, self.key.clone()).await?;
-        for segment in segments.iter_mut() {
-            segment.sequence = sequence_number.fetch_add(1, Ordering::Relaxed);
+        if timeline.is_static() {
+            // Segment times restart in each period, so number the segments of each stream instead
+            let mut sequences: HashMap<u64, u64> = HashMap::new();
+            for segment in segments.iter_mut() {
+                let sequence = sequences.entry(segment.stream_id).or_default();
+                segment.sequence = *sequence;
+                segment.time = None;
+                *sequence += 1;
+            }
+        } else {
+            for segment in segments.iter_mut() {
+                segment.sequence = sequence_number.fetch_add(1, Ordering::Relaxed);
+            }
         }
         sender.send(Ok(segments)).unwrap();
 
@@ -113,6 +153,13 @@ impl StreamingSource for CommonDashLiveSource {
     where
         W: tokio::io::AsyncWrite + Unpin + Send + Sync + 'static,
     {
-        fetch_segment(self.client.clone(), segment, writer, None).await
+        fetch_segment(
+            self.client.clone(),
+            segment,
+            writer,
+            self.shaka_packager_command.clone(),
+            self.defer_decryption,
+        )
+        .await
     }
 }
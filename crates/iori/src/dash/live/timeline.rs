@@ -9,14 +9,18 @@ use chrono::{DateTime, Duration, TimeDelta, Utc};
 use dash_mpd::{
     AdaptationSet, MPD, Period, Representation, SegmentBase, SegmentList, SegmentTemplate,
 };
+use reqwest::header::RANGE;
 use url::Url;
 
 use std::sync::Arc;
 
 use crate::{
     ByteRange, HttpClient, InitialSegment, IoriError, IoriResult, SegmentType,
+    ad::AdFilter,
+    chapter::{SegmentEvent, SegmentEventKind},
     dash::{
         segment::DashSegment,
+        sidx::SegmentIndex,
         template::{Template, TemplateUrl},
         url::{UriExt, is_absolute_url, merge_baseurls, parse_media_range},
     },
@@ -62,6 +66,9 @@ pub struct MPDTimeline {
 
     presentation_delay: TimeDelta,
     time_shift_buffer_depth: Option<TimeDelta>,
+
+    /// Filter of ad segments
+    ad_filter: AdFilter,
 }
 
 impl MPDTimeline {
@@ -89,6 +96,9 @@ impl MPDTimeline {
             let period = DashPeriod::from_mpd(&base_url, period, last_mut)?;
             periods.push(period);
         }
+        if presentation.is_static() {
+            fill_last_period_duration(&mut periods, mpd.mediaPresentationDuration)?;
+        }
 
         Ok(Self {
             client,
@@ -103,9 +113,15 @@ impl MPDTimeline {
                 .map(TimeDelta::from_std)
                 .transpose()?
                 .unwrap_or_else(TimeDelta::zero),
+            ad_filter: AdFilter::default(),
         })
     }
 
+    pub fn with_ad_filter(mut self, ad_filter: AdFilter) -> Self {
+        self.ad_filter = ad_filter;
+        self
+    }
+
     pub fn is_static(&self) -> bool {
         self.presentation.is_static()
     }
@@ -182,7 +198,94 @@ impl MPDTimeline {
             // FIXME: do not use adaption index as stream id
             for (stream_id, adaptation_set) in period.adaptation_sets.iter().enumerate() {
                 match &adaptation_set.representation {
-                    DashRepresentation::IndexedAddressing(_) => todo!(),
+                    DashRepresentation::IndexedAddressing {
+                        media,
+                        initialization,
+                        index_range,
+                        sample_timeline,
+                        id,
+                        mime_type,
+                    } => {
+                        let initial_segment = match initialization {
+                            Some(initialization) => InitialSegment::Encrypted(Arc::new(
+                                self.fetch_bytes(
+                                    &initialization.url,
+                                    initialization.range.as_ref(),
+                                )
+                                .await?,
+                            )),
+                            None => InitialSegment::None,
+                        };
+
+                        // (byte range, start point, start time, end time)
+                        let references = if let Some(index_range) = index_range {
+                            let data = self.fetch_bytes(media, Some(index_range)).await?;
+                            let index = SegmentIndex::parse(&data, index_range.offset)?;
+                            // sidx has its own timescale, which may differ from SegmentBase@timescale
+                            let sample_timeline = SampleTimeline {
+                                timescale: index.timescale,
+                                presentation_time_offset: sample_timeline.presentation_time_offset,
+                            };
+
+                            let mut references = Vec::with_capacity(index.references.len());
+                            for reference in index.references {
+                                references.push((
+                                    Some(reference.range),
+                                    reference.time,
+                                    sample_timeline.map_time(period.start_time, reference.time)?,
+                                    Some(sample_timeline.map_time(
+                                        period.start_time,
+                                        reference.time + reference.duration,
+                                    )?),
+                                ));
+                            }
+                            references
+                        } else {
+                            // Without an index, the whole track file is a single media segment
+                            vec![(None, 0, period.start_time, None)]
+                        };
+
+                        for (
+                            i,
+                            (byte_range, segment_start_point, segment_start_time, segment_end_time),
+                        ) in references.into_iter().enumerate()
+                        {
+                            if segment_start_time > effective_time_shift_buffer_end {
+                                break;
+                            }
+                            if let Some(period_duration) = period.duration {
+                                if segment_start_time >= period.start_time + period_duration {
+                                    break;
+                                }
+                            }
+                            if is_before_effective_time_shift_buffer_start(segment_start_time) {
+                                continue;
+                            }
+                            last_time = Some(segment_start_time);
+
+                            if self.is_ad_segment(period, media, segment_start_time) {
+                                continue;
+                            }
+
+                            segments.push(DashSegment {
+                                url: media.clone(),
+                                filename: format!("{}_{i:06}.m4s", id.as_deref().unwrap_or("s")),
+                                r#type: adaptation_set.content_type.as_ref().map_or_else(
+                                    || SegmentType::from_mime_type(mime_type.as_deref()),
+                                    |r| r.to_segment_type(),
+                                ),
+                                initial_segment: initial_segment.clone(),
+                                key: key.clone(),
+                                byte_range,
+                                sequence: 0,
+                                stream_id: stream_id as u64,
+                                time: Some(segment_start_point),
+                                duration: segment_end_time
+                                    .map(|end| (end - segment_start_time).as_seconds_f64()),
+                                events: period.segment_events(segment_start_time, segment_end_time),
+                            });
+                        }
+                    }
                     DashRepresentation::ExplicitAddressing {
                         initialization,
                         media,
@@ -229,6 +332,8 @@ impl MPDTimeline {
 
                                 let segment_start_time = sample_timeline
                                     .map_time(period.start_time, segment_start_point)?;
+                                let segment_end_time =
+                                    sample_timeline.map_time(period.start_time, start_time_pts)?;
 
                                 if segment_start_time > effective_time_shift_buffer_end {
                                     break;
@@ -270,6 +375,10 @@ impl MPDTimeline {
                                     }
                                 }
 
+                                if self.is_ad_segment(period, &segment_url, segment_start_time) {
+                                    continue;
+                                }
+
                                 segments.push(DashSegment {
                                     url: segment_url,
                                     filename: segment_filename,
@@ -283,6 +392,11 @@ impl MPDTimeline {
                                     sequence: 0,
                                     stream_id: stream_id as u64,
                                     time: Some(segment_start_point),
+                                    duration: Some(
+                                        (segment_end_time - segment_start_time).as_seconds_f64(),
+                                    ),
+                                    events: period
+                                        .segment_events(segment_start_time, Some(segment_end_time)),
                                 });
                             }
                         }
@@ -327,6 +441,10 @@ impl MPDTimeline {
                                 ((segment_number - start_number) as f64 * duration) as u64;
                             let segment_start_time =
                                 sample_timeline.map_time(period.start_time, segment_start_point)?;
+                            let segment_end_point =
+                                ((segment_number + 1 - start_number) as f64 * duration) as u64;
+                            let segment_end_time =
+                                sample_timeline.map_time(period.start_time, segment_end_point)?;
 
                             if segment_start_time > effective_time_shift_buffer_end {
                                 break;
@@ -367,6 +485,10 @@ impl MPDTimeline {
                                 }
                             }
 
+                            if self.is_ad_segment(period, &segment_url, segment_start_time) {
+                                continue;
+                            }
+
                             segments.push(DashSegment {
                                 url: segment_url,
                                 filename: segment_filename,
@@ -380,6 +502,11 @@ impl MPDTimeline {
                                 sequence: 0,
                                 stream_id: stream_id as u64,
                                 time: Some(segment_start_point),
+                                duration: Some(
+                                    (segment_end_time - segment_start_time).as_seconds_f64(),
+                                ),
+                                events: period
+                                    .segment_events(segment_start_time, Some(segment_end_time)),
                             });
                         }
                     }
@@ -396,14 +523,11 @@ impl MPDTimeline {
 
                         let initial_segment = if let Some(initialization) = initialization {
                             InitialSegment::Encrypted(Arc::new(
-                                self.client
-                                    .get(initialization.url.clone())
-                                    // TODO: support range
-                                    .send()
-                                    .await?
-                                    .bytes()
-                                    .await?
-                                    .to_vec(),
+                                self.fetch_bytes(
+                                    &initialization.url,
+                                    initialization.range.as_ref(),
+                                )
+                                .await?,
                             ))
                         } else {
                             InitialSegment::None
@@ -417,6 +541,8 @@ impl MPDTimeline {
 
                             let segment_start_time =
                                 sample_timeline.map_time(period.start_time, segment_start_point)?;
+                            let segment_end_time =
+                                sample_timeline.map_time(period.start_time, start_time_pts)?;
 
                             if segment_start_time > effective_time_shift_buffer_end {
                                 break;
@@ -436,6 +562,10 @@ impl MPDTimeline {
                                 .unwrap_or("data.m4s".to_string())
                                 .to_string();
 
+                            if self.is_ad_segment(period, &segment.url, segment_start_time) {
+                                continue;
+                            }
+
                             segments.push(DashSegment {
                                 url: segment.url.clone(),
                                 filename: segment_filename,
@@ -449,6 +579,11 @@ impl MPDTimeline {
                                 sequence: 0,
                                 stream_id: stream_id as u64,
                                 time: Some(segment_start_point),
+                                duration: Some(
+                                    (segment_end_time - segment_start_time).as_seconds_f64(),
+                                ),
+                                events: period
+                                    .segment_events(segment_start_time, Some(segment_end_time)),
                             });
                         }
                     }
@@ -459,6 +594,30 @@ impl MPDTimeline {
         Ok((segments, last_time))
     }
 
+    /// Whether the segment is an ad which should be dropped.
+    fn is_ad_segment(&self, period: &DashPeriod, url: &Url, start_time: DateTime<Utc>) -> bool {
+        if !self.ad_filter.is_enabled() {
+            return false;
+        }
+
+        let reason = self
+            .ad_filter
+            .check_period(period.id.as_deref())
+            .or_else(|| {
+                self.ad_filter
+                    .check_segment(url.as_str(), period.is_in_ad_break(start_time))
+            });
+        reason.is_some_and(|reason| self.ad_filter.should_drop(format!("segment {url}"), reason))
+    }
+
+    async fn fetch_bytes(&self, url: &Url, range: Option<&ByteRange>) -> IoriResult<Vec<u8>> {
+        let mut request = self.client.get(url.clone());
+        if let Some(range) = range {
+            request = request.header(RANGE, range.to_http_range());
+        }
+        Ok(request.send().await?.bytes().await?.to_vec())
+    }
+
     /// Sync clock for internal clock
     pub async fn sync_time(&mut self, mpd: &MPD) -> IoriResult<()> {
         self.presentation.sync_time(mpd, self.client.clone()).await
@@ -474,11 +633,18 @@ impl MPDTimeline {
         self.sync_time(&mpd).await.unwrap();
 
         let mut periods: Vec<DashPeriod> = Vec::with_capacity(mpd.periods.len());
-        for period in mpd.periods {
+        for mut period in mpd.periods {
+            if self.is_static() && periods.is_empty() && period.start.is_none() {
+                period.start = Some(std::time::Duration::ZERO);
+            }
+
             let last_mut = periods.last_mut();
             let period = DashPeriod::from_mpd(&base_url, period, last_mut)?;
             periods.push(period);
         }
+        if self.is_static() {
+            fill_last_period_duration(&mut periods, mpd.mediaPresentationDuration)?;
+        }
         self.periods = periods;
 
         Ok(())
@@ -552,6 +718,8 @@ impl DashPresentation {
 }
 
 pub struct DashPeriod {
+    /// Period@id
+    id: Option<String>,
     /// The start of a period is specified either explicitly as an offset from the MPD timeline zero point
     /// (Period@start) or implicitly by the end of the previous period ([DASH] 5.3.2). The duration of a
     /// period is specified either explicitly with Period@duration or implicitly by the start point of the
@@ -565,6 +733,8 @@ pub struct DashPeriod {
     duration: Option<Duration>,
 
     adaptation_sets: Vec<DashAdaptationSet>,
+    /// Events from `EventStream`s of the period, with their presentation time
+    events: Vec<(DateTime<Utc>, SegmentEvent)>,
 }
 
 impl DashPeriod {
@@ -607,6 +777,31 @@ impl DashPeriod {
             segment_template: period.SegmentTemplate.as_ref(),
         };
 
+        let mut events = Vec::new();
+        for event_stream in period.event_streams.iter() {
+            let scheme = event_stream.schemeIdUri.as_deref().unwrap_or_default();
+            // MPD validity expiration and other player events
+            if scheme.starts_with("urn:mpeg:dash:event") {
+                continue;
+            }
+            let is_scte35 = scheme.contains("scte35");
+            let timescale = event_stream.timescale.unwrap_or(1).max(1) as f64;
+            let offset = event_stream.presentationTimeOffset.unwrap_or(0);
+
+            for event in event_stream.event.iter() {
+                let time = event.presentationTime.unwrap_or(0).saturating_sub(offset);
+                let time = start_time + TimeDelta::from_secs_f64(time as f64 / timescale)?;
+                let duration = event.duration.map(|d| d as f64 / timescale);
+                let event = if is_scte35 {
+                    SegmentEvent::new(SegmentEventKind::AdStart)
+                } else {
+                    SegmentEvent::new(SegmentEventKind::Marker)
+                        .with_title(event.messageData.clone().or_else(|| event.id.clone()))
+                };
+                events.push((time, event.with_duration(duration)));
+            }
+        }
+
         let mut adaptation_sets = Vec::with_capacity(period.adaptations.len());
         for adaptation_set in period.adaptations {
             let period_base_url = period.BaseURL.first().map(|u| u.base.as_str());
@@ -619,11 +814,40 @@ impl DashPeriod {
         }
 
         Ok(Self {
+            id: period.id,
             start_time,
             duration,
             adaptation_sets,
+            events,
+        })
+    }
+
+    /// Whether the time is inside an ad break of a SCTE-35 event with a duration.
+    fn is_in_ad_break(&self, time: DateTime<Utc>) -> bool {
+        self.events.iter().any(|(start, event)| {
+            event.kind == SegmentEventKind::AdStart
+                && event.duration.is_some_and(|duration| {
+                    time >= *start
+                        && TimeDelta::from_secs_f64(duration)
+                            .is_ok_and(|duration| time < *start + duration)
+                })
         })
     }
+
+    /// Events starting in `[start, end)`, with offsets relative to `start`.
+    ///
+    /// Without `end`, the segment lasts until the end of the period.
+    fn segment_events(
+        &self,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Vec<SegmentEvent> {
+        self.events
+            .iter()
+            .filter(|(time, _)| *time >= start && end.is_none_or(|end| *time < end))
+            .map(|(time, event)| event.clone().with_offset((*time - start).as_seconds_f64()))
+            .collect()
+    }
 }
 
 pub struct DashAdaptationSet {
@@ -712,10 +936,17 @@ pub enum DashRepresentation {
     /// index segment, an initialization segment and a sequence of media segments.
     ///
     /// > Note: This addressing mode is sometimes called "SegmentBase" in other documents.
-    ///
-    /// Not supported yet.
-    #[allow(unused)]
-    IndexedAddressing(SegmentBase),
+    IndexedAddressing {
+        /// URL of the CMAF track file
+        media: Url,
+        initialization: Option<SegmentListItem>,
+        /// Byte range of the index segment (`sidx` box) in the track file
+        index_range: Option<ByteRange>,
+        sample_timeline: SampleTimeline,
+
+        id: Option<String>,
+        mime_type: Option<String>,
+    },
     /// A representation that uses explicit addressing consists of a set of media segments accessed
     /// via URLs constructed using a template defined in the MPD, with the exact sample timeline time
     /// span covered by the samples in each media segment described in the MPD.
@@ -791,8 +1022,53 @@ impl DashRepresentation {
                 .as_ref()
                 .or(inherited.segment_base)
             {
-                // TODO: extract the needed data from segment_base
-                Self::IndexedAddressing(segment_base.clone())
+                let index_range = segment_base
+                    .indexRange
+                    .as_deref()
+                    .map(parse_media_range)
+                    .transpose()?;
+                let initialization = match &segment_base.Initialization {
+                    Some(r) => {
+                        let range = r.range.as_deref().map(parse_media_range).transpose()?;
+                        match (&r.sourceURL, range) {
+                            (Some(url), range) => Some(SegmentListItem {
+                                url: merge_baseurls(&base_url, url)?,
+                                range,
+                            }),
+                            (None, Some(range)) => Some(SegmentListItem {
+                                url: base_url.clone(),
+                                range: Some(range),
+                            }),
+                            // Initialization data is in the track file itself, before the index.
+                            // Without an index, the whole file is a single self-initializing
+                            // segment and needs no separate initialization segment.
+                            (None, None) => index_range
+                                .as_ref()
+                                .filter(|index_range| index_range.offset > 0)
+                                .map(|index_range| SegmentListItem {
+                                    url: base_url.clone(),
+                                    range: Some(ByteRange::new(0, Some(index_range.offset))),
+                                }),
+                        }
+                    }
+                    None => None,
+                };
+                let timescale = segment_base.timescale.unwrap_or(1);
+
+                Self::IndexedAddressing {
+                    media: base_url.clone(),
+                    initialization,
+                    index_range,
+                    sample_timeline: SampleTimeline {
+                        timescale,
+                        presentation_time_offset: TimeDelta::from_secs_f64(
+                            segment_base.presentationTimeOffset.unwrap_or(0) as f64
+                                / timescale as f64,
+                        )?,
+                    },
+                    id,
+                    mime_type,
+                }
             } else if let Some(segment_list) = representation
                 .SegmentList
                 .as_ref()
@@ -929,7 +1205,7 @@ impl DashRepresentation {
 
     fn availability_time_offset(&self) -> TimeDelta {
         match self {
-            Self::IndexedAddressing(_) => TimeDelta::zero(),
+            Self::IndexedAddressing { .. } => TimeDelta::zero(),
             Self::ExplicitAddressing {
                 availability_time_offset,
                 ..
@@ -943,6 +1219,21 @@ impl DashRepresentation {
     }
 }
 
+/// In a static presentation, the last period MAY omit Period@duration, in which case it lasts
+/// until the end of the presentation given by `MPD@mediaPresentationDuration`.
+fn fill_last_period_duration(
+    periods: &mut [DashPeriod],
+    media_presentation_duration: Option<std::time::Duration>,
+) -> IoriResult<()> {
+    if let (Some(last), Some(total)) = (periods.last_mut(), media_presentation_duration) {
+        if last.duration.is_none() {
+            let end = DateTime::UNIX_EPOCH + TimeDelta::from_std(total)?;
+            last.duration = Some(end - last.start_time);
+        }
+    }
+    Ok(())
+}
+
 pub struct TimelineSegment {
     pub time: Option<u64>,
     pub duration: u64,
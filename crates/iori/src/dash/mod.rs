@@ -1,5 +1,6 @@
 pub mod archive;
 pub mod live;
 pub mod segment;
+pub(crate) mod sidx;
 pub mod template;
 pub(crate) mod url;
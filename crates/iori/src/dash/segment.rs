@@ -1,6 +1,6 @@
 use crate::{
-    decrypt::IoriKey, ByteRange, InitialSegment, RemoteStreamingSegment, SegmentFormat,
-    SegmentType, StreamingSegment,
+    chapter::SegmentEvent, decrypt::IoriKey, ByteRange, InitialSegment, RemoteStreamingSegment,
+    SegmentFormat, SegmentType, StreamingSegment,
 };
 use std::sync::Arc;
 
@@ -21,6 +21,11 @@ pub struct DashSegment {
 
     /// $Time$
     pub time: Option<u64>,
+    /// Duration of the segment in seconds, from `@d` or `@duration` and the timescale
+    pub duration: Option<f64>,
+
+    /// Events from `EventStream`s of the period during the segment
+    pub events: Vec<SegmentEvent>,
 }
 
 impl StreamingSegment for DashSegment {
@@ -51,6 +56,14 @@ impl StreamingSegment for DashSegment {
     fn format(&self) -> SegmentFormat {
         SegmentFormat::Mp4
     }
+
+    fn duration(&self) -> Option<f64> {
+        self.duration
+    }
+
+    fn events(&self) -> Vec<SegmentEvent> {
+        self.events.clone()
+    }
 }
 
 impl RemoteStreamingSegment for DashSegment {
@@ -0,0 +1,133 @@
+use crate::{
+    ByteRange, IoriError, IoriResult,
+    util::mp4::{find_box, read_u16, read_u32, read_u64},
+};
+
+/// A media subsegment referenced by a `sidx` box.
+#[derive(Debug, PartialEq)]
+pub struct SidxReference {
+    /// Byte range of the subsegment in the media file
+    pub range: ByteRange,
+    /// Presentation time of the subsegment in timescale units
+    pub time: u64,
+    /// Duration of the subsegment in timescale units
+    pub duration: u64,
+}
+
+/// Segment Index Box, defined in ISO/IEC 14496-12 8.16.3.
+#[derive(Debug)]
+pub struct SegmentIndex {
+    pub timescale: u64,
+    pub references: Vec<SidxReference>,
+}
+
+impl SegmentIndex {
+    /// Parse the `sidx` box from the data fetched at `indexRange`.
+    ///
+    /// `data_offset` is the offset of `data` in the media file, which is needed to
+    /// resolve the anchor point of the referenced subsegments.
+    pub fn parse(data: &[u8], data_offset: u64) -> IoriResult<Self> {
+        let sidx = find_box(data, b"sidx")
+            .ok_or_else(|| IoriError::MpdParsing("No sidx box found in index range".to_string()))?;
+        let invalid = || IoriError::MpdParsing("Invalid sidx box".to_string());
+
+        let payload = sidx.data;
+        let version = *payload.first().ok_or_else(invalid)?;
+        // version(1) + flags(3) + reference_ID(4)
+        let timescale = read_u32(payload, 8).ok_or_else(invalid)? as u64;
+        let (earliest_presentation_time, first_offset, mut pos) = if version == 0 {
+            (
+                read_u32(payload, 12).ok_or_else(invalid)? as u64,
+                read_u32(payload, 16).ok_or_else(invalid)? as u64,
+                20,
+            )
+        } else {
+            (
+                read_u64(payload, 12).ok_or_else(invalid)?,
+                read_u64(payload, 20).ok_or_else(invalid)?,
+                28,
+            )
+        };
+        // reserved(2)
+        pos += 2;
+        let reference_count = read_u16(payload, pos).ok_or_else(invalid)?;
+        pos += 2;
+
+        // The anchor point is the first byte after the sidx box
+        let mut offset = data_offset + sidx.end() as u64 + first_offset;
+        let mut time = earliest_presentation_time;
+        let mut references = Vec::with_capacity(reference_count as usize);
+        for _ in 0..reference_count {
+            let reference = read_u32(payload, pos).ok_or_else(invalid)?;
+            let duration = read_u32(payload, pos + 4).ok_or_else(invalid)? as u64;
+            // SAP flags are ignored
+            pos += 12;
+
+            let reference_type = reference >> 31;
+            let referenced_size = (reference & 0x7fff_ffff) as u64;
+            if reference_type == 1 {
+                // Hierarchical indexes are rarely used by DASH services
+                return Err(IoriError::MpdParsing(
+                    "Nested sidx references are not supported".to_string(),
+                ));
+            }
+
+            references.push(SidxReference {
+                range: ByteRange::new(offset, Some(referenced_size)),
+                time,
+                duration,
+            });
+            offset += referenced_size;
+            time += duration;
+        }
+
+        Ok(Self {
+            timescale,
+            references,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sidx_v0() {
+        let mut payload = vec![0, 0, 0, 0];
+        payload.extend_from_slice(&1u32.to_be_bytes()); // reference_ID
+        payload.extend_from_slice(&1000u32.to_be_bytes()); // timescale
+        payload.extend_from_slice(&0u32.to_be_bytes()); // earliest_presentation_time
+        payload.extend_from_slice(&0u32.to_be_bytes()); // first_offset
+        payload.extend_from_slice(&[0, 0]); // reserved
+        payload.extend_from_slice(&2u16.to_be_bytes()); // reference_count
+        for (size, duration) in [(100u32, 2000u32), (200, 1500)] {
+            payload.extend_from_slice(&size.to_be_bytes());
+            payload.extend_from_slice(&duration.to_be_bytes());
+            payload.extend_from_slice(&0x9000_0000u32.to_be_bytes());
+        }
+
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(b"sidx");
+        data.extend_from_slice(&payload);
+        let sidx_size = data.len() as u64;
+
+        let index = SegmentIndex::parse(&data, 500).unwrap();
+        assert_eq!(index.timescale, 1000);
+        assert_eq!(
+            index.references,
+            vec![
+                SidxReference {
+                    range: ByteRange::new(500 + sidx_size, Some(100)),
+                    time: 0,
+                    duration: 2000,
+                },
+                SidxReference {
+                    range: ByteRange::new(500 + sidx_size + 100, Some(200)),
+                    time: 2000,
+                    duration: 1500,
+                },
+            ]
+        );
+    }
+}
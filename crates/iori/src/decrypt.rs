@@ -1,25 +1,48 @@
+pub mod cenc;
+pub mod deferred;
+
 use std::{
     collections::HashMap,
     ffi::OsString,
     fs::File,
-    io::{Cursor, Read, Write},
+    io::{Read, Write},
     path::PathBuf,
     process::Command,
 };
 
 use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
 use m3u8_rs::KeyMethod;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::{
     error::{IoriError, IoriResult},
-    util::http::HttpClient,
+    util::{http::HttpClient, mp4::find_box},
 };
 
-#[derive(Debug)]
+/// Key and parameters to decrypt a segment.
+///
+/// Keys are serialized with their method and hex encoded key material, to be stored along
+/// with segments which are decrypted later.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "kebab-case")]
 pub enum IoriKey {
-    Aes128 { key: [u8; 16], iv: [u8; 16] },
-    ClearKey { keys: HashMap<String, String> },
-    SampleAes { key: [u8; 16], iv: [u8; 16] },
+    #[serde(rename = "aes-128")]
+    Aes128 {
+        #[serde(with = "hex_bytes")]
+        key: [u8; 16],
+        #[serde(with = "hex_bytes")]
+        iv: [u8; 16],
+    },
+    ClearKey {
+        keys: HashMap<String, String>,
+    },
+    SampleAes {
+        #[serde(with = "hex_bytes")]
+        key: [u8; 16],
+        #[serde(with = "hex_bytes")]
+        iv: [u8; 16],
+    },
 }
 
 impl IoriKey {
@@ -92,22 +115,10 @@ impl IoriKey {
                 "SAMPLE-AES-CENC" | "SAMPLE-AES-CTR" => {
                     tracing::debug!("{name} encryption detected. Using manual key.");
 
-                    // <kid>:<key>;<kid>:<key>;...
                     let Some(manual_key) = manual_key else {
                         return Err(IoriError::DecryptionKeyRequired);
                     };
-                    let mut keys = HashMap::new();
-                    for pair in manual_key.split(';') {
-                        match pair.split_once(':') {
-                            Some((kid, key)) if is_valid_kid_key_pair(kid, key) => {
-                                keys.insert(kid.to_string(), key.to_string());
-                            }
-                            _ => tracing::warn!("Ignored invalid key format: {}", pair),
-                        }
-                    }
-                    if keys.is_empty() {
-                        return Err(IoriError::InvalidHexKey(manual_key));
-                    }
+                    let keys = parse_clear_keys(&manual_key)?;
 
                     Some(Self::ClearKey { keys })
                 }
@@ -116,6 +127,32 @@ impl IoriKey {
         })
     }
 
+    /// Replace the key material, keeping other parameters like the IV.
+    ///
+    /// `key` is a hex key for AES-128 and SAMPLE-AES, or `<kid>:<key>` pairs separated by `;`
+    /// for CENC.
+    pub fn with_key(&self, key: &str) -> IoriResult<Self> {
+        Ok(match self {
+            Self::Aes128 { iv, .. } => Self::Aes128 {
+                key: parse_hex_key(key)?,
+                iv: *iv,
+            },
+            Self::ClearKey { .. } => Self::ClearKey {
+                keys: parse_clear_keys(key)?,
+            },
+            Self::SampleAes { iv, .. } => Self::SampleAes {
+                key: parse_hex_key(key)?,
+                iv: *iv,
+            },
+        })
+    }
+
+    /// Whether segments are decrypted sample by sample, which requires the initialization
+    /// segment to locate samples even if it is not encrypted itself.
+    pub fn is_sample_encryption(&self) -> bool {
+        !matches!(self, Self::Aes128 { .. })
+    }
+
     pub fn to_decryptor(&self, shaka_packager_command: Option<PathBuf>) -> IoriDecryptor {
         match self {
             IoriKey::Aes128 { key, iv } => IoriDecryptor::Aes128(Box::new(cbc::Decryptor::<
@@ -130,7 +167,7 @@ impl IoriKey {
                         keys: keys.clone(),
                     }
                 } else {
-                    IoriDecryptor::Mp4Decrypt { keys: keys.clone() }
+                    IoriDecryptor::Cenc { keys: keys.clone() }
                 }
             }
             IoriKey::SampleAes { key, iv } => IoriDecryptor::SampleAes { key: *key, iv: *iv },
@@ -140,7 +177,8 @@ impl IoriKey {
 
 pub enum IoriDecryptor {
     Aes128(Box<cbc::Decryptor<aes::Aes128>>),
-    Mp4Decrypt {
+    /// Decrypts ISO-BMFF Common Encryption natively. See [cenc::CencDecryptor].
+    Cenc {
         keys: HashMap<String, String>,
     },
     ShakaPackager {
@@ -157,7 +195,12 @@ impl IoriDecryptor {
     pub async fn decrypt(self, data: &[u8]) -> IoriResult<Vec<u8>> {
         Ok(match self {
             IoriDecryptor::Aes128(decryptor) => decryptor.decrypt_padded_vec_mut::<Pkcs7>(data)?,
-            IoriDecryptor::Mp4Decrypt { keys } => mp4decrypt::mp4decrypt(data, &keys, None)?,
+            IoriDecryptor::Cenc { keys } => {
+                // decryption is CPU-bound, so keep it off the async executor
+                let data = data.to_vec();
+                tokio::task::spawn_blocking(move || cenc::CencDecryptor::new(&keys)?.decrypt(&data))
+                    .await??
+            }
             IoriDecryptor::ShakaPackager { command, keys } => {
                 let temp_dir = tempfile::tempdir()?;
                 let rand_suffix = rand::random::<u64>();
@@ -195,13 +238,81 @@ impl IoriDecryptor {
                 file.read_to_end(&mut data)?;
                 data
             }
+            IoriDecryptor::SampleAes { key, iv } if find_box(data, b"moov").is_some() => {
+                let data = data.to_vec();
+                tokio::task::spawn_blocking(move || {
+                    cenc::CencDecryptor::with_key(&key, &iv).decrypt(&data)
+                })
+                .await??
+            }
             IoriDecryptor::SampleAes { key, iv } => {
-                let mut reader = Cursor::new(data);
-                let mut writer = Vec::new();
-                iori_ssa::decrypt(&mut reader, &mut writer, key, iv).map(|_| writer)?
+                let mut output = Vec::new();
+                iori_ssa::decrypt_async(data, &mut output, key, iv).await?;
+                output
             }
         })
     }
+
+    /// Decrypt data into `writer`.
+    ///
+    /// SAMPLE-AES MPEG-TS and packed audio are written in chunks as they are decrypted,
+    /// without buffering the whole output. Other schemes write the output at once.
+    pub async fn decrypt_to<W>(self, data: &[u8], writer: &mut W) -> IoriResult<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        match self {
+            IoriDecryptor::SampleAes { key, iv } if find_box(data, b"moov").is_none() => {
+                iori_ssa::decrypt_async(data, writer, key, iv).await?;
+            }
+            decryptor => writer.write_all(&decryptor.decrypt(data).await?).await?,
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex_key(key: &str) -> IoriResult<[u8; 16]> {
+    hex::decode(key)?
+        .try_into()
+        .map_err(IoriError::InvalidBinaryKey)
+}
+
+/// Parse keys in the format of `<kid>:<key>;<kid>:<key>;...`.
+fn parse_clear_keys(manual_key: &str) -> IoriResult<HashMap<String, String>> {
+    let mut keys = HashMap::new();
+    for pair in manual_key.split(';') {
+        match pair.split_once(':') {
+            Some((kid, key)) if is_valid_kid_key_pair(kid, key) => {
+                keys.insert(kid.to_string(), key.to_string());
+            }
+            _ => tracing::warn!("Ignored invalid key format: {}", pair),
+        }
+    }
+    if keys.is_empty() {
+        return Err(IoriError::InvalidHexKey(manual_key.to_string()));
+    }
+    Ok(keys)
+}
+
+/// Serialize 16-byte keys and IVs as hex strings.
+mod hex_bytes {
+    use hex::FromHex;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(bytes: &[u8; 16], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 16], D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let hex = String::deserialize(deserializer)?;
+        <[u8; 16]>::from_hex(hex).map_err(D::Error::custom)
+    }
 }
 
 fn is_valid_kid_key_pair(kid: &str, key: &str) -> bool {
@@ -0,0 +1,1009 @@
+//! Decryption of ISO-BMFF Common Encryption (ISO/IEC 23001-7) without external tools.
+//!
+//! Encryption parameters are read from `tenc` in the initialization segment and may be
+//! overridden per sample by `seig` sample groups. Per-sample IVs and subsamples are read
+//! from `senc`, or from the auxiliary information referenced by `saiz` and `saio` if there
+//! is no `senc`. The `cenc` (AES-CTR) and `cbcs` (AES-CBC with pattern) schemes are
+//! supported.
+//!
+//! Samples are decrypted in place. Encryption boxes are renamed to `free` and encrypted
+//! sample entries get back their original format from `frma`, so no offset or size in the
+//! file has to be rewritten.
+use std::collections::HashMap;
+
+use aes::{
+    Aes128, Block,
+    cipher::{BlockDecrypt, BlockEncrypt, KeyInit},
+};
+
+use crate::{
+    IoriError, IoriResult,
+    util::mp4::{
+        Mp4Box, Mp4BoxIter, SampleDefaults, parse_moof, parse_trex, read_track_id, read_u16,
+        read_u32, read_u64,
+    },
+};
+
+/// User type of the PIFF sample encryption box, which is used instead of `senc` by
+/// Smooth Streaming and some older DASH streams.
+const PIFF_SAMPLE_ENCRYPTION: [u8; 16] = [
+    0xa2, 0x39, 0x4f, 0x52, 0x5a, 0x9b, 0x4f, 0x14, 0xa2, 0x44, 0x6c, 0x42, 0x7c, 0x64, 0x8d, 0xf4,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scheme {
+    /// AES-CTR on whole protected ranges
+    Cenc,
+    /// AES-CBC with a pattern of encrypted and clear blocks
+    Cbcs,
+}
+
+/// Encryption parameters of samples, from `tenc` or a `seig` sample group entry.
+#[derive(Debug, Clone, PartialEq)]
+struct EncryptionParams {
+    is_protected: bool,
+    per_sample_iv_size: u8,
+    kid: [u8; 16],
+    crypt_byte_block: u8,
+    skip_byte_block: u8,
+    constant_iv: Option<Vec<u8>>,
+}
+
+impl EncryptionParams {
+    /// Parse the layout shared by `tenc`, after its version and flags, and `seig` entries.
+    fn parse(data: &[u8]) -> Option<Self> {
+        // reserved(1), crypt_byte_block(4 bits) and skip_byte_block(4 bits),
+        // isProtected(1), Per_Sample_IV_Size(1), KID(16)
+        let pattern = *data.get(1)?;
+        let is_protected = *data.get(2)? != 0;
+        let per_sample_iv_size = *data.get(3)?;
+        let kid = data.get(4..20)?.try_into().ok()?;
+        let constant_iv = if is_protected && per_sample_iv_size == 0 {
+            let size = *data.get(20)? as usize;
+            Some(data.get(21..21 + size)?.to_vec())
+        } else {
+            None
+        };
+
+        Some(Self {
+            is_protected,
+            per_sample_iv_size,
+            kid,
+            crypt_byte_block: pattern >> 4,
+            skip_byte_block: pattern & 0x0f,
+            constant_iv,
+        })
+    }
+
+    /// Size of the entry in a `sgpd` box without explicit entry lengths.
+    fn entry_size(data: &[u8]) -> Option<usize> {
+        let is_protected = *data.get(2)? != 0;
+        let per_sample_iv_size = *data.get(3)?;
+        if is_protected && per_sample_iv_size == 0 {
+            Some(21 + *data.get(20)? as usize)
+        } else {
+            Some(20)
+        }
+    }
+}
+
+/// An encrypted track in the initialization segment.
+#[derive(Debug, Clone)]
+struct ProtectedTrack {
+    scheme: Scheme,
+    params: EncryptionParams,
+    /// `seig` entries in `stbl/sgpd`
+    groups: Vec<EncryptionParams>,
+}
+
+/// IV and subsamples of a sample, from `senc` or sample auxiliary information.
+#[derive(Debug, Clone, Default)]
+struct SampleEncryption {
+    iv: Vec<u8>,
+    /// Sizes of clear and protected data of each subsample
+    subsamples: Vec<(u16, u32)>,
+}
+
+/// Decryptor of ISO-BMFF Common Encryption with keys of known KIDs.
+pub struct CencDecryptor {
+    ciphers: HashMap<[u8; 16], Aes128>,
+    /// Key for any KID and IV of the HLS `EXT-X-KEY` tag, for SAMPLE-AES streams
+    fallback: Option<(Aes128, [u8; 16])>,
+}
+
+impl CencDecryptor {
+    /// Create a decryptor with hex encoded keys by their hex encoded KIDs.
+    pub fn new(keys: &HashMap<String, String>) -> IoriResult<Self> {
+        let mut ciphers = HashMap::new();
+        for (kid, key) in keys {
+            let kid: [u8; 16] = hex::decode(kid)?
+                .try_into()
+                .map_err(IoriError::InvalidBinaryKey)?;
+            let key: [u8; 16] = hex::decode(key)?
+                .try_into()
+                .map_err(IoriError::InvalidBinaryKey)?;
+            ciphers.insert(kid, Aes128::new(&key.into()));
+        }
+        Ok(Self {
+            ciphers,
+            fallback: None,
+        })
+    }
+
+    /// Create a decryptor of HLS SAMPLE-AES fragmented MP4 streams, which use the `cbcs`
+    /// scheme with a single key for any KID.
+    ///
+    /// `iv` is used if the initialization segment has no `tenc` box to read the constant IV
+    /// from. Encrypted sample entries without `schm` are assumed to use `cbcs`.
+    pub fn with_key(key: &[u8; 16], iv: &[u8; 16]) -> Self {
+        Self {
+            ciphers: HashMap::new(),
+            fallback: Some((Aes128::new(key.into()), *iv)),
+        }
+    }
+
+    /// Decrypt fragmented MP4 data. Fragments of encrypted tracks must be preceded by the
+    /// initialization segment describing them.
+    pub fn decrypt(&self, data: &[u8]) -> IoriResult<Vec<u8>> {
+        let mut output = data.to_vec();
+        let mut renames = Vec::new();
+        let mut tracks = HashMap::new();
+        let mut trex = HashMap::new();
+
+        for top in Mp4BoxIter::new(data) {
+            match &top.r#type {
+                b"moov" => {
+                    tracks = self.parse_tracks(data, &top, &mut renames)?;
+                    trex = parse_trex(&top);
+                }
+                b"moof" => {
+                    if tracks.is_empty() && has_sample_encryption(&top) {
+                        return Err(IoriError::Mp4Parsing(
+                            "Encrypted fragment without initialization segment".to_string(),
+                        ));
+                    }
+                    self.decrypt_fragment(data, &top, &mut output, &tracks, &trex)?;
+                    renames.extend(encryption_boxes(data, &top));
+                }
+                b"pssh" => renames.push((top.offset, *b"free")),
+                _ => {}
+            }
+        }
+
+        for (offset, r#type) in renames {
+            output[offset + 4..offset + 8].copy_from_slice(&r#type);
+        }
+        Ok(output)
+    }
+
+    fn cipher(&self, kid: &[u8; 16]) -> IoriResult<&Aes128> {
+        self.ciphers
+            .get(kid)
+            .or(self.fallback.as_ref().map(|(cipher, _)| cipher))
+            .ok_or_else(|| IoriError::KeyNotFound(hex::encode(kid)))
+    }
+
+    /// Find encrypted sample entries of tracks in a `moov` box, and record renames which
+    /// restore their original formats.
+    fn parse_tracks(
+        &self,
+        data: &[u8],
+        moov: &Mp4Box,
+        renames: &mut Vec<(usize, [u8; 4])>,
+    ) -> IoriResult<HashMap<u32, ProtectedTrack>> {
+        let mut tracks = HashMap::new();
+
+        for trak in moov.children(0) {
+            if &trak.r#type == b"pssh" {
+                renames.push((offset_of(data, trak.raw), *b"free"));
+                continue;
+            }
+            if &trak.r#type != b"trak" {
+                continue;
+            }
+
+            let Some(track_id) = read_track_id(&trak) else {
+                continue;
+            };
+            let Some(stbl) = trak
+                .find_child(b"mdia")
+                .and_then(|mdia| mdia.find_child(b"minf"))
+                .and_then(|minf| minf.find_child(b"stbl"))
+            else {
+                continue;
+            };
+            let Some(stsd) = stbl.find_child(b"stsd") else {
+                continue;
+            };
+
+            // version and flags(4) + entry_count(4)
+            for entry in stsd.children(8) {
+                let skip = match &entry.r#type {
+                    // SampleEntry(8) + VisualSampleEntry(70)
+                    b"encv" => 78,
+                    // SampleEntry(8) + AudioSampleEntry(20), with extra fields in QuickTime
+                    // sound sample description versions 1 and 2
+                    b"enca" => match read_u16(entry.data, 8) {
+                        Some(1) => 44,
+                        Some(2) => 64,
+                        _ => 28,
+                    },
+                    _ => continue,
+                };
+                let Some(sinf) = entry.children(skip).find(|b| &b.r#type == b"sinf") else {
+                    continue;
+                };
+
+                let scheme = match sinf.find_child(b"schm").and_then(|b| b.data.get(4..8)) {
+                    Some(b"cenc") => Scheme::Cenc,
+                    Some(b"cbcs") => Scheme::Cbcs,
+                    None if self.fallback.is_some() => Scheme::Cbcs,
+                    None => Scheme::Cenc,
+                    Some(scheme) => {
+                        return Err(IoriError::UnsupportedEncryptionScheme(
+                            String::from_utf8_lossy(scheme).to_string(),
+                        ));
+                    }
+                };
+                let params = sinf
+                    .find_child(b"schi")
+                    .and_then(|schi| schi.find_child(b"tenc"))
+                    .and_then(|tenc| EncryptionParams::parse(tenc.data.get(4..)?))
+                    .or_else(|| {
+                        // HLS SAMPLE-AES uses the 1:9 pattern with the IV of the playlist
+                        let (_, iv) = self.fallback.as_ref()?;
+                        Some(EncryptionParams {
+                            is_protected: true,
+                            per_sample_iv_size: 0,
+                            kid: [0; 16],
+                            crypt_byte_block: 1,
+                            skip_byte_block: 9,
+                            constant_iv: Some(iv.to_vec()),
+                        })
+                    })
+                    .ok_or_else(|| {
+                        IoriError::Mp4Parsing("Invalid or missing tenc box".to_string())
+                    })?;
+
+                if let Some(format) = sinf.find_child(b"frma").and_then(|frma| frma.data.get(..4)) {
+                    renames.push((offset_of(data, entry.raw), format.try_into().unwrap()));
+                }
+                renames.push((offset_of(data, sinf.raw), *b"free"));
+
+                let groups = stbl
+                    .children(0)
+                    .find(|b| &b.r#type == b"sgpd" && b.data.get(4..8) == Some(&b"seig"[..]))
+                    .map(|sgpd| parse_seig_entries(&sgpd))
+                    .unwrap_or_default();
+
+                tracks.insert(
+                    track_id,
+                    ProtectedTrack {
+                        scheme,
+                        params,
+                        groups,
+                    },
+                );
+                break;
+            }
+        }
+
+        Ok(tracks)
+    }
+
+    fn decrypt_fragment(
+        &self,
+        data: &[u8],
+        moof: &Mp4Box,
+        output: &mut [u8],
+        tracks: &HashMap<u32, ProtectedTrack>,
+        trex: &HashMap<u32, SampleDefaults>,
+    ) -> IoriResult<()> {
+        let fragments = parse_moof(moof, trex)?;
+        let trafs = moof.children(0).filter(|b| &b.r#type == b"traf");
+        for (traf, fragment) in trafs.zip(fragments) {
+            let Some(track) = tracks.get(&fragment.track_id) else {
+                continue;
+            };
+
+            let groups = sample_groups(&traf, &track.groups, fragment.samples.len())?;
+            let params: Vec<&EncryptionParams> = (0..fragment.samples.len())
+                .map(|index| {
+                    groups
+                        .get(index)
+                        .and_then(Option::as_ref)
+                        .unwrap_or(&track.params)
+                })
+                .collect();
+            let encryptions = read_sample_encryption(data, moof, &traf, &params)?;
+
+            for (index, sample) in fragment.samples.iter().enumerate() {
+                let params = params[index];
+                if !params.is_protected {
+                    continue;
+                }
+
+                let encryption = encryptions.get(index).cloned().unwrap_or_default();
+                let iv = match &params.constant_iv {
+                    Some(iv) if params.per_sample_iv_size == 0 => iv.as_slice(),
+                    _ => encryption.iv.as_slice(),
+                };
+                if iv.is_empty() || iv.len() > 16 {
+                    return Err(invalid_fragment("missing IV"));
+                }
+
+                let end = sample.offset + sample.size as usize;
+                let sample_data = output
+                    .get_mut(sample.offset..end)
+                    .ok_or_else(|| invalid_fragment("sample out of range"))?;
+                decrypt_sample(
+                    track.scheme,
+                    self.cipher(&params.kid)?,
+                    iv,
+                    (params.crypt_byte_block, params.skip_byte_block),
+                    &encryption.subsamples,
+                    sample_data,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_fragment(reason: &str) -> IoriError {
+    IoriError::Mp4Parsing(format!("Invalid encrypted fragment: {reason}"))
+}
+
+/// Offset of a slice of `data` in it.
+fn offset_of(data: &[u8], part: &[u8]) -> usize {
+    part.as_ptr() as usize - data.as_ptr() as usize
+}
+
+/// Whether a box describes sample encryption, and is meaningless after decryption.
+fn is_encryption_box(b: &Mp4Box) -> bool {
+    match &b.r#type {
+        b"senc" | b"saiz" | b"saio" | b"pssh" => true,
+        b"sbgp" | b"sgpd" => b.data.get(4..8) == Some(&b"seig"[..]),
+        b"uuid" => b.data.get(..16) == Some(&PIFF_SAMPLE_ENCRYPTION[..]),
+        _ => false,
+    }
+}
+
+/// Renames of encryption boxes in a `moof` box to `free`.
+fn encryption_boxes(data: &[u8], moof: &Mp4Box) -> Vec<(usize, [u8; 4])> {
+    let trafs = moof.children(0).filter(|b| &b.r#type == b"traf");
+    moof.children(0)
+        .chain(trafs.flat_map(|traf| traf.children(0)))
+        .filter(is_encryption_box)
+        .map(|b| (offset_of(data, b.raw), *b"free"))
+        .collect()
+}
+
+/// Whether a `moof` box has sample encryption information.
+fn has_sample_encryption(moof: &Mp4Box) -> bool {
+    moof.children(0)
+        .filter(|b| &b.r#type == b"traf")
+        .flat_map(|traf| traf.children(0))
+        .any(|b| matches!(&b.r#type, b"senc" | b"saiz" | b"uuid") && is_encryption_box(&b))
+}
+
+/// Parse `seig` entries of a sample group description (`sgpd`) box.
+fn parse_seig_entries(sgpd: &Mp4Box) -> Vec<EncryptionParams> {
+    let data = sgpd.data;
+    let version = data.first().copied().unwrap_or_default();
+    // version and flags(4) + grouping_type(4)
+    let mut pos = 8;
+    let mut default_length = 0;
+    if version == 1 {
+        default_length = read_u32(data, pos).unwrap_or_default() as usize;
+        pos += 4;
+    } else if version >= 2 {
+        // default_sample_description_index
+        pos += 4;
+    }
+    let Some(entry_count) = read_u32(data, pos) else {
+        return Vec::new();
+    };
+    pos += 4;
+
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        let length = if version == 1 && default_length == 0 {
+            let Some(length) = read_u32(data, pos) else {
+                break;
+            };
+            pos += 4;
+            length as usize
+        } else if version == 1 {
+            default_length
+        } else {
+            match data.get(pos..).and_then(EncryptionParams::entry_size) {
+                Some(length) => length,
+                None => break,
+            }
+        };
+        let Some(params) = data
+            .get(pos..pos + length)
+            .and_then(EncryptionParams::parse)
+        else {
+            break;
+        };
+        pos += length;
+        entries.push(params);
+    }
+    entries
+}
+
+/// Encryption parameters of each sample in a track fragment from its `seig` sample group,
+/// or `None` if the sample is not in any group.
+///
+/// The groups must not cover more than `sample_count` samples of the fragment.
+fn sample_groups(
+    traf: &Mp4Box,
+    track_groups: &[EncryptionParams],
+    sample_count: usize,
+) -> IoriResult<Vec<Option<EncryptionParams>>> {
+    let Some(sbgp) = traf
+        .children(0)
+        .find(|b| &b.r#type == b"sbgp" && b.data.get(4..8) == Some(&b"seig"[..]))
+    else {
+        return Ok(Vec::new());
+    };
+    let fragment_groups = traf
+        .children(0)
+        .find(|b| &b.r#type == b"sgpd" && b.data.get(4..8) == Some(&b"seig"[..]))
+        .map(|sgpd| parse_seig_entries(&sgpd))
+        .unwrap_or_default();
+
+    let data = sbgp.data;
+    // version and flags(4) + grouping_type(4), and grouping_type_parameter(4) in version 1
+    let mut pos = if data.first() == Some(&1) { 12 } else { 8 };
+    let entry_count = read_u32(data, pos).unwrap_or_default();
+    pos += 4;
+
+    let mut groups = Vec::new();
+    for _ in 0..entry_count {
+        let (Some(count), Some(index)) = (read_u32(data, pos), read_u32(data, pos + 4)) else {
+            break;
+        };
+        pos += 8;
+        if count as usize > sample_count - groups.len() {
+            return Err(invalid_fragment(
+                "sbgp covers more samples than the fragment",
+            ));
+        }
+
+        // indices above 0x10000 refer to entries in the fragment
+        let params = match index {
+            0 => None,
+            0x10001.. => fragment_groups.get((index - 0x10001) as usize),
+            _ => track_groups.get(index as usize - 1),
+        };
+        groups.extend(std::iter::repeat_n(params.cloned(), count as usize));
+    }
+    Ok(groups)
+}
+
+/// Read the IV and subsamples of each sample in a track fragment.
+fn read_sample_encryption(
+    data: &[u8],
+    moof: &Mp4Box,
+    traf: &Mp4Box,
+    params: &[&EncryptionParams],
+) -> IoriResult<Vec<SampleEncryption>> {
+    let iv_size = |index: usize| {
+        params
+            .get(index)
+            .map_or(0, |params| params.per_sample_iv_size as usize)
+    };
+
+    let senc = traf.children(0).find(|b| match &b.r#type {
+        b"senc" => true,
+        b"uuid" => b.data.get(..16) == Some(&PIFF_SAMPLE_ENCRYPTION[..]),
+        _ => false,
+    });
+    if let Some(senc) = senc {
+        let senc_data = if &senc.r#type == b"uuid" {
+            &senc.data[16..]
+        } else {
+            senc.data
+        };
+        let flags = read_u32(senc_data, 0).ok_or_else(|| invalid_fragment("senc"))? & 0xffffff;
+        let mut pos = 4;
+        if flags & 0x01 != 0 {
+            // PIFF AlgorithmID(3), IV_size(1) and KID(16) overrides, which always match tenc
+            pos += 20;
+        }
+        let sample_count =
+            read_u32(senc_data, pos).ok_or_else(|| invalid_fragment("senc"))? as usize;
+        pos += 4;
+
+        // each entry has at least the smallest IV, and the number of subsamples
+        let min_entry_size = params
+            .iter()
+            .map(|params| params.per_sample_iv_size as usize)
+            .min()
+            .unwrap_or_default()
+            + if flags & 0x02 != 0 { 2 } else { 0 };
+        let remaining = senc_data.len().saturating_sub(pos);
+        if sample_count > params.len()
+            || (min_entry_size > 0 && sample_count > remaining / min_entry_size)
+        {
+            return Err(invalid_fragment("senc sample count exceeds its data"));
+        }
+
+        let mut samples = Vec::with_capacity(sample_count);
+        for index in 0..sample_count {
+            let (sample, size) = senc_data
+                .get(pos..)
+                .and_then(|data| parse_sample_encryption(data, iv_size(index), flags & 0x02 != 0))
+                .ok_or_else(|| invalid_fragment("senc"))?;
+            pos += size;
+            samples.push(sample);
+        }
+        return Ok(samples);
+    }
+
+    // sample auxiliary information, which has the same layout as senc entries
+    let (Some(saiz), Some(saio)) = (traf.find_child(b"saiz"), traf.find_child(b"saio")) else {
+        return Ok(Vec::new());
+    };
+    let invalid = || invalid_fragment("saiz or saio");
+
+    let saiz_flags = read_u32(saiz.data, 0).ok_or_else(invalid)?;
+    let mut pos = if saiz_flags & 0x01 != 0 { 12 } else { 4 };
+    let default_size = *saiz.data.get(pos).ok_or_else(invalid)?;
+    let sample_count = read_u32(saiz.data, pos + 1).ok_or_else(invalid)? as usize;
+    pos += 5;
+    // all entries are read from the segment, which bounds the number of samples
+    if sample_count > params.len() || sample_count > data.len() / (default_size as usize).max(1) {
+        return Err(invalid_fragment("saiz sample count exceeds its data"));
+    }
+    let sizes: Vec<usize> = if default_size == 0 {
+        let sizes = saiz.data.get(pos..pos + sample_count).ok_or_else(invalid)?;
+        sizes.iter().map(|size| *size as usize).collect()
+    } else {
+        vec![default_size as usize; sample_count]
+    };
+
+    let saio_flags = read_u32(saio.data, 0).ok_or_else(invalid)?;
+    let pos = if saio_flags & 0x01 != 0 { 12 } else { 4 };
+    // all samples are expected to be contiguous after the first offset, relative to moof
+    let offset = match saio.data.first() {
+        Some(1) => read_u64(saio.data, pos + 4).ok_or_else(invalid)? as usize,
+        _ => read_u32(saio.data, pos + 4).ok_or_else(invalid)? as usize,
+    };
+
+    let mut pos = moof.offset + offset;
+    let mut samples = Vec::with_capacity(sample_count);
+    for (index, size) in sizes.into_iter().enumerate() {
+        let info = data.get(pos..pos + size).ok_or_else(invalid)?;
+        let iv_size = iv_size(index).min(size);
+        let (sample, _) =
+            parse_sample_encryption(info, iv_size, size > iv_size).ok_or_else(invalid)?;
+        pos += size;
+        samples.push(sample);
+    }
+    Ok(samples)
+}
+
+/// Parse the IV and subsamples of a sample, and return them with the size of the entry.
+fn parse_sample_encryption(
+    data: &[u8],
+    iv_size: usize,
+    has_subsamples: bool,
+) -> Option<(SampleEncryption, usize)> {
+    let iv = data.get(..iv_size)?.to_vec();
+    let mut pos = iv_size;
+
+    let mut subsamples = Vec::new();
+    if has_subsamples {
+        let count = read_u16(data, pos)?;
+        pos += 2;
+        for _ in 0..count {
+            subsamples.push((read_u16(data, pos)?, read_u32(data, pos + 2)?));
+            pos += 6;
+        }
+    }
+
+    Some((SampleEncryption { iv, subsamples }, pos))
+}
+
+/// Decrypt the protected ranges of a sample in place.
+fn decrypt_sample(
+    scheme: Scheme,
+    cipher: &Aes128,
+    iv: &[u8],
+    (crypt_byte_block, skip_byte_block): (u8, u8),
+    subsamples: &[(u16, u32)],
+    sample: &mut [u8],
+) -> IoriResult<()> {
+    // 8-byte IVs are followed by the block counter
+    let mut full_iv = [0; 16];
+    full_iv[..iv.len()].copy_from_slice(iv);
+
+    // the whole sample is protected without subsamples
+    let mut ranges = Vec::new();
+    if subsamples.is_empty() {
+        ranges.push(0..sample.len());
+    } else {
+        let mut pos = 0;
+        for (clear, protected) in subsamples {
+            pos += *clear as usize;
+            ranges.push(pos..pos + *protected as usize);
+            pos += *protected as usize;
+        }
+        if pos > sample.len() {
+            return Err(invalid_fragment("subsamples larger than sample"));
+        }
+    }
+
+    match scheme {
+        Scheme::Cenc => {
+            // the key stream continues across subsamples of a sample
+            let mut ctr = Ctr::new(cipher, full_iv);
+            for range in ranges {
+                ctr.apply(&mut sample[range]);
+            }
+        }
+        Scheme::Cbcs => {
+            // a pattern of 0:0 means all blocks are encrypted
+            let (crypt, skip) = match crypt_byte_block {
+                0 => (1, 0),
+                crypt => (crypt as usize, skip_byte_block as usize),
+            };
+            // the IV is reset for each subsample
+            for range in ranges {
+                decrypt_cbc_pattern(cipher, full_iv, crypt, skip, &mut sample[range]);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// AES-CTR with a 64-bit block counter in the lower half of the IV.
+struct Ctr<'a> {
+    cipher: &'a Aes128,
+    counter: [u8; 16],
+    key_stream: Block,
+    position: usize,
+}
+
+impl<'a> Ctr<'a> {
+    fn new(cipher: &'a Aes128, iv: [u8; 16]) -> Self {
+        Self {
+            cipher,
+            counter: iv,
+            key_stream: Block::default(),
+            position: 16,
+        }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.position == 16 {
+                self.key_stream = Block::from(self.counter);
+                self.cipher.encrypt_block(&mut self.key_stream);
+                let block_counter = u64::from_be_bytes(self.counter[8..].try_into().unwrap());
+                self.counter[8..].copy_from_slice(&block_counter.wrapping_add(1).to_be_bytes());
+                self.position = 0;
+            }
+            *byte ^= self.key_stream[self.position];
+            self.position += 1;
+        }
+    }
+}
+
+/// AES-CBC on `crypt` of every `crypt + skip` blocks, chaining across encrypted blocks.
+/// The trailing partial block is not encrypted.
+fn decrypt_cbc_pattern(cipher: &Aes128, iv: [u8; 16], crypt: usize, skip: usize, data: &mut [u8]) {
+    let mut chain = Block::from(iv);
+    for (index, chunk) in data.chunks_exact_mut(16).enumerate() {
+        if index % (crypt + skip) >= crypt {
+            continue;
+        }
+
+        let ciphertext = Block::clone_from_slice(chunk);
+        let mut block = ciphertext;
+        cipher.decrypt_block(&mut block);
+        for (byte, (plain, previous)) in chunk.iter_mut().zip(block.iter().zip(chain.iter())) {
+            *byte = plain ^ previous;
+        }
+        chain = ciphertext;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decrypt::IoriKey,
+        util::mp4::{write_box, write_full_box},
+    };
+
+    const KID: [u8; 16] = [0x11; 16];
+    const KEY: [u8; 16] = [0x22; 16];
+
+    /// Build an initialization segment with an encrypted video track.
+    fn init_segment(scheme: Option<&[u8; 4]>, tenc: Option<&[u8]>) -> Vec<u8> {
+        let mut sinf = Vec::new();
+        write_box(&mut sinf, b"frma", b"avc1");
+        if let Some(scheme) = scheme {
+            let mut schm = scheme.to_vec();
+            schm.extend_from_slice(&0x10000u32.to_be_bytes());
+            write_full_box(&mut sinf, b"schm", 0, 0, &schm);
+        }
+        if let Some(tenc) = tenc {
+            let mut schi = Vec::new();
+            write_full_box(&mut schi, b"tenc", 1, 0, tenc);
+            write_box(&mut sinf, b"schi", &schi);
+        }
+
+        let mut encv = vec![0; 78];
+        write_box(&mut encv, b"sinf", &sinf);
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        write_box(&mut stsd, b"encv", &encv);
+        let mut stbl = Vec::new();
+        write_full_box(&mut stbl, b"stsd", 0, 0, &stsd);
+        let mut minf = Vec::new();
+        write_box(&mut minf, b"stbl", &stbl);
+        let mut mdia = Vec::new();
+        write_box(&mut mdia, b"minf", &minf);
+
+        let mut tkhd = vec![0; 8];
+        tkhd.extend_from_slice(&1u32.to_be_bytes());
+        tkhd.extend_from_slice(&[0; 68]);
+        let mut trak = Vec::new();
+        write_full_box(&mut trak, b"tkhd", 0, 3, &tkhd);
+        write_box(&mut trak, b"mdia", &mdia);
+
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"trak", &trak);
+        write_full_box(&mut moov, b"pssh", 0, 0, &[0; 20]);
+
+        let mut init = Vec::new();
+        write_box(&mut init, b"ftyp", b"iso6\0\0\0\0");
+        write_box(&mut init, b"moov", &moov);
+        init
+    }
+
+    /// Build a fragment with samples of the given sizes, and an optional `senc` payload.
+    fn fragment(samples: &[&[u8]], senc: Option<&[u8]>) -> Vec<u8> {
+        let build = |data_offset: u32| {
+            let mut traf = Vec::new();
+            let mut tfhd = 1u32.to_be_bytes().to_vec();
+            tfhd.extend_from_slice(&1u32.to_be_bytes());
+            // default-sample-duration
+            write_full_box(&mut traf, b"tfhd", 0, 0x08, &tfhd);
+            let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+            trun.extend_from_slice(&data_offset.to_be_bytes());
+            for sample in samples {
+                trun.extend_from_slice(&(sample.len() as u32).to_be_bytes());
+            }
+            write_full_box(&mut traf, b"trun", 0, 0x201, &trun);
+            if let Some(senc) = senc {
+                write_full_box(&mut traf, b"senc", 0, 0x02, senc);
+            }
+            let mut moof_payload = Vec::new();
+            write_box(&mut moof_payload, b"traf", &traf);
+            let mut moof = Vec::new();
+            write_box(&mut moof, b"moof", &moof_payload);
+            moof
+        };
+
+        let moof_size = build(0).len() as u32;
+        let mut output = build(moof_size + 8);
+        write_box(&mut output, b"mdat", &samples.concat());
+        output
+    }
+
+    fn cipher() -> Aes128 {
+        Aes128::new(&KEY.into())
+    }
+
+    fn encrypt_cbc_pattern(iv: [u8; 16], crypt: usize, skip: usize, data: &mut [u8]) {
+        let mut chain = Block::from(iv);
+        for (index, chunk) in data.chunks_exact_mut(16).enumerate() {
+            if index % (crypt + skip) >= crypt {
+                continue;
+            }
+            let mut block = Block::clone_from_slice(chunk);
+            for (byte, previous) in block.iter_mut().zip(chain.iter()) {
+                *byte ^= previous;
+            }
+            cipher().encrypt_block(&mut block);
+            chunk.copy_from_slice(&block);
+            chain = block;
+        }
+    }
+
+    fn decryptor() -> CencDecryptor {
+        CencDecryptor::new(&HashMap::from([(hex::encode(KID), hex::encode(KEY))])).unwrap()
+    }
+
+    #[test]
+    fn test_decrypt_cenc() -> IoriResult<()> {
+        let sample1: Vec<u8> = (0..100).collect();
+        let sample2: Vec<u8> = (0..50).map(|i| i * 3).collect();
+        let iv1 = [1u8; 8];
+        let iv2 = [2u8; 8];
+
+        // sample 1 has subsamples of 10 clear and 40 protected bytes each, and sample 2 is
+        // protected entirely
+        let cipher = cipher();
+        let mut encrypted1 = sample1.clone();
+        let mut ctr = Ctr::new(&cipher, [iv1, [0; 8]].concat().try_into().unwrap());
+        ctr.apply(&mut encrypted1[10..50]);
+        ctr.apply(&mut encrypted1[60..100]);
+        let mut encrypted2 = sample2.clone();
+        Ctr::new(&cipher, [iv2, [0; 8]].concat().try_into().unwrap()).apply(&mut encrypted2);
+
+        let mut senc = 2u32.to_be_bytes().to_vec();
+        senc.extend_from_slice(&iv1);
+        senc.extend_from_slice(&2u16.to_be_bytes());
+        for _ in 0..2 {
+            senc.extend_from_slice(&10u16.to_be_bytes());
+            senc.extend_from_slice(&40u32.to_be_bytes());
+        }
+        senc.extend_from_slice(&iv2);
+        senc.extend_from_slice(&0u16.to_be_bytes());
+
+        let mut tenc = vec![0, 0, 1, 8];
+        tenc.extend_from_slice(&KID);
+
+        let mut data = init_segment(Some(b"cenc"), Some(&tenc));
+        data.extend(fragment(&[&encrypted1, &encrypted2], Some(&senc)));
+        let decrypted = decryptor().decrypt(&data)?;
+        assert_eq!(decrypted.len(), data.len());
+        assert!(decrypted.ends_with(&[sample1, sample2].concat()));
+
+        // encryption boxes are hidden and the original format is restored
+        let boxes: Vec<_> = Mp4BoxIter::new(&decrypted).collect();
+        let moov = &boxes[1];
+        assert!(moov.find_child(b"pssh").is_none());
+        let stsd = moov
+            .find_child(b"trak")
+            .and_then(|b| b.find_child(b"mdia"))
+            .and_then(|b| b.find_child(b"minf"))
+            .and_then(|b| b.find_child(b"stbl"))
+            .and_then(|b| b.find_child(b"stsd"))
+            .unwrap();
+        let entry = stsd.children(8).next().unwrap();
+        assert_eq!(&entry.r#type, b"avc1");
+        assert!(entry.children(78).all(|b| &b.r#type == b"free"));
+        let traf = boxes[2].find_child(b"traf").unwrap();
+        assert!(traf.find_child(b"senc").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_cbcs() -> IoriResult<()> {
+        // 20 full blocks and a partial block after 5 clear bytes
+        let sample: Vec<u8> = (0..333u32).map(|i| (i * 7) as u8).collect();
+        let iv = [3u8; 16];
+
+        let mut encrypted = sample.clone();
+        encrypt_cbc_pattern(iv, 1, 9, &mut encrypted[5..]);
+        assert_ne!(encrypted, sample);
+
+        let mut senc = 1u32.to_be_bytes().to_vec();
+        senc.extend_from_slice(&1u16.to_be_bytes());
+        senc.extend_from_slice(&5u16.to_be_bytes());
+        senc.extend_from_slice(&328u32.to_be_bytes());
+
+        // pattern 1:9 with a constant IV
+        let mut tenc = vec![0, 0x19, 1, 0];
+        tenc.extend_from_slice(&KID);
+        tenc.push(16);
+        tenc.extend_from_slice(&iv);
+        let init = init_segment(Some(b"cbcs"), Some(&tenc));
+
+        let mut data = init;
+        data.extend(fragment(&[&encrypted], Some(&senc)));
+        let decrypted = decryptor().decrypt(&data)?;
+        assert!(decrypted.ends_with(&sample));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_untrusted_sample_count() {
+        let mut tenc = vec![0, 0, 1, 8];
+        tenc.extend_from_slice(&KID);
+        let mut data = init_segment(Some(b"cenc"), Some(&tenc));
+
+        // a huge count is rejected before allocating entries for it
+        let mut senc = u32::MAX.to_be_bytes().to_vec();
+        senc.extend_from_slice(&[0; 8]);
+        senc.extend_from_slice(&0u16.to_be_bytes());
+        data.extend(fragment(&[&[0; 32]], Some(&senc)));
+
+        assert!(matches!(
+            decryptor().decrypt(&data),
+            Err(IoriError::Mp4Parsing(_))
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_missing_key() {
+        let mut tenc = vec![0, 0, 1, 8];
+        tenc.extend_from_slice(&[0x33; 16]);
+        let mut data = init_segment(Some(b"cenc"), Some(&tenc));
+        let mut senc = 1u32.to_be_bytes().to_vec();
+        senc.extend_from_slice(&[0; 8]);
+        senc.extend_from_slice(&0u16.to_be_bytes());
+        data.extend(fragment(&[&[0; 32]], Some(&senc)));
+
+        assert!(matches!(
+            decryptor().decrypt(&data),
+            Err(IoriError::KeyNotFound(kid)) if kid == hex::encode([0x33; 16])
+        ));
+    }
+
+    #[test]
+    fn test_fragment_without_init() {
+        let mut senc = 1u32.to_be_bytes().to_vec();
+        senc.extend_from_slice(&[0; 8]);
+        senc.extend_from_slice(&0u16.to_be_bytes());
+        let data = fragment(&[&[0; 32]], Some(&senc));
+        assert!(decryptor().decrypt(&data).is_err());
+
+        // clear fragments are kept as is
+        let data = fragment(&[&[1; 32]], None);
+        assert_eq!(decryptor().decrypt(&data).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_sample_aes_fmp4() -> IoriResult<()> {
+        let key = [0x44; 16];
+        let iv = [0x55; 16];
+        // 4 full blocks and a partial block in 2 subsamples
+        let sample: Vec<u8> = (0..200u32).map(|i| (i * 5) as u8).collect();
+        let cipher = Aes128::new(&key.into());
+        let mut encrypted = sample.clone();
+        for range in [32..96, 100..200] {
+            let mut chain = Block::from(iv);
+            for (index, chunk) in encrypted[range].chunks_exact_mut(16).enumerate() {
+                if index % 10 != 0 {
+                    continue;
+                }
+                let mut block = Block::clone_from_slice(chunk);
+                for (byte, previous) in block.iter_mut().zip(chain.iter()) {
+                    *byte ^= previous;
+                }
+                cipher.encrypt_block(&mut block);
+                chunk.copy_from_slice(&block);
+                chain = block;
+            }
+        }
+
+        let mut senc = 1u32.to_be_bytes().to_vec();
+        senc.extend_from_slice(&2u16.to_be_bytes());
+        for (clear, protected) in [(32u16, 64u32), (4, 100)] {
+            senc.extend_from_slice(&clear.to_be_bytes());
+            senc.extend_from_slice(&protected.to_be_bytes());
+        }
+
+        // the KID of tenc is unknown, and the key of the playlist is used
+        let mut tenc = vec![0, 0x19, 1, 0];
+        tenc.extend_from_slice(&[0x66; 16]);
+        tenc.push(16);
+        tenc.extend_from_slice(&iv);
+        let with_tenc = init_segment(Some(b"cbcs"), Some(&tenc));
+        // without schm and tenc, the IV of the playlist is used
+        let without_tenc = init_segment(None, None);
+
+        let decryptor_key = IoriKey::SampleAes { key, iv };
+        for init in [with_tenc, without_tenc] {
+            let mut data = init;
+            data.extend(fragment(&[&encrypted], Some(&senc)));
+            let decrypted = decryptor_key.to_decryptor(None).decrypt(&data).await?;
+            assert!(decrypted.ends_with(&sample));
+        }
+
+        Ok(())
+    }
+}
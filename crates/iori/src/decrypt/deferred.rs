@@ -0,0 +1,237 @@
+//! Deferred decryption of cached segments.
+//!
+//! Sources decrypt segments while downloading by default, so keys must be known up front
+//! and a wrong key means downloading everything again. With deferred decryption, segments
+//! are cached encrypted along with their initialization segments, and
+//! [DeferredDecryptionMerger](crate::merge::DeferredDecryptionMerger) records the key of
+//! each segment in [SEGMENTS_FILE] next to them. [decrypt_cache_dir] decrypts them later,
+//! optionally with another key, without modifying the cached files.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncBufReadExt;
+
+use super::IoriKey;
+use crate::{
+    InitialSegment, IoriResult, SegmentFormat, SegmentInfo,
+    cache::file::{INDEX_FILE, IndexedSegment, read_index},
+};
+
+/// Name of the JSON-lines file listing cached segments in the cache directory.
+pub const SEGMENTS_FILE: &str = "segments.jsonl";
+
+/// A segment cached without decryption.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedSegment {
+    pub stream_id: u64,
+    pub sequence: u64,
+    /// Name of the cached file in the cache directory.
+    pub file_name: String,
+    /// Key of the segment, or `None` if it is not encrypted.
+    pub key: Option<IoriKey>,
+    /// Size of the clear initialization segment at the start of the file, which is kept
+    /// as is when decrypting. Always 0 for sample encryption, which decrypts the
+    /// initialization segment along with the segment.
+    #[serde(default)]
+    pub clear_prefix: usize,
+}
+
+impl CachedSegment {
+    pub fn new(segment: &SegmentInfo, file_name: String) -> Self {
+        let clear_prefix = match &segment.initial_segment {
+            InitialSegment::Clear(data)
+                if segment
+                    .key
+                    .as_ref()
+                    .is_some_and(|key| !key.is_sample_encryption()) =>
+            {
+                data.len()
+            }
+            _ => 0,
+        };
+        Self {
+            stream_id: segment.stream_id,
+            sequence: segment.sequence,
+            file_name,
+            key: segment.key.as_deref().cloned(),
+            clear_prefix,
+        }
+    }
+
+    /// Decrypt data of the cached file.
+    ///
+    /// If `key` is set, it replaces the recorded key material. See [IoriKey::with_key].
+    pub async fn decrypt(
+        &self,
+        data: Vec<u8>,
+        key: Option<&str>,
+        shaka_packager_command: Option<PathBuf>,
+    ) -> IoriResult<Vec<u8>> {
+        let Some(segment_key) = &self.key else {
+            return Ok(data);
+        };
+        let segment_key = match key {
+            Some(key) => segment_key.with_key(key)?,
+            None => segment_key.clone(),
+        };
+
+        let prefix = self.clear_prefix.min(data.len());
+        let decryptor = segment_key.to_decryptor(shaka_packager_command);
+        let decrypted = decryptor.decrypt(&data[prefix..]).await?;
+
+        let mut result = data;
+        result.truncate(prefix);
+        result.extend(decrypted);
+        Ok(result)
+    }
+}
+
+/// Read segments recorded in [SEGMENTS_FILE] of the cache directory, ordered by stream id
+/// and sequence.
+///
+/// A segment recorded more than once, like after resuming a download into the same
+/// directory, is only returned once with its latest record.
+pub async fn read_cached_segments(cache_dir: &Path) -> IoriResult<Vec<CachedSegment>> {
+    let file = tokio::fs::File::open(cache_dir.join(SEGMENTS_FILE)).await?;
+    let mut lines = tokio::io::BufReader::new(file).lines();
+
+    let mut segments = BTreeMap::new();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let segment: CachedSegment = serde_json::from_str(&line)?;
+        segments.insert((segment.stream_id, segment.sequence), segment);
+    }
+    Ok(segments.into_values().collect())
+}
+
+/// Decrypt segments cached with deferred decryption into `output_dir`, keeping their file
+/// names. Segments which are not encrypted are copied.
+///
+/// The decrypted segments are recorded in [INDEX_FILE] of `output_dir`, with their streams
+/// and the formats detected while downloading, so that they can be merged like a cache
+/// directory.
+///
+/// Cached files are not modified, so decrypting can be repeated with another key. Returns
+/// the number of segments written.
+pub async fn decrypt_cache_dir(
+    cache_dir: &Path,
+    output_dir: &Path,
+    key: Option<&str>,
+    shaka_packager_command: Option<PathBuf>,
+) -> IoriResult<usize> {
+    let segments = read_cached_segments(cache_dir).await?;
+    tokio::fs::create_dir_all(output_dir).await?;
+
+    // types and formats are only known if the cache recorded them
+    let indexed: BTreeMap<_, _> = read_index(cache_dir)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|segment| ((segment.stream_id, segment.sequence), segment))
+        .collect();
+    let mut index = Vec::new();
+
+    for segment in segments.iter() {
+        let data = tokio::fs::read(cache_dir.join(&segment.file_name)).await?;
+        let data = segment
+            .decrypt(data, key, shaka_packager_command.clone())
+            .await
+            .inspect_err(|e| tracing::error!("Failed to decrypt {}: {e}", segment.file_name))?;
+        tokio::fs::write(output_dir.join(&segment.file_name), data).await?;
+
+        let entry = match indexed.get(&(segment.stream_id, segment.sequence)) {
+            Some(entry) => entry.clone(),
+            None => IndexedSegment {
+                stream_id: segment.stream_id,
+                sequence: segment.sequence,
+                file_name: segment.file_name.clone(),
+                r#type: Default::default(),
+                format: SegmentFormat::from_filename(&segment.file_name)
+                    .as_ext()
+                    .to_string(),
+            },
+        };
+        serde_json::to_writer(&mut index, &entry)?;
+        index.push(b'\n');
+    }
+    tokio::fs::write(output_dir.join(INDEX_FILE), index).await?;
+
+    Ok(segments.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use aes::cipher::{BlockEncryptMut, KeyIvInit, block_padding::Pkcs7};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_decrypt_cache_dir() -> IoriResult<()> {
+        let key = [1; 16];
+        let iv = [2; 16];
+        let init = b"init".to_vec();
+        let encrypted = cbc::Encryptor::<aes::Aes128>::new((&key).into(), (&iv).into())
+            .encrypt_padded_vec_mut::<Pkcs7>(b"segment data");
+
+        let cache_dir = tempfile::tempdir()?;
+        let output_dir = cache_dir.path().join("decrypted");
+        let mut data = init.clone();
+        data.extend_from_slice(&encrypted);
+        tokio::fs::write(cache_dir.path().join("00_000000_a.mp4"), &data).await?;
+
+        // recorded with a wrong key
+        let segment = SegmentInfo {
+            file_name: "a.mp4".to_string(),
+            initial_segment: InitialSegment::Clear(Arc::new(init)),
+            key: Some(Arc::new(IoriKey::Aes128 { key: [0; 16], iv })),
+            ..Default::default()
+        };
+        let cached = CachedSegment::new(&segment, "00_000000_a.mp4".to_string());
+        assert_eq!(cached.clear_prefix, 4);
+
+        let line = serde_json::to_string(&cached)?;
+        assert!(line.contains(r#""method":"aes-128""#));
+        assert!(line.contains(&format!(r#""iv":"{}""#, hex::encode(iv))));
+        // recorded twice by a resumed download
+        tokio::fs::write(
+            cache_dir.path().join(SEGMENTS_FILE),
+            format!("{line}\n{line}\n"),
+        )
+        .await?;
+        assert_eq!(read_cached_segments(cache_dir.path()).await?, [cached]);
+
+        // the wrong key fails to unpad, or produces garbage
+        let result = decrypt_cache_dir(cache_dir.path(), &output_dir, None, None).await;
+        assert!(
+            result.is_err()
+                || tokio::fs::read(output_dir.join("00_000000_a.mp4")).await?
+                    != b"initsegment data"
+        );
+
+        // decrypt again with the right key
+        let count =
+            decrypt_cache_dir(cache_dir.path(), &output_dir, Some(&hex::encode(key)), None).await?;
+        assert_eq!(count, 1);
+        assert_eq!(
+            tokio::fs::read(output_dir.join("00_000000_a.mp4")).await?,
+            b"initsegment data"
+        );
+        let index = read_index(&output_dir).await?;
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[0].segment_format(), SegmentFormat::Mp4);
+        // cached files are kept encrypted
+        assert_eq!(
+            tokio::fs::read(cache_dir.path().join("00_000000_a.mp4")).await?,
+            data
+        );
+
+        Ok(())
+    }
+}
@@ -3,3 +3,147 @@ pub use sequencial::SequencialDownloader;
 
 mod parallel;
 pub use parallel::ParallelDownloader;
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncReadExt, AsyncWrite};
+
+use crate::{
+    IoriResult, SNIFF_SIZE, SegmentFormat, SegmentInfo, SegmentType,
+    cache::{CacheSource, CacheSourceWriter},
+};
+
+/// A cache writer which keeps the first [SNIFF_SIZE] bytes written, so that the format of
+/// a segment can be detected without reading it back from the cache.
+pub(crate) struct SniffWriter {
+    inner: CacheSourceWriter,
+    head: Vec<u8>,
+}
+
+impl SniffWriter {
+    pub(crate) fn new(inner: CacheSourceWriter) -> Self {
+        Self {
+            inner,
+            head: Vec::with_capacity(SNIFF_SIZE),
+        }
+    }
+
+    /// Drop the cache writer and return the head of the written data.
+    pub(crate) fn into_head(self) -> Vec<u8> {
+        self.head
+    }
+}
+
+impl AsyncWrite for SniffWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            let remaining = SNIFF_SIZE - this.head.len();
+            this.head.extend_from_slice(&buf[..written.min(remaining)]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Detect the format of a downloaded segment, and record it in the cache so that the
+/// cache can be merged later with the same format.
+///
+/// `head` is the beginning of the data written by [SniffWriter]. It is `None` for segments
+/// which were already cached.
+pub(crate) async fn record_segment<C>(
+    segment: &mut SegmentInfo,
+    cache: &C,
+    format: Option<&SegmentFormat>,
+    head: Option<&[u8]>,
+) where
+    C: CacheSource,
+{
+    detect_format(segment, cache, format, head).await;
+    if let Err(e) = cache.record_segment(segment).await {
+        tracing::warn!("Failed to record {} in the cache: {e}", segment.file_name);
+    }
+}
+
+/// Correct the format hint of a downloaded segment by sniffing its data, so that mergers do
+/// not depend on the extension in the URL.
+///
+/// If `format` is set, it is used for all media segments instead of sniffing. Raw
+/// segments are not changed.
+async fn detect_format<C>(
+    segment: &mut SegmentInfo,
+    cache: &C,
+    format: Option<&SegmentFormat>,
+    head: Option<&[u8]>,
+) where
+    C: CacheSource,
+{
+    if matches!(segment.format, SegmentFormat::Raw(_)) {
+        return;
+    }
+    if let Some(format) = format {
+        if segment.r#type != SegmentType::Subtitle {
+            segment.format = format.clone();
+        }
+        return;
+    }
+
+    let head = match head {
+        Some(head) => head.to_vec(),
+        None => match read_head(segment, cache).await {
+            Ok(Some(head)) => head,
+            // the cached data can not be read without consuming it
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("Failed to detect format of {}: {e}", segment.file_name);
+                return;
+            }
+        },
+    };
+
+    let format = segment.format.clone().refine(&head);
+    if format != segment.format {
+        tracing::debug!(
+            "Detected {} format for {}",
+            format.as_ext(),
+            segment.file_name
+        );
+        segment.format = format;
+    }
+}
+
+/// Read the head of an already cached segment from its stored location.
+///
+/// Readers of some cache sources drop the segment once it is read, so the head is only
+/// read from caches which store segments as files.
+async fn read_head<C>(segment: &SegmentInfo, cache: &C) -> IoriResult<Option<Vec<u8>>>
+where
+    C: CacheSource,
+{
+    let Some(path) = cache.segment_path(segment).await else {
+        return Ok(None);
+    };
+
+    let mut head = Vec::with_capacity(SNIFF_SIZE);
+    tokio::fs::File::open(path)
+        .await?
+        .take(SNIFF_SIZE as u64)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(Some(head))
+}
@@ -1,6 +1,10 @@
+use super::{record_segment, SniffWriter};
 use crate::{
-    cache::CacheSource, error::IoriResult, merge::Merger, IoriError, SegmentInfo, StreamingSegment,
-    StreamingSource,
+    cache::CacheSource,
+    error::IoriResult,
+    merge::Merger,
+    processor::{SegmentProcessor, SegmentProcessors},
+    IoriError, SegmentFormat, SegmentInfo, StreamingSegment, StreamingSource,
 };
 use std::{
     num::NonZeroU32,
@@ -28,6 +32,8 @@ where
 
     cache: Arc<C>,
     merger: Arc<Mutex<M>>,
+    processors: SegmentProcessors,
+    format: Option<SegmentFormat>,
 
     retries: u32,
 }
@@ -52,6 +58,8 @@ where
         source: S,
         merger: M,
         cache: C,
+        processors: SegmentProcessors,
+        format: Option<SegmentFormat>,
         concurrency: NonZeroU32,
         retries: u32,
     ) -> Self {
@@ -61,6 +69,8 @@ where
             source: Arc::new(source),
             merger: Arc::new(Mutex::new(merger)),
             cache: Arc::new(cache),
+            processors,
+            format,
             concurrency,
             permits,
 
@@ -108,7 +118,7 @@ where
             tracing::info!("{} new segments were added to queue.", segments.len());
 
             for segment in segments {
-                let segment_info = SegmentInfo::from(&segment);
+                let mut segment_info = SegmentInfo::from(&segment);
 
                 let permit = self.permits.clone().acquire_owned().await.unwrap();
                 let segments_downloaded = self.downloaded.clone();
@@ -119,12 +129,14 @@ where
                 let source = self.source.clone();
                 let merger = self.merger.clone();
                 let cache = self.cache.clone();
+                let processors = self.processors.clone();
+                let format = self.format.clone();
 
                 let mut retries = self.retries;
                 tokio::spawn(async move {
                     let filename = segment.file_name();
 
-                    loop {
+                    let head = loop {
                         if retries == 0 {
                             tracing::error!(
                                 "Processing {filename} failed, max retries exceed, drop."
@@ -141,12 +153,19 @@ where
                         let writer = cache.open_writer(&segment_info).await.transpose();
                         let Some(writer) = writer else {
                             segments_downloaded.fetch_add(1, Ordering::Relaxed);
+                            record_segment(
+                                &mut segment_info,
+                                cache.as_ref(),
+                                format.as_ref(),
+                                None,
+                            )
+                            .await;
                             _ = merger.lock().await.update(segment_info, cache).await;
                             return;
                         };
 
                         let mut writer = match writer {
-                            Ok(writer) => writer,
+                            Ok(writer) => SniffWriter::new(writer),
                             Err(e) => {
                                 tracing::warn!(
                                     "Failed to open writer for {filename}: {e}. Retrying later."
@@ -157,15 +176,21 @@ where
                         };
 
                         // Workaround for `higher-ranked lifetime error`
-                        let result = assert_send(source.fetch_segment(&segment, &mut writer)).await;
+                        let result = assert_send(processors.fetch_segment(
+                            source.as_ref(),
+                            &segment,
+                            &segment_info,
+                            &mut writer,
+                        ))
+                        .await;
                         let result = match result {
                             // graceful shutdown
                             Ok(_) => writer.shutdown().await.map_err(IoriError::IOError),
                             Err(e) => Err(e),
                         };
-                        drop(writer);
+                        let head = writer.into_head();
                         match result {
-                            Ok(_) => break,
+                            Ok(_) => break head,
                             Err(e) => {
                                 // invalidate the cache on failure
                                 _ = cache.invalidate(&segment_info).await;
@@ -174,7 +199,7 @@ where
                                 retries -= 1;
                             }
                         }
-                    }
+                    };
 
                     // here we can not drop semaphore, because the merger might take some time to process the merging
 
@@ -192,6 +217,13 @@ where
                         "Processing {filename} finished. ({downloaded} / {total} or {percentage:.2}%)"
                     );
 
+                    record_segment(
+                        &mut segment_info,
+                        cache.as_ref(),
+                        format.as_ref(),
+                        Some(head.as_slice()),
+                    )
+                    .await;
                     _ = merger.lock().await.update(segment_info, cache).await;
 
                     // drop permit to release the semaphore
@@ -240,6 +272,8 @@ pub struct ParallelDownloaderBuilder<M, C, MR = ()> {
     retries: u32,
     merger: Option<M>,
     cache: Option<C>,
+    processors: SegmentProcessors,
+    format: Option<SegmentFormat>,
 
     _merge_result: std::marker::PhantomData<MR>,
 }
@@ -255,6 +289,8 @@ where
             retries: 3,
             merger: None,
             cache: None,
+            processors: SegmentProcessors::new(),
+            format: None,
             _merge_result: Default::default(),
         }
     }
@@ -279,6 +315,20 @@ where
         self
     }
 
+    /// Add a processor of segment data before it is written into the cache.
+    ///
+    /// Processors are applied in the order they are added.
+    pub fn processor(mut self, processor: impl SegmentProcessor) -> Self {
+        self.processors.push(processor);
+        self
+    }
+
+    /// Use the format for all media segments instead of detecting it from their data.
+    pub fn segment_format(mut self, format: SegmentFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
     fn build<S>(self, source: S) -> ParallelDownloader<S, M, C>
     where
         S: StreamingSource + Send + Sync + 'static,
@@ -287,6 +337,8 @@ where
             source,
             self.merger.expect("Merger is not set"),
             self.cache.expect("Cache is not set"),
+            self.processors,
+            self.format,
             self.concurrency,
             self.retries,
         )
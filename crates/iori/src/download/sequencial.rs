@@ -2,8 +2,13 @@ use std::sync::Arc;
 
 use tokio::io::AsyncWriteExt;
 
+use super::{record_segment, SniffWriter};
 use crate::{
-    cache::CacheSource, error::IoriResult, merge::Merger, IoriError, SegmentInfo, StreamingSource,
+    cache::CacheSource,
+    error::IoriResult,
+    merge::Merger,
+    processor::{SegmentProcessor, SegmentProcessors},
+    IoriError, SegmentFormat, SegmentInfo, StreamingSource,
 };
 
 pub struct SequencialDownloader<S, M, C>
@@ -15,6 +20,8 @@ where
     source: S,
     merger: M,
     cache: Arc<C>,
+    processors: SegmentProcessors,
+    format: Option<SegmentFormat>,
 }
 
 impl<S, M, C> SequencialDownloader<S, M, C>
@@ -28,36 +35,64 @@ where
             source,
             merger,
             cache: Arc::new(cache),
+            processors: SegmentProcessors::new(),
+            format: None,
         }
     }
 
-    pub async fn download(&mut self) -> IoriResult<()> {
+    /// Add a processor of segment data before it is written into the cache.
+    ///
+    /// Processors are applied in the order they are added.
+    pub fn processor(mut self, processor: impl SegmentProcessor) -> Self {
+        self.processors.push(processor);
+        self
+    }
+
+    /// Use the format for all media segments instead of detecting it from their data.
+    pub fn segment_format(mut self, format: SegmentFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub async fn download(&mut self) -> IoriResult<M::Result> {
         let mut receiver = self.source.fetch_info().await?;
 
         while let Some(segment) = receiver.recv().await {
             for segment in segment? {
-                let segment_info = SegmentInfo::from(&segment);
+                let mut segment_info = SegmentInfo::from(&segment);
                 let writer = self.cache.open_writer(&segment_info).await?;
-                let Some(mut writer) = writer else {
+                let Some(writer) = writer else {
                     continue;
                 };
+                let mut writer = SniffWriter::new(writer);
 
-                let fetch_result = self.source.fetch_segment(&segment, &mut writer).await;
+                let fetch_result = self
+                    .processors
+                    .fetch_segment(&self.source, &segment, &segment_info, &mut writer)
+                    .await;
                 let fetch_result = match fetch_result {
                     // graceful shutdown
                     Ok(_) => writer.shutdown().await.map_err(IoriError::IOError),
                     Err(e) => Err(e),
                 };
-                drop(writer);
+                let head = writer.into_head();
 
                 match fetch_result {
-                    Ok(_) => self.merger.update(segment_info, self.cache.clone()).await?,
+                    Ok(_) => {
+                        record_segment(
+                            &mut segment_info,
+                            self.cache.as_ref(),
+                            self.format.as_ref(),
+                            Some(head.as_slice()),
+                        )
+                        .await;
+                        self.merger.update(segment_info, self.cache.clone()).await?
+                    }
                     Err(_) => self.merger.fail(segment_info, self.cache.clone()).await?,
                 }
             }
         }
 
-        self.merger.finish(self.cache.clone()).await?;
-        Ok(())
+        self.merger.finish(self.cache.clone()).await
     }
 }
@@ -18,8 +18,11 @@ pub enum IoriError {
     #[error("Invalid binary key: {0:?}")]
     InvalidBinaryKey(Vec<u8>),
 
-    #[error("mp4decrypt error: {0}")]
-    Mp4DecryptError(#[from] mp4decrypt::Error),
+    #[error("No key for KID {0}")]
+    KeyNotFound(String),
+
+    #[error("Unsupported encryption scheme: {0}")]
+    UnsupportedEncryptionScheme(String),
 
     #[error("iori-ssa error: {0:?}")]
     IoriSsaError(#[from] iori_ssa::Error),
@@ -83,6 +86,33 @@ pub enum IoriError {
     #[error("Invalid date time: {0}")]
     DateTimeParsing(String),
 
+    #[error("Invalid mp4: {0}")]
+    Mp4Parsing(String),
+
+    // Merge errors
+    #[error("Invalid failure policy: {0}")]
+    InvalidFailurePolicy(String),
+
+    #[error("{missing:.2}% of segments are missing, exceeding the limit of {limit}%")]
+    TooManyMissingSegments { missing: f64, limit: f64 },
+
+    #[error("Failed to remux: {0}")]
+    Remuxing(String),
+
+    // Subtitle errors
+    #[error("Invalid subtitle: {0}")]
+    SubtitleParsing(String),
+
+    // Processor errors
+    #[error("Failed to process segment: {0}")]
+    SegmentProcessing(String),
+
+    #[error("Invalid segment {file_name}: {reason}")]
+    InvalidSegment { file_name: String, reason: String },
+
+    #[error(transparent)]
+    XmlError(#[from] quick_xml::Error),
+
     #[cfg(feature = "ffmpeg")]
     #[error(transparent)]
     RsmpegError(#[from] rsmpeg::error::RsmpegError),
@@ -91,7 +121,6 @@ pub enum IoriError {
     #[error(transparent)]
     InvalidTrackPath(#[from] std::ffi::NulError),
 
-    #[cfg(feature = "ffmpeg")]
     #[error(transparent)]
     JoinError(#[from] tokio::task::JoinError),
 }
@@ -4,16 +4,22 @@ use reqwest::header::RANGE;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::{
+    decrypt::IoriKey,
     error::{IoriError, IoriResult},
     util::http::HttpClient,
     InitialSegment, RemoteStreamingSegment, StreamingSegment, ToSegmentData,
 };
 
+/// Fetch a segment and write it with its initialization segment into `tmp_file`.
+///
+/// The segment is decrypted unless `defer_decryption` is set, in which case encrypted data
+/// is written as is, to be decrypted later with its key.
 pub async fn fetch_segment<S, W>(
     client: HttpClient,
     segment: &S,
     tmp_file: &mut W,
     shaka_packager_command: Option<PathBuf>,
+    defer_decryption: bool,
 ) -> IoriResult<()>
 where
     S: StreamingSegment + ToSegmentData,
@@ -21,28 +27,41 @@ where
 {
     let bytes = segment.to_segment_data(client).await?;
 
+    // AES-128 encrypted data must consist of whole blocks, or it is truncated
+    let is_aes128 = segment
+        .key()
+        .is_some_and(|key| matches!(*key, IoriKey::Aes128 { .. }));
+    if is_aes128 && !bytes.len().is_multiple_of(16) {
+        return Err(IoriError::InvalidSegment {
+            file_name: segment.file_name().to_string(),
+            reason: format!("{} bytes are not aligned to AES blocks", bytes.len()),
+        });
+    }
+
     // TODO: use bytes_stream to improve performance
     // .bytes_stream();
+    // samples can not be located without the initialization segment, even if it is clear
+    let needs_initial_segment = segment.key().is_some_and(|key| key.is_sample_encryption());
     let decryptor = segment
         .key()
+        .filter(|_| !defer_decryption)
         .map(|key| key.to_decryptor(shaka_packager_command));
     if let Some(decryptor) = decryptor {
-        let decrypted_bytes = match segment.initial_segment() {
-            crate::InitialSegment::Encrypted(data) => {
+        match segment.initial_segment() {
+            crate::InitialSegment::Clear(data) if !needs_initial_segment => {
+                tmp_file.write_all(&data).await?;
+                decryptor.decrypt_to(&bytes, tmp_file).await?;
+            }
+            crate::InitialSegment::Clear(data) | crate::InitialSegment::Encrypted(data) => {
                 let mut result = data.to_vec();
                 result.extend_from_slice(&bytes);
-                decryptor.decrypt(&result).await?
+                decryptor.decrypt_to(&result, tmp_file).await?;
             }
-            crate::InitialSegment::Clear(data) => {
-                tmp_file.write_all(&data).await?;
-                decryptor.decrypt(&bytes).await?
-            }
-            crate::InitialSegment::None => decryptor.decrypt(&bytes).await?,
-        };
-        tmp_file.write_all(&decrypted_bytes).await?;
+            crate::InitialSegment::None => decryptor.decrypt_to(&bytes, tmp_file).await?,
+        }
     } else {
-        // If no key is provided, no matter whether the initial segment is encrypted or not,
-        // we should write the initial segment to the file.
+        // If no key is provided or decryption is deferred, no matter whether the initial
+        // segment is encrypted or not, we should write the initial segment to the file.
         if let InitialSegment::Clear(initial_segment) | InitialSegment::Encrypted(initial_segment) =
             segment.initial_segment()
         {
@@ -67,7 +86,7 @@ where
         let byte_range = self.byte_range();
         let headers = self.headers();
         async move {
-            let mut request = client.get(url);
+            let mut request = client.get(url.clone());
             if let Some(headers) = headers {
                 request = request.headers(headers);
             }
@@ -83,7 +102,17 @@ where
                 return Err(IoriError::HttpError(status));
             }
 
+            // some CDNs close the connection early with a success status
+            let content_length = response.content_length();
             let bytes = response.bytes().await?;
+            if let Some(content_length) =
+                content_length.filter(|length| *length != bytes.len() as u64)
+            {
+                return Err(IoriError::InvalidSegment {
+                    file_name: url.to_string(),
+                    reason: format!("expected {content_length} bytes, got {}", bytes.len()),
+                });
+            }
             Ok(bytes)
         }
     }
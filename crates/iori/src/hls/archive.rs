@@ -7,6 +7,7 @@ use tokio::{
 use url::Url;
 
 use crate::{
+    ad::AdFilter,
     error::IoriResult,
     fetch::fetch_segment,
     hls::{segment::M3u8Segment, source::HlsPlaylistSource},
@@ -20,6 +21,7 @@ pub struct CommonM3u8ArchiveSource {
     range: SegmentRange,
     retry: u32,
     shaka_packager_command: Option<PathBuf>,
+    defer_decryption: bool,
 }
 
 /// A subrange for m3u8 archive sources to choose which segment to use
@@ -81,6 +83,7 @@ impl CommonM3u8ArchiveSource {
                 key,
             ))),
             shaka_packager_command,
+            defer_decryption: false,
             range,
             retry: 3,
         }
@@ -90,6 +93,21 @@ impl CommonM3u8ArchiveSource {
         self.retry = retry;
         self
     }
+
+    /// Cache segments without decrypting them, to be decrypted after downloading.
+    pub fn with_deferred_decryption(mut self, defer_decryption: bool) -> Self {
+        self.defer_decryption = defer_decryption;
+        self
+    }
+
+    /// Skip segments in ad breaks detected by the filter.
+    pub fn with_ad_filter(mut self, ad_filter: AdFilter) -> Self {
+        // the playlist is not shared before fetching
+        if let Some(playlist) = Arc::get_mut(&mut self.playlist) {
+            playlist.get_mut().set_ad_filter(ad_filter);
+        }
+        self
+    }
 }
 
 impl StreamingSource for CommonM3u8ArchiveSource {
@@ -139,6 +157,7 @@ impl StreamingSource for CommonM3u8ArchiveSource {
             segment,
             writer,
             self.shaka_packager_command.clone(),
+            self.defer_decryption,
         )
         .await?;
         Ok(())
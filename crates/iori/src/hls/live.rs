@@ -7,6 +7,7 @@ use tokio::{
 use url::Url;
 
 use crate::{
+    ad::AdFilter,
     error::{IoriError, IoriResult},
     fetch::fetch_segment,
     hls::{segment::M3u8Segment, source::HlsPlaylistSource},
@@ -19,6 +20,7 @@ pub struct HlsLiveSource {
     playlist: Arc<Mutex<HlsPlaylistSource>>,
     retry: u32,
     shaka_packager_command: Option<PathBuf>,
+    defer_decryption: bool,
 }
 
 impl HlsLiveSource {
@@ -36,6 +38,7 @@ impl HlsLiveSource {
                 key,
             ))),
             shaka_packager_command,
+            defer_decryption: false,
             retry: 3,
         }
     }
@@ -44,6 +47,21 @@ impl HlsLiveSource {
         self.retry = retry;
         self
     }
+
+    /// Cache segments without decrypting them, to be decrypted after downloading.
+    pub fn with_deferred_decryption(mut self, defer_decryption: bool) -> Self {
+        self.defer_decryption = defer_decryption;
+        self
+    }
+
+    /// Skip segments in ad breaks detected by the filter.
+    pub fn with_ad_filter(mut self, ad_filter: AdFilter) -> Self {
+        // the playlist is not shared before fetching
+        if let Some(playlist) = Arc::get_mut(&mut self.playlist) {
+            playlist.get_mut().set_ad_filter(ad_filter);
+        }
+        self
+    }
 }
 
 impl StreamingSource for HlsLiveSource {
@@ -138,6 +156,7 @@ impl StreamingSource for HlsLiveSource {
             segment,
             writer,
             self.shaka_packager_command.clone(),
+            self.defer_decryption,
         )
         .await?;
         Ok(())
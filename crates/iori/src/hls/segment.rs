@@ -1,6 +1,6 @@
 use crate::{
-    decrypt::IoriKey, ByteRange, InitialSegment, RemoteStreamingSegment, SegmentFormat,
-    SegmentType, StreamingSegment,
+    chapter::SegmentEvent, decrypt::IoriKey, ByteRange, InitialSegment, RemoteStreamingSegment,
+    SegmentFormat, SegmentType, StreamingSegment,
 };
 use std::sync::Arc;
 
@@ -24,6 +24,8 @@ pub struct M3u8Segment {
     pub segment_type: Option<SegmentType>,
     pub duration: f32,
     pub format: SegmentFormat,
+    /// Events from `EXT-X-DATERANGE` and cue tags before the segment
+    pub events: Vec<SegmentEvent>,
 }
 
 impl StreamingSegment for M3u8Segment {
@@ -54,6 +56,14 @@ impl StreamingSegment for M3u8Segment {
     fn format(&self) -> SegmentFormat {
         self.format.clone()
     }
+
+    fn duration(&self) -> Option<f64> {
+        Some(self.duration as f64)
+    }
+
+    fn events(&self) -> Vec<SegmentEvent> {
+        self.events.clone()
+    }
 }
 
 impl RemoteStreamingSegment for M3u8Segment {
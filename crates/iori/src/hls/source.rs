@@ -11,6 +11,7 @@ use m3u8_rs::{AlternativeMedia, AlternativeMediaType, MediaPlaylist, Playlist};
 use reqwest::Url;
 
 use crate::{
+    ad::{AdBreakState, AdFilter},
     decrypt::IoriKey,
     error::IoriResult,
     hls::{segment::M3u8Segment, utils::load_m3u8},
@@ -18,7 +19,7 @@ use crate::{
     InitialSegment, SegmentFormat, SegmentType,
 };
 
-use super::utils::load_playlist_with_retry;
+use super::utils::{load_playlist_with_retry, segment_events};
 
 /// Core part to perform network operations
 pub struct HlsMediaPlaylistSource {
@@ -37,6 +38,12 @@ pub struct HlsMediaPlaylistSource {
 
     client: HttpClient,
     initial_playlist: Option<MediaPlaylist>,
+
+    /// Filter of ad segments
+    ad_filter: AdFilter,
+    ad_break: AdBreakState,
+    /// Media sequence of the last segment passed to the ad filter, including dropped ones
+    latest_filtered_sequence: Option<u64>,
 }
 
 /// A source to fetch segments from a Media Playlist
@@ -67,9 +74,18 @@ impl HlsMediaPlaylistSource {
             client,
             segment_type,
             stream_id,
+
+            ad_filter: AdFilter::default(),
+            ad_break: AdBreakState::default(),
+            latest_filtered_sequence: None,
         }
     }
 
+    pub fn with_ad_filter(mut self, ad_filter: AdFilter) -> Self {
+        self.ad_filter = ad_filter;
+        self
+    }
+
     pub async fn load_segments(
         &mut self,
         latest_media_sequence: &Option<u64>,
@@ -157,6 +173,28 @@ impl HlsMediaPlaylistSource {
                 }
             }
 
+            let events = segment_events(segment);
+            if self.ad_filter.is_enabled() {
+                // ads at the end of the last playlist are not returned, so they are loaded again
+                if self
+                    .latest_filtered_sequence
+                    .is_some_and(|latest| media_sequence <= latest)
+                {
+                    continue;
+                }
+                self.latest_filtered_sequence = Some(media_sequence);
+
+                let in_cue_break =
+                    self.ad_break
+                        .update(&events, segment.duration as f64, segment.discontinuity);
+                let reason = self.ad_filter.check_segment(url.as_str(), in_cue_break);
+                if reason.is_some_and(|reason| {
+                    self.ad_filter.should_drop(format!("segment {url}"), reason)
+                }) {
+                    continue;
+                }
+            }
+
             let m3u8_segment = M3u8Segment {
                 stream_id: self.stream_id,
                 url,
@@ -172,6 +210,7 @@ impl HlsMediaPlaylistSource {
                 duration: segment.duration,
                 segment_type: self.segment_type,
                 format,
+                events,
             };
             segments.push(m3u8_segment);
 
@@ -208,6 +247,8 @@ pub struct HlsPlaylistSource {
 
     key: Option<String>,
     client: HttpClient,
+    /// Filter of ad segments, applied to each stream
+    ad_filter: AdFilter,
 }
 
 impl HlsPlaylistSource {
@@ -217,9 +258,14 @@ impl HlsPlaylistSource {
             key: key.map(str::to_string),
             client,
             streams: Vec::new(),
+            ad_filter: AdFilter::default(),
         }
     }
 
+    pub fn set_ad_filter(&mut self, ad_filter: AdFilter) {
+        self.ad_filter = ad_filter;
+    }
+
     pub async fn load_streams(&mut self, retry: u32) -> IoriResult<Vec<Option<u64>>> {
         let playlist = load_playlist_with_retry(&self.client, &self.url, retry).await?;
 
@@ -249,14 +295,17 @@ impl HlsPlaylistSource {
                 });
                 let variant = variants.first().expect("No variant found");
                 let variant_url = self.url.join(&variant.uri)?;
-                self.streams.push(HlsMediaPlaylistSource::new(
-                    self.client.clone(),
-                    variant_url.to_string(),
-                    None,
-                    self.key.as_deref(),
-                    Some(SegmentType::Video),
-                    0,
-                ));
+                self.streams.push(
+                    HlsMediaPlaylistSource::new(
+                        self.client.clone(),
+                        variant_url.to_string(),
+                        None,
+                        self.key.as_deref(),
+                        Some(SegmentType::Video),
+                        0,
+                    )
+                    .with_ad_filter(self.ad_filter.clone()),
+                );
 
                 fn load_variant<'a>(
                     group_id: &str,
@@ -285,14 +334,17 @@ impl HlsPlaylistSource {
                     {
                         let m3u8_url = self.url.join(audio_url)?.to_string();
                         if !self.streams.iter().any(|s| s.url == m3u8_url) {
-                            self.streams.push(HlsMediaPlaylistSource::new(
-                                self.client.clone(),
-                                m3u8_url,
-                                None,
-                                self.key.as_deref(),
-                                Some(SegmentType::Audio),
-                                1,
-                            ));
+                            self.streams.push(
+                                HlsMediaPlaylistSource::new(
+                                    self.client.clone(),
+                                    m3u8_url,
+                                    None,
+                                    self.key.as_deref(),
+                                    Some(SegmentType::Audio),
+                                    1,
+                                )
+                                .with_ad_filter(self.ad_filter.clone()),
+                            );
                         }
                     }
                 }
@@ -302,27 +354,33 @@ impl HlsPlaylistSource {
                     {
                         let m3u8_url = self.url.join(video_url)?.to_string();
                         if !self.streams.iter().any(|s| s.url == m3u8_url) {
-                            self.streams.push(HlsMediaPlaylistSource::new(
-                                self.client.clone(),
-                                self.url.join(video_url)?.to_string(),
-                                None,
-                                self.key.as_deref(),
-                                Some(SegmentType::Video),
-                                2,
-                            ));
+                            self.streams.push(
+                                HlsMediaPlaylistSource::new(
+                                    self.client.clone(),
+                                    self.url.join(video_url)?.to_string(),
+                                    None,
+                                    self.key.as_deref(),
+                                    Some(SegmentType::Video),
+                                    2,
+                                )
+                                .with_ad_filter(self.ad_filter.clone()),
+                            );
                         }
                     }
                 }
             }
             Playlist::MediaPlaylist(pl) => {
-                self.streams.push(HlsMediaPlaylistSource::new(
-                    self.client.clone(),
-                    self.url.to_string(),
-                    Some(pl),
-                    self.key.as_deref(),
-                    Some(SegmentType::Video),
-                    0,
-                ));
+                self.streams.push(
+                    HlsMediaPlaylistSource::new(
+                        self.client.clone(),
+                        self.url.to_string(),
+                        Some(pl),
+                        self.key.as_deref(),
+                        Some(SegmentType::Video),
+                        0,
+                    )
+                    .with_ad_filter(self.ad_filter.clone()),
+                );
             }
         }
         Ok(vec![None; self.streams.len()])
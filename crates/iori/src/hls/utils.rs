@@ -1,7 +1,8 @@
-use m3u8_rs::{MediaPlaylist, Playlist};
+use m3u8_rs::{MediaPlaylist, MediaSegment, Playlist};
 use reqwest::{Client, Url};
 
 use crate::{
+    chapter::{SegmentEvent, SegmentEventKind},
     error::{IoriError, IoriResult},
     util::http::HttpClient,
 };
@@ -113,3 +114,85 @@ pub async fn load_m3u8(
         Playlist::MediaPlaylist(pl) => Ok((url, pl)),
     }
 }
+
+/// Events from `EXT-X-DATERANGE`, `EXT-X-CUE-OUT` and `EXT-X-CUE-IN` tags of a segment.
+pub(crate) fn segment_events(segment: &MediaSegment) -> Vec<SegmentEvent> {
+    let mut events = Vec::new();
+
+    if let Some(daterange) = &segment.daterange {
+        let attributes = daterange.other_attributes.as_ref();
+        let has_attribute = |name: &str| attributes.is_some_and(|a| a.contains_key(name));
+        let kind = if has_attribute("SCTE35-OUT") {
+            SegmentEventKind::AdStart
+        } else if has_attribute("SCTE35-IN") {
+            SegmentEventKind::AdEnd
+        } else {
+            SegmentEventKind::Marker
+        };
+        let title = daterange
+            .class
+            .clone()
+            .filter(|_| kind == SegmentEventKind::Marker)
+            .or_else(|| Some(daterange.id.clone()));
+        events.push(
+            SegmentEvent::new(kind)
+                .with_title(title)
+                .with_duration(daterange.duration.or(daterange.planned_duration)),
+        );
+    }
+
+    for tag in &segment.unknown_tags {
+        match tag.tag.as_str() {
+            // #EXT-X-CUE-OUT:30 or #EXT-X-CUE-OUT:DURATION=30
+            "X-CUE-OUT" => {
+                let duration = tag.rest.as_deref().and_then(|rest| {
+                    let rest = rest.trim();
+                    let rest = rest.strip_prefix("DURATION=").unwrap_or(rest);
+                    rest.split(',').next()?.trim().parse().ok()
+                });
+                events.push(SegmentEvent::new(SegmentEventKind::AdStart).with_duration(duration));
+            }
+            "X-CUE-IN" => events.push(SegmentEvent::new(SegmentEventKind::AdEnd)),
+            _ => {}
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_events() {
+        let playlist = r#"#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXTINF:6,
+0.ts
+#EXT-X-CUE-OUT:DURATION=30
+#EXTINF:6,
+1.ts
+#EXT-X-CUE-IN
+#EXTINF:6,
+2.ts
+#EXT-X-DATERANGE:ID="talk",CLASS="Talk",START-DATE="2024-01-01T00:00:18Z",DURATION=12.0
+#EXTINF:6,
+3.ts
+"#;
+        let playlist = m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap();
+        let events: Vec<_> = playlist.segments.iter().map(segment_events).collect();
+        assert!(events[0].is_empty());
+        assert_eq!(
+            events[1],
+            [SegmentEvent::new(SegmentEventKind::AdStart).with_duration(Some(30.))]
+        );
+        assert_eq!(events[2], [SegmentEvent::new(SegmentEventKind::AdEnd)]);
+        assert_eq!(
+            events[3],
+            [SegmentEvent::new(SegmentEventKind::Marker)
+                .with_title(Some("Talk".to_string()))
+                .with_duration(Some(12.))]
+        );
+    }
+}
@@ -1,9 +1,15 @@
+pub mod ad;
 pub mod cache;
+pub mod chapter;
 pub mod decrypt;
 pub mod download;
 pub mod fetch;
 pub mod merge;
+pub mod metadata;
+pub mod processor;
 pub mod raw;
+pub mod subtitle;
+pub mod timed_metadata;
 
 pub mod dash;
 pub mod hls;
@@ -86,6 +92,16 @@ pub trait StreamingSegment {
 
     /// Format hint for the segment
     fn format(&self) -> SegmentFormat;
+
+    /// Duration of the segment in seconds, if known
+    fn duration(&self) -> Option<f64> {
+        None
+    }
+
+    /// Timed events starting in the segment, like ad breaks or named ranges
+    fn events(&self) -> Vec<chapter::SegmentEvent> {
+        Vec::new()
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
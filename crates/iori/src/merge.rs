@@ -1,18 +1,36 @@
 mod auto;
+mod chapter;
 mod concat;
+mod deferred;
 #[cfg(feature = "ffmpeg")]
 mod ffmpeg;
+mod fmp4;
+mod incremental;
 mod pipe;
+mod playlist;
+mod policy;
+mod remux;
+mod report;
+mod serve;
 mod skip;
+mod tee;
 
 pub use auto::AutoMerger;
 pub use concat::ConcatAfterMerger;
+pub use deferred::DeferredDecryptionMerger;
+pub use incremental::IncrementalMerger;
 pub use pipe::PipeMerger;
+pub use playlist::PlaylistMerger;
+pub use policy::FailurePolicy;
+pub use remux::RemuxMerger;
+pub use report::{FailedSegment, Gap, MergeReport, TrackReport};
+pub use serve::ServeMerger;
 pub use skip::SkipMerger;
+pub use tee::TeeMerger;
 use tokio::io::AsyncWrite;
 
-use crate::{cache::CacheSource, error::IoriResult, SegmentInfo};
-use std::{future::Future, path::PathBuf};
+use crate::{cache::CacheSource, error::IoriResult, metadata::Metadata, SegmentInfo};
+use std::{future::Future, net::SocketAddr, path::PathBuf};
 
 pub trait Merger {
     /// Result of the merge.
@@ -47,6 +65,12 @@ pub enum IoriMerger {
     Skip(SkipMerger),
     Concat(ConcatAfterMerger),
     Auto(AutoMerger),
+    Remux(RemuxMerger),
+    Incremental(IncrementalMerger),
+    Tee(TeeMerger),
+    Serve(ServeMerger),
+    Playlist(PlaylistMerger),
+    DeferredDecryption(DeferredDecryptionMerger),
 }
 
 impl IoriMerger {
@@ -65,12 +89,22 @@ impl IoriMerger {
         Self::Pipe(PipeMerger::file(recycle, output_file))
     }
 
-    pub fn pipe_mux(recycle: bool, output_file: PathBuf, extra_commands: Option<String>) -> Self {
-        Self::Pipe(PipeMerger::mux(recycle, output_file, extra_commands))
+    pub fn pipe_mux(
+        recycle: bool,
+        output_file: PathBuf,
+        extra_commands: Option<String>,
+        streams: usize,
+    ) -> Self {
+        Self::Pipe(PipeMerger::mux(
+            recycle,
+            output_file,
+            extra_commands,
+            streams,
+        ))
     }
 
     pub fn skip() -> Self {
-        Self::Skip(SkipMerger)
+        Self::Skip(SkipMerger::new())
     }
 
     pub fn concat(output_file: PathBuf, keep_segments: bool) -> Self {
@@ -80,10 +114,58 @@ impl IoriMerger {
     pub fn auto(output_file: PathBuf, keep_segments: bool) -> Self {
         Self::Auto(AutoMerger::new(output_file, keep_segments))
     }
+
+    pub fn remux(output_file: PathBuf, keep_segments: bool) -> Self {
+        Self::Remux(RemuxMerger::new(output_file, keep_segments))
+    }
+
+    pub fn incremental(output_file: PathBuf, keep_segments: bool) -> Self {
+        Self::Incremental(IncrementalMerger::new(output_file, keep_segments))
+    }
+
+    pub fn tee(mergers: Vec<IoriMerger>) -> Self {
+        Self::Tee(TeeMerger::new(mergers))
+    }
+
+    pub fn serve(addr: SocketAddr, keep_segments: bool) -> IoriResult<Self> {
+        Ok(Self::Serve(ServeMerger::new(addr, keep_segments)?))
+    }
+
+    pub fn playlist() -> Self {
+        Self::Playlist(PlaylistMerger::new())
+    }
+
+    pub fn deferred_decryption() -> Self {
+        Self::DeferredDecryption(DeferredDecryptionMerger::new())
+    }
+
+    /// Set metadata written into the output file, for mergers which support it.
+    pub fn with_metadata(self, metadata: Metadata) -> Self {
+        match self {
+            Self::Concat(merger) => Self::Concat(merger.with_metadata(metadata)),
+            Self::Auto(merger) => Self::Auto(merger.with_metadata(metadata)),
+            Self::Remux(merger) => Self::Remux(merger.with_metadata(metadata)),
+            Self::Incremental(merger) => Self::Incremental(merger.with_metadata(metadata)),
+            Self::Tee(merger) => Self::Tee(merger.with_metadata(metadata)),
+            merger => merger,
+        }
+    }
+
+    /// Whether the merger removes each segment from the cache while downloading, instead of
+    /// keeping segments until it finishes.
+    pub(crate) fn invalidates_segments(&self) -> bool {
+        match self {
+            Self::Pipe(merger) => merger.invalidates_segments(),
+            Self::Incremental(merger) => merger.invalidates_segments(),
+            Self::Tee(merger) => merger.invalidates_segments(),
+            Self::Serve(merger) => merger.invalidates_segments(),
+            _ => false,
+        }
+    }
 }
 
 impl Merger for IoriMerger {
-    type Result = (); // TODO: merger might have different result types
+    type Result = MergeReport;
 
     async fn update(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
         match self {
@@ -91,6 +173,12 @@ impl Merger for IoriMerger {
             Self::Skip(merger) => merger.update(segment, cache).await,
             Self::Concat(merger) => merger.update(segment, cache).await,
             Self::Auto(merger) => merger.update(segment, cache).await,
+            Self::Remux(merger) => merger.update(segment, cache).await,
+            Self::Incremental(merger) => merger.update(segment, cache).await,
+            Self::Tee(merger) => merger.update(segment, cache).await,
+            Self::Serve(merger) => merger.update(segment, cache).await,
+            Self::Playlist(merger) => merger.update(segment, cache).await,
+            Self::DeferredDecryption(merger) => merger.update(segment, cache).await,
         }
     }
 
@@ -100,6 +188,12 @@ impl Merger for IoriMerger {
             Self::Skip(merger) => merger.fail(segment, cache).await,
             Self::Concat(merger) => merger.fail(segment, cache).await,
             Self::Auto(merger) => merger.fail(segment, cache).await,
+            Self::Remux(merger) => merger.fail(segment, cache).await,
+            Self::Incremental(merger) => merger.fail(segment, cache).await,
+            Self::Tee(merger) => merger.fail(segment, cache).await,
+            Self::Serve(merger) => merger.fail(segment, cache).await,
+            Self::Playlist(merger) => merger.fail(segment, cache).await,
+            Self::DeferredDecryption(merger) => merger.fail(segment, cache).await,
         }
     }
 
@@ -109,6 +203,12 @@ impl Merger for IoriMerger {
             Self::Skip(merger) => merger.finish(cache).await,
             Self::Concat(merger) => merger.finish(cache).await,
             Self::Auto(merger) => merger.finish(cache).await,
+            Self::Remux(merger) => merger.finish(cache).await,
+            Self::Incremental(merger) => merger.finish(cache).await,
+            Self::Tee(merger) => merger.finish(cache).await,
+            Self::Serve(merger) => merger.finish(cache).await,
+            Self::Playlist(merger) => merger.finish(cache).await,
+            Self::DeferredDecryption(merger) => merger.finish(cache).await,
         }
     }
 }
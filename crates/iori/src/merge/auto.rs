@@ -1,15 +1,27 @@
 use crate::{
-    cache::CacheSource, error::IoriResult, util::path::IoriPathExt, SegmentFormat, SegmentInfo,
-    SegmentType,
+    cache::CacheSource,
+    chapter::to_ogm_chapters,
+    error::IoriResult,
+    metadata::Metadata,
+    subtitle::{Subtitle, SubtitleFormat},
+    util::{mpegts, path::IoriPathExt},
+    SegmentFormat, SegmentInfo, SegmentType,
 };
 use std::{
     collections::HashMap,
     io::Write,
     path::{Path, PathBuf},
 };
-use tokio::{fs::File, io::BufWriter, process::Command};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, BufWriter},
+    process::Command,
+};
 
-use super::{concat::ConcatSegment, Merger};
+use super::{
+    chapter::collect_chapters, concat::ConcatSegment, fmp4::merge_fmp4, FailurePolicy, MergeReport,
+    Merger,
+};
 
 /// AutoMerger is a merger that automatically chooses the best strategy to merge segments.
 ///
@@ -17,23 +29,50 @@ use super::{concat::ConcatSegment, Merger};
 /// - It will use concat to merge segments.
 /// - If there is only one track, the behavior is the same as [ConcatAfterMerger].
 ///
+/// For fragmented MP4:
+/// - It will write the initialization segment once and append all fragments, without
+///   external tools. Multiple fragmented MP4 tracks are multiplexed into a single MP4 file.
+/// - The output can be rewritten into a progressive MP4 with [AutoMerger::with_progressive_mp4].
+///
+/// For multiple MPEG-TS tracks:
+/// - They are remuxed into a single MP4 file without external tools, like [RemuxMerger](super::RemuxMerger).
+///
 /// For other formats:
 /// - It will use mkvmerge to merge segments.
 ///
+/// For subtitles in fragmented MP4 (`wvtt` or `stpp`) or HLS WebVTT segments:
+/// - Cues are extracted into a standalone subtitle file in [SubtitleFormat].
+/// - WebVTT cue times are mapped with `X-TIMESTAMP-MAP`, relative to the first video PTS.
+/// - The subtitle file is muxed by mkvmerge, or saved next to the output file
+///   when merging with ffmpeg or natively.
+///
 /// If there are multiple tracks to merge, it will use mkvmerge to merge them.
-/// If there are any missing segments, the merge will be skipped.
+/// If there are any missing segments, the merge will be skipped by default.
+/// This can be changed with [AutoMerger::with_failure_policy].
+///
+/// [Metadata] set with [AutoMerger::with_metadata] is written as tags of MP4 and MKV outputs
+/// which are muxed. Other outputs, like a single MPEG-TS track, get a sidecar `.json` file.
+/// Chapters are built from ad breaks and markers of the segments, see [crate::chapter].
 pub struct AutoMerger {
     segments: HashMap<u64, Vec<ConcatSegment>>,
 
     /// Keep downloaded segments after merging.
     keep_segments: bool,
 
-    has_failed: bool,
+    /// What to do when some segments failed to download.
+    failure_policy: FailurePolicy,
 
     /// Final output file path. It may not have an extension.
     output_file: PathBuf,
     /// A list of file extensions which should skip adding an auto extension.
     allowed_extensions: Vec<&'static str>,
+
+    /// Output format of subtitles extracted from fragmented MP4.
+    subtitle_format: SubtitleFormat,
+    /// Rewrite natively merged fragmented MP4 into a progressive MP4.
+    progressive_mp4: bool,
+    /// Metadata written into the output file.
+    metadata: Metadata,
 }
 
 impl AutoMerger {
@@ -41,16 +80,40 @@ impl AutoMerger {
         Self {
             segments: HashMap::new(),
             keep_segments,
-            has_failed: false,
+            failure_policy: FailurePolicy::default(),
 
             output_file,
             allowed_extensions: vec!["mkv", "mp4", "ts"],
+
+            subtitle_format: SubtitleFormat::default(),
+            progressive_mp4: false,
+            metadata: Metadata::default(),
         }
     }
+
+    pub fn with_subtitle_format(mut self, subtitle_format: SubtitleFormat) -> Self {
+        self.subtitle_format = subtitle_format;
+        self
+    }
+
+    pub fn with_progressive_mp4(mut self, progressive_mp4: bool) -> Self {
+        self.progressive_mp4 = progressive_mp4;
+        self
+    }
+
+    pub fn with_failure_policy(mut self, failure_policy: FailurePolicy) -> Self {
+        self.failure_policy = failure_policy;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
 }
 
 impl Merger for AutoMerger {
-    type Result = ();
+    type Result = MergeReport;
 
     async fn update(&mut self, segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
         self.segments
@@ -72,32 +135,150 @@ impl Merger for AutoMerger {
                 segment,
                 success: false,
             });
-        self.has_failed = true;
         Ok(())
     }
 
     async fn finish(&mut self, cache: impl CacheSource) -> IoriResult<Self::Result> {
         tracing::info!("Merging chunks...");
+        let mut report = MergeReport::new(self.segments.values().flatten());
+        report.failure_policy = self.failure_policy;
 
-        if self.has_failed {
-            tracing::warn!("Some segments failed to download. Skipping merging.");
+        if !self.failure_policy.should_merge(&report)? {
             if let Some(location) = cache.location_hint() {
                 tracing::warn!("You can find the downloaded segments at {location}");
             }
-            return Ok(());
+            report.skipped = true;
+            return Ok(report);
         }
 
-        let mut tracks = Vec::new();
+        // segments are read for timestamps, initialization and chapters before they are merged
+        cache.retain_segments();
+
+        let parts = self.split_parts();
+        let mut outputs = Vec::new();
+        for (index, streams) in parts.iter().enumerate() {
+            let mut output_file = self.output_file.clone();
+            if parts.len() > 1 {
+                output_file.add_suffix(format!("part{}", index + 1));
+            }
+            if let Some(output) = self
+                .merge_streams(streams, output_file, &cache, &mut report)
+                .await?
+            {
+                outputs.push(output);
+            }
+        }
+
+        if let Some(output) = outputs.first() {
+            report.set_output(output.clone());
+        }
+        if outputs.len() > 1 {
+            report.parts = outputs.clone();
+        }
+        report.measure_output().await;
+
+        if !self.keep_segments {
+            tracing::info!("End of merging.");
+            tracing::info!("Starting cleaning temporary files.");
+            cache.clear().await?;
+            report.cache_cleared = true;
+        }
+
+        for output in outputs {
+            tracing::info!(
+                "All finished. Please checkout your files at {}",
+                output.display()
+            );
+        }
+        Ok(report)
+    }
+}
+
+impl AutoMerger {
+    /// Group successful segments of each stream into parts to merge.
+    ///
+    /// Segments are split at gaps with [FailurePolicy::Split] if gaps of all streams are
+    /// aligned. Otherwise, all segments are merged into a single part with gaps.
+    fn split_parts(&self) -> Vec<Vec<(u64, Vec<&SegmentInfo>)>> {
+        let mut runs: Vec<(u64, Vec<Vec<&SegmentInfo>>)> = Vec::new();
         for (stream_id, segments) in self.segments.iter() {
-            let mut segments: Vec<_> = segments.iter().map(|s| &s.segment).collect();
+            let mut segments: Vec<_> = segments.iter().collect();
+            segments.sort_by_key(|s| s.segment.sequence);
+            let stream_runs = segments
+                .chunk_by(|a, b| a.success == b.success)
+                .filter(|run| run[0].success)
+                .map(|run| run.iter().map(|s| &s.segment).collect())
+                .collect();
+            runs.push((*stream_id, stream_runs));
+        }
+
+        if matches!(self.failure_policy, FailurePolicy::Split)
+            && runs.iter().any(|(_, r)| r.len() > 1)
+        {
+            let part_count = runs[0].1.len();
+            if runs.iter().all(|(_, r)| r.len() == part_count) {
+                return (0..part_count)
+                    .map(|index| {
+                        runs.iter()
+                            .map(|(stream_id, r)| (*stream_id, r[index].clone()))
+                            .collect()
+                    })
+                    .collect();
+            }
+            tracing::warn!("Gaps are not aligned between streams. Merging with gaps instead.");
+        }
+
+        vec![runs
+            .into_iter()
+            .map(|(stream_id, r)| (stream_id, r.concat()))
+            .filter(|(_, segments)| !segments.is_empty())
+            .collect()]
+    }
+
+    /// Merge streams into a single output, returning the output file.
+    ///
+    /// Subtitles which can not be muxed are saved next to the output file.
+    async fn merge_streams(
+        &self,
+        streams: &[(u64, Vec<&SegmentInfo>)],
+        output_file: PathBuf,
+        cache: &impl CacheSource,
+        report: &mut MergeReport,
+    ) -> IoriResult<Option<PathBuf>> {
+        let base_pts = first_video_pts(streams, cache).await?;
+        let mut metadata = self.metadata.clone();
+        if metadata.chapters.is_empty() {
+            metadata.chapters = collect_chapters(streams, cache).await;
+        }
+
+        let mut tracks = Vec::new();
+        let mut subtitles = Vec::new();
+        #[cfg_attr(feature = "ffmpeg", allow(unused_mut))]
+        let mut fmp4_tracks: Vec<(u64, Vec<&SegmentInfo>)> = Vec::new();
+        for (stream_id, segments) in streams.iter() {
+            let mut segments = segments.clone();
 
             let first_segment = segments[0];
-            let mut output_path = self.output_file.to_owned();
+            let mut output_path = output_file.to_owned();
             output_path.add_suffix(format!("{stream_id:02}"));
             output_path.set_extension(first_segment.format.as_ext());
 
             segments.sort_by(|a, b| a.sequence.cmp(&b.sequence));
 
+            let is_mp4_subtitle = segments.iter().all(|s| is_mp4_subtitle(s));
+            let is_webvtt = segments.iter().all(|s| is_webvtt(s));
+            if is_mp4_subtitle || is_webvtt {
+                output_path.set_extension(self.subtitle_format.as_ext());
+                let subtitle = if is_webvtt {
+                    merge_webvtt(&segments, cache, base_pts).await?
+                } else {
+                    extract_subtitle(&segments, cache).await?
+                };
+                tokio::fs::write(&output_path, subtitle.to_format(self.subtitle_format)).await?;
+                subtitles.push((*stream_id, output_path));
+                continue;
+            }
+
             let can_concat = segments.iter().all(|s| {
                 matches!(
                     s.format,
@@ -105,68 +286,206 @@ impl Merger for AutoMerger {
                 ) || matches!(s.r#type, SegmentType::Subtitle)
             });
             if can_concat {
-                concat_merge(&segments, &cache, &output_path).await?;
+                concat_merge(&segments, cache, &output_path).await?;
             } else {
                 #[cfg(feature = "ffmpeg")]
                 {
                     output_path.set_extension("ts");
-                    super::ffmpeg::ffmpeg_concat(&segments, &cache, &output_path).await?;
+                    super::ffmpeg::ffmpeg_concat(&segments, cache, &output_path).await?;
                 }
                 #[cfg(not(feature = "ffmpeg"))]
                 {
+                    let is_fmp4 = segments.iter().all(|s| {
+                        matches!(
+                            s.format,
+                            SegmentFormat::Mp4
+                                | SegmentFormat::M4a
+                                | SegmentFormat::Cmfv
+                                | SegmentFormat::Cmfa
+                        )
+                    });
+                    if is_fmp4 {
+                        fmp4_tracks.push((*stream_id, segments));
+                        continue;
+                    }
+
                     output_path.set_extension("mkv");
-                    mkvmerge_concat(&segments, &cache, &output_path).await?;
+                    mkvmerge_concat(&segments, cache, &output_path).await?;
                 }
             }
 
             tracks.push(output_path);
         }
 
+        // Fragmented MP4 tracks are merged natively. They are muxed into the output file
+        // directly if there is no other track, or merged into separate tracks for mkvmerge.
+        let mut native_output = None;
+        if !fmp4_tracks.is_empty() {
+            if tracks.is_empty() {
+                let output = output_file.with_replaced_extension("mp4", &self.allowed_extensions);
+                let streams: Vec<_> = fmp4_tracks.into_iter().map(|(_, s)| s).collect();
+                merge_fmp4(&streams, cache, &output, self.progressive_mp4, &metadata).await?;
+                native_output = Some(output);
+            } else {
+                for (stream_id, segments) in fmp4_tracks {
+                    let mut output_path = output_file.to_owned();
+                    output_path.add_suffix(format!("{stream_id:02}"));
+                    output_path.set_extension("mp4");
+                    // metadata is written when muxing tracks
+                    merge_fmp4(
+                        &[segments],
+                        cache,
+                        &output_path,
+                        self.progressive_mp4,
+                        &Metadata::default(),
+                    )
+                    .await?;
+                    tracks.push(output_path);
+                }
+            }
+        }
+
+        // Multiple MPEG-TS tracks are remuxed natively
+        #[cfg(not(feature = "ffmpeg"))]
+        if native_output.is_none()
+            && tracks.len() > 1
+            && tracks
+                .iter()
+                .all(|t| t.extension().is_some_and(|e| e == "ts"))
+        {
+            let output = output_file.with_replaced_extension("mp4", &self.allowed_extensions);
+            super::remux::remux_ts_files(&tracks, &output, &metadata).await?;
+            for track in tracks.drain(..) {
+                tokio::fs::remove_file(track).await?;
+            }
+            native_output = Some(output);
+        }
+
+        // mkvmerge can mux subtitles along with other tracks
+        #[cfg(not(feature = "ffmpeg"))]
+        if native_output.is_none() {
+            tracks.extend(subtitles.drain(..).map(|(_, path)| path));
+        }
+        if tracks.is_empty() && native_output.is_none() && subtitles.len() == 1 {
+            tracks.extend(subtitles.drain(..).map(|(_, path)| path));
+        }
+
         tracing::info!("Merging streams...");
 
-        let output_path = if tracks.len() == 1 {
+        let output_path = if native_output.is_some() {
+            native_output
+        } else if tracks.is_empty() {
+            None
+        } else if tracks.len() == 1 {
             let track_format = tracks[0].extension().and_then(|e| e.to_str());
             let output = match track_format {
-                Some(ext) => self
-                    .output_file
-                    .with_replaced_extension(ext, &self.allowed_extensions),
-                None => self.output_file.clone(),
+                Some(ext) => output_file.with_replaced_extension(ext, &self.allowed_extensions),
+                None => output_file.clone(),
             };
             tokio::fs::rename(&tracks[0], &output).await?;
-            output
+            metadata.write_sidecar(&output).await?;
+            Some(output)
         } else {
-            #[cfg(feature = "ffmpeg")]
-            {
-                let output = self
-                    .output_file
-                    .with_replaced_extension("mp4", &self.allowed_extensions);
-                super::ffmpeg::ffmpeg_merge(tracks, &output).await?;
-                output
-            }
-            #[cfg(not(feature = "ffmpeg"))]
-            {
-                let output = self
-                    .output_file
-                    .with_replaced_extension("mkv", &self.allowed_extensions);
-                mkvmerge_merge(tracks, &output).await?;
-                output
-            }
+            Some(mux_tracks(tracks, &output_file, &self.allowed_extensions, &metadata).await?)
         };
 
-        if !self.keep_segments {
-            tracing::info!("End of merging.");
-            tracing::info!("Starting cleaning temporary files.");
-            cache.clear().await?;
+        // Save remaining subtitles next to the output file
+        for (stream_id, subtitle) in subtitles {
+            let ext = subtitle
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("vtt");
+            let output = output_file.with_replaced_extension(
+                &format!("{stream_id:02}.{ext}"),
+                &self.allowed_extensions,
+            );
+            tokio::fs::rename(&subtitle, &output).await?;
+            tracing::info!("Subtitle saved to {}", output.display());
+            report.set_track_path(stream_id, output);
         }
 
-        tracing::info!(
-            "All finished. Please checkout your files at {}",
-            output_path.display()
-        );
-        Ok(())
+        Ok(output_path)
     }
 }
 
+/// Read the first PTS of the video track, which is the base of WebVTT timestamp mapping.
+async fn first_video_pts(
+    streams: &[(u64, Vec<&SegmentInfo>)],
+    cache: &impl CacheSource,
+) -> IoriResult<Option<u64>> {
+    for (_, segments) in streams {
+        let Some(first_segment) = segments.iter().min_by_key(|s| s.sequence) else {
+            continue;
+        };
+        if !matches!(first_segment.r#type, SegmentType::Video)
+            || !matches!(first_segment.format, SegmentFormat::Mpeg2TS)
+        {
+            continue;
+        }
+
+        let data = read_segment(first_segment, cache).await?;
+        return Ok(mpegts::first_pts(&data));
+    }
+    Ok(None)
+}
+
+/// Whether the segment is a subtitle in fragmented MP4, such as `wvtt` or `stpp`.
+pub(super) fn is_mp4_subtitle(segment: &SegmentInfo) -> bool {
+    matches!(segment.r#type, SegmentType::Subtitle) && matches!(segment.format, SegmentFormat::Mp4)
+}
+
+/// Whether the segment is an HLS WebVTT subtitle.
+pub(super) fn is_webvtt(segment: &SegmentInfo) -> bool {
+    let is_vtt = match &segment.format {
+        SegmentFormat::Raw(ext) | SegmentFormat::Other(ext) => ext == "vtt" || ext == "webvtt",
+        _ => false,
+    };
+    matches!(segment.r#type, SegmentType::Subtitle) && is_vtt
+}
+
+pub(super) async fn read_segment(
+    segment: &SegmentInfo,
+    cache: &impl CacheSource,
+) -> IoriResult<Vec<u8>> {
+    let mut reader = cache.open_reader(segment).await?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+pub(super) async fn extract_subtitle(
+    segments: &[&SegmentInfo],
+    cache: &impl CacheSource,
+) -> IoriResult<Subtitle> {
+    tracing::debug!("Extracting subtitles...");
+
+    let mut subtitle = Subtitle::new();
+    for segment in segments {
+        let data = read_segment(segment, cache).await?;
+        subtitle.extend(Subtitle::from_fmp4(&data)?);
+    }
+    subtitle.normalize();
+    Ok(subtitle)
+}
+
+pub(super) async fn merge_webvtt(
+    segments: &[&SegmentInfo],
+    cache: &impl CacheSource,
+    base_pts: Option<u64>,
+) -> IoriResult<Subtitle> {
+    tracing::debug!("Merging WebVTT segments...");
+
+    let mut documents = Vec::with_capacity(segments.len());
+    for segment in segments {
+        let data = read_segment(segment, cache).await?;
+        documents.push(String::from_utf8_lossy(&data).into_owned());
+    }
+
+    let mut subtitle = Subtitle::from_hls_webvtt(documents.iter().map(String::as_str), base_pts)?;
+    subtitle.normalize();
+    Ok(subtitle)
+}
+
 #[allow(unused)]
 async fn concat_merge<O>(
     segments: &[&SegmentInfo],
@@ -220,20 +539,88 @@ where
     Ok(())
 }
 
+/// Mux multiple track files into a single output file, returning the output file.
+///
+/// Track files are removed after muxing. Multiple MPEG-TS tracks are remuxed into MP4
+/// natively, and other tracks are merged with mkvmerge. With the `ffmpeg` feature, all
+/// tracks are merged with ffmpeg instead. `metadata` is written as tags and chapters of the
+/// output.
+pub(super) async fn mux_tracks(
+    tracks: Vec<PathBuf>,
+    output_file: &Path,
+    allowed_extensions: &[&str],
+    metadata: &Metadata,
+) -> IoriResult<PathBuf> {
+    #[cfg(feature = "ffmpeg")]
+    {
+        let output = output_file.with_replaced_extension("mp4", allowed_extensions);
+        super::ffmpeg::ffmpeg_merge(tracks, &output, metadata).await?;
+        // chapters are not written by ffmpeg
+        if !metadata.chapters.is_empty() {
+            metadata.write_sidecar(&output).await?;
+        }
+        Ok(output)
+    }
+    #[cfg(not(feature = "ffmpeg"))]
+    {
+        let is_ts = tracks
+            .iter()
+            .all(|t| t.extension().is_some_and(|e| e == "ts"));
+        if is_ts {
+            let output = output_file.with_replaced_extension("mp4", allowed_extensions);
+            super::remux::remux_ts_files(&tracks, &output, metadata).await?;
+            for track in tracks {
+                tokio::fs::remove_file(track).await?;
+            }
+            Ok(output)
+        } else {
+            let output = output_file.with_replaced_extension("mkv", allowed_extensions);
+            mkvmerge_merge(tracks, &output, metadata).await?;
+            Ok(output)
+        }
+    }
+}
+
 #[allow(unused)]
-async fn mkvmerge_merge<O>(tracks: Vec<PathBuf>, output: O) -> IoriResult<()>
+async fn mkvmerge_merge<O>(tracks: Vec<PathBuf>, output: O, metadata: &Metadata) -> IoriResult<()>
 where
     O: AsRef<Path>,
 {
     assert!(tracks.len() > 1);
 
     let mkvmerge = which::which("mkvmerge")?;
-    let mut merge = Command::new(mkvmerge)
+    let mut command = Command::new(mkvmerge);
+    command
         .args(tracks.iter())
         .arg("-o")
-        .arg(output.as_ref().with_extension("mkv"))
-        .spawn()?;
+        .arg(output.as_ref().with_extension("mkv"));
+
+    // The tags and chapters files must live until mkvmerge exits
+    let mut tags = None;
+    if metadata.has_tags() {
+        if let Some(title) = &metadata.title {
+            command.arg("--title").arg(title);
+        }
+
+        let mut temp = tempfile::Builder::new().suffix(".xml").tempfile()?;
+        temp.write_all(metadata.to_matroska_tags().as_bytes())?;
+        temp.flush()?;
+        command.arg("--global-tags").arg(temp.path());
+        tags = Some(temp);
+    }
+    let mut chapters = None;
+    if !metadata.chapters.is_empty() {
+        let mut temp = tempfile::Builder::new().suffix(".txt").tempfile()?;
+        temp.write_all(to_ogm_chapters(&metadata.chapters).as_bytes())?;
+        temp.flush()?;
+        command.arg("--chapters").arg(temp.path());
+        chapters = Some(temp);
+    }
+
+    let mut merge = command.spawn()?;
     merge.wait().await?;
+    drop(tags);
+    drop(chapters);
 
     // remove temporary files
     for track in tracks {
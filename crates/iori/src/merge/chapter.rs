@@ -0,0 +1,101 @@
+use crate::{
+    SegmentFormat, SegmentInfo, SegmentType,
+    cache::CacheSource,
+    chapter::{Chapter, SegmentEvent, build_chapters, read_emsg_events},
+};
+
+use super::{auto::read_segment, concat::ConcatSegment};
+
+/// Group successful segments by stream, ordered by stream id.
+pub(super) fn successful_streams<'a>(
+    segments: impl IntoIterator<Item = &'a ConcatSegment>,
+) -> Vec<(u64, Vec<&'a SegmentInfo>)> {
+    let mut streams: Vec<(u64, Vec<&SegmentInfo>)> = Vec::new();
+    for segment in segments.into_iter().filter(|s| s.success) {
+        let segment = &segment.segment;
+        match streams.iter_mut().find(|(id, _)| *id == segment.stream_id) {
+            Some((_, segments)) => segments.push(segment),
+            None => streams.push((segment.stream_id, vec![segment])),
+        }
+    }
+    streams.sort_by_key(|(id, _)| *id);
+    streams
+}
+
+/// Sort segments of each stream, with video streams first.
+fn sorted_streams<'a>(streams: &[(u64, Vec<&'a SegmentInfo>)]) -> Vec<Vec<&'a SegmentInfo>> {
+    let mut streams: Vec<Vec<&SegmentInfo>> = streams
+        .iter()
+        .map(|(_, segments)| {
+            let mut segments = segments.clone();
+            segments.sort_by_key(|s| s.sequence);
+            segments
+        })
+        .filter(|segments| !segments.is_empty())
+        .collect();
+    streams.sort_by_key(|segments| !matches!(segments[0].r#type, SegmentType::Video));
+    streams
+}
+
+/// Build chapters from events attached to segments by the source, preferring video streams.
+pub(super) fn source_chapters(streams: &[(u64, Vec<&SegmentInfo>)]) -> Vec<Chapter> {
+    sorted_streams(streams)
+        .iter()
+        .find(|segments| segments.iter().any(|s| !s.events.is_empty()))
+        .map(|segments| {
+            let events = segments.iter().map(|s| s.events.clone()).collect();
+            timeline_chapters(segments, events)
+        })
+        .unwrap_or_default()
+}
+
+/// Build chapters from timed events of the streams in a part.
+///
+/// Events attached by the source are used if any, see [source_chapters]. Otherwise,
+/// SCTE-35 `emsg` boxes are read from the first fragmented MP4 stream. Chapter times are
+/// relative to the start of the part.
+pub(super) async fn collect_chapters(
+    streams: &[(u64, Vec<&SegmentInfo>)],
+    cache: &impl CacheSource,
+) -> Vec<Chapter> {
+    let chapters = source_chapters(streams);
+    if !chapters.is_empty() {
+        return chapters;
+    }
+
+    let streams = sorted_streams(streams);
+    let Some(segments) = streams.iter().find(|segments| {
+        segments.iter().all(|s| {
+            matches!(
+                s.format,
+                SegmentFormat::Mp4 | SegmentFormat::M4a | SegmentFormat::Cmfv | SegmentFormat::Cmfa
+            )
+        })
+    }) else {
+        return Vec::new();
+    };
+    let mut events = Vec::with_capacity(segments.len());
+    for segment in segments {
+        match read_segment(segment, cache).await {
+            Ok(data) => events.push(read_emsg_events(&data)),
+            Err(error) => {
+                tracing::warn!("Failed to read events of {}: {error}", segment.file_name);
+                events.push(Vec::new());
+            }
+        }
+    }
+    timeline_chapters(segments, events)
+}
+
+/// Place events of each segment on the timeline of concatenated segments.
+fn timeline_chapters(segments: &[&SegmentInfo], events: Vec<Vec<SegmentEvent>>) -> Vec<Chapter> {
+    let mut time = 0.;
+    let mut timed_events = Vec::new();
+    for (segment, events) in segments.iter().zip(events) {
+        for event in events {
+            timed_events.push((time + event.offset, event));
+        }
+        time += segment.duration.unwrap_or_default();
+    }
+    build_chapters(timed_events, time)
+}
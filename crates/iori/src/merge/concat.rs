@@ -1,11 +1,24 @@
-use super::Merger;
+use super::{
+    auto::mux_tracks,
+    chapter::{collect_chapters, successful_streams},
+    FailurePolicy, MergeReport, Merger,
+};
 use crate::{
-    cache::CacheSource, error::IoriResult, util::path::DuplicateOutputFileNamer, SegmentInfo,
+    cache::CacheSource, error::IoriResult, metadata::Metadata, util::path::IoriPathExt, SegmentInfo,
 };
 use std::path::PathBuf;
-use tokio::fs::File;
+use tokio::{fs::File, io::AsyncWriteExt};
 
 /// Concat all segments into a single file after all segments are downloaded.
+///
+/// If there are multiple streams, each stream is concatenated into a separate track, and
+/// the tracks are muxed into a single output file like [AutoMerger](super::AutoMerger).
+///
+/// By default, the output is split into parts at missing segments.
+/// This can be changed with [ConcatAfterMerger::with_failure_policy].
+///
+/// [Metadata] is written as tags of muxed outputs, or to a sidecar `.json` file of each
+/// concatenated output.
 pub struct ConcatAfterMerger {
     segments: Vec<ConcatSegment>,
 
@@ -13,6 +26,12 @@ pub struct ConcatAfterMerger {
     output_file: PathBuf,
     /// Keep downloaded segments after merging.
     keep_segments: bool,
+    /// What to do when some segments failed to download.
+    failure_policy: FailurePolicy,
+    /// A list of file extensions which should skip adding an auto extension.
+    allowed_extensions: Vec<&'static str>,
+    /// Metadata written into the output file.
+    metadata: Metadata,
 }
 
 impl ConcatAfterMerger {
@@ -21,12 +40,25 @@ impl ConcatAfterMerger {
             segments: Vec::new(),
             output_file,
             keep_segments,
+            failure_policy: FailurePolicy::Split,
+            allowed_extensions: vec!["mkv", "mp4", "ts"],
+            metadata: Metadata::default(),
         }
     }
+
+    pub fn with_failure_policy(mut self, failure_policy: FailurePolicy) -> Self {
+        self.failure_policy = failure_policy;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
 }
 
 impl Merger for ConcatAfterMerger {
-    type Result = ();
+    type Result = MergeReport;
 
     async fn update(&mut self, segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
         self.segments.push(ConcatSegment {
@@ -47,19 +79,115 @@ impl Merger for ConcatAfterMerger {
 
     async fn finish(&mut self, cache: impl CacheSource) -> IoriResult<Self::Result> {
         tracing::info!("Merging chunks...");
-        concat_merge(&mut self.segments, &cache, self.output_file.clone()).await?;
+        let mut report = MergeReport::new(&self.segments);
+        report.failure_policy = self.failure_policy;
+
+        if !self.failure_policy.should_merge(&report)? {
+            if let Some(location) = cache.location_hint() {
+                tracing::warn!("You can find the downloaded segments at {location}");
+            }
+            report.skipped = true;
+            return Ok(report);
+        }
+
+        // segments are read for chapters before they are merged
+        cache.retain_segments();
+
+        let split = matches!(self.failure_policy, FailurePolicy::Split);
+        self.segments
+            .sort_by_key(|s| (s.segment.stream_id, s.segment.sequence));
+        let stream_count = self
+            .segments
+            .chunk_by(|a, b| a.segment.stream_id == b.segment.stream_id)
+            .count();
+        let mut metadata = self.metadata.clone();
+        if metadata.chapters.is_empty() {
+            let streams = successful_streams(&self.segments);
+            metadata.chapters = collect_chapters(&streams, &cache).await;
+        }
+
+        if stream_count > 1 {
+            if split {
+                tracing::warn!(
+                    "Can not split multiple streams into parts. Merging with gaps instead."
+                );
+            }
+
+            // concat each stream into a separate track, and mux them together
+            let mut tracks = Vec::new();
+            for segments in self
+                .segments
+                .chunk_by_mut(|a, b| a.segment.stream_id == b.segment.stream_id)
+            {
+                let first_segment = &segments[0].segment;
+                let mut track_path = self.output_file.clone();
+                track_path.add_suffix(format!("{:02}", first_segment.stream_id));
+                track_path.set_extension(first_segment.format.as_ext());
+
+                let (parts, _) = concat_merge(segments, &cache, track_path, false).await?;
+                tracks.extend(parts);
+            }
+
+            let output = match tracks.len() {
+                0 => None,
+                1 => {
+                    let output = match tracks[0].extension().and_then(|e| e.to_str()) {
+                        Some(ext) => self
+                            .output_file
+                            .with_replaced_extension(ext, &self.allowed_extensions),
+                        None => self.output_file.clone(),
+                    };
+                    tokio::fs::rename(&tracks[0], &output).await?;
+                    metadata.write_sidecar(&output).await?;
+                    Some(output)
+                }
+                _ => Some(
+                    mux_tracks(
+                        tracks,
+                        &self.output_file,
+                        &self.allowed_extensions,
+                        &metadata,
+                    )
+                    .await?,
+                ),
+            };
+            if let Some(output) = output {
+                report.set_output(output);
+            }
+            report.measure_output().await;
+        } else {
+            let (parts, bytes_written) =
+                concat_merge(&mut self.segments, &cache, self.output_file.clone(), split).await?;
+            report.bytes_written = bytes_written;
+            // chapters span the timeline of all parts, which does not apply to each part
+            if parts.len() > 1 {
+                metadata.chapters.clear();
+            }
+            for part in parts.iter() {
+                metadata.write_sidecar(part).await?;
+            }
+            if let Some(output) = parts.first() {
+                report.set_output(output.clone());
+            }
+            if parts.len() > 1 {
+                report.parts = parts;
+            }
+        }
 
         if !self.keep_segments {
             tracing::info!("End of merging.");
             tracing::info!("Starting cleaning temporary files.");
             cache.clear().await?;
+            report.cache_cleared = true;
         }
 
-        tracing::info!(
-            "All finished. Please checkout your files at {}",
-            self.output_file.display()
-        );
-        Ok(())
+        if let Some(output) = &report.output {
+            tracing::info!(
+                "All finished. Please checkout your files at {}",
+                output.display()
+            );
+        }
+        Ok(report)
     }
 }
 
@@ -76,27 +204,58 @@ pub(crate) struct ConcatSegment {
     pub success: bool,
 }
 
+/// Concat successful segments, returning the output parts and the number of bytes written.
+///
+/// If `split` is set, a new part is started after each run of failed segments.
 async fn concat_merge(
     segments: &mut [ConcatSegment],
     cache: &impl CacheSource,
     output_path: PathBuf,
-) -> IoriResult<()> {
+    split: bool,
+) -> IoriResult<(Vec<PathBuf>, u64)> {
     segments.sort_by(|a, b| a.segment.sequence.cmp(&b.segment.sequence));
     let segments = trim_end(segments, |s| !s.success);
 
-    let mut namer = DuplicateOutputFileNamer::new(output_path.clone());
-    let mut output = File::create(output_path).await?;
+    // parts are named like `output.1.ts`, and the only part is renamed to the output path
+    let extension = output_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_string();
+    let mut parts = Vec::new();
+    let mut output: Option<File> = None;
+    let mut bytes_written = 0;
     for segment in segments {
-        let success = segment.success;
-        let segment = &segment.segment;
-        if !success {
-            output = File::create(namer.next_path()).await?;
+        if !segment.success {
+            if split {
+                if let Some(mut output) = output.take() {
+                    output.flush().await?;
+                }
+            }
+            continue;
         }
 
-        let mut reader = cache.open_reader(segment).await?;
-        tokio::io::copy(&mut reader, &mut output).await?;
+        if output.is_none() {
+            let path = output_path.with_extension(format!("{}.{extension}", parts.len() + 1));
+            output = Some(File::create(&path).await?);
+            parts.push(path);
+        }
+        let Some(output) = output.as_mut() else {
+            continue;
+        };
+
+        let mut reader = cache.open_reader(&segment.segment).await?;
+        bytes_written += tokio::io::copy(&mut reader, output).await?;
+    }
+    if let Some(mut output) = output {
+        output.flush().await?;
+    }
+
+    if parts.len() == 1 {
+        tokio::fs::rename(&parts[0], &output_path).await?;
+        parts = vec![output_path];
     }
-    Ok(())
+    Ok((parts, bytes_written))
 }
 
 #[cfg(test)]
@@ -0,0 +1,155 @@
+use std::path::PathBuf;
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+};
+
+use super::{MergeReport, Merger};
+use crate::{
+    IoriResult, SegmentInfo,
+    cache::CacheSource,
+    decrypt::deferred::{CachedSegment, SEGMENTS_FILE},
+};
+
+/// DeferredDecryptionMerger keeps segments downloaded without decryption in the cache, and
+/// records their keys in [SEGMENTS_FILE] next to them, instead of merging them.
+///
+/// Segments can be decrypted later with
+/// [decrypt_cache_dir](crate::decrypt::deferred::decrypt_cache_dir), and then merged. The
+/// cache must store segments as local files, like
+/// [FileCacheSource](crate::cache::file::FileCacheSource). Otherwise segments are not
+/// recorded.
+#[derive(Default)]
+pub struct DeferredDecryptionMerger {
+    writer: Option<BufWriter<File>>,
+    cache_dir: Option<PathBuf>,
+
+    /// Number of segments recorded.
+    recorded: usize,
+    /// Number of segments which are not stored as local files.
+    unrecorded: usize,
+}
+
+impl DeferredDecryptionMerger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn record(&mut self, segment: &SegmentInfo, path: PathBuf) -> IoriResult<()> {
+        let (Some(cache_dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+            self.unrecorded += 1;
+            return Ok(());
+        };
+
+        if self.writer.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(cache_dir.join(SEGMENTS_FILE))
+                .await?;
+            self.writer = Some(BufWriter::new(file));
+            self.cache_dir = Some(cache_dir.to_path_buf());
+        }
+        let writer = self.writer.as_mut().unwrap();
+
+        let entry = CachedSegment::new(segment, file_name.to_string_lossy().to_string());
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+        // keep the file complete if downloading is interrupted
+        writer.flush().await?;
+
+        self.recorded += 1;
+        Ok(())
+    }
+}
+
+impl Merger for DeferredDecryptionMerger {
+    type Result = MergeReport;
+
+    async fn update(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        match cache.segment_path(&segment).await {
+            Some(path) => self.record(&segment, path).await,
+            None => {
+                self.unrecorded += 1;
+                Ok(())
+            }
+        }
+    }
+
+    async fn fail(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        cache.invalidate(&segment).await
+    }
+
+    async fn finish(&mut self, _cache: impl CacheSource) -> IoriResult<Self::Result> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.shutdown().await?;
+        }
+        if self.unrecorded > 0 {
+            tracing::warn!(
+                "{} segments are not stored as local files, and can not be decrypted later.",
+                self.unrecorded
+            );
+        }
+        if let Some(cache_dir) = &self.cache_dir {
+            tracing::info!(
+                "{} segments are kept without decryption in {}",
+                self.recorded,
+                cache_dir.display()
+            );
+        }
+
+        Ok(MergeReport {
+            skipped: true,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        cache::file::FileCacheSource,
+        decrypt::{IoriKey, deferred::read_cached_segments},
+    };
+
+    #[tokio::test]
+    async fn test_deferred_decryption_merger() -> IoriResult<()> {
+        let dir = tempfile::tempdir()?;
+        let cache_dir = dir.path().join("cache");
+        let cache = Arc::new(FileCacheSource::new(cache_dir.clone())?);
+
+        let key = IoriKey::SampleAes {
+            key: [1; 16],
+            iv: [2; 16],
+        };
+        let mut merger = DeferredDecryptionMerger::new();
+        for sequence in 0..2 {
+            let segment = SegmentInfo {
+                sequence,
+                file_name: format!("{sequence}.ts"),
+                key: (sequence == 1).then(|| Arc::new(key.clone())),
+                ..Default::default()
+            };
+            let mut writer = cache.open_writer(&segment).await?.unwrap();
+            writer.write_all(b"data").await?;
+            writer.shutdown().await?;
+            merger.update(segment, cache.clone()).await?;
+        }
+        let report = merger.finish(cache.clone()).await?;
+
+        assert!(report.skipped);
+        assert!(!report.cache_cleared);
+        let segments = read_cached_segments(&cache_dir).await?;
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].key, None);
+        assert_eq!(segments[1].key, Some(key));
+        assert!(cache_dir.join(&segments[1].file_name).is_file());
+
+        Ok(())
+    }
+}
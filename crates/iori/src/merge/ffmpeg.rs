@@ -3,7 +3,9 @@ use std::{
     fs::File,
     io::Write,
     path::{Path, PathBuf},
+    pin::Pin,
     sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
 };
 
 use rsmpeg::{
@@ -18,9 +20,13 @@ use rsmpeg::{
     },
     UnsafeDerefMut,
 };
-use tokio::io::AsyncReadExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite},
+    sync::mpsc::Receiver,
+};
+use tokio_util::sync::PollSender;
 
-use crate::{cache::CacheSource, IoriResult, SegmentInfo};
+use crate::{cache::CacheSource, metadata::Metadata, IoriResult, SegmentInfo};
 
 // Reference: https://github.com/YeautyYE/ez-ffmpeg/blob/a249e8ad35196cdf345e3f3dc93c87cfb263bfef/src/core/mod.rs#L434-L463
 #[cfg(any(
@@ -102,7 +108,11 @@ unsafe extern "C" fn ffmpeg_log_callback(
     }
 }
 
-pub(crate) async fn ffmpeg_merge<O>(tracks: Vec<PathBuf>, output: O) -> IoriResult<()>
+pub(crate) async fn ffmpeg_merge<O>(
+    tracks: Vec<PathBuf>,
+    output: O,
+    metadata: &Metadata,
+) -> IoriResult<()>
 where
     O: AsRef<Path>,
 {
@@ -115,11 +125,25 @@ where
         .iter()
         .map(|track| CString::new(track.as_os_str().as_encoded_bytes()))
         .collect::<Result<Vec<_>, _>>()?;
+    let c_metadata = metadata
+        .ffmpeg_tags()
+        .into_iter()
+        .map(|(key, value)| Ok((CString::new(key)?, CString::new(value)?)))
+        .collect::<Result<Vec<_>, std::ffi::NulError>>()?;
 
     tokio::task::spawn_blocking(move || -> IoriResult<()> {
         let c_output = CString::new(output.as_os_str().as_encoded_bytes())?;
         let mut output_format_context = AVFormatContextOutput::create(&c_output, None)?;
 
+        let mut metadata: Option<AVDictionary> = None;
+        for (key, value) in c_metadata {
+            metadata = Some(match metadata {
+                Some(metadata) => metadata.set(&key, &value, 0),
+                None => AVDictionary::new(&key, &value, 0),
+            });
+        }
+        output_format_context.set_metadata(metadata);
+
         let mut input_contexts = vec![];
         for c_track in c_tracks {
             let mut input_options = Some(AVDictionary::new(c"analyzeduration", c"100M", 0).set(
@@ -294,6 +318,151 @@ where
     Ok(())
 }
 
+/// Mux streams received from `inputs` into MPEG-TS, written to `writer`.
+///
+/// Audio and video streams of all inputs are copied into the output, interleaved by their
+/// decode time. Inputs are read as data arrives, so this blocks until all inputs are closed.
+pub(crate) fn ffmpeg_mux<W>(inputs: Vec<Receiver<Vec<u8>>>, writer: W) -> IoriResult<()>
+where
+    W: Write + Send + 'static,
+{
+    unsafe {
+        av_log_set_callback(Some(ffmpeg_log_callback));
+    }
+
+    let (mut output_context, _) = open_output_context(writer)?;
+    output_context
+        .set_oformat(AVOutputFormat::guess_format(Some(c"ts"), Some(c"output.ts"), None).unwrap());
+
+    let mut input_contexts = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        input_contexts.push(open_stream_input_context(input)?);
+    }
+
+    // [input][stream] -> output_stream_index
+    let mut total_stream_count = 0;
+    let mut stream_mapping = Vec::new();
+    for input_context in &input_contexts {
+        let mut mapping = Vec::new();
+        for input_stream in input_context.streams() {
+            let codec_type = input_stream.codecpar().codec_type();
+            if !codec_type.is_video() && !codec_type.is_audio() {
+                mapping.push(None);
+                continue;
+            }
+
+            let mut output_stream = output_context.new_stream();
+            let mut codecpar = input_stream.codecpar().clone();
+            let is_codec_invalid = codecpar.codec_tag.to_be_bytes().iter().any(|c| *c == 0);
+            if is_codec_invalid {
+                let codecpar = unsafe { codecpar.deref_mut() };
+                codecpar.codec_tag = 0;
+            }
+            output_stream.codecpar_mut().copy(&codecpar);
+            output_stream.set_time_base(input_stream.time_base);
+            mapping.push(Some(total_stream_count));
+            total_stream_count += 1;
+        }
+        stream_mapping.push(mapping);
+    }
+
+    output_context.write_header(&mut None)?;
+
+    // the next packet of each input
+    let mut packets = Vec::with_capacity(input_contexts.len());
+    for input_context in input_contexts.iter_mut() {
+        packets.push(input_context.read_packet()?);
+    }
+
+    loop {
+        // write the packet with the earliest decode time first
+        let next = packets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, packet)| {
+                let packet = packet.as_ref()?;
+                let time_base =
+                    input_contexts[index].streams()[packet.stream_index as usize].time_base;
+                // AV_NOPTS_VALUE
+                let dts = if packet.dts == i64::MIN {
+                    0
+                } else {
+                    packet.dts
+                };
+                Some((
+                    index,
+                    dts as f64 * time_base.num as f64 / time_base.den as f64,
+                ))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(index, _)| index);
+        let Some(index) = next else {
+            break;
+        };
+
+        let mut packet = packets[index].take().unwrap();
+        packets[index] = input_contexts[index].read_packet()?;
+
+        let input_stream_index = packet.stream_index as usize;
+        let Some(output_stream_index) = stream_mapping[index][input_stream_index] else {
+            continue;
+        };
+
+        {
+            let output_stream = &output_context.streams()[output_stream_index];
+            let input_stream = &input_contexts[index].streams()[input_stream_index];
+
+            packet.rescale_ts(input_stream.time_base, output_stream.time_base);
+            packet.set_stream_index(output_stream_index as i32);
+            packet.set_pos(-1);
+        }
+
+        output_context.interleaved_write_frame(&mut packet)?;
+    }
+
+    output_context.write_trailer()?;
+    Ok(())
+}
+
+/// Number of chunks buffered for each input of [ffmpeg_mux], before writing waits for the
+/// muxer to catch up.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Writer which sends data to an input of [ffmpeg_mux].
+pub(crate) struct ChannelWriter(PollSender<Vec<u8>>);
+
+impl ChannelWriter {
+    pub(crate) fn new() -> (Self, Receiver<Vec<u8>>) {
+        let (sender, receiver) = tokio::sync::mpsc::channel(CHANNEL_CAPACITY);
+        (Self(PollSender::new(sender)), receiver)
+    }
+}
+
+impl AsyncWrite for ChannelWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let sender = &mut self.get_mut().0;
+        let result = ready!(sender.poll_reserve(cx))
+            .and_then(|_| sender.send_item(buf.to_vec()))
+            .map(|_| buf.len())
+            .map_err(|_| std::io::ErrorKind::BrokenPipe.into());
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // close the input of the muxer
+        self.get_mut().0.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
 fn open_input_context(input: Vec<u8>) -> IoriResult<AVFormatContextInput> {
     let mut current: usize = 0;
 
@@ -320,6 +489,39 @@ fn open_input_context(input: Vec<u8>) -> IoriResult<AVFormatContextInput> {
     Ok(input_format_context)
 }
 
+/// Open an input context reading data from `receiver` until it is closed.
+fn open_stream_input_context(receiver: Receiver<Vec<u8>>) -> IoriResult<AVFormatContextInput> {
+    let mut chunk = Vec::new();
+    let mut current: usize = 0;
+
+    let io_context = AVIOContextCustom::alloc_context(
+        AVMem::new(4096),
+        false,
+        vec![],
+        Some(Box::new(move |_, buf| {
+            while current >= chunk.len() {
+                match receiver.blocking_recv() {
+                    Some(data) => {
+                        chunk = data;
+                        current = 0;
+                    }
+                    None => return rsmpeg::ffi::AVERROR_EOF,
+                }
+            }
+            let read_len = buf.len().min(chunk.len() - current);
+            buf[0..read_len].copy_from_slice(&chunk[current..current + read_len]);
+            current += read_len;
+            read_len as i32
+        })),
+        None,
+        None,
+    );
+
+    let input_format_context =
+        AVFormatContextInput::from_io_context(AVIOContextContainer::Custom(io_context))?;
+    Ok(input_format_context)
+}
+
 fn open_output_context<W>(writer: W) -> IoriResult<(AVFormatContextOutput, Arc<Mutex<W>>)>
 where
     W: Write + Send + 'static,
@@ -0,0 +1,965 @@
+//! Merge fragmented MP4 tracks without external tools.
+//!
+//! The initialization segment of each track is written once, followed by the `moof` and
+//! `mdat` boxes of all segments with renumbered sequence numbers. Multiple tracks are
+//! multiplexed by merging their `moov` boxes and interleaving segments by decode time.
+//!
+//! Optionally, the fragments can be rewritten into a progressive MP4, which has a single
+//! `moov` box with full sample tables followed by one `mdat` box.
+use std::{collections::HashMap, ops::Range, path::Path};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+};
+
+use super::auto::read_segment;
+use crate::{
+    IoriError, IoriResult, SegmentInfo,
+    cache::CacheSource,
+    metadata::Metadata,
+    util::mp4::{
+        FragmentSample, Mp4Box, Mp4BoxIter, SampleDefaults, find_box, parse_moof, parse_trex,
+        read_timescale, read_track_id, read_u32, write_box, write_full_box,
+    },
+};
+
+/// Merge fragmented MP4 streams into a single MP4 file.
+///
+/// Each item of `streams` is a list of segments of one stream, sorted by sequence. Every
+/// segment must start with the initialization segment of its stream.
+///
+/// `metadata` replaces the `udta` box of the first stream if it is not empty.
+#[cfg_attr(feature = "ffmpeg", allow(unused))]
+pub(crate) async fn merge_fmp4<O>(
+    streams: &[Vec<&SegmentInfo>],
+    cache: &impl CacheSource,
+    output: O,
+    progressive: bool,
+    metadata: &Metadata,
+) -> IoriResult<()>
+where
+    O: AsRef<Path>,
+{
+    tracing::debug!("Merging fragmented MP4...");
+
+    let mut inputs = Vec::with_capacity(streams.len());
+    let mut next_track_id = 1;
+    for segments in streams {
+        inputs.push(Input::load(segments, cache, &mut next_track_id).await?);
+    }
+    let Some(first) = inputs.first() else {
+        return Err(IoriError::Mp4Parsing("No stream to merge".to_string()));
+    };
+
+    let udta = metadata.to_mp4_udta();
+    let mut output = BufWriter::new(File::create(output.as_ref()).await?);
+    let ftyp = first.ftyp.clone().unwrap_or_default();
+    output.write_all(&ftyp).await?;
+    if progressive {
+        write_progressive(&inputs, cache, ftyp.len() as u64, &udta, &mut output).await?;
+    } else {
+        write_fragmented(&inputs, cache, &udta, &mut output).await?;
+    }
+    output.flush().await?;
+
+    Ok(())
+}
+
+/// A fragmented MP4 stream to merge.
+struct Input<'a> {
+    segments: &'a [&'a SegmentInfo],
+    ftyp: Option<Vec<u8>>,
+    moov: Vec<u8>,
+    /// Map from track IDs in the stream to track IDs in the output file
+    track_ids: HashMap<u32, u32>,
+    /// Timescale of each track in the stream
+    timescales: HashMap<u32, u32>,
+    trex: HashMap<u32, SampleDefaults>,
+}
+
+impl<'a> Input<'a> {
+    async fn load(
+        segments: &'a [&'a SegmentInfo],
+        cache: &impl CacheSource,
+        next_track_id: &mut u32,
+    ) -> IoriResult<Self> {
+        let Some(first_segment) = segments.first() else {
+            return Err(IoriError::Mp4Parsing("No segment to merge".to_string()));
+        };
+        let data = read_segment(first_segment, cache).await?;
+
+        let moov = find_box(&data, b"moov").ok_or_else(|| {
+            IoriError::Mp4Parsing("No moov box found in the initialization segment".to_string())
+        })?;
+        let mut track_ids = HashMap::new();
+        let mut timescales = HashMap::new();
+        for trak in moov.children(0).filter(|b| &b.r#type == b"trak") {
+            let (Some(track_id), Some(timescale)) = (read_track_id(&trak), read_timescale(&trak))
+            else {
+                continue;
+            };
+            track_ids.insert(track_id, *next_track_id);
+            timescales.insert(track_id, timescale.max(1));
+            *next_track_id += 1;
+        }
+        if track_ids.is_empty() {
+            return Err(IoriError::Mp4Parsing(
+                "No track found in moov box".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            segments,
+            ftyp: find_box(&data, b"ftyp").map(|b| b.raw.to_vec()),
+            trex: parse_trex(&moov),
+            moov: moov.raw.to_vec(),
+            track_ids,
+            timescales,
+        })
+    }
+
+    fn moov(&self) -> Mp4Box<'_> {
+        Mp4BoxIter::new(&self.moov)
+            .next()
+            .expect("moov box has been validated")
+    }
+
+    fn traks(&self) -> impl Iterator<Item = (Mp4Box<'_>, u32, u32)> {
+        self.moov()
+            .children(0)
+            .filter(|b| &b.r#type == b"trak")
+            .filter_map(move |trak| {
+                let track_id = read_track_id(&trak)?;
+                let output_track_id = *self.track_ids.get(&track_id)?;
+                Some((trak, track_id, output_track_id))
+            })
+    }
+}
+
+/// A `traf` box in a fragment.
+struct TrackRun {
+    track_id: u32,
+    /// Offset of track_ID in `tfhd`, relative to the segment data
+    tfhd_offset: usize,
+    samples: Vec<FragmentSample>,
+}
+
+/// A `moof` box and the `mdat` boxes following it.
+struct Fragment {
+    range: Range<usize>,
+    /// Offset of sequence_number in `mfhd`, relative to the segment data
+    mfhd_offset: Option<usize>,
+    trafs: Vec<TrackRun>,
+}
+
+/// Samples of a track which are stored contiguously in a segment.
+struct Chunk<'s> {
+    /// Track ID in the output file
+    track_id: u32,
+    samples: &'s [FragmentSample],
+}
+
+impl Chunk<'_> {
+    fn range(&self) -> Range<usize> {
+        let first = &self.samples[0];
+        let last = &self.samples[self.samples.len() - 1];
+        first.offset..last.offset + last.size as usize
+    }
+}
+
+struct LoadedSegment {
+    data: Vec<u8>,
+    fragments: Vec<Fragment>,
+}
+
+impl LoadedSegment {
+    fn parse(data: Vec<u8>, trex: &HashMap<u32, SampleDefaults>) -> IoriResult<Self> {
+        let mut fragments: Vec<Fragment> = Vec::new();
+        for mp4_box in Mp4BoxIter::new(&data) {
+            match &mp4_box.r#type {
+                b"moof" => {
+                    let payload = mp4_box.offset + mp4_box.header_size();
+                    let mfhd_offset = mp4_box
+                        .find_child(b"mfhd")
+                        // version(1) + flags(3)
+                        .map(|mfhd| payload + mfhd.offset + mfhd.header_size() + 4);
+
+                    let trafs = mp4_box.children(0).filter(|b| &b.r#type == b"traf");
+                    let mut runs = Vec::new();
+                    for (traf, fragment) in trafs.zip(parse_moof(&mp4_box, trex)?) {
+                        let Some(tfhd) = traf.find_child(b"tfhd") else {
+                            continue;
+                        };
+                        if fragment
+                            .samples
+                            .iter()
+                            .any(|s| s.offset + s.size as usize > data.len())
+                        {
+                            return Err(IoriError::Mp4Parsing(
+                                "Sample data out of range".to_string(),
+                            ));
+                        }
+                        runs.push(TrackRun {
+                            track_id: fragment.track_id,
+                            tfhd_offset: payload
+                                + traf.offset
+                                + traf.header_size()
+                                + tfhd.offset
+                                + tfhd.header_size()
+                                + 4,
+                            samples: fragment.samples,
+                        });
+                    }
+
+                    fragments.push(Fragment {
+                        range: mp4_box.offset..mp4_box.end(),
+                        mfhd_offset,
+                        trafs: runs,
+                    });
+                }
+                b"mdat" => {
+                    if let Some(fragment) = fragments.last_mut() {
+                        if fragment.range.end == mp4_box.offset {
+                            fragment.range.end = mp4_box.end();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self { data, fragments })
+    }
+
+    /// Decode time of the first sample in seconds.
+    fn start_time(&self, input: &Input) -> f64 {
+        self.fragments
+            .iter()
+            .flat_map(|f| &f.trafs)
+            .find_map(|traf| {
+                let sample = traf.samples.first()?;
+                let timescale = input.timescales.get(&traf.track_id)?;
+                Some(sample.decode_time as f64 / *timescale as f64)
+            })
+            .unwrap_or_default()
+    }
+
+    fn chunks(&self, input: &Input) -> Vec<Chunk<'_>> {
+        let mut chunks = Vec::new();
+        for traf in self.fragments.iter().flat_map(|f| &f.trafs) {
+            let Some(&track_id) = input.track_ids.get(&traf.track_id) else {
+                continue;
+            };
+            chunks.extend(
+                traf.samples
+                    .chunk_by(|a, b| a.offset + a.size as usize == b.offset)
+                    .map(|samples| Chunk { track_id, samples }),
+            );
+        }
+        chunks
+    }
+}
+
+/// Read segments of all inputs, interleaved by decode time.
+struct SegmentQueue<'i, 'a> {
+    inputs: &'i [Input<'a>],
+    /// Index of the next segment to read in each input
+    next: Vec<usize>,
+    /// Segment read ahead in each input
+    pending: Vec<Option<LoadedSegment>>,
+}
+
+impl<'i, 'a> SegmentQueue<'i, 'a> {
+    fn new(inputs: &'i [Input<'a>]) -> Self {
+        Self {
+            inputs,
+            next: vec![0; inputs.len()],
+            pending: inputs.iter().map(|_| None).collect(),
+        }
+    }
+
+    async fn next(
+        &mut self,
+        cache: &impl CacheSource,
+    ) -> IoriResult<Option<(&'i Input<'a>, LoadedSegment)>> {
+        let inputs = self.inputs;
+        for (index, input) in inputs.iter().enumerate() {
+            if self.pending[index].is_some() {
+                continue;
+            }
+            if let Some(segment) = input.segments.get(self.next[index]) {
+                self.next[index] += 1;
+                let data = read_segment(segment, cache).await?;
+                self.pending[index] = Some(LoadedSegment::parse(data, &input.trex)?);
+            }
+        }
+
+        let next = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter_map(|(index, segment)| {
+                Some((index, segment.as_ref()?.start_time(&inputs[index])))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index);
+        Ok(next.and_then(|index| Some((&inputs[index], self.pending[index].take()?))))
+    }
+}
+
+async fn write_fragmented(
+    inputs: &[Input<'_>],
+    cache: &impl CacheSource,
+    udta: &[u8],
+    output: &mut (impl AsyncWrite + Unpin),
+) -> IoriResult<()> {
+    output.write_all(&fragmented_moov(inputs, udta)?).await?;
+
+    let mut sequence_number: u32 = 1;
+    let mut queue = SegmentQueue::new(inputs);
+    while let Some((input, mut segment)) = queue.next(cache).await? {
+        for fragment in &segment.fragments {
+            if let Some(offset) = fragment.mfhd_offset {
+                put_u32(&mut segment.data, offset, sequence_number)?;
+            }
+            for traf in &fragment.trafs {
+                if let Some(track_id) = input.track_ids.get(&traf.track_id) {
+                    put_u32(&mut segment.data, traf.tfhd_offset, *track_id)?;
+                }
+            }
+            output
+                .write_all(&segment.data[fragment.range.clone()])
+                .await?;
+            sequence_number = sequence_number.wrapping_add(1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge `moov` boxes of all inputs, keeping the movie extends box for fragments.
+fn fragmented_moov(inputs: &[Input], udta: &[u8]) -> IoriResult<Vec<u8>> {
+    let first = inputs[0].moov();
+    let mut payload = Vec::new();
+    payload.extend(mvhd(inputs, &first, None)?);
+
+    for input in inputs {
+        for (trak, _, track_id) in input.traks() {
+            let mut trak_data = trak.raw.to_vec();
+            if let Some(tkhd) = trak.find_child(b"tkhd") {
+                let offset = if tkhd.data.first() == Some(&1) {
+                    20
+                } else {
+                    12
+                };
+                put_u32(
+                    &mut trak_data,
+                    trak.header_size() + tkhd.offset + tkhd.header_size() + offset,
+                    track_id,
+                )?;
+            }
+            payload.extend(trak_data);
+        }
+    }
+
+    let mut mvex = Vec::new();
+    if let Some(mehd) = first
+        .find_child(b"mvex")
+        .and_then(|m| m.find_child(b"mehd"))
+    {
+        mvex.extend_from_slice(mehd.raw);
+    }
+    for input in inputs {
+        let Some(input_mvex) = input.moov().find_child(b"mvex") else {
+            continue;
+        };
+        for trex in input_mvex.children(0).filter(|b| &b.r#type == b"trex") {
+            let Some(track_id) = read_u32(trex.data, 4).and_then(|id| input.track_ids.get(&id))
+            else {
+                continue;
+            };
+            let mut trex_data = trex.raw.to_vec();
+            put_u32(&mut trex_data, trex.header_size() + 4, *track_id)?;
+            mvex.extend(trex_data);
+        }
+    }
+    write_box(&mut payload, b"mvex", &mvex);
+
+    extend_other_boxes(&mut payload, &first, udta);
+
+    let mut moov = Vec::new();
+    write_box(&mut moov, b"moov", &payload);
+    Ok(moov)
+}
+
+/// Samples and chunks of a track in the progressive output.
+#[derive(Default)]
+pub(super) struct SampleTable {
+    pub samples: Vec<FragmentSample>,
+    /// Offset relative to the start of `mdat` payload, and sample count of each chunk
+    pub chunks: Vec<(u64, u32)>,
+}
+
+impl SampleTable {
+    pub(super) fn duration(&self) -> u64 {
+        self.samples.iter().map(|s| s.duration as u64).sum()
+    }
+
+    /// Write sample table boxes after `stsd`.
+    ///
+    /// `base_offset` is added to chunk offsets, and `co64` is used instead of `stco` if
+    /// `large_offsets` is set.
+    pub(super) fn write_boxes(&self, payload: &mut Vec<u8>, base_offset: u64, large_offsets: bool) {
+        let samples = &self.samples;
+
+        // decoding time to sample
+        let mut stts: Vec<(u32, u32)> = Vec::new();
+        for sample in samples {
+            match stts.last_mut() {
+                Some((count, duration)) if *duration == sample.duration => *count += 1,
+                _ => stts.push((1, sample.duration)),
+            }
+        }
+        write_table(payload, b"stts", 0, &stts);
+
+        // composition time to sample
+        if samples.iter().any(|s| s.composition_offset != 0) {
+            let mut ctts: Vec<(u32, u32)> = Vec::new();
+            for sample in samples {
+                let offset = sample.composition_offset as u32;
+                match ctts.last_mut() {
+                    Some((count, last)) if *last == offset => *count += 1,
+                    _ => ctts.push((1, offset)),
+                }
+            }
+            let version = samples.iter().any(|s| s.composition_offset < 0) as u8;
+            write_table(payload, b"ctts", version, &ctts);
+        }
+
+        // sync samples, all samples are sync samples if absent
+        if samples.iter().any(|s| !s.is_sync()) {
+            let sync_samples: Vec<u32> = samples
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.is_sync())
+                .map(|(index, _)| index as u32 + 1)
+                .collect();
+            write_table(payload, b"stss", 0, &sync_samples);
+        }
+
+        // sample to chunk, with sample_description_index always 1
+        let mut stsc: Vec<(u32, u32, u32)> = Vec::new();
+        for (index, (_, count)) in self.chunks.iter().enumerate() {
+            if stsc.last().is_none_or(|(_, last, _)| last != count) {
+                stsc.push((index as u32 + 1, *count, 1));
+            }
+        }
+        write_table(payload, b"stsc", 0, &stsc);
+
+        // sample sizes
+        let mut stsz = Vec::new();
+        let first_size = samples.first().map(|s| s.size).unwrap_or_default();
+        if samples.iter().all(|s| s.size == first_size) {
+            stsz.extend_from_slice(&first_size.to_be_bytes());
+            stsz.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        } else {
+            stsz.extend_from_slice(&0u32.to_be_bytes());
+            stsz.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+            for sample in samples {
+                stsz.extend_from_slice(&sample.size.to_be_bytes());
+            }
+        }
+        write_full_box(payload, b"stsz", 0, 0, &stsz);
+
+        // chunk offsets
+        if large_offsets {
+            let offsets: Vec<u64> = self.chunks.iter().map(|(o, _)| base_offset + o).collect();
+            write_table(payload, b"co64", 0, &offsets);
+        } else {
+            let offsets: Vec<u32> = self
+                .chunks
+                .iter()
+                .map(|(o, _)| (base_offset + o) as u32)
+                .collect();
+            write_table(payload, b"stco", 0, &offsets);
+        }
+    }
+}
+
+async fn write_progressive(
+    inputs: &[Input<'_>],
+    cache: &impl CacheSource,
+    ftyp_size: u64,
+    udta: &[u8],
+    output: &mut (impl AsyncWrite + Unpin),
+) -> IoriResult<()> {
+    // The first pass collects sample tables, and the second pass copies sample data
+    // in the same order.
+    let mut tables: HashMap<u32, SampleTable> = HashMap::new();
+    let mut mdat_size = 0;
+    let mut queue = SegmentQueue::new(inputs);
+    while let Some((input, segment)) = queue.next(cache).await? {
+        for chunk in segment.chunks(input) {
+            let table = tables.entry(chunk.track_id).or_default();
+            table.chunks.push((mdat_size, chunk.samples.len() as u32));
+            table.samples.extend_from_slice(chunk.samples);
+            mdat_size += chunk.range().len() as u64;
+        }
+    }
+
+    let mdat_header_size = if mdat_size + 8 > u32::MAX as u64 {
+        16
+    } else {
+        8
+    };
+    // Chunk offsets do not change the size of moov, but whether they fit in 32 bits does.
+    let mut large_offsets = false;
+    let mut moov_size = progressive_moov(inputs, &tables, 0, large_offsets, udta)?.len() as u64;
+    if ftyp_size + moov_size + mdat_header_size + mdat_size > u32::MAX as u64 {
+        large_offsets = true;
+        moov_size = progressive_moov(inputs, &tables, 0, large_offsets, udta)?.len() as u64;
+    }
+    let base_offset = ftyp_size + moov_size + mdat_header_size;
+    let moov = progressive_moov(inputs, &tables, base_offset, large_offsets, udta)?;
+    output.write_all(&moov).await?;
+
+    if mdat_header_size == 16 {
+        output.write_all(&1u32.to_be_bytes()).await?;
+        output.write_all(b"mdat").await?;
+        output.write_all(&(mdat_size + 16).to_be_bytes()).await?;
+    } else {
+        output
+            .write_all(&(mdat_size as u32 + 8).to_be_bytes())
+            .await?;
+        output.write_all(b"mdat").await?;
+    }
+
+    let mut queue = SegmentQueue::new(inputs);
+    while let Some((input, segment)) = queue.next(cache).await? {
+        for chunk in segment.chunks(input) {
+            output.write_all(&segment.data[chunk.range()]).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Build a `moov` box with sample tables of all tracks.
+///
+/// `base_offset` is the offset of `mdat` payload in the output file.
+fn progressive_moov(
+    inputs: &[Input],
+    tables: &HashMap<u32, SampleTable>,
+    base_offset: u64,
+    large_offsets: bool,
+    udta: &[u8],
+) -> IoriResult<Vec<u8>> {
+    let first = inputs[0].moov();
+    let movie_timescale = first
+        .find_child(b"mvhd")
+        .and_then(|mvhd| {
+            let version = *mvhd.data.first()?;
+            read_u32(mvhd.data, if version == 1 { 20 } else { 12 })
+        })
+        .unwrap_or(1000)
+        .max(1) as u64;
+
+    let empty = SampleTable::default();
+    let mut movie_duration = 0;
+    let mut traks = Vec::new();
+    for input in inputs {
+        for (trak, source_track_id, track_id) in input.traks() {
+            let table = tables.get(&track_id).unwrap_or(&empty);
+            let media_duration = table.duration();
+            let timescale = input.timescales[&source_track_id] as u64;
+            let track_duration =
+                (media_duration as u128 * movie_timescale as u128 / timescale as u128) as u64;
+            movie_duration = movie_duration.max(track_duration);
+
+            let mut payload = Vec::new();
+            for child in trak.children(0) {
+                match &child.r#type {
+                    b"tkhd" => {
+                        let mut tkhd = set_duration(&child, 8, track_duration)?;
+                        let offset = if tkhd.first() == Some(&1) { 20 } else { 12 };
+                        put_u32(&mut tkhd, offset, track_id)?;
+                        write_box(&mut payload, b"tkhd", &tkhd);
+                    }
+                    b"mdia" => {
+                        let mdia = progressive_mdia(
+                            &child,
+                            table,
+                            media_duration,
+                            base_offset,
+                            large_offsets,
+                        )?;
+                        write_box(&mut payload, b"mdia", &mdia);
+                    }
+                    _ => payload.extend_from_slice(child.raw),
+                }
+            }
+            write_box(&mut traks, b"trak", &payload);
+        }
+    }
+
+    let mut payload = Vec::new();
+    payload.extend(mvhd(inputs, &first, Some(movie_duration))?);
+    payload.extend(traks);
+    extend_other_boxes(&mut payload, &first, udta);
+
+    let mut moov = Vec::new();
+    write_box(&mut moov, b"moov", &payload);
+    Ok(moov)
+}
+
+fn progressive_mdia(
+    mdia: &Mp4Box,
+    table: &SampleTable,
+    media_duration: u64,
+    base_offset: u64,
+    large_offsets: bool,
+) -> IoriResult<Vec<u8>> {
+    let mut payload = Vec::new();
+    for child in mdia.children(0) {
+        match &child.r#type {
+            b"mdhd" => {
+                let mdhd = set_duration(&child, 4, media_duration)?;
+                write_box(&mut payload, b"mdhd", &mdhd);
+            }
+            b"minf" => {
+                let mut minf = Vec::new();
+                for child in child.children(0) {
+                    if &child.r#type == b"stbl" {
+                        let stbl = progressive_stbl(&child, table, base_offset, large_offsets)?;
+                        write_box(&mut minf, b"stbl", &stbl);
+                    } else {
+                        minf.extend_from_slice(child.raw);
+                    }
+                }
+                write_box(&mut payload, b"minf", &minf);
+            }
+            _ => payload.extend_from_slice(child.raw),
+        }
+    }
+    Ok(payload)
+}
+
+fn progressive_stbl(
+    stbl: &Mp4Box,
+    table: &SampleTable,
+    base_offset: u64,
+    large_offsets: bool,
+) -> IoriResult<Vec<u8>> {
+    let mut payload = Vec::new();
+
+    let stsd = stbl
+        .find_child(b"stsd")
+        .ok_or_else(|| IoriError::Mp4Parsing("No stsd box found in stbl box".to_string()))?;
+    payload.extend_from_slice(stsd.raw);
+
+    table.write_boxes(&mut payload, base_offset, large_offsets);
+
+    // keep other boxes like sample groups
+    for child in stbl.children(0) {
+        if !matches!(
+            &child.r#type,
+            b"stsd" | b"stts" | b"ctts" | b"stss" | b"stsc" | b"stsz" | b"stz2" | b"stco" | b"co64"
+        ) {
+            payload.extend_from_slice(child.raw);
+        }
+    }
+
+    Ok(payload)
+}
+
+/// Build `mvhd` of the output file from the first input.
+fn mvhd(inputs: &[Input], moov: &Mp4Box, duration: Option<u64>) -> IoriResult<Vec<u8>> {
+    let mvhd = moov
+        .find_child(b"mvhd")
+        .ok_or_else(|| IoriError::Mp4Parsing("No mvhd box found in moov box".to_string()))?;
+    let mut payload = match duration {
+        Some(duration) => set_duration(&mvhd, 4, duration)?,
+        None => mvhd.data.to_vec(),
+    };
+
+    // next_track_ID is the last field of mvhd
+    let next_track_id = inputs.iter().map(|i| i.track_ids.len() as u32).sum::<u32>() + 1;
+    let offset = payload
+        .len()
+        .checked_sub(4)
+        .ok_or_else(|| IoriError::Mp4Parsing("Invalid mvhd box".to_string()))?;
+    put_u32(&mut payload, offset, next_track_id)?;
+
+    let mut mvhd = Vec::new();
+    write_box(&mut mvhd, b"mvhd", &payload);
+    Ok(mvhd)
+}
+
+/// Append boxes other than `mvhd`, `trak` and `mvex` in `moov`, like `pssh` and `udta`.
+///
+/// The `udta` box is replaced with `udta` if it is not empty.
+fn extend_other_boxes(payload: &mut Vec<u8>, moov: &Mp4Box, udta: &[u8]) {
+    for child in moov.children(0) {
+        if matches!(&child.r#type, b"mvhd" | b"trak" | b"mvex") {
+            continue;
+        }
+        if &child.r#type == b"udta" && !udta.is_empty() {
+            continue;
+        }
+        payload.extend_from_slice(child.raw);
+    }
+    payload.extend_from_slice(udta);
+}
+
+/// Rewrite the duration in the payload of a `mvhd`, `tkhd` or `mdhd` box.
+///
+/// These boxes share the same layout before duration, except for `middle` bytes of fields
+/// between modification_time and duration. A version 0 box is upgraded to version 1 if
+/// the duration does not fit in 32 bits.
+fn set_duration(mp4_box: &Mp4Box, middle: usize, duration: u64) -> IoriResult<Vec<u8>> {
+    let data = mp4_box.data;
+    let version = data.first().copied().unwrap_or_default();
+    let time_size = if version == 1 { 8 } else { 4 };
+    let times_end = 4 + time_size * 2;
+    let duration_start = times_end + middle;
+    let rest_start = duration_start + time_size;
+    if data.len() < rest_start {
+        return Err(IoriError::Mp4Parsing(format!(
+            "Invalid {} box",
+            String::from_utf8_lossy(&mp4_box.r#type)
+        )));
+    }
+
+    let mut payload = Vec::with_capacity(data.len() + 12);
+    if version == 1 || duration > u32::MAX as u64 {
+        payload.push(1);
+        payload.extend_from_slice(&data[1..4]);
+        if version == 1 {
+            payload.extend_from_slice(&data[4..times_end]);
+        } else {
+            // creation_time and modification_time
+            for offset in [4, 8] {
+                let time = read_u32(data, offset).unwrap_or_default() as u64;
+                payload.extend_from_slice(&time.to_be_bytes());
+            }
+        }
+        payload.extend_from_slice(&data[times_end..duration_start]);
+        payload.extend_from_slice(&duration.to_be_bytes());
+    } else {
+        payload.extend_from_slice(&data[..duration_start]);
+        payload.extend_from_slice(&(duration as u32).to_be_bytes());
+    }
+    payload.extend_from_slice(&data[rest_start..]);
+    Ok(payload)
+}
+
+trait TableEntry {
+    fn write(&self, output: &mut Vec<u8>);
+}
+
+impl TableEntry for u32 {
+    fn write(&self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl TableEntry for u64 {
+    fn write(&self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl TableEntry for (u32, u32) {
+    fn write(&self, output: &mut Vec<u8>) {
+        self.0.write(output);
+        self.1.write(output);
+    }
+}
+
+impl TableEntry for (u32, u32, u32) {
+    fn write(&self, output: &mut Vec<u8>) {
+        self.0.write(output);
+        self.1.write(output);
+        self.2.write(output);
+    }
+}
+
+/// Write a full box with entry_count followed by entries.
+fn write_table<T: TableEntry>(output: &mut Vec<u8>, r#type: &[u8; 4], version: u8, entries: &[T]) {
+    let mut payload = (entries.len() as u32).to_be_bytes().to_vec();
+    for entry in entries {
+        entry.write(&mut payload);
+    }
+    write_full_box(output, r#type, version, 0, &payload);
+}
+
+/// Overwrite a big-endian u32 at `offset`, which is read from box headers of segments.
+fn put_u32(data: &mut [u8], offset: usize, value: u32) -> IoriResult<()> {
+    offset
+        .checked_add(4)
+        .and_then(|end| data.get_mut(offset..end))
+        .ok_or_else(|| IoriError::Mp4Parsing(format!("Field out of bounds at {offset}")))?
+        .copy_from_slice(&value.to_be_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::mp4::read_u64;
+
+    fn full_box(r#type: &[u8; 4], version: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_full_box(&mut data, r#type, version, 0, payload);
+        data
+    }
+
+    fn input(track_id: u32, output_track_id: u32) -> Input<'static> {
+        let mut mvhd = vec![0; 96];
+        mvhd[8..12].copy_from_slice(&1000u32.to_be_bytes());
+        let mut tkhd = vec![0; 8];
+        tkhd.extend_from_slice(&track_id.to_be_bytes());
+        let mut trex = track_id.to_be_bytes().to_vec();
+        trex.extend_from_slice(&[0; 16]);
+
+        let mut payload = full_box(b"mvhd", 0, &mvhd);
+        write_box(&mut payload, b"trak", &full_box(b"tkhd", 0, &tkhd));
+        write_box(&mut payload, b"mvex", &full_box(b"trex", 0, &trex));
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"moov", &payload);
+
+        Input {
+            segments: &[],
+            ftyp: None,
+            moov,
+            track_ids: HashMap::from([(track_id, output_track_id)]),
+            timescales: HashMap::from([(track_id, 1000)]),
+            trex: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_fragmented_moov() {
+        let inputs = [input(1, 1), input(1, 2)];
+        let moov = fragmented_moov(&inputs, &[]).unwrap();
+        let moov = find_box(&moov, b"moov").unwrap();
+
+        let mvhd = moov.find_child(b"mvhd").unwrap();
+        assert_eq!(read_u32(mvhd.data, mvhd.data.len() - 4), Some(3));
+
+        let track_ids: Vec<_> = moov
+            .children(0)
+            .filter(|b| &b.r#type == b"trak")
+            .map(|trak| read_track_id(&trak).unwrap())
+            .collect();
+        assert_eq!(track_ids, [1, 2]);
+
+        let trex_ids: Vec<_> = moov
+            .find_child(b"mvex")
+            .unwrap()
+            .children(0)
+            .map(|trex| read_u32(trex.data, 4).unwrap())
+            .collect();
+        assert_eq!(trex_ids, [1, 2]);
+    }
+
+    #[test]
+    fn test_moov_metadata() {
+        let inputs = [input(1, 1)];
+        let metadata = Metadata {
+            title: Some("title".to_string()),
+            ..Default::default()
+        };
+        let udta = metadata.to_mp4_udta();
+        let moov = fragmented_moov(&inputs, &udta).unwrap();
+        let moov = find_box(&moov, b"moov").unwrap();
+
+        let udtas: Vec<_> = moov
+            .children(0)
+            .filter(|b| &b.r#type == b"udta")
+            .map(|b| b.raw)
+            .collect();
+        assert_eq!(udtas, [udta.as_slice()]);
+    }
+
+    #[test]
+    fn test_set_duration() {
+        // version 0 mdhd: creation_time, modification_time, timescale, duration, language
+        let mut payload = vec![0; 4];
+        for value in [1u32, 2, 90000, 0] {
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        payload.extend_from_slice(&[0x55, 0xc4, 0, 0]);
+        let mdhd = full_box(b"mdhd", 0, &payload[4..]);
+        let mdhd = find_box(&mdhd, b"mdhd").unwrap();
+
+        let small = set_duration(&mdhd, 4, 180000).unwrap();
+        assert_eq!(small.len(), payload.len());
+        assert_eq!(read_u32(&small, 16), Some(180000));
+        assert_eq!(&small[20..], &[0x55, 0xc4, 0, 0]);
+
+        let large = set_duration(&mdhd, 4, 1 << 33).unwrap();
+        assert_eq!(large[0], 1);
+        assert_eq!(large.len(), payload.len() + 12);
+        assert_eq!(read_u64(&large, 4), Some(1));
+        assert_eq!(read_u32(&large, 20), Some(90000));
+        assert_eq!(read_u64(&large, 24), Some(1 << 33));
+        assert_eq!(&large[32..], &[0x55, 0xc4, 0, 0]);
+    }
+
+    #[test]
+    fn test_progressive_stbl() {
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        write_box(&mut stsd, b"avc1", &[0; 8]);
+        let mut stbl = Vec::new();
+        write_box(&mut stbl, b"stbl", &full_box(b"stsd", 0, &stsd));
+        let stbl = find_box(&stbl, b"stbl").unwrap();
+
+        let sample = |duration, size, flags, composition_offset| FragmentSample {
+            decode_time: 0,
+            duration,
+            size,
+            flags,
+            composition_offset,
+            offset: 0,
+        };
+        let table = SampleTable {
+            samples: vec![
+                sample(1000, 10, 0, 0),
+                sample(1000, 20, 0x0001_0000, 2000),
+                sample(500, 30, 0x0001_0000, 0),
+            ],
+            chunks: vec![(0, 2), (30, 1)],
+        };
+        let payload = progressive_stbl(&stbl, &table, 100, false).unwrap();
+
+        let boxes: Vec<_> = Mp4BoxIter::new(&payload).collect();
+        let types: Vec<_> = boxes.iter().map(|b| &b.r#type).collect();
+        assert_eq!(
+            types,
+            [
+                b"stsd", b"stts", b"ctts", b"stss", b"stsc", b"stsz", b"stco"
+            ]
+        );
+
+        let data = |index: usize| boxes[index].data[4..].to_vec();
+        let words =
+            |values: &[u32]| -> Vec<u8> { values.iter().flat_map(|v| v.to_be_bytes()).collect() };
+        assert_eq!(data(1), words(&[2, 2, 1000, 1, 500]));
+        assert_eq!(data(2), words(&[3, 1, 0, 1, 2000, 1, 0]));
+        assert_eq!(data(3), words(&[1, 1]));
+        assert_eq!(data(4), words(&[2, 1, 2, 1, 2, 1, 1]));
+        assert_eq!(data(5), words(&[0, 3, 10, 20, 30]));
+        assert_eq!(data(6), words(&[2, 100, 130]));
+    }
+
+    #[test]
+    fn test_put_u32_out_of_bounds() {
+        let mut data = vec![0; 8];
+        put_u32(&mut data, 4, 1).unwrap();
+        assert_eq!(data, [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(put_u32(&mut data, 6, 1).is_err());
+        assert!(put_u32(&mut data, usize::MAX - 2, 1).is_err());
+    }
+}
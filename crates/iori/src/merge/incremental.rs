@@ -0,0 +1,359 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use super::{
+    FailurePolicy, MergeReport, Merger,
+    auto::{extract_subtitle, is_mp4_subtitle, is_webvtt, merge_webvtt, mux_tracks},
+    chapter::{source_chapters, successful_streams},
+    concat::ConcatSegment,
+};
+use crate::{
+    IoriError, IoriResult, SegmentFormat, SegmentInfo, SegmentType,
+    cache::{CacheSource, CacheSourceReader},
+    metadata::Metadata,
+    subtitle::SubtitleFormat,
+    util::{mp4::split_init_segment, mpegts, ordered_stream::OrderedStream, path::IoriPathExt},
+};
+
+type SendSegment = (
+    SegmentInfo,
+    CacheSourceReader,
+    Pin<Box<dyn Future<Output = IoriResult<()>> + Send>>,
+);
+
+/// Track files written while downloading.
+#[derive(Default)]
+struct AppendedTracks {
+    /// Track file of each stream
+    paths: BTreeMap<u64, PathBuf>,
+    /// First PTS of the video track, which is the base of WebVTT timestamp mapping.
+    base_pts: Option<u64>,
+}
+
+/// IncrementalMerger appends segments to a file per track while downloading.
+///
+/// A segment is appended as soon as all previous segments of its stream are downloaded
+/// or failed, and is removed from the cache right after it is written. Only the final mux
+/// is left for [Merger::finish]:
+/// - A single track is renamed to the output file.
+/// - Multiple MPEG-TS tracks are remuxed into a single MP4 file without external tools.
+/// - Other tracks are merged with mkvmerge, or ffmpeg if the `ffmpeg` feature is enabled.
+///
+/// For fragmented MP4, the initialization segment is written once for each track.
+/// Subtitles in fragmented MP4 or HLS WebVTT segments are kept in the cache and extracted
+/// in [Merger::finish], like [AutoMerger](super::AutoMerger) does.
+///
+/// Missing segments are left as gaps in the output by default. This can be changed with
+/// [IncrementalMerger::with_failure_policy]. Segments are appended before failures are known,
+/// so [FailurePolicy::Skip] keeps the track files without muxing them, and
+/// [FailurePolicy::Split] merges with gaps. [Metadata] is written as tags of muxed outputs,
+/// or to a sidecar `.json` file of a single track output.
+pub struct IncrementalMerger {
+    segments: Vec<ConcatSegment>,
+    /// Subtitle segments to extract after downloading.
+    subtitles: BTreeMap<u64, Vec<SegmentInfo>>,
+
+    /// Keep downloaded segments after appending them.
+    keep_segments: bool,
+
+    /// What to do when some segments failed to download.
+    failure_policy: FailurePolicy,
+
+    /// Final output file path. It may not have an extension.
+    output_file: PathBuf,
+    /// A list of file extensions which should skip adding an auto extension.
+    allowed_extensions: Vec<&'static str>,
+    /// Output format of extracted subtitles.
+    subtitle_format: SubtitleFormat,
+    /// Metadata written into the output file.
+    metadata: Metadata,
+
+    sender: Option<mpsc::UnboundedSender<(u64, u64, Option<SendSegment>)>>,
+    /// Appending task, which returns the written track files.
+    future: Option<JoinHandle<IoriResult<AppendedTracks>>>,
+}
+
+impl IncrementalMerger {
+    pub fn new(output_file: PathBuf, keep_segments: bool) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stream = OrderedStream::new(rx);
+        let future = tokio::spawn(append_segments(stream, output_file.clone(), keep_segments));
+
+        Self {
+            segments: Vec::new(),
+            subtitles: BTreeMap::new(),
+            keep_segments,
+            failure_policy: FailurePolicy::MergeWithGaps,
+
+            output_file,
+            allowed_extensions: vec!["mkv", "mp4", "ts"],
+            subtitle_format: SubtitleFormat::default(),
+            metadata: Metadata::default(),
+
+            sender: Some(tx),
+            future: Some(future),
+        }
+    }
+
+    pub fn with_subtitle_format(mut self, subtitle_format: SubtitleFormat) -> Self {
+        self.subtitle_format = subtitle_format;
+        self
+    }
+
+    pub fn with_failure_policy(mut self, failure_policy: FailurePolicy) -> Self {
+        self.failure_policy = failure_policy;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Whether each segment is removed from the cache once it is appended.
+    pub(crate) fn invalidates_segments(&self) -> bool {
+        !self.keep_segments
+    }
+
+    fn send(&self, message: (u64, u64, Option<SendSegment>)) {
+        if let Some(sender) = &self.sender {
+            // The appending task only stops early on error, which is returned by `finish`.
+            _ = sender.send(message);
+        }
+    }
+}
+
+impl Merger for IncrementalMerger {
+    type Result = MergeReport;
+
+    async fn update(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        self.segments.push(ConcatSegment {
+            segment: segment.clone(),
+            success: true,
+        });
+
+        if is_mp4_subtitle(&segment) || is_webvtt(&segment) {
+            self.subtitles
+                .entry(segment.stream_id)
+                .or_default()
+                .push(segment);
+            return Ok(());
+        }
+
+        let stream_id = segment.stream_id;
+        let sequence = segment.sequence;
+        let reader = cache.open_reader(&segment).await?;
+        let invalidate = {
+            let segment = segment.clone();
+            async move { cache.invalidate(&segment).await }
+        };
+        self.send((
+            stream_id,
+            sequence,
+            Some((segment, reader, Box::pin(invalidate))),
+        ));
+
+        Ok(())
+    }
+
+    async fn fail(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        cache.invalidate(&segment).await?;
+
+        self.send((segment.stream_id, segment.sequence, None));
+        self.segments.push(ConcatSegment {
+            segment,
+            success: false,
+        });
+
+        Ok(())
+    }
+
+    async fn finish(&mut self, cache: impl CacheSource) -> IoriResult<Self::Result> {
+        // drop the sender so that the appending task can finish
+        drop(self.sender.take());
+
+        let Some(future) = self.future.take() else {
+            return Err(IoriError::IOError(std::io::Error::other(
+                "Incremental merger is already finished",
+            )));
+        };
+        let tracks = future.await??;
+
+        let mut report = MergeReport::new(&self.segments);
+        report.failure_policy = self.failure_policy;
+        if !self.failure_policy.should_merge(&report)? {
+            tracing::warn!("Track files are kept without muxing:");
+            for path in tracks.paths.values() {
+                tracing::warn!("  - {}", path.display());
+            }
+            report.skipped = true;
+            return Ok(report);
+        }
+
+        let mut subtitles = Vec::new();
+        for (stream_id, segments) in self.subtitles.iter_mut() {
+            segments.sort_by_key(|s| s.sequence);
+            let segments: Vec<_> = segments.iter().collect();
+            let subtitle = if segments.iter().all(|s| is_webvtt(s)) {
+                merge_webvtt(&segments, &cache, tracks.base_pts).await?
+            } else {
+                extract_subtitle(&segments, &cache).await?
+            };
+
+            let mut output_path = self.output_file.clone();
+            output_path.add_suffix(format!("{stream_id:02}"));
+            output_path.set_extension(self.subtitle_format.as_ext());
+            tokio::fs::write(&output_path, subtitle.to_format(self.subtitle_format)).await?;
+            subtitles.push((*stream_id, output_path));
+        }
+
+        tracing::info!("Merging streams...");
+
+        // segments are removed after appending, so only events from the source are used
+        let mut metadata = self.metadata.clone();
+        if metadata.chapters.is_empty() {
+            metadata.chapters = source_chapters(&successful_streams(&self.segments));
+        }
+
+        let mut tracks: Vec<PathBuf> = tracks.paths.into_values().collect();
+        #[cfg_attr(feature = "ffmpeg", allow(unused_variables))]
+        let can_remux = tracks.len() > 1
+            && tracks
+                .iter()
+                .all(|t| t.extension().is_some_and(|e| e == "ts"));
+
+        // mkvmerge can mux subtitles along with other tracks
+        #[cfg(not(feature = "ffmpeg"))]
+        if !can_remux && !tracks.is_empty() {
+            tracks.extend(subtitles.drain(..).map(|(_, path)| path));
+        }
+        if tracks.is_empty() && subtitles.len() == 1 {
+            tracks.extend(subtitles.drain(..).map(|(_, path)| path));
+        }
+
+        let output_path = if tracks.is_empty() {
+            None
+        } else if tracks.len() == 1 {
+            let track_format = tracks[0].extension().and_then(|e| e.to_str());
+            let output = match track_format {
+                Some(ext) => self
+                    .output_file
+                    .with_replaced_extension(ext, &self.allowed_extensions),
+                None => self.output_file.clone(),
+            };
+            tokio::fs::rename(&tracks[0], &output).await?;
+            metadata.write_sidecar(&output).await?;
+            Some(output)
+        } else {
+            Some(
+                mux_tracks(
+                    tracks,
+                    &self.output_file,
+                    &self.allowed_extensions,
+                    &metadata,
+                )
+                .await?,
+            )
+        };
+
+        // Save remaining subtitles next to the output file
+        for (stream_id, subtitle) in subtitles {
+            let ext = subtitle
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("vtt");
+            let output = self.output_file.with_replaced_extension(
+                &format!("{stream_id:02}.{ext}"),
+                &self.allowed_extensions,
+            );
+            tokio::fs::rename(&subtitle, &output).await?;
+            tracing::info!("Subtitle saved to {}", output.display());
+            report.set_track_path(stream_id, output);
+        }
+
+        if let Some(output) = output_path {
+            tracing::info!(
+                "All finished. Please checkout your files at {}",
+                output.display()
+            );
+            report.set_output(output);
+        }
+        report.measure_output().await;
+
+        if !self.keep_segments {
+            cache.clear().await?;
+            report.cache_cleared = true;
+        }
+
+        Ok(report)
+    }
+}
+
+/// Append ordered segments to the file of their stream.
+async fn append_segments(
+    mut stream: OrderedStream<Option<SendSegment>>,
+    output_file: PathBuf,
+    keep_segments: bool,
+) -> IoriResult<AppendedTracks> {
+    let mut tracks = AppendedTracks::default();
+    let mut writers: HashMap<u64, BufWriter<File>> = HashMap::new();
+
+    while let Some((stream_id, segment)) = stream.next().await {
+        let Some((segment, mut reader, invalidate)) = segment else {
+            continue;
+        };
+
+        let is_first = !writers.contains_key(&stream_id);
+        if is_first {
+            let mut path = output_file.clone();
+            path.add_suffix(format!("{stream_id:02}"));
+            path.set_extension(segment.format.as_ext());
+            writers.insert(stream_id, BufWriter::new(File::create(&path).await?));
+            tracks.paths.insert(stream_id, path);
+        }
+        let writer = writers.get_mut(&stream_id).unwrap();
+
+        match segment.format {
+            SegmentFormat::Mpeg2TS
+                if is_first
+                    && tracks.base_pts.is_none()
+                    && matches!(segment.r#type, SegmentType::Video) =>
+            {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data).await?;
+                tracks.base_pts = mpegts::first_pts(&data);
+                writer.write_all(&data).await?;
+            }
+            SegmentFormat::Mp4 | SegmentFormat::M4a | SegmentFormat::Cmfv | SegmentFormat::Cmfa
+                if !is_first =>
+            {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data).await?;
+                writer.write_all(split_init_segment(&data).1).await?;
+            }
+            _ => {
+                tokio::io::copy(&mut reader, writer).await?;
+            }
+        }
+
+        if !keep_segments {
+            invalidate.await?;
+        }
+    }
+
+    for writer in writers.values_mut() {
+        writer.flush().await?;
+    }
+    Ok(tracks)
+}
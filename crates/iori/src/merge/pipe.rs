@@ -1,13 +1,13 @@
-use super::Merger;
+use super::{concat::ConcatSegment, MergeReport, Merger};
 use crate::{
     cache::CacheSource,
-    error::IoriResult,
+    error::{IoriError, IoriResult},
     util::{ordered_stream::OrderedStream, path::DuplicateOutputFileNamer},
     SegmentInfo, SegmentType,
 };
-use std::{future::Future, path::PathBuf, pin::Pin, process::Stdio};
+use std::{collections::HashMap, future::Future, path::PathBuf, pin::Pin, process::Stdio};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     process::Command,
     sync::mpsc,
     task::JoinHandle,
@@ -24,9 +24,13 @@ type SendSegment = (
 /// If there are any missing segments, it will skip them.
 pub struct PipeMerger {
     recycle: bool,
+    /// Output file, if the segments are piped to a file.
+    output: Option<PathBuf>,
+    segments: Vec<ConcatSegment>,
 
     sender: Option<mpsc::UnboundedSender<(u64, u64, Option<SendSegment>)>>,
-    future: Option<JoinHandle<()>>,
+    /// Piping task, which returns the number of bytes written.
+    future: Option<JoinHandle<IoriResult<u64>>>,
 }
 
 impl PipeMerger {
@@ -42,18 +46,23 @@ impl PipeMerger {
 
         let mut stream: OrderedStream<Option<SendSegment>> = OrderedStream::new(rx);
         let future = tokio::spawn(async move {
+            let mut bytes_written = 0;
             while let Some((_, segment)) = stream.next().await {
                 if let Some((mut reader, _type, invalidate)) = segment {
-                    _ = tokio::io::copy(&mut reader, &mut writer).await;
+                    bytes_written += tokio::io::copy(&mut reader, &mut writer).await?;
                     if recycle {
                         _ = invalidate.await;
                     }
                 }
             }
+            writer.flush().await?;
+            Ok::<_, IoriError>(bytes_written)
         });
 
         Self {
             recycle,
+            output: None,
+            segments: Vec::new(),
 
             sender: Some(tx),
             future: Some(future),
@@ -63,196 +72,170 @@ impl PipeMerger {
     pub fn file(recycle: bool, target_path: PathBuf) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
 
+        let output = Some(target_path.clone());
         let mut stream: OrderedStream<Option<SendSegment>> = OrderedStream::new(rx);
         let future = tokio::spawn(async move {
+            let mut bytes_written = 0;
             let mut namer = DuplicateOutputFileNamer::new(target_path.clone());
-            let mut target = Some(
-                tokio::fs::File::create(&target_path)
-                    .await
-                    .expect("Failed to create file"),
-            );
+            let mut target = Some(tokio::fs::File::create(&target_path).await?);
             while let Some((_, segment)) = stream.next().await {
                 if let Some((mut reader, _type, invalidate)) = segment {
                     if target.is_none() {
-                        let file = tokio::fs::File::create(namer.next_path())
-                            .await
-                            .expect("Failed to create file");
-                        target = Some(file);
+                        target = Some(tokio::fs::File::create(namer.next_path()).await?);
                     }
 
                     if let Some(target) = &mut target {
-                        _ = tokio::io::copy(&mut reader, target).await;
+                        bytes_written += tokio::io::copy(&mut reader, target).await?;
                     }
                     if recycle {
                         _ = invalidate.await;
                     }
-                } else {
-                    target = None;
+                } else if let Some(mut target) = target.take() {
+                    target.flush().await?;
                 }
             }
+            if let Some(mut target) = target {
+                target.flush().await?;
+            }
+            Ok::<_, IoriError>(bytes_written)
         });
 
         Self {
             recycle,
+            output,
+            segments: Vec::new(),
 
             sender: Some(tx),
             future: Some(future),
         }
     }
 
-    pub fn mux(recycle: bool, output: PathBuf, extra_command: Option<String>) -> Self {
+    /// Mux audio and video streams into MPEG-TS while downloading.
+    ///
+    /// Each stream is piped to the muxer as a separate input. Muxing starts after the first
+    /// segment of `streams` streams is downloaded, or all segments are downloaded. Streams
+    /// found after muxing started, and subtitles, are skipped.
+    ///
+    /// If the `ffmpeg` feature is enabled, streams are muxed in process unless
+    /// `extra_command` is given. Otherwise, they are piped to an `ffmpeg` process.
+    pub fn mux(
+        recycle: bool,
+        output: PathBuf,
+        extra_command: Option<String>,
+        streams: usize,
+    ) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
 
-        let mut stream: OrderedStream<Option<SendSegment>> = OrderedStream::new(rx);
-
-        #[cfg(target_os = "windows")]
-        let (mut audio_pipe, audio_receiver) = {
-            let pipe_name = format!(r"\\.\pipe\iori-pipe-mux-audio-{}", rand::random::<u64>());
-            let server = tokio::net::windows::named_pipe::ServerOptions::new()
-                .first_pipe_instance(true)
-                .create(&pipe_name)
-                .unwrap();
-            (server, pipe_name)
-        };
-
-        #[cfg(not(target_os = "windows"))]
-        let (mut audio_pipe, audio_receiver) = {
-            let (audio_pipe, audio_receiver) = tokio::net::unix::pipe::pipe().unwrap();
-            let audio_receiver = audio_receiver.into_nonblocking_fd().unwrap();
-            (audio_pipe, audio_receiver)
-        };
+        // the output is either stdout or specified by the extra command
+        let output_file =
+            (extra_command.is_none() && output.as_os_str() != "-").then(|| output.clone());
 
+        let mut stream: OrderedStream<Option<SendSegment>> = OrderedStream::new(rx);
         let future = tokio::spawn(async move {
-            // TODO: maybe creating a new process might be better
-            let mut video_pipe = tokio::spawn(async move {
-                let mut command = Command::new("ffmpeg");
-                command
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit());
-
-                #[cfg(not(target_os = "windows"))]
-                {
-                    use command_fds::{CommandFdExt, FdMapping};
-                    command
-                        .fd_mappings(vec![FdMapping {
-                            parent_fd: audio_receiver,
-                            child_fd: 3,
-                        }])
-                        .unwrap();
-                }
-
-                command.args(["-y", "-fflags", "+genpts"]); // , "-loglevel", "quiet"
-
-                if extra_command.is_some() {
-                    command.arg("-re");
-                }
-
-                // video input: stdin
-                command.args(["-i", "pipe:0"]);
-                // audio input: mapped fd 3 or named pipe
-                #[cfg(target_os = "windows")]
-                command.args(["-i", &audio_receiver]);
-                #[cfg(not(target_os = "windows"))]
-                command.args(["-i", "pipe:3"]);
-
-                #[rustfmt::skip]
-                command.args([
-                    "-map", "0",
-                    "-map", "1",
-                    "-strict", "unofficial",
-                    "-c", "copy",
-                    "-metadata", &format!(r#"date="{}""#, chrono::Utc::now().to_rfc3339()),
-                    "-ignore_unknown",
-                    "-copy_unknown",
-                ]);
-
-                if let Some(dest) = extra_command.and_then(|s| shlex::split(&s)) {
-                    command.args(dest);
-                } else {
-                    command.args(["-f", "mpegts", "-shortest"]).arg(output);
-                }
-
-                let mut process = command.spawn().unwrap();
-                let stdin = process.stdin.take().unwrap();
-                tokio::spawn(async move {
-                    process.wait().await.unwrap();
-                });
-
-                stdin
-            })
-            .await
-            .unwrap();
-
-            let (video_sender, mut video_receiver) = mpsc::unbounded_channel::<SendSegment>();
-            let video_handle = tokio::spawn(async move {
-                while let Some((mut reader, _, invalidate)) = video_receiver.recv().await {
-                    tokio::io::copy(&mut reader, &mut video_pipe).await.unwrap();
-                    invalidate.await.unwrap();
+            // the muxer needs all inputs to start, so wait for the first segment of each stream
+            let mut stream_ids = Vec::new();
+            let mut pending = Vec::new();
+            while stream_ids.len() < streams {
+                let Some((stream_id, segment)) = stream.next().await else {
+                    break;
+                };
+                let Some(segment) = segment else {
+                    continue;
+                };
+                if !matches!(segment.1, SegmentType::Video | SegmentType::Audio) {
+                    if recycle {
+                        _ = segment.2.await;
+                    }
+                    continue;
                 }
-            });
-
-            let (audio_sender, mut audio_receiver) = mpsc::unbounded_channel::<SendSegment>();
-            let audio_handle = tokio::spawn(async move {
-                #[cfg(target_os = "windows")]
-                audio_pipe.connect().await.unwrap();
-
-                while let Some((mut reader, _, invalidate)) = audio_receiver.recv().await {
-                    tokio::io::copy(&mut reader, &mut audio_pipe).await.unwrap();
-                    invalidate.await.unwrap();
+                if !stream_ids.contains(&stream_id) {
+                    stream_ids.push(stream_id);
                 }
-            });
-
-            while let Some((_, segment)) = stream.next().await {
-                if let Some((reader, r#type, invalidate)) = segment {
-                    match r#type {
-                        SegmentType::Video => {
-                            video_sender.send((reader, r#type, invalidate)).unwrap();
-                        }
-                        SegmentType::Audio => {
-                            audio_sender.send((reader, r#type, invalidate)).unwrap();
-                        }
-                        SegmentType::Subtitle | SegmentType::Unknown => {
-                            if recycle {
-                                _ = invalidate.await;
-                            }
+                pending.push((stream_id, segment));
+            }
+            if stream_ids.is_empty() {
+                return Ok(0);
+            }
+            stream_ids.sort();
+
+            let (writers, muxer) = spawn_muxer(stream_ids.len(), output, extra_command)?;
+
+            let mut senders = HashMap::new();
+            let mut handles = Vec::new();
+            for (stream_id, mut writer) in stream_ids.into_iter().zip(writers) {
+                let (sender, mut receiver) = mpsc::unbounded_channel::<SendSegment>();
+                senders.insert(stream_id, sender);
+                handles.push(tokio::spawn(async move {
+                    let mut bytes_written = 0;
+                    while let Some((mut reader, _, invalidate)) = receiver.recv().await {
+                        bytes_written += tokio::io::copy(&mut reader, &mut writer).await?;
+                        if recycle {
+                            _ = invalidate.await;
                         }
                     }
+                    // close the input of the muxer
+                    writer.shutdown().await?;
+                    Ok::<_, IoriError>(bytes_written)
+                }));
+            }
+
+            for (stream_id, segment) in pending {
+                dispatch(&senders, stream_id, segment, recycle).await;
+            }
+            while let Some((stream_id, segment)) = stream.next().await {
+                if let Some(segment) = segment {
+                    dispatch(&senders, stream_id, segment, recycle).await;
                 }
             }
 
-            tracing::debug!("Waiting for video handler...");
-            drop(video_sender);
-            video_handle.await.unwrap();
+            tracing::debug!("Waiting for stream handlers...");
+            drop(senders);
+            let mut bytes_written = 0;
+            for handle in handles {
+                bytes_written += handle.await??;
+            }
+
+            tracing::debug!("Waiting for muxer...");
+            muxer.await??;
 
-            tracing::debug!("Waiting for audio handler...");
-            drop(audio_sender);
-            audio_handle.await.unwrap();
+            Ok::<_, IoriError>(bytes_written)
         });
 
         Self {
             recycle,
+            output: output_file,
+            segments: Vec::new(),
 
             sender: Some(tx),
             future: Some(future),
         }
     }
 
+    /// Whether each segment is removed from the cache once it is piped.
+    pub(crate) fn invalidates_segments(&self) -> bool {
+        self.recycle
+    }
+
     fn send(&self, message: (u64, u64, Option<SendSegment>)) {
         if let Some(sender) = &self.sender {
-            sender.send(message).expect("Failed to send segment");
+            // The piping task only stops early on error, which is returned by `finish`.
+            _ = sender.send(message);
         }
     }
 }
 
 impl Merger for PipeMerger {
-    type Result = ();
+    type Result = MergeReport;
 
     async fn update(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
         let stream_id = segment.stream_id;
         let sequence = segment.sequence;
         let r#type = segment.r#type;
         let reader = cache.open_reader(&segment).await?;
+        self.segments.push(ConcatSegment {
+            segment: segment.clone(),
+            success: true,
+        });
         let invalidate = async move { cache.invalidate(&segment).await };
 
         self.send((
@@ -269,6 +252,10 @@ impl Merger for PipeMerger {
         cache.invalidate(&segment).await?;
 
         self.send((stream_id, segment.sequence, None));
+        self.segments.push(ConcatSegment {
+            segment,
+            success: false,
+        });
 
         Ok(())
     }
@@ -277,16 +264,159 @@ impl Merger for PipeMerger {
         // drop the sender so that the future can finish
         drop(self.sender.take());
 
-        self.future
-            .take()
-            .unwrap()
-            .await
-            .expect("Failed to join pipe");
+        let Some(future) = self.future.take() else {
+            return Ok(MergeReport::new(&self.segments));
+        };
+        let bytes_written = future.await??;
+
+        let mut report = MergeReport::new(&self.segments);
+        report.bytes_written = bytes_written;
+        if let Some(output) = &self.output {
+            report.set_output(output.clone());
+        }
 
         if self.recycle {
             cache.clear().await?;
+            report.cache_cleared = true;
         }
 
-        Ok(())
+        Ok(report)
+    }
+}
+
+/// Send a segment to the input of its stream, or skip it if the stream is not muxed.
+async fn dispatch(
+    senders: &HashMap<u64, mpsc::UnboundedSender<SendSegment>>,
+    stream_id: u64,
+    segment: SendSegment,
+    recycle: bool,
+) {
+    let sender = senders
+        .get(&stream_id)
+        .filter(|_| matches!(segment.1, SegmentType::Video | SegmentType::Audio));
+    match sender {
+        Some(sender) => {
+            _ = sender.send(segment);
+        }
+        None => {
+            if recycle {
+                _ = segment.2.await;
+            }
+        }
+    }
+}
+
+type TrackWriter = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Start muxing `tracks` inputs, returning a writer for each input and the muxing task.
+fn spawn_muxer(
+    tracks: usize,
+    output: PathBuf,
+    extra_command: Option<String>,
+) -> IoriResult<(Vec<TrackWriter>, JoinHandle<IoriResult<()>>)> {
+    #[cfg(feature = "ffmpeg")]
+    if extra_command.is_none() {
+        let (writers, inputs): (Vec<_>, Vec<_>) = (0..tracks)
+            .map(|_| super::ffmpeg::ChannelWriter::new())
+            .unzip();
+        let muxer = tokio::task::spawn_blocking(move || {
+            if output.as_os_str() == "-" {
+                super::ffmpeg::ffmpeg_mux(inputs, std::io::stdout())
+            } else {
+                super::ffmpeg::ffmpeg_mux(inputs, std::fs::File::create(&output)?)
+            }
+        });
+        let writers = writers
+            .into_iter()
+            .map(|writer| Box::pin(writer) as TrackWriter)
+            .collect();
+        return Ok((writers, muxer));
+    }
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+    command.args(["-y", "-fflags", "+genpts"]); // , "-loglevel", "quiet"
+
+    let mut writers: Vec<TrackWriter> = Vec::with_capacity(tracks);
+    #[cfg(not(target_os = "windows"))]
+    let mut fd_mappings = Vec::with_capacity(tracks);
+    for index in 0..tracks {
+        // named pipe on windows, which is connected before writing
+        #[cfg(target_os = "windows")]
+        let input = {
+            let pipe_name = format!(r"\\.\pipe\iori-pipe-mux-{index}-{}", rand::random::<u64>());
+            let mut server = tokio::net::windows::named_pipe::ServerOptions::new()
+                .first_pipe_instance(true)
+                .create(&pipe_name)?;
+            let (writer, mut reader) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(async move {
+                if server.connect().await.is_ok() {
+                    _ = tokio::io::copy(&mut reader, &mut server).await;
+                }
+            });
+            writers.push(Box::pin(writer));
+            pipe_name
+        };
+
+        // mapped fd from 3 on other platforms
+        #[cfg(not(target_os = "windows"))]
+        let input = {
+            use command_fds::FdMapping;
+
+            let child_fd = 3 + index as i32;
+            let (writer, receiver) = tokio::net::unix::pipe::pipe()?;
+            fd_mappings.push(FdMapping {
+                parent_fd: receiver.into_nonblocking_fd()?,
+                child_fd,
+            });
+            writers.push(Box::pin(writer));
+            format!("pipe:{child_fd}")
+        };
+
+        if extra_command.is_some() {
+            command.arg("-re");
+        }
+        command.args(["-i", &input]);
     }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        use command_fds::CommandFdExt;
+        command
+            .fd_mappings(fd_mappings)
+            .map_err(std::io::Error::other)?;
+    }
+
+    for index in 0..tracks {
+        command.args(["-map", &index.to_string()]);
+    }
+    #[rustfmt::skip]
+    command.args([
+        "-strict", "unofficial",
+        "-c", "copy",
+        "-metadata", &format!(r#"date="{}""#, chrono::Utc::now().to_rfc3339()),
+        "-ignore_unknown",
+        "-copy_unknown",
+    ]);
+
+    if let Some(dest) = extra_command.and_then(|s| shlex::split(&s)) {
+        command.args(dest);
+    } else {
+        command.args(["-f", "mpegts", "-shortest"]).arg(output);
+    }
+
+    let mut process = command.spawn()?;
+    let muxer = tokio::spawn(async move {
+        let status = process.wait().await?;
+        if !status.success() {
+            return Err(IoriError::IOError(std::io::Error::other(format!(
+                "ffmpeg exited with {status}"
+            ))));
+        }
+        Ok::<_, IoriError>(())
+    });
+    Ok((writers, muxer))
 }
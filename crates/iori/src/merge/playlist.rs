@@ -0,0 +1,482 @@
+use std::{
+    fmt::{self, Display, Write as _},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use tokio::io::AsyncReadExt;
+
+use super::{FailurePolicy, MergeReport, Merger, concat::ConcatSegment};
+use crate::{
+    IoriResult, SegmentFormat, SegmentInfo, SegmentType,
+    cache::{CacheSource, CacheSourceReader},
+    util::path::IoriPathExt,
+};
+
+/// Duration of segments without a known duration, in seconds.
+const FALLBACK_DURATION: f64 = 6.;
+/// Bandwidth advertised in master playlists. The real bitrate is not measured, and players
+/// only use it to choose between variants.
+const BANDWIDTH: u64 = 1_000_000;
+
+/// PlaylistMerger writes an HLS playlist referencing downloaded segments in the cache,
+/// instead of merging them.
+///
+/// Segments are kept in the cache, so the playlist is playable right after downloading
+/// and the segments can still be merged later. Fragmented MP4 segments are referenced
+/// with `EXT-X-BYTERANGE` after their initialization segment, which is referenced by
+/// `EXT-X-MAP`. Missing segments are skipped with `EXT-X-DISCONTINUITY`.
+///
+/// If there are multiple streams, a media playlist is written for each stream next to the
+/// master playlist.
+///
+/// The cache must store segments as local files, like
+/// [FileCacheSource](crate::cache::file::FileCacheSource). Otherwise writing the playlist
+/// is skipped.
+#[derive(Default)]
+pub struct PlaylistMerger {
+    segments: Vec<ConcatSegment>,
+
+    /// Path of the playlist. Defaults to `index.m3u8` next to the cached segments.
+    output_file: Option<PathBuf>,
+}
+
+impl PlaylistMerger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_output_file(mut self, output_file: PathBuf) -> Self {
+        self.output_file = Some(output_file);
+        self
+    }
+}
+
+impl Merger for PlaylistMerger {
+    type Result = MergeReport;
+
+    async fn update(&mut self, segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
+        self.segments.push(ConcatSegment {
+            segment,
+            success: true,
+        });
+        Ok(())
+    }
+
+    async fn fail(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        cache.invalidate(&segment).await?;
+        self.segments.push(ConcatSegment {
+            segment,
+            success: false,
+        });
+        Ok(())
+    }
+
+    async fn finish(&mut self, cache: impl CacheSource) -> IoriResult<Self::Result> {
+        let mut report = MergeReport::new(&self.segments);
+        report.failure_policy = FailurePolicy::MergeWithGaps;
+        report.failure_policy.should_merge(&report)?;
+
+        self.segments
+            .sort_by_key(|s| (s.segment.stream_id, s.segment.sequence));
+
+        // locate segments first, as the playlist is written next to them by default
+        let mut files = Vec::new();
+        for ConcatSegment { segment, success } in self.segments.iter() {
+            let path = if *success {
+                cache.segment_path(segment).await
+            } else {
+                None
+            };
+            if *success && !path.as_ref().is_some_and(|p| p.is_absolute()) {
+                tracing::warn!("Segments are not stored as local files. Skip writing playlist.");
+                report.skipped = true;
+                return Ok(report);
+            }
+            files.push(path);
+        }
+
+        let Some(output_file) = self.output_file.clone().or_else(|| {
+            files
+                .iter()
+                .flatten()
+                .next()
+                .map(|p| p.with_file_name("index.m3u8"))
+        }) else {
+            tracing::warn!("No segment is downloaded. Skip writing playlist.");
+            report.skipped = true;
+            return Ok(report);
+        };
+        let output_dir = output_file.parent().unwrap_or(Path::new(""));
+
+        let mut streams = Vec::new();
+        let mut index = 0;
+        for stream in self
+            .segments
+            .chunk_by(|a, b| a.segment.stream_id == b.segment.stream_id)
+        {
+            let stream_files = &files[index..index + stream.len()];
+            index += stream.len();
+
+            let playlist = media_playlist(stream, stream_files, output_dir, &cache).await?;
+            streams.push((&stream[0].segment, playlist));
+        }
+
+        if let [(_, playlist)] = streams.as_slice() {
+            tokio::fs::write(&output_file, playlist.to_string()).await?;
+        } else {
+            let mut master = Vec::new();
+            for (segment, playlist) in streams {
+                let mut path = output_file.clone();
+                path.add_suffix(format!("{:02}", segment.stream_id));
+                path.set_extension("m3u8");
+                tokio::fs::write(&path, playlist.to_string()).await?;
+
+                master.push(PlaylistStream {
+                    stream_id: segment.stream_id,
+                    r#type: segment.r#type,
+                    uri: uri(&path, output_dir),
+                });
+                report.set_track_path(segment.stream_id, path);
+            }
+            let master = master_playlist(&master).unwrap_or_default();
+            tokio::fs::write(&output_file, master).await?;
+        }
+
+        tracing::info!("Playlist written to {}", output_file.display());
+        report.set_output(output_file);
+        report.measure_output().await;
+
+        Ok(report)
+    }
+}
+
+/// Generate the media playlist of a stream, from its segments sorted by sequence and the
+/// paths of downloaded segments.
+async fn media_playlist(
+    segments: &[ConcatSegment],
+    files: &[Option<PathBuf>],
+    output_dir: &Path,
+    cache: &impl CacheSource,
+) -> IoriResult<MediaPlaylist> {
+    let mut playlist = MediaPlaylist {
+        playlist_type: Some("VOD"),
+        ended: true,
+        ..Default::default()
+    };
+
+    let mut gap = false;
+    // content and `EXT-X-MAP` attributes of the last initialization segment
+    let mut last_init: Option<(Vec<u8>, String)> = None;
+    for (ConcatSegment { segment, .. }, path) in segments.iter().zip(files) {
+        let Some(path) = path else {
+            gap = true;
+            continue;
+        };
+        let uri = uri(path, output_dir);
+
+        let mut entry = PlaylistSegment {
+            uri: uri.clone(),
+            duration: segment.duration,
+            byte_range: None,
+            map: None,
+            discontinuity: gap && !playlist.segments.is_empty(),
+        };
+        gap = false;
+
+        if is_fragmented_mp4(&segment.format) {
+            let mut reader = cache.open_reader(segment).await?;
+            let init = read_init_segment(&mut reader).await?;
+            if !init.is_empty() {
+                let size = tokio::fs::metadata(path).await?.len();
+                let length = init.len() as u64;
+                entry.byte_range = Some((size - length, length));
+
+                match &last_init {
+                    Some((data, map)) if *data == init => entry.map = Some(map.clone()),
+                    _ => {
+                        // a new initialization segment usually starts a new period
+                        entry.discontinuity |= last_init.is_some();
+                        let map = format!("URI=\"{uri}\",BYTERANGE=\"{length}@0\"");
+                        entry.map = Some(map.clone());
+                        last_init = Some((init, map));
+                    }
+                }
+            }
+        }
+
+        playlist.segments.push(entry);
+    }
+
+    Ok(playlist)
+}
+
+/// URI of a file in a playlist in `output_dir`.
+fn uri(path: &Path, output_dir: &Path) -> String {
+    path.strip_prefix(output_dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Read the initialization segment at the start of a fragmented MP4 segment, which
+/// consists of `ftyp` and `moov` boxes, without reading the fragments after it.
+async fn read_init_segment(reader: &mut CacheSourceReader) -> IoriResult<Vec<u8>> {
+    let mut init = Vec::new();
+    loop {
+        let mut header = [0; 8];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        // boxes with a 64-bit size or extending to the end are never small enough to be
+        // part of an initialization segment
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        if !matches!(&header[4..], b"ftyp" | b"moov") || size < 8 {
+            break;
+        }
+
+        let start = init.len();
+        init.extend_from_slice(&header);
+        init.resize(start + size, 0);
+        reader.read_exact(&mut init[start + 8..]).await?;
+    }
+    Ok(init)
+}
+
+pub(super) fn is_fragmented_mp4(format: &SegmentFormat) -> bool {
+    matches!(
+        format,
+        SegmentFormat::Mp4 | SegmentFormat::M4a | SegmentFormat::Cmfv | SegmentFormat::Cmfa
+    )
+}
+
+/// A segment in an HLS media playlist.
+pub(super) struct PlaylistSegment {
+    pub uri: String,
+    /// Duration in seconds, if known.
+    pub duration: Option<f64>,
+    /// Length and offset of the segment in the file.
+    pub byte_range: Option<(u64, u64)>,
+    /// Attributes of `EXT-X-MAP`, which is written when it changes.
+    pub map: Option<String>,
+    /// Whether the segment follows an `EXT-X-DISCONTINUITY`.
+    ///
+    /// It is not written for the first segment, which should have been counted by
+    /// [MediaPlaylist::discontinuity_sequence].
+    pub discontinuity: bool,
+}
+
+/// An HLS media playlist.
+#[derive(Default)]
+pub(super) struct MediaPlaylist {
+    pub segments: Vec<PlaylistSegment>,
+    pub media_sequence: u64,
+    pub discontinuity_sequence: u64,
+    /// `EVENT` or `VOD`, or `None` for a sliding window playlist.
+    pub playlist_type: Option<&'static str>,
+    /// Whether `EXT-X-ENDLIST` is written.
+    pub ended: bool,
+}
+
+impl Display for MediaPlaylist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target_duration = self
+            .segments
+            .iter()
+            .map(|s| s.duration.unwrap_or(FALLBACK_DURATION))
+            .fold(1., f64::max)
+            .ceil();
+
+        writeln!(f, "#EXTM3U")?;
+        writeln!(f, "#EXT-X-VERSION:6")?;
+        writeln!(f, "#EXT-X-TARGETDURATION:{target_duration}")?;
+        writeln!(f, "#EXT-X-MEDIA-SEQUENCE:{}", self.media_sequence)?;
+        writeln!(
+            f,
+            "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+            self.discontinuity_sequence
+        )?;
+        if let Some(playlist_type) = self.playlist_type {
+            writeln!(f, "#EXT-X-PLAYLIST-TYPE:{playlist_type}")?;
+        }
+
+        let mut last_map = None;
+        for (index, segment) in self.segments.iter().enumerate() {
+            if segment.discontinuity && index > 0 {
+                writeln!(f, "#EXT-X-DISCONTINUITY")?;
+            }
+            if let Some(map) = segment.map.as_ref().filter(|&map| last_map != Some(map)) {
+                writeln!(f, "#EXT-X-MAP:{map}")?;
+            }
+            last_map = segment.map.as_ref();
+
+            let duration = segment.duration.unwrap_or(FALLBACK_DURATION);
+            writeln!(f, "#EXTINF:{duration:.3},")?;
+            if let Some((length, offset)) = segment.byte_range {
+                writeln!(f, "#EXT-X-BYTERANGE:{length}@{offset}")?;
+            }
+            writeln!(f, "{}", segment.uri)?;
+        }
+
+        if self.ended {
+            writeln!(f, "#EXT-X-ENDLIST")?;
+        }
+        Ok(())
+    }
+}
+
+/// A stream in an HLS master playlist.
+pub(super) struct PlaylistStream {
+    pub stream_id: u64,
+    pub r#type: SegmentType,
+    /// URI of the media playlist.
+    pub uri: String,
+}
+
+/// Generate the master playlist of streams, or `None` if there is only one stream.
+///
+/// Video streams are variants, while audio and subtitle streams are renditions of them.
+/// If there is no video stream, audio streams are variants instead.
+pub(super) fn master_playlist(streams: &[PlaylistStream]) -> Option<String> {
+    if streams.len() < 2 {
+        return None;
+    }
+
+    let has_video = streams
+        .iter()
+        .any(|s| matches!(s.r#type, SegmentType::Video));
+    let is_variant = |stream: &&PlaylistStream| {
+        matches!(stream.r#type, SegmentType::Video | SegmentType::Unknown)
+            || (!has_video && matches!(stream.r#type, SegmentType::Audio))
+    };
+
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:6\n");
+    let mut groups = String::new();
+    for stream in streams.iter().filter(|s| !is_variant(s)) {
+        let (kind, group) = match stream.r#type {
+            SegmentType::Audio => ("AUDIO", "audio"),
+            _ => ("SUBTITLES", "subs"),
+        };
+        // the first rendition of each group is the default
+        let default = !groups.contains(kind);
+        writeln!(
+            playlist,
+            "#EXT-X-MEDIA:TYPE={kind},GROUP-ID=\"{group}\",NAME=\"{group} {}\",DEFAULT={},AUTOSELECT=YES,URI=\"{}\"",
+            stream.stream_id,
+            if default { "YES" } else { "NO" },
+            stream.uri
+        )
+        .unwrap();
+        if default {
+            write!(groups, ",{kind}=\"{group}\"").unwrap();
+        }
+    }
+
+    for stream in streams.iter().filter(is_variant) {
+        writeln!(playlist, "#EXT-X-STREAM-INF:BANDWIDTH={BANDWIDTH}{groups}").unwrap();
+        writeln!(playlist, "{}", stream.uri).unwrap();
+    }
+    Some(playlist)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::{cache::file::FileCacheSource, util::mp4::write_box};
+
+    #[test]
+    fn test_master_playlist() {
+        let stream = |stream_id, r#type| PlaylistStream {
+            stream_id,
+            r#type,
+            uri: format!("{stream_id}.m3u8"),
+        };
+        assert_eq!(master_playlist(&[stream(0, SegmentType::Video)]), None);
+
+        let streams = [
+            stream(0, SegmentType::Video),
+            stream(1, SegmentType::Audio),
+            stream(2, SegmentType::Subtitle),
+            stream(3, SegmentType::Audio),
+        ];
+        assert_eq!(
+            master_playlist(&streams).unwrap(),
+            "#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"audio 1\",DEFAULT=YES,AUTOSELECT=YES,URI=\"1.m3u8\"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"subs 2\",DEFAULT=YES,AUTOSELECT=YES,URI=\"2.m3u8\"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"audio 3\",DEFAULT=NO,AUTOSELECT=YES,URI=\"3.m3u8\"
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,AUDIO=\"audio\",SUBTITLES=\"subs\"
+0.m3u8
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_playlist_merger() -> IoriResult<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = Arc::new(FileCacheSource::new(dir.path().join("cache"))?);
+
+        let mut init = Vec::new();
+        write_box(&mut init, b"ftyp", b"isom");
+        write_box(&mut init, b"moov", &[0; 8]);
+        let mut fragment = Vec::new();
+        write_box(&mut fragment, b"moof", &[0; 4]);
+        write_box(&mut fragment, b"mdat", &[0; 16]);
+
+        let mut merger = PlaylistMerger::new();
+        for sequence in 0..3 {
+            let segment = SegmentInfo {
+                sequence,
+                file_name: format!("{sequence}.m4s"),
+                format: SegmentFormat::Mp4,
+                duration: Some(2.),
+                ..Default::default()
+            };
+            if sequence == 1 {
+                merger.fail(segment, cache.clone()).await?;
+                continue;
+            }
+
+            let mut writer = cache.open_writer(&segment).await?.unwrap();
+            writer.write_all(&init).await?;
+            writer.write_all(&fragment).await?;
+            writer.shutdown().await?;
+            drop(writer);
+            merger.update(segment, cache.clone()).await?;
+        }
+
+        let report = merger.finish(cache.clone()).await?;
+        let output = dir.path().join("cache").join("index.m3u8");
+        assert_eq!(report.output.as_ref(), Some(&output));
+        assert!(!report.cache_cleared);
+        assert_eq!(
+            tokio::fs::read_to_string(output).await?,
+            "#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-DISCONTINUITY-SEQUENCE:0
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-MAP:URI=\"00_000000_0.m4s\",BYTERANGE=\"28@0\"
+#EXTINF:2.000,
+#EXT-X-BYTERANGE:36@28
+00_000000_0.m4s
+#EXT-X-DISCONTINUITY
+#EXTINF:2.000,
+#EXT-X-BYTERANGE:36@28
+00_000002_2.m4s
+#EXT-X-ENDLIST
+"
+        );
+
+        Ok(())
+    }
+}
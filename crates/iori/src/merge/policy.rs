@@ -0,0 +1,113 @@
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use super::MergeReport;
+use crate::{IoriError, IoriResult};
+
+/// What to do when some segments failed to download.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Skip merging and keep downloaded segments in the cache.
+    #[default]
+    Skip,
+    /// Merge downloaded segments into a single output, leaving gaps where segments are missing.
+    MergeWithGaps,
+    /// Split the output into parts at gaps.
+    Split,
+    /// Merge with gaps, or fail if more than the given percentage of segments is missing.
+    MaxMissing(f64),
+}
+
+impl FailurePolicy {
+    /// Decide whether to merge with the failed segments in the report.
+    ///
+    /// Returns `false` if merging should be skipped.
+    pub(crate) fn should_merge(&self, report: &MergeReport) -> IoriResult<bool> {
+        let failed = report.failed_segments.len();
+        if failed == 0 {
+            return Ok(true);
+        }
+
+        let missing = failed as f64 / (failed + report.downloaded_segments) as f64 * 100.;
+        match self {
+            Self::Skip => {
+                tracing::warn!("Some segments failed to download. Skipping merging.");
+                return Ok(false);
+            }
+            Self::MaxMissing(limit) if missing > *limit => {
+                return Err(IoriError::TooManyMissingSegments {
+                    missing,
+                    limit: *limit,
+                });
+            }
+            _ => {}
+        }
+
+        tracing::warn!("{failed} segments ({missing:.2}%) failed to download. Merging with gaps:");
+        for gap in report.gaps.iter() {
+            match gap.start {
+                Some(start) => tracing::warn!(
+                    "  - Stream {}: {} segment(s) at {start:.3}s",
+                    gap.stream_id,
+                    gap.segments
+                ),
+                None => tracing::warn!(
+                    "  - Stream {}: {} segment(s) from sequence {}",
+                    gap.stream_id,
+                    gap.segments,
+                    gap.sequence
+                ),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl FromStr for FailurePolicy {
+    type Err = IoriError;
+
+    /// Parse `skip`, `gaps`, `split`, or a percentage like `5%` for [FailurePolicy::MaxMissing].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "gaps" | "merge-with-gaps" => Ok(Self::MergeWithGaps),
+            "split" => Ok(Self::Split),
+            value => value
+                .trim_end_matches('%')
+                .parse()
+                .ok()
+                .filter(|limit: &f64| (0. ..=100.).contains(limit))
+                .map(Self::MaxMissing)
+                .ok_or_else(|| IoriError::InvalidFailurePolicy(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_failure_policy() {
+        assert_eq!(
+            "skip".parse::<FailurePolicy>().unwrap(),
+            FailurePolicy::Skip
+        );
+        assert_eq!(
+            "gaps".parse::<FailurePolicy>().unwrap(),
+            FailurePolicy::MergeWithGaps
+        );
+        assert_eq!(
+            "Split".parse::<FailurePolicy>().unwrap(),
+            FailurePolicy::Split
+        );
+        assert_eq!(
+            "2.5%".parse::<FailurePolicy>().unwrap(),
+            FailurePolicy::MaxMissing(2.5)
+        );
+        assert!("150%".parse::<FailurePolicy>().is_err());
+        assert!("unknown".parse::<FailurePolicy>().is_err());
+    }
+}
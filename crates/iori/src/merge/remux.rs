@@ -0,0 +1,738 @@
+//! Remux MPEG-TS into MP4 without external tools.
+//!
+//! Elementary streams are demuxed from PES packets and written as MP4 samples right away.
+//! Sample tables are collected in memory and written in a `moov` box after `mdat`.
+//!
+//! Supported codecs are H.264, H.265, AAC in ADTS and AC-3. Other streams are skipped.
+mod codec;
+
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use codec::{AudioCodec, AudioConfig, VideoCodec, VideoConfig};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+
+use super::{
+    FailurePolicy, MergeReport, Merger,
+    auto::read_segment,
+    chapter::{source_chapters, successful_streams},
+    concat::ConcatSegment,
+    fmp4::SampleTable,
+};
+use crate::{
+    IoriError, IoriResult, SegmentFormat, SegmentInfo,
+    cache::CacheSource,
+    metadata::Metadata,
+    util::{
+        mp4::{FragmentSample, write_box, write_full_box},
+        mpegts::{PesPacket, StreamType, TsDemuxer},
+        path::IoriPathExt,
+    },
+};
+
+/// RemuxMerger remuxes MPEG-TS segments into a single MP4 file after all segments are downloaded.
+///
+/// All streams are remuxed into the same file. Streams in other formats are skipped, and
+/// merging fails if no MPEG-TS stream with a supported track is found.
+/// If there are any missing segments, the merge will be skipped by default.
+/// This can be changed with [RemuxMerger::with_failure_policy]. The output is never split,
+/// so [FailurePolicy::Split] merges with gaps.
+pub struct RemuxMerger {
+    segments: HashMap<u64, Vec<ConcatSegment>>,
+
+    /// Keep downloaded segments after merging.
+    keep_segments: bool,
+
+    /// What to do when some segments failed to download.
+    failure_policy: FailurePolicy,
+
+    /// Final output file path. It may not have an extension.
+    output_file: PathBuf,
+    /// Metadata written into the output file.
+    metadata: Metadata,
+}
+
+impl RemuxMerger {
+    pub fn new(output_file: PathBuf, keep_segments: bool) -> Self {
+        Self {
+            segments: HashMap::new(),
+            keep_segments,
+            failure_policy: FailurePolicy::default(),
+            output_file,
+            metadata: Metadata::default(),
+        }
+    }
+
+    pub fn with_failure_policy(mut self, failure_policy: FailurePolicy) -> Self {
+        self.failure_policy = failure_policy;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+impl Merger for RemuxMerger {
+    type Result = MergeReport;
+
+    async fn update(&mut self, segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
+        self.segments
+            .entry(segment.stream_id)
+            .or_default()
+            .push(ConcatSegment {
+                segment,
+                success: true,
+            });
+        Ok(())
+    }
+
+    async fn fail(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        cache.invalidate(&segment).await?;
+        self.segments
+            .entry(segment.stream_id)
+            .or_default()
+            .push(ConcatSegment {
+                segment,
+                success: false,
+            });
+        Ok(())
+    }
+
+    async fn finish(&mut self, cache: impl CacheSource) -> IoriResult<Self::Result> {
+        tracing::info!("Remuxing chunks...");
+        let mut report = MergeReport::new(self.segments.values().flatten());
+        report.failure_policy = self.failure_policy;
+
+        if !self.failure_policy.should_merge(&report)? {
+            if let Some(location) = cache.location_hint() {
+                tracing::warn!("You can find the downloaded segments at {location}");
+            }
+            report.skipped = true;
+            return Ok(report);
+        }
+
+        let output = self
+            .output_file
+            .with_replaced_extension("mp4", &["mp4", "mkv", "ts"]);
+        let mut metadata = self.metadata.clone();
+        if metadata.chapters.is_empty() {
+            metadata.chapters =
+                source_chapters(&successful_streams(self.segments.values().flatten()));
+        }
+
+        let mut stream_ids: Vec<_> = self.segments.keys().copied().collect();
+        stream_ids.sort();
+        let mut streams = Vec::new();
+        for stream_id in stream_ids {
+            let mut segments: Vec<_> = self.segments[&stream_id]
+                .iter()
+                .filter(|s| s.success)
+                .map(|s| &s.segment)
+                .collect();
+            if segments.is_empty() {
+                continue;
+            }
+            segments.sort_by(|a, b| a.sequence.cmp(&b.sequence));
+
+            if !segments
+                .iter()
+                .all(|s| matches!(s.format, SegmentFormat::Mpeg2TS))
+            {
+                tracing::warn!("Stream {stream_id} is not in MPEG-TS. Skipping.");
+                continue;
+            }
+            streams.push((stream_id, segments));
+        }
+        if streams.is_empty() {
+            if let Some(location) = cache.location_hint() {
+                tracing::warn!("You can find the downloaded segments at {location}");
+            }
+            return Err(IoriError::Remuxing("no MPEG-TS stream found".to_string()));
+        }
+
+        let mut remuxer = Mp4Remuxer::create(&output).await?.with_metadata(&metadata);
+        for (stream_id, segments) in streams {
+            let mut stream = TsStream::default();
+            for segment in segments {
+                let data = read_segment(segment, &cache).await?;
+                remuxer.push(&mut stream, &data).await?;
+            }
+            remuxer.end(&mut stream).await?;
+            report.set_track_path(stream_id, output.clone());
+        }
+        if remuxer.tracks.is_empty() {
+            drop(remuxer);
+            tokio::fs::remove_file(&output).await?;
+            return Err(IoriError::Remuxing(
+                "no supported track found in MPEG-TS streams".to_string(),
+            ));
+        }
+        remuxer.finish().await?;
+        report.output = Some(output.clone());
+        report.measure_output().await;
+
+        if !self.keep_segments {
+            tracing::info!("End of merging.");
+            tracing::info!("Starting cleaning temporary files.");
+            cache.clear().await?;
+            report.cache_cleared = true;
+        }
+
+        tracing::info!(
+            "All finished. Please checkout your files at {}",
+            output.display()
+        );
+        Ok(report)
+    }
+}
+
+/// Remux MPEG-TS files into a single MP4 file.
+#[cfg_attr(feature = "ffmpeg", allow(unused))]
+pub(crate) async fn remux_ts_files<O>(
+    tracks: &[PathBuf],
+    output: O,
+    metadata: &Metadata,
+) -> IoriResult<()>
+where
+    O: AsRef<Path>,
+{
+    tracing::debug!("Remuxing MPEG-TS tracks...");
+
+    let mut remuxer = Mp4Remuxer::create(output).await?.with_metadata(metadata);
+    let mut buffer = vec![0; 1024 * 1024];
+    for track in tracks {
+        let mut file = File::open(track).await?;
+        let mut stream = TsStream::default();
+        loop {
+            let size = file.read(&mut buffer).await?;
+            if size == 0 {
+                break;
+            }
+            remuxer.push(&mut stream, &buffer[..size]).await?;
+        }
+        remuxer.end(&mut stream).await?;
+    }
+    remuxer.finish().await
+}
+
+/// Demuxing state of a transport stream.
+#[derive(Default)]
+struct TsStream {
+    demuxer: TsDemuxer,
+    /// Track index of each PID, or `None` if the stream is not supported
+    tracks: HashMap<u16, Option<usize>>,
+}
+
+enum TrackKind {
+    Video {
+        codec: VideoCodec,
+        config: VideoConfig,
+    },
+    Audio {
+        codec: AudioCodec,
+        config: Option<AudioConfig>,
+        /// Incomplete audio frame of the previous PES packet
+        pending: Vec<u8>,
+    },
+}
+
+struct Track {
+    kind: TrackKind,
+    /// 90kHz for video, and the sample rate for audio
+    timescale: u32,
+    table: SampleTable,
+    /// Presentation time of the first sample in 90kHz units
+    start_pts: Option<u64>,
+    /// Last timestamp of the track for unwrapping 33-bit timestamps
+    last_timestamp: Option<u64>,
+}
+
+impl Track {
+    fn new(kind: TrackKind) -> Self {
+        Self {
+            kind,
+            timescale: 90000,
+            table: SampleTable::default(),
+            start_pts: None,
+            last_timestamp: None,
+        }
+    }
+}
+
+const TIMESTAMP_WRAP: u64 = 1 << 33;
+const MOVIE_TIMESCALE: u64 = 1000;
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Write MP4 samples from transport streams.
+struct Mp4Remuxer {
+    output: BufWriter<File>,
+    /// Current offset in the output file
+    position: u64,
+    /// Offset of the `mdat` box, whose size is written when finished
+    mdat_offset: u64,
+    tracks: Vec<Track>,
+    /// Track of the last written sample. A new chunk starts when the track changes.
+    last_track: Option<usize>,
+    /// Last timestamp of any track, which is the reference to unwrap the first timestamp of
+    /// a new track
+    last_timestamp: Option<u64>,
+    /// `udta` box with metadata, appended to `moov`
+    udta: Vec<u8>,
+}
+
+impl Mp4Remuxer {
+    async fn create(path: impl AsRef<Path>) -> IoriResult<Self> {
+        let mut output = BufWriter::new(File::create(path.as_ref()).await?);
+
+        let mut ftyp = Vec::new();
+        let mut brands = b"isom".to_vec();
+        brands.extend_from_slice(&0x200u32.to_be_bytes());
+        brands.extend_from_slice(b"isomiso2avc1mp41");
+        write_box(&mut ftyp, b"ftyp", &brands);
+        output.write_all(&ftyp).await?;
+
+        // mdat with a 64-bit largesize, which is filled when finished
+        let mdat_offset = ftyp.len() as u64;
+        output.write_all(&1u32.to_be_bytes()).await?;
+        output.write_all(b"mdat").await?;
+        output.write_all(&0u64.to_be_bytes()).await?;
+
+        Ok(Self {
+            output,
+            position: mdat_offset + 16,
+            mdat_offset,
+            tracks: Vec::new(),
+            last_track: None,
+            last_timestamp: None,
+            udta: Vec::new(),
+        })
+    }
+
+    fn with_metadata(mut self, metadata: &Metadata) -> Self {
+        self.udta = metadata.to_mp4_udta();
+        self
+    }
+
+    async fn push(&mut self, stream: &mut TsStream, data: &[u8]) -> IoriResult<()> {
+        for packet in stream.demuxer.push(data) {
+            self.write_pes(stream, packet).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush the end of a transport stream.
+    async fn end(&mut self, stream: &mut TsStream) -> IoriResult<()> {
+        for packet in stream.demuxer.flush() {
+            self.write_pes(stream, packet).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_pes(&mut self, stream: &mut TsStream, packet: PesPacket) -> IoriResult<()> {
+        let index = *stream.tracks.entry(packet.pid).or_insert_with(|| {
+            let kind = match packet.stream_type {
+                StreamType::H264 => TrackKind::Video {
+                    codec: VideoCodec::H264,
+                    config: VideoConfig::default(),
+                },
+                StreamType::H265 => TrackKind::Video {
+                    codec: VideoCodec::H265,
+                    config: VideoConfig::default(),
+                },
+                StreamType::Aac | StreamType::Ac3 => TrackKind::Audio {
+                    codec: if packet.stream_type == StreamType::Aac {
+                        AudioCodec::Aac
+                    } else {
+                        AudioCodec::Ac3
+                    },
+                    config: None,
+                    pending: Vec::new(),
+                },
+                stream_type => {
+                    tracing::warn!(
+                        "Unsupported stream {stream_type:?} with PID {}. Skipping.",
+                        packet.pid
+                    );
+                    return None;
+                }
+            };
+            self.tracks.push(Track::new(kind));
+            Some(self.tracks.len() - 1)
+        });
+        let Some(index) = index else {
+            return Ok(());
+        };
+
+        // each track is unwrapped on its own, so that a wrap of one track does not affect others
+        let last_timestamp = self.tracks[index].last_timestamp.or(self.last_timestamp);
+        let pts = packet.pts.map(|pts| unwrap_timestamp(last_timestamp, pts));
+        let dts = packet
+            .dts
+            .map(|dts| unwrap_timestamp(last_timestamp, dts))
+            .or(pts);
+        if let Some(dts) = dts {
+            self.tracks[index].last_timestamp = Some(dts);
+            self.last_timestamp = Some(dts);
+        }
+
+        let track = &mut self.tracks[index];
+        match &mut track.kind {
+            TrackKind::Video { codec, config } => {
+                let (Some(pts), Some(dts)) = (pts, dts) else {
+                    return Ok(());
+                };
+                let (data, is_sync) = codec::convert_access_unit(*codec, &packet.data, config);
+                // wait for the first random access point with parameter sets
+                let has_config = config.sps.is_some() && config.pps.is_some();
+                if data.is_empty() || (track.table.samples.is_empty() && !(is_sync && has_config)) {
+                    return Ok(());
+                }
+
+                track.start_pts.get_or_insert(pts);
+                let sample = FragmentSample {
+                    decode_time: dts,
+                    // filled when finished
+                    duration: 0,
+                    size: data.len() as u32,
+                    flags: if is_sync { 0 } else { 0x0001_0000 },
+                    composition_offset: pts.saturating_sub(dts) as i32,
+                    offset: 0,
+                };
+                self.write_sample(index, sample, &data).await?;
+            }
+            TrackKind::Audio {
+                codec,
+                config,
+                pending,
+            } => {
+                let codec = *codec;
+                pending.extend_from_slice(&packet.data);
+
+                let mut frames = Vec::new();
+                loop {
+                    let (skip, frame) = codec::next_audio_frame(codec, pending)?;
+                    let Some(frame) = frame else {
+                        pending.drain(..skip);
+                        break;
+                    };
+                    if config.is_none() {
+                        *config = Some(frame.config);
+                        track.timescale = frame.sample_rate;
+                        track.start_pts = pts;
+                    }
+                    frames.push((frame.samples, pending[frame.payload.clone()].to_vec()));
+                    pending.drain(..skip + frame.size);
+                }
+
+                for (samples, data) in frames {
+                    let sample = FragmentSample {
+                        decode_time: 0,
+                        duration: samples,
+                        size: data.len() as u32,
+                        flags: 0,
+                        composition_offset: 0,
+                        offset: 0,
+                    };
+                    self.write_sample(index, sample, &data).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write_sample(
+        &mut self,
+        index: usize,
+        mut sample: FragmentSample,
+        data: &[u8],
+    ) -> IoriResult<()> {
+        let table = &mut self.tracks[index].table;
+        match table.chunks.last_mut() {
+            Some((_, count)) if self.last_track == Some(index) => *count += 1,
+            _ => table.chunks.push((self.position, 1)),
+        }
+        self.last_track = Some(index);
+
+        sample.offset = self.position as usize;
+        table.samples.push(sample);
+        self.output.write_all(data).await?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> IoriResult<()> {
+        let mdat_size = self.position - self.mdat_offset;
+        let moov = self.build_moov();
+        self.output.write_all(&moov).await?;
+        self.output.flush().await?;
+
+        let mut file = self.output.into_inner();
+        file.seek(SeekFrom::Start(self.mdat_offset + 8)).await?;
+        file.write_all(&mdat_size.to_be_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    fn build_moov(&mut self) -> Vec<u8> {
+        for track in self.tracks.iter_mut() {
+            if matches!(track.kind, TrackKind::Video { .. }) {
+                fill_video_durations(&mut track.table.samples);
+            }
+        }
+
+        let large_offsets = self.position > u32::MAX as u64;
+        let start_pts = self
+            .tracks
+            .iter()
+            .filter(|t| !t.table.samples.is_empty())
+            .filter_map(|t| t.start_pts)
+            .min()
+            .unwrap_or_default();
+
+        let mut movie_duration = 0;
+        let mut traks = Vec::new();
+        let mut track_id = 0;
+        for track in self.tracks.iter() {
+            if track.table.samples.is_empty() {
+                continue;
+            }
+            let (sample_entry, width, height) = match &track.kind {
+                TrackKind::Video { codec, config } => {
+                    match codec::video_sample_entry(*codec, config) {
+                        Some(entry) => entry,
+                        None => {
+                            tracing::warn!("Invalid {codec:?} parameter sets. Skipping track.");
+                            continue;
+                        }
+                    }
+                }
+                TrackKind::Audio { config, .. } => match config {
+                    Some(config) => (config.sample_entry(track.timescale), 0, 0),
+                    None => continue,
+                },
+            };
+            track_id += 1;
+
+            let media_duration = track.table.duration();
+            let delay = track
+                .start_pts
+                .unwrap_or(start_pts)
+                .saturating_sub(start_pts)
+                * MOVIE_TIMESCALE
+                / 90000;
+            let edit_duration = media_duration * MOVIE_TIMESCALE / track.timescale as u64;
+            let track_duration = delay + edit_duration;
+            movie_duration = movie_duration.max(track_duration);
+
+            let mut trak = Vec::new();
+            write_tkhd(&mut trak, track_id, track_duration, width, height);
+
+            // edit list, delaying the track and skipping the initial composition offset
+            let mut elst = Vec::new();
+            let mut entry_count = 1u32;
+            if delay > 0 {
+                entry_count += 1;
+                elst.extend_from_slice(&delay.to_be_bytes());
+                elst.extend_from_slice(&(-1i64).to_be_bytes());
+                elst.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+            }
+            let media_time = track.table.samples[0].composition_offset.max(0) as i64;
+            elst.extend_from_slice(&edit_duration.to_be_bytes());
+            elst.extend_from_slice(&media_time.to_be_bytes());
+            elst.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+            let mut elst_payload = entry_count.to_be_bytes().to_vec();
+            elst_payload.extend(elst);
+            let mut edts = Vec::new();
+            write_full_box(&mut edts, b"elst", 1, 0, &elst_payload);
+            write_box(&mut trak, b"edts", &edts);
+
+            let mut mdia = Vec::new();
+            write_mdhd(&mut mdia, track.timescale, media_duration);
+            let is_video = matches!(track.kind, TrackKind::Video { .. });
+            let (handler_type, name) = if is_video {
+                (b"vide", "VideoHandler")
+            } else {
+                (b"soun", "SoundHandler")
+            };
+            let mut hdlr = vec![0; 4];
+            hdlr.extend_from_slice(handler_type);
+            hdlr.extend_from_slice(&[0; 12]);
+            hdlr.extend_from_slice(name.as_bytes());
+            hdlr.push(0);
+            write_full_box(&mut mdia, b"hdlr", 0, 0, &hdlr);
+
+            let mut minf = Vec::new();
+            if is_video {
+                // graphicsmode, opcolor
+                write_full_box(&mut minf, b"vmhd", 0, 1, &[0; 8]);
+            } else {
+                // balance, reserved
+                write_full_box(&mut minf, b"smhd", 0, 0, &[0; 4]);
+            }
+            let mut dref = 1u32.to_be_bytes().to_vec();
+            // media data is in the same file
+            write_full_box(&mut dref, b"url ", 0, 1, &[]);
+            let mut dinf = Vec::new();
+            write_full_box(&mut dinf, b"dref", 0, 0, &dref);
+            write_box(&mut minf, b"dinf", &dinf);
+
+            let mut stbl = Vec::new();
+            let mut stsd = 1u32.to_be_bytes().to_vec();
+            stsd.extend(sample_entry);
+            write_full_box(&mut stbl, b"stsd", 0, 0, &stsd);
+            // chunk offsets are absolute already
+            track.table.write_boxes(&mut stbl, 0, large_offsets);
+            write_box(&mut minf, b"stbl", &stbl);
+
+            write_box(&mut mdia, b"minf", &minf);
+            write_box(&mut trak, b"mdia", &mdia);
+            write_box(&mut traks, b"trak", &trak);
+        }
+
+        let mut moov = Vec::new();
+        write_mvhd(&mut moov, movie_duration, track_id + 1);
+        moov.extend(traks);
+        moov.extend_from_slice(&self.udta);
+
+        let mut output = Vec::new();
+        write_box(&mut output, b"moov", &moov);
+        output
+    }
+}
+
+/// Unwrap a 33-bit timestamp to the value closest to the last timestamp.
+fn unwrap_timestamp(last: Option<u64>, timestamp: u64) -> u64 {
+    let Some(last) = last else {
+        return timestamp;
+    };
+    let base = last - last % TIMESTAMP_WRAP + timestamp;
+    [
+        base.checked_sub(TIMESTAMP_WRAP),
+        Some(base),
+        Some(base + TIMESTAMP_WRAP),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by_key(|candidate| candidate.abs_diff(last))
+    .unwrap_or(timestamp)
+}
+
+/// Fill durations of video samples from the decode time of the next sample.
+fn fill_video_durations(samples: &mut [FragmentSample]) {
+    // 1/30 second in 90kHz, used when the duration is unknown
+    let mut last_duration = 3000;
+    for index in 0..samples.len() {
+        let duration = samples
+            .get(index + 1)
+            .and_then(|next| next.decode_time.checked_sub(samples[index].decode_time))
+            // discontinuities longer than 10 seconds are removed
+            .filter(|duration| *duration > 0 && *duration < 900000)
+            .map_or(last_duration, |duration| duration as u32);
+        samples[index].duration = duration;
+        last_duration = duration;
+    }
+}
+
+fn write_mvhd(output: &mut Vec<u8>, duration: u64, next_track_id: u32) {
+    // creation_time, modification_time
+    let mut mvhd = vec![0; 16];
+    mvhd.extend_from_slice(&(MOVIE_TIMESCALE as u32).to_be_bytes());
+    mvhd.extend_from_slice(&duration.to_be_bytes());
+    // rate, volume
+    mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    mvhd.extend_from_slice(&0x0100u16.to_be_bytes());
+    // reserved
+    mvhd.extend_from_slice(&[0; 10]);
+    for value in MATRIX {
+        mvhd.extend_from_slice(&value.to_be_bytes());
+    }
+    // pre_defined
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&next_track_id.to_be_bytes());
+    write_full_box(output, b"mvhd", 1, 0, &mvhd);
+}
+
+fn write_tkhd(output: &mut Vec<u8>, track_id: u32, duration: u64, width: u32, height: u32) {
+    // creation_time, modification_time
+    let mut tkhd = vec![0; 16];
+    tkhd.extend_from_slice(&track_id.to_be_bytes());
+    // reserved
+    tkhd.extend_from_slice(&[0; 4]);
+    tkhd.extend_from_slice(&duration.to_be_bytes());
+    // reserved, layer, alternate_group
+    tkhd.extend_from_slice(&[0; 12]);
+    // volume, 1.0 for audio tracks
+    let volume: u16 = if width == 0 { 0x0100 } else { 0 };
+    tkhd.extend_from_slice(&volume.to_be_bytes());
+    // reserved
+    tkhd.extend_from_slice(&[0; 2]);
+    for value in MATRIX {
+        tkhd.extend_from_slice(&value.to_be_bytes());
+    }
+    tkhd.extend_from_slice(&(width << 16).to_be_bytes());
+    tkhd.extend_from_slice(&(height << 16).to_be_bytes());
+    // track_enabled | track_in_movie
+    write_full_box(output, b"tkhd", 1, 0x03, &tkhd);
+}
+
+fn write_mdhd(output: &mut Vec<u8>, timescale: u32, duration: u64) {
+    // creation_time, modification_time
+    let mut mdhd = vec![0; 16];
+    mdhd.extend_from_slice(&timescale.to_be_bytes());
+    mdhd.extend_from_slice(&duration.to_be_bytes());
+    // language `und`, pre_defined
+    mdhd.extend_from_slice(&0x55c4u16.to_be_bytes());
+    mdhd.extend_from_slice(&[0; 2]);
+    write_full_box(output, b"mdhd", 1, 0, &mdhd);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(decode_time: u64) -> FragmentSample {
+        FragmentSample {
+            decode_time,
+            duration: 0,
+            size: 0,
+            flags: 0,
+            composition_offset: 0,
+            offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_fill_video_durations() {
+        let mut samples = vec![sample(0), sample(3003), sample(6006), sample(2_000_000)];
+        fill_video_durations(&mut samples);
+        let durations: Vec<_> = samples.iter().map(|s| s.duration).collect();
+        assert_eq!(durations, [3003, 3003, 3003, 3003]);
+    }
+
+    #[test]
+    fn test_unwrap_timestamp() {
+        assert_eq!(unwrap_timestamp(None, 100), 100);
+
+        let last = Some(TIMESTAMP_WRAP - 1000);
+        assert_eq!(unwrap_timestamp(last, 500), TIMESTAMP_WRAP + 500);
+        assert_eq!(
+            unwrap_timestamp(last, TIMESTAMP_WRAP - 3000),
+            TIMESTAMP_WRAP - 3000
+        );
+
+        let last = Some(TIMESTAMP_WRAP + 500);
+        assert_eq!(
+            unwrap_timestamp(last, TIMESTAMP_WRAP - 1000),
+            TIMESTAMP_WRAP - 1000
+        );
+    }
+}
@@ -0,0 +1,808 @@
+//! Elementary stream parsers and MP4 sample entries for remuxing.
+use crate::{
+    IoriError, IoriResult,
+    util::mp4::{write_box, write_full_box},
+};
+
+/// Read bits from RBSP data, most significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit as u32)
+    }
+
+    fn read_bits(&mut self, count: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()?;
+        }
+        Some(value)
+    }
+
+    fn skip_bits(&mut self, count: usize) -> Option<()> {
+        if self.position + count > self.data.len() * 8 {
+            return None;
+        }
+        self.position += count;
+        Some(())
+    }
+
+    /// Read an unsigned Exp-Golomb code.
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+
+    /// Read a signed Exp-Golomb code.
+    fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()?;
+        Some(if value % 2 == 1 {
+            value.div_ceil(2) as i32
+        } else {
+            -((value / 2) as i32)
+        })
+    }
+}
+
+/// Split an Annex B byte stream into NAL units, without start codes.
+pub(super) fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut start = None;
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index] == 0 && data[index + 1] == 0 && data[index + 2] == 1 {
+            if let Some(start) = start {
+                units.push(trim_trailing_zeros(&data[start..index]));
+            }
+            index += 3;
+            start = Some(index);
+        } else {
+            index += 1;
+        }
+    }
+    if let Some(start) = start {
+        units.push(trim_trailing_zeros(&data[start..]));
+    }
+    units.retain(|unit| !unit.is_empty());
+    units
+}
+
+fn trim_trailing_zeros(data: &[u8]) -> &[u8] {
+    let end = data.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+    &data[..end]
+}
+
+/// Remove emulation prevention bytes from a NAL unit.
+fn to_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// Video codecs carried in MPEG-TS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum VideoCodec {
+    H264,
+    H265,
+}
+
+impl VideoCodec {
+    fn nal_type(&self, unit: &[u8]) -> u8 {
+        match self {
+            Self::H264 => unit[0] & 0x1f,
+            Self::H265 => (unit[0] >> 1) & 0x3f,
+        }
+    }
+}
+
+/// Parameter sets and picture size of a video stream.
+#[derive(Debug, Clone, Default)]
+pub(super) struct VideoConfig {
+    pub vps: Option<Vec<u8>>,
+    pub sps: Option<Vec<u8>>,
+    pub pps: Option<Vec<u8>>,
+}
+
+/// Convert an access unit in Annex B format to an MP4 sample with 4-byte length prefixes.
+///
+/// Parameter sets are moved into `config` and access unit delimiters are dropped.
+/// Returns the sample data and whether the access unit is a random access point.
+pub(super) fn convert_access_unit(
+    codec: VideoCodec,
+    data: &[u8],
+    config: &mut VideoConfig,
+) -> (Vec<u8>, bool) {
+    let mut sample = Vec::with_capacity(data.len() + 16);
+    let mut is_sync = false;
+    for unit in split_annex_b(data) {
+        let nal_type = codec.nal_type(unit);
+        let parameter_set = match (codec, nal_type) {
+            // AUD
+            (VideoCodec::H264, 9) | (VideoCodec::H265, 35) => continue,
+            (VideoCodec::H265, 32) => Some(&mut config.vps),
+            (VideoCodec::H264, 7) | (VideoCodec::H265, 33) => Some(&mut config.sps),
+            (VideoCodec::H264, 8) | (VideoCodec::H265, 34) => Some(&mut config.pps),
+            _ => None,
+        };
+        if let Some(parameter_set) = parameter_set {
+            parameter_set.get_or_insert_with(|| unit.to_vec());
+            continue;
+        }
+
+        is_sync |= match codec {
+            // IDR
+            VideoCodec::H264 => nal_type == 5,
+            // IRAP
+            VideoCodec::H265 => (16..=23).contains(&nal_type),
+        };
+        sample.extend_from_slice(&(unit.len() as u32).to_be_bytes());
+        sample.extend_from_slice(unit);
+    }
+    (sample, is_sync)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PictureSize {
+    width: u32,
+    height: u32,
+}
+
+/// Parse the picture size from a H.264 SPS, defined in ITU-T H.264 7.3.2.1.1.
+fn parse_avc_sps(sps: &[u8]) -> Option<PictureSize> {
+    let rbsp = to_rbsp(sps);
+    let profile_idc = *rbsp.get(1)?;
+    let mut reader = BitReader::new(rbsp.get(4..)?);
+
+    // seq_parameter_set_id
+    reader.read_ue()?;
+    let mut chroma_format_idc = 1;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc == 3 {
+            // separate_colour_plane_flag
+            reader.skip_bits(1)?;
+        }
+        // bit_depth_luma_minus8, bit_depth_chroma_minus8
+        reader.read_ue()?;
+        reader.read_ue()?;
+        // qpprime_y_zero_transform_bypass_flag
+        reader.skip_bits(1)?;
+        // seq_scaling_matrix_present_flag
+        if reader.read_bit()? == 1 {
+            let count = if chroma_format_idc == 3 { 12 } else { 8 };
+            for index in 0..count {
+                if reader.read_bit()? == 1 {
+                    skip_scaling_list(&mut reader, if index < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    // log2_max_frame_num_minus4
+    reader.read_ue()?;
+    match reader.read_ue()? {
+        // log2_max_pic_order_cnt_lsb_minus4
+        0 => {
+            reader.read_ue()?;
+        }
+        1 => {
+            // delta_pic_order_always_zero_flag
+            reader.skip_bits(1)?;
+            // offset_for_non_ref_pic, offset_for_top_to_bottom_field
+            reader.read_se()?;
+            reader.read_se()?;
+            for _ in 0..reader.read_ue()? {
+                // offset_for_ref_frame
+                reader.read_se()?;
+            }
+        }
+        _ => {}
+    }
+    // max_num_ref_frames
+    reader.read_ue()?;
+    // gaps_in_frame_num_value_allowed_flag
+    reader.skip_bits(1)?;
+    let width_in_mbs = reader.read_ue()? + 1;
+    let height_in_map_units = reader.read_ue()? + 1;
+    let frame_mbs_only = reader.read_bit()?;
+    if frame_mbs_only == 0 {
+        // mb_adaptive_frame_field_flag
+        reader.skip_bits(1)?;
+    }
+    // direct_8x8_inference_flag
+    reader.skip_bits(1)?;
+
+    let mut width = width_in_mbs * 16;
+    let mut height = (2 - frame_mbs_only) * height_in_map_units * 16;
+    if reader.read_bit()? == 1 {
+        let (crop_x, crop_y) = match chroma_format_idc {
+            0 => (1, 2 - frame_mbs_only),
+            1 => (2, 2 * (2 - frame_mbs_only)),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        let (left, right) = (reader.read_ue()?, reader.read_ue()?);
+        let (top, bottom) = (reader.read_ue()?, reader.read_ue()?);
+        width = width.saturating_sub((left + right) * crop_x);
+        height = height.saturating_sub((top + bottom) * crop_y);
+    }
+
+    Some(PictureSize { width, height })
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = reader.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
+
+/// Fields of a H.265 SPS needed by `hvcC`, defined in ITU-T H.265 7.3.2.2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HevcSps {
+    /// general_profile_space to general_level_idc in profile_tier_level
+    general_profile_tier_level: [u8; 12],
+    max_sub_layers: u8,
+    temporal_id_nesting: bool,
+    chroma_format_idc: u32,
+    bit_depth_luma_minus8: u32,
+    bit_depth_chroma_minus8: u32,
+    size: PictureSize,
+}
+
+fn parse_hevc_sps(sps: &[u8]) -> Option<HevcSps> {
+    let rbsp = to_rbsp(sps);
+    // skip the 2-byte NAL unit header
+    let mut reader = BitReader::new(rbsp.get(2..)?);
+
+    // sps_video_parameter_set_id
+    reader.skip_bits(4)?;
+    let max_sub_layers_minus1 = reader.read_bits(3)? as usize;
+    let temporal_id_nesting = reader.read_bit()? == 1;
+    let general_profile_tier_level: [u8; 12] = rbsp.get(3..15)?.try_into().ok()?;
+    reader.skip_bits(96)?;
+
+    let mut sub_layers = Vec::with_capacity(max_sub_layers_minus1);
+    for _ in 0..max_sub_layers_minus1 {
+        let profile_present = reader.read_bit()? == 1;
+        let level_present = reader.read_bit()? == 1;
+        sub_layers.push((profile_present, level_present));
+    }
+    if max_sub_layers_minus1 > 0 {
+        reader.skip_bits(2 * (8 - max_sub_layers_minus1))?;
+    }
+    for (profile_present, level_present) in sub_layers {
+        if profile_present {
+            reader.skip_bits(88)?;
+        }
+        if level_present {
+            reader.skip_bits(8)?;
+        }
+    }
+
+    // sps_seq_parameter_set_id
+    reader.read_ue()?;
+    let chroma_format_idc = reader.read_ue()?;
+    if chroma_format_idc == 3 {
+        // separate_colour_plane_flag
+        reader.skip_bits(1)?;
+    }
+    let mut width = reader.read_ue()?;
+    let mut height = reader.read_ue()?;
+    if reader.read_bit()? == 1 {
+        let sub_width = if matches!(chroma_format_idc, 1 | 2) {
+            2
+        } else {
+            1
+        };
+        let sub_height = if chroma_format_idc == 1 { 2 } else { 1 };
+        let (left, right) = (reader.read_ue()?, reader.read_ue()?);
+        let (top, bottom) = (reader.read_ue()?, reader.read_ue()?);
+        width = width.saturating_sub((left + right) * sub_width);
+        height = height.saturating_sub((top + bottom) * sub_height);
+    }
+    let bit_depth_luma_minus8 = reader.read_ue()?;
+    let bit_depth_chroma_minus8 = reader.read_ue()?;
+
+    Some(HevcSps {
+        general_profile_tier_level,
+        max_sub_layers: max_sub_layers_minus1 as u8 + 1,
+        temporal_id_nesting,
+        chroma_format_idc,
+        bit_depth_luma_minus8,
+        bit_depth_chroma_minus8,
+        size: PictureSize { width, height },
+    })
+}
+
+/// Build the sample entry of a video track. Returns the entry with picture width and height.
+///
+/// `None` is returned if required parameter sets are missing or invalid.
+pub(super) fn video_sample_entry(
+    codec: VideoCodec,
+    config: &VideoConfig,
+) -> Option<(Vec<u8>, u32, u32)> {
+    let sps = config.sps.as_deref()?;
+    let pps = config.pps.as_deref()?;
+
+    let (r#type, size, config_box) = match codec {
+        VideoCodec::H264 => {
+            let size = parse_avc_sps(sps)?;
+            let mut avcc = vec![
+                // configurationVersion
+                1,
+                // AVCProfileIndication, profile_compatibility, AVCLevelIndication
+                *sps.get(1)?,
+                *sps.get(2)?,
+                *sps.get(3)?,
+                // lengthSizeMinusOne = 3
+                0xff,
+                // numOfSequenceParameterSets = 1
+                0xe1,
+            ];
+            avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+            avcc.extend_from_slice(sps);
+            avcc.push(1);
+            avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+            avcc.extend_from_slice(pps);
+
+            let mut config_box = Vec::new();
+            write_box(&mut config_box, b"avcC", &avcc);
+            (b"avc1", size, config_box)
+        }
+        VideoCodec::H265 => {
+            let vps = config.vps.as_deref()?;
+            let info = parse_hevc_sps(sps)?;
+            let mut hvcc = vec![1];
+            hvcc.extend_from_slice(&info.general_profile_tier_level);
+            hvcc.extend_from_slice(&[
+                // min_spatial_segmentation_idc = 0
+                0xf0,
+                0x00,
+                // parallelismType = 0
+                0xfc,
+                0xfc | info.chroma_format_idc as u8,
+                0xf8 | info.bit_depth_luma_minus8 as u8,
+                0xf8 | info.bit_depth_chroma_minus8 as u8,
+                // avgFrameRate = 0
+                0x00,
+                0x00,
+                // constantFrameRate = 0, numTemporalLayers, temporalIdNested, lengthSizeMinusOne = 3
+                ((info.max_sub_layers & 0x07) << 3)
+                    | ((info.temporal_id_nesting as u8) << 2)
+                    | 0x03,
+                // numOfArrays
+                3,
+            ]);
+            for (nal_type, unit) in [(32u8, vps), (33, sps), (34, pps)] {
+                // array_completeness = 1
+                hvcc.push(0x80 | nal_type);
+                hvcc.extend_from_slice(&1u16.to_be_bytes());
+                hvcc.extend_from_slice(&(unit.len() as u16).to_be_bytes());
+                hvcc.extend_from_slice(unit);
+            }
+
+            let mut config_box = Vec::new();
+            write_box(&mut config_box, b"hvcC", &hvcc);
+            (b"hvc1", info.size, config_box)
+        }
+    };
+
+    // VisualSampleEntry, defined in ISO/IEC 14496-12 12.1.3
+    let mut entry = vec![0; 6];
+    // data_reference_index
+    entry.extend_from_slice(&1u16.to_be_bytes());
+    // pre_defined, reserved, pre_defined[3]
+    entry.extend_from_slice(&[0; 16]);
+    entry.extend_from_slice(&(size.width as u16).to_be_bytes());
+    entry.extend_from_slice(&(size.height as u16).to_be_bytes());
+    // horizresolution and vertresolution, 72 dpi
+    entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+    entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+    // reserved
+    entry.extend_from_slice(&[0; 4]);
+    // frame_count
+    entry.extend_from_slice(&1u16.to_be_bytes());
+    // compressorname
+    entry.extend_from_slice(&[0; 32]);
+    // depth
+    entry.extend_from_slice(&0x0018u16.to_be_bytes());
+    // pre_defined
+    entry.extend_from_slice(&0xffffu16.to_be_bytes());
+    entry.extend(config_box);
+
+    let mut sample_entry = Vec::new();
+    write_box(&mut sample_entry, r#type, &entry);
+    Some((sample_entry, size.width, size.height))
+}
+
+/// Audio codecs carried in MPEG-TS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum AudioCodec {
+    /// AAC in ADTS
+    Aac,
+    Ac3,
+}
+
+/// An audio frame found in an elementary stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct AudioFrame {
+    /// Range of the raw frame in the data, excluding the ADTS header
+    pub payload: std::ops::Range<usize>,
+    /// Total size of the frame including the header
+    pub size: usize,
+    pub sample_rate: u32,
+    /// Number of PCM samples in the frame
+    pub samples: u32,
+    pub config: AudioConfig,
+}
+
+/// Information for the sample entry of an audio track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum AudioConfig {
+    Aac {
+        object_type: u8,
+        sampling_index: u8,
+        channel_config: u8,
+    },
+    Ac3 {
+        fscod: u8,
+        bsid: u8,
+        bsmod: u8,
+        acmod: u8,
+        lfeon: u8,
+        frmsizecod: u8,
+    },
+}
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+const AC3_SAMPLE_RATES: [u32; 3] = [48000, 44100, 32000];
+
+const AC3_BITRATES: [u32; 19] = [
+    32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640,
+];
+
+/// Find the next complete audio frame in the data.
+///
+/// Returns the number of bytes to skip before the frame, and the frame if complete.
+pub(super) fn next_audio_frame(
+    codec: AudioCodec,
+    data: &[u8],
+) -> IoriResult<(usize, Option<AudioFrame>)> {
+    let mut offset = 0;
+    while offset + 7 <= data.len() {
+        let frame = match codec {
+            AudioCodec::Aac => parse_adts(&data[offset..])?,
+            AudioCodec::Ac3 => parse_ac3(&data[offset..]),
+        };
+        match frame {
+            Some(frame) if offset + frame.size <= data.len() => {
+                let payload = offset + frame.payload.start..offset + frame.payload.end;
+                return Ok((offset, Some(AudioFrame { payload, ..frame })));
+            }
+            // wait for more data
+            Some(_) => return Ok((offset, None)),
+            None => offset += 1,
+        }
+    }
+    Ok((offset, None))
+}
+
+/// Parse an ADTS frame header, defined in ISO/IEC 13818-7 6.2.
+///
+/// Frames with more than one raw data block can not be stored as a single MP4 sample, and
+/// are rejected.
+fn parse_adts(data: &[u8]) -> IoriResult<Option<AudioFrame>> {
+    if data.len() < 7 || data[0] != 0xff || data[1] & 0xf6 != 0xf0 {
+        return Ok(None);
+    }
+    let protection_absent = data[1] & 0x01 == 1;
+    let object_type = (data[2] >> 6) + 1;
+    let sampling_index = (data[2] >> 2) & 0x0f;
+    let channel_config = ((data[2] & 0x01) << 2) | (data[3] >> 6);
+    let size =
+        (((data[3] & 0x03) as usize) << 11) | ((data[4] as usize) << 3) | (data[5] >> 5) as usize;
+    let header_size = if protection_absent { 7 } else { 9 };
+    let Some(&sample_rate) = AAC_SAMPLE_RATES.get(sampling_index as usize) else {
+        return Ok(None);
+    };
+    if size <= header_size {
+        return Ok(None);
+    }
+    let raw_data_blocks = (data[6] & 0x03) + 1;
+    if raw_data_blocks > 1 {
+        return Err(IoriError::Remuxing(format!(
+            "ADTS frame with {raw_data_blocks} raw data blocks is not supported"
+        )));
+    }
+
+    Ok(Some(AudioFrame {
+        payload: header_size..size,
+        size,
+        sample_rate,
+        samples: 1024,
+        config: AudioConfig::Aac {
+            object_type,
+            sampling_index,
+            channel_config,
+        },
+    }))
+}
+
+/// Parse an AC-3 sync frame header, defined in ETSI TS 102 366 4.3.
+fn parse_ac3(data: &[u8]) -> Option<AudioFrame> {
+    if data.len() < 7 || data[0..2] != [0x0b, 0x77] {
+        return None;
+    }
+    let fscod = data[4] >> 6;
+    let frmsizecod = data[4] & 0x3f;
+    let sample_rate = *AC3_SAMPLE_RATES.get(fscod as usize)?;
+    let bitrate = *AC3_BITRATES.get(frmsizecod as usize / 2)?;
+    // frame size in 16-bit words, with an extra word for odd frmsizecod at 44.1kHz
+    let mut words = bitrate * 1000 * 1536 / 16 / sample_rate;
+    if fscod == 1 {
+        words += (frmsizecod & 1) as u32;
+    }
+
+    let bsid = data[5] >> 3;
+    let bsmod = data[5] & 0x07;
+    let mut reader = BitReader::new(&data[6..]);
+    let acmod = reader.read_bits(3)? as u8;
+    if acmod & 0x01 != 0 && acmod != 1 {
+        // cmixlev
+        reader.skip_bits(2)?;
+    }
+    if acmod & 0x04 != 0 {
+        // surmixlev
+        reader.skip_bits(2)?;
+    }
+    if acmod == 2 {
+        // dsurmod
+        reader.skip_bits(2)?;
+    }
+    let lfeon = reader.read_bit()? as u8;
+
+    let size = words as usize * 2;
+    Some(AudioFrame {
+        payload: 0..size,
+        size,
+        sample_rate,
+        samples: 1536,
+        config: AudioConfig::Ac3 {
+            fscod,
+            bsid,
+            bsmod,
+            acmod,
+            lfeon,
+            frmsizecod,
+        },
+    })
+}
+
+impl AudioConfig {
+    fn channel_count(&self) -> u16 {
+        match *self {
+            Self::Aac { channel_config, .. } => match channel_config {
+                7 => 8,
+                count => count as u16,
+            },
+            Self::Ac3 { acmod, lfeon, .. } => {
+                [2, 1, 2, 3, 3, 4, 4, 5][acmod as usize & 0x07] + lfeon as u16
+            }
+        }
+    }
+
+    /// Build the sample entry of an audio track.
+    pub(super) fn sample_entry(&self, sample_rate: u32) -> Vec<u8> {
+        // AudioSampleEntry, defined in ISO/IEC 14496-12 12.2.3
+        let mut entry = vec![0; 6];
+        // data_reference_index
+        entry.extend_from_slice(&1u16.to_be_bytes());
+        // reserved
+        entry.extend_from_slice(&[0; 8]);
+        entry.extend_from_slice(&self.channel_count().to_be_bytes());
+        // samplesize
+        entry.extend_from_slice(&16u16.to_be_bytes());
+        // pre_defined, reserved
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&(sample_rate.min(0xffff) << 16).to_be_bytes());
+
+        let r#type = match *self {
+            Self::Aac {
+                object_type,
+                sampling_index,
+                channel_config,
+            } => {
+                let audio_specific_config = (((object_type as u16) << 11)
+                    | ((sampling_index as u16) << 7)
+                    | ((channel_config as u16) << 3))
+                    .to_be_bytes();
+                write_full_box(
+                    &mut entry,
+                    b"esds",
+                    0,
+                    0,
+                    &es_descriptor(&audio_specific_config),
+                );
+                b"mp4a"
+            }
+            Self::Ac3 {
+                fscod,
+                bsid,
+                bsmod,
+                acmod,
+                lfeon,
+                frmsizecod,
+            } => {
+                // AC3SpecificBox, defined in ETSI TS 102 366 F.4
+                let value = ((fscod as u32) << 22)
+                    | ((bsid as u32) << 17)
+                    | ((bsmod as u32) << 14)
+                    | ((acmod as u32) << 11)
+                    | ((lfeon as u32) << 10)
+                    | (((frmsizecod >> 1) as u32) << 5);
+                write_box(&mut entry, b"dac3", &value.to_be_bytes()[1..]);
+                b"ac-3"
+            }
+        };
+
+        let mut sample_entry = Vec::new();
+        write_box(&mut sample_entry, r#type, &entry);
+        sample_entry
+    }
+}
+
+/// Build an ES_Descriptor for AAC, defined in ISO/IEC 14496-1 7.2.6.5.
+fn es_descriptor(audio_specific_config: &[u8]) -> Vec<u8> {
+    // objectTypeIndication = Audio ISO/IEC 14496-3,
+    // streamType = AudioStream, upStream = 0, reserved = 1
+    let mut decoder_config = vec![0x40, 0x15];
+    // bufferSizeDB, maxBitrate, avgBitrate
+    decoder_config.extend_from_slice(&[0; 11]);
+    write_descriptor(&mut decoder_config, 0x05, audio_specific_config);
+
+    // ES_ID, flags
+    let mut es = vec![0, 0, 0];
+    write_descriptor(&mut es, 0x04, &decoder_config);
+    // SLConfigDescriptor with predefined = 2
+    write_descriptor(&mut es, 0x06, &[0x02]);
+
+    let mut descriptor = Vec::new();
+    write_descriptor(&mut descriptor, 0x03, &es);
+    descriptor
+}
+
+fn write_descriptor(output: &mut Vec<u8>, tag: u8, payload: &[u8]) {
+    output.push(tag);
+    output.push(payload.len() as u8);
+    output.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_annex_b() {
+        let data = [
+            0, 0, 0, 1, 0x09, 0xf0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x65, 0x88,
+        ];
+        let units = split_annex_b(&data);
+        assert_eq!(units, [&[0x09, 0xf0][..], &[0x67, 0x42], &[0x65, 0x88]]);
+        assert_eq!(to_rbsp(&[0x67, 0, 0, 3, 1, 0, 0, 3]), [0x67, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse_avc_sps() {
+        // Baseline 1280x720
+        let sps = [0x67, 0x42, 0xc0, 0x1f, 0xda, 0x01, 0x40, 0x16, 0xe8];
+        assert_eq!(
+            parse_avc_sps(&sps),
+            Some(PictureSize {
+                width: 1280,
+                height: 720,
+            })
+        );
+    }
+
+    #[test]
+    fn test_convert_access_unit() {
+        let data = [
+            0, 0, 0, 1, 0x09, 0xf0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce, 0, 0, 1, 0x65, 0x88,
+        ];
+        let mut config = VideoConfig::default();
+        let (sample, is_sync) = convert_access_unit(VideoCodec::H264, &data, &mut config);
+        assert!(is_sync);
+        assert_eq!(sample, [0, 0, 0, 2, 0x65, 0x88]);
+        assert_eq!(config.sps.as_deref(), Some(&[0x67, 0x42][..]));
+        assert_eq!(config.pps.as_deref(), Some(&[0x68, 0xce][..]));
+    }
+
+    #[test]
+    fn test_next_adts_frame() {
+        // AAC LC, 48kHz, stereo, 10 bytes
+        let mut data = vec![0x00, 0xff, 0xf1, 0x4c, 0x80, 0x01, 0x5f, 0xfc];
+        data.extend_from_slice(&[1, 2, 3]);
+
+        let (skip, frame) = next_audio_frame(AudioCodec::Aac, &data).unwrap();
+        assert_eq!(skip, 1);
+        let frame = frame.unwrap();
+        assert_eq!(frame.payload, 8..11);
+        assert_eq!(frame.sample_rate, 48000);
+        assert_eq!(
+            frame.config,
+            AudioConfig::Aac {
+                object_type: 2,
+                sampling_index: 3,
+                channel_config: 2,
+            }
+        );
+
+        let (skip, frame) = next_audio_frame(AudioCodec::Aac, &data[..10]).unwrap();
+        assert_eq!((skip, frame), (1, None));
+
+        // two raw data blocks in a frame
+        data[7] = 0xfd;
+        assert!(next_audio_frame(AudioCodec::Aac, &data).is_err());
+    }
+
+    #[test]
+    fn test_parse_ac3() {
+        // 48kHz, 192kbps, bsid 8, 2/0 with LFE
+        let data = [0x0b, 0x77, 0, 0, 0x14, 0x40, 0x54, 0];
+        let frame = parse_ac3(&data).unwrap();
+        assert_eq!(frame.size, 768);
+        assert_eq!(frame.sample_rate, 48000);
+        assert_eq!(
+            frame.config,
+            AudioConfig::Ac3 {
+                fscod: 0,
+                bsid: 8,
+                bsmod: 0,
+                acmod: 2,
+                lfeon: 1,
+                frmsizecod: 20,
+            }
+        );
+        assert_eq!(frame.config.channel_count(), 3);
+    }
+}
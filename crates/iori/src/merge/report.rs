@@ -0,0 +1,269 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::Serialize;
+
+use super::{FailurePolicy, concat::ConcatSegment};
+use crate::SegmentType;
+
+/// Summary of a merge, returned by [Merger::finish](super::Merger::finish).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MergeReport {
+    /// Final output file. `None` if merging was skipped or the output is not a file.
+    ///
+    /// It is the first part if the output is split.
+    pub output: Option<PathBuf>,
+    /// All parts of the output, if it is split at gaps by [FailurePolicy::Split].
+    pub parts: Vec<PathBuf>,
+    /// Streams received by the merger, ordered by stream id.
+    pub tracks: Vec<TrackReport>,
+    /// Number of segments downloaded successfully.
+    pub downloaded_segments: usize,
+    /// Segments which failed to download, and are missing from the output.
+    pub failed_segments: Vec<FailedSegment>,
+    /// Runs of consecutive failed segments in each stream.
+    pub gaps: Vec<Gap>,
+    /// Policy applied to failed segments.
+    pub failure_policy: FailurePolicy,
+    /// Total size of output files in bytes.
+    pub bytes_written: u64,
+    /// Duration of the longest stream in seconds, if segment durations are known.
+    pub duration: Option<f64>,
+    /// Whether merging was skipped.
+    pub skipped: bool,
+    /// Whether downloaded segments were removed from the cache.
+    pub cache_cleared: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackReport {
+    pub stream_id: u64,
+    pub r#type: SegmentType,
+    /// File containing this stream. It is the final output if the stream is muxed into it.
+    pub path: Option<PathBuf>,
+    /// Number of segments downloaded successfully.
+    pub segments: usize,
+    /// Duration of downloaded segments in seconds, if known.
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedSegment {
+    pub stream_id: u64,
+    pub sequence: u64,
+    pub file_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Gap {
+    pub stream_id: u64,
+    /// Sequence of the first missing segment.
+    pub sequence: u64,
+    /// Number of missing segments.
+    pub segments: usize,
+    /// Start of the gap in seconds from the start of the stream, if segment durations are known.
+    pub start: Option<f64>,
+    /// Duration of the gap in seconds, if known.
+    pub duration: Option<f64>,
+}
+
+impl MergeReport {
+    /// Create a report of segments received by a merger, before any file is written.
+    pub(crate) fn new<'a>(segments: impl IntoIterator<Item = &'a ConcatSegment>) -> Self {
+        let mut report = Self::default();
+        let mut streams: BTreeMap<u64, Vec<&ConcatSegment>> = BTreeMap::new();
+        for segment in segments {
+            streams
+                .entry(segment.segment.stream_id)
+                .or_default()
+                .push(segment);
+        }
+
+        let mut tracks: BTreeMap<u64, TrackReport> = BTreeMap::new();
+        for ConcatSegment { segment, success } in streams.values().flatten() {
+            let track = tracks
+                .entry(segment.stream_id)
+                .or_insert_with(|| TrackReport {
+                    stream_id: segment.stream_id,
+                    r#type: segment.r#type,
+                    path: None,
+                    segments: 0,
+                    duration: Some(0.),
+                });
+
+            if *success {
+                report.downloaded_segments += 1;
+                track.segments += 1;
+                track.duration = track
+                    .duration
+                    .zip(segment.duration)
+                    .map(|(total, duration)| total + duration);
+            } else {
+                report.failed_segments.push(FailedSegment {
+                    stream_id: segment.stream_id,
+                    sequence: segment.sequence,
+                    file_name: segment.file_name.clone(),
+                });
+            }
+        }
+
+        report
+            .failed_segments
+            .sort_by_key(|s| (s.stream_id, s.sequence));
+
+        for (stream_id, segments) in streams.iter_mut() {
+            segments.sort_by_key(|s| s.segment.sequence);
+
+            let mut offset = Some(0.);
+            let mut last_success = true;
+            for ConcatSegment { segment, success } in segments.iter() {
+                if !success {
+                    match report.gaps.last_mut() {
+                        Some(gap) if !last_success => {
+                            gap.segments += 1;
+                            gap.duration = gap.duration.zip(segment.duration).map(|(a, b)| a + b);
+                        }
+                        _ => report.gaps.push(Gap {
+                            stream_id: *stream_id,
+                            sequence: segment.sequence,
+                            segments: 1,
+                            start: offset,
+                            duration: segment.duration,
+                        }),
+                    }
+                }
+                last_success = *success;
+                offset = offset.zip(segment.duration).map(|(a, b)| a + b);
+            }
+        }
+
+        report.tracks = tracks.into_values().collect();
+        report.duration = report
+            .tracks
+            .iter()
+            .filter_map(|t| t.duration)
+            .reduce(f64::max);
+        report
+    }
+
+    /// Set the final output file, which contains all streams without their own file.
+    pub(crate) fn set_output(&mut self, output: PathBuf) {
+        for track in self.tracks.iter_mut() {
+            track.path.get_or_insert_with(|| output.clone());
+        }
+        self.output = Some(output);
+    }
+
+    pub(crate) fn set_track_path(&mut self, stream_id: u64, path: PathBuf) {
+        if let Some(track) = self.tracks.iter_mut().find(|t| t.stream_id == stream_id) {
+            track.path = Some(path);
+        }
+    }
+
+    /// Sum up sizes of all output files into [MergeReport::bytes_written].
+    pub(crate) async fn measure_output(&mut self) {
+        let mut paths: Vec<PathBuf> = self
+            .output
+            .iter()
+            .chain(self.parts.iter())
+            .chain(self.tracks.iter().filter_map(|t| t.path.as_ref()))
+            .cloned()
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        for path in paths {
+            if let Ok(metadata) = tokio::fs::metadata(&path).await {
+                self.bytes_written += metadata.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SegmentInfo;
+
+    fn segment(stream_id: u64, sequence: u64, success: bool) -> ConcatSegment {
+        ConcatSegment {
+            segment: SegmentInfo {
+                stream_id,
+                sequence,
+                file_name: format!("{stream_id}_{sequence}.ts"),
+                duration: Some(2.),
+                ..Default::default()
+            },
+            success,
+        }
+    }
+
+    #[test]
+    fn test_merge_report() {
+        let segments = [
+            segment(2, 1, true),
+            segment(1, 2, false),
+            segment(1, 1, true),
+            segment(1, 0, true),
+            segment(2, 0, false),
+        ];
+        let mut report = MergeReport::new(&segments);
+        report.set_track_path(2, PathBuf::from("output.vtt"));
+        report.set_output(PathBuf::from("output.mp4"));
+
+        assert_eq!(report.downloaded_segments, 3);
+        assert_eq!(report.duration, Some(4.));
+        assert_eq!(
+            report
+                .failed_segments
+                .iter()
+                .map(|s| s.file_name.as_str())
+                .collect::<Vec<_>>(),
+            ["1_2.ts", "2_0.ts"]
+        );
+        assert_eq!(report.tracks.len(), 2);
+        assert_eq!(report.tracks[0].segments, 2);
+        assert_eq!(report.tracks[0].path, Some(PathBuf::from("output.mp4")));
+        assert_eq!(report.tracks[1].path, Some(PathBuf::from("output.vtt")));
+        assert_eq!(report.tracks[1].duration, Some(2.));
+    }
+
+    #[test]
+    fn test_merge_report_gaps() {
+        let segments = [
+            segment(1, 0, true),
+            segment(1, 2, false),
+            segment(1, 1, false),
+            segment(1, 3, true),
+            segment(1, 4, false),
+            segment(2, 0, false),
+        ];
+        let report = MergeReport::new(&segments);
+
+        assert_eq!(
+            report.gaps,
+            [
+                Gap {
+                    stream_id: 1,
+                    sequence: 1,
+                    segments: 2,
+                    start: Some(2.),
+                    duration: Some(4.),
+                },
+                Gap {
+                    stream_id: 1,
+                    sequence: 4,
+                    segments: 1,
+                    start: Some(8.),
+                    duration: Some(2.),
+                },
+                Gap {
+                    stream_id: 2,
+                    sequence: 0,
+                    segments: 1,
+                    start: Some(0.),
+                    duration: Some(2.),
+                },
+            ]
+        );
+    }
+}
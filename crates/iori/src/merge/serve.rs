@@ -0,0 +1,753 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use super::{
+    FailurePolicy, MergeReport, Merger,
+    concat::ConcatSegment,
+    playlist::{
+        MediaPlaylist, PlaylistSegment, PlaylistStream, is_fragmented_mp4, master_playlist,
+    },
+};
+use crate::{
+    InitialSegment, IoriResult, SegmentFormat, SegmentInfo, SegmentType,
+    cache::{CacheSource, DynCacheSource},
+    util::mp4::split_init_segment,
+};
+
+/// Maximum size of an HTTP request head.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Clients are considered to have finished playing after no request for this long.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ServeMerger serves downloaded segments over HTTP as a live HLS stream, so that several
+/// players like mpv, VLC or OBS can watch a stream while it is being downloaded.
+///
+/// - `/index.m3u8` is the master playlist. Video streams are variants, while audio and
+///   subtitle streams are renditions of them. If there is only one stream, its media
+///   playlist is served directly.
+/// - `/{stream_id}.m3u8` is the media playlist of a stream.
+///
+/// Segments are published in order of their sequence. A failed segment is skipped with
+/// `EXT-X-DISCONTINUITY`. Fragmented MP4 segments are served without their initialization
+/// segment, which is referenced by `EXT-X-MAP` instead.
+///
+/// Without a window, the playlist grows as an `EVENT` playlist and players can seek back to
+/// the start. With a window of `n` segments, only the latest `n` segments are listed, and
+/// older segments are removed from the cache unless `keep_segments` is set.
+///
+/// After downloading, [Merger::finish] keeps serving until clients stop requesting, or
+/// the linger timeout set by [ServeMerger::with_linger] passes. Only then is the cache
+/// cleared, which in a [TeeMerger](super::TeeMerger) only takes effect once all mergers have
+/// cleared it. The server stops when the merger is dropped.
+pub struct ServeMerger {
+    segments: Vec<ConcatSegment>,
+    state: Arc<Mutex<ServeState>>,
+    local_addr: SocketAddr,
+
+    /// Keep downloaded segments after they leave the window or the download finishes.
+    keep_segments: bool,
+    /// Longest time to keep serving after downloading finished.
+    linger: Duration,
+
+    server: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct ServeState {
+    streams: BTreeMap<u64, ServeStream>,
+    /// Cache to read segments from, set by the first call to the merger.
+    cache: Option<Arc<dyn DynCacheSource>>,
+    /// Number of segments to keep in media playlists. `None` to keep all segments.
+    window: Option<usize>,
+    /// Whether downloading has finished.
+    ended: bool,
+    /// Time of the last request from a client.
+    last_request: Option<Instant>,
+}
+
+struct ServeStream {
+    r#type: SegmentType,
+    /// Segments waiting for previous segments, `None` if failed to download.
+    pending: BTreeMap<u64, Option<ServeSegment>>,
+    /// Sequence of the next segment to publish.
+    next_sequence: u64,
+    /// Segments listed in the media playlist.
+    published: VecDeque<ServeSegment>,
+    /// Number of segments removed from the start of the playlist.
+    media_sequence: u64,
+    /// Number of discontinuities removed from the start of the playlist.
+    discontinuity_sequence: u64,
+    /// Whether segments are missing before the next published segment.
+    gap: bool,
+    /// Initialization segment of the last published segment.
+    last_init: Option<usize>,
+    /// Distinct initialization segments of fragmented MP4 segments.
+    inits: Vec<Arc<Vec<u8>>>,
+}
+
+struct ServeSegment {
+    segment: SegmentInfo,
+    /// Index of the initialization segment in [ServeStream::inits].
+    init: Option<usize>,
+    discontinuity: bool,
+}
+
+impl ServeMerger {
+    /// Bind an HTTP server to `addr` and start serving.
+    pub fn new(addr: SocketAddr, keep_segments: bool) -> IoriResult<Self> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let local_addr = listener.local_addr()?;
+
+        let state: Arc<Mutex<ServeState>> = Default::default();
+        let server = tokio::spawn(serve(listener, state.clone()));
+        tracing::info!("Serving stream at http://{local_addr}/index.m3u8");
+
+        Ok(Self {
+            segments: Vec::new(),
+            state,
+            local_addr,
+            keep_segments,
+            linger: Duration::from_secs(30),
+            server,
+        })
+    }
+
+    /// Keep serving for at most `linger` after downloading finished, while clients are
+    /// still requesting. Defaults to 30 seconds.
+    pub fn with_linger(mut self, linger: Duration) -> Self {
+        self.linger = linger;
+        self
+    }
+
+    /// Only keep the latest `segments` segments in media playlists.
+    pub fn with_window(self, segments: usize) -> Self {
+        self.state.lock().unwrap().window = Some(segments.max(1));
+        self
+    }
+
+    /// Address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Whether segments are removed from the cache once they leave the window.
+    pub(crate) fn invalidates_segments(&self) -> bool {
+        !self.keep_segments && self.state.lock().unwrap().window.is_some()
+    }
+
+    /// Publish a segment, or a failed segment if `segment` is `None`, and remove segments
+    /// which left the window from the cache.
+    async fn publish(
+        &mut self,
+        stream_id: u64,
+        sequence: u64,
+        segment: Option<(SegmentInfo, Option<Arc<Vec<u8>>>)>,
+        cache: impl CacheSource,
+    ) -> IoriResult<()> {
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            state.cache = Some(Arc::new(cache));
+            let window = state.window;
+            let stream = state
+                .streams
+                .entry(stream_id)
+                .or_insert_with(|| ServeStream::new(SegmentType::Unknown));
+            let segment = segment.map(|(segment, init)| {
+                stream.r#type = segment.r#type;
+                ServeSegment {
+                    init: init.map(|init| stream.add_init(init)),
+                    segment,
+                    discontinuity: false,
+                }
+            });
+            stream.push(sequence, segment, window)
+        };
+
+        if !self.keep_segments {
+            let cache = self.state.lock().unwrap().cache.clone().unwrap();
+            for segment in evicted {
+                cache.invalidate(&segment).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ServeMerger {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl Merger for ServeMerger {
+    type Result = MergeReport;
+
+    async fn update(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        // segments are read again for each request
+        cache.retain_segments();
+        self.segments.push(ConcatSegment {
+            segment: segment.clone(),
+            success: true,
+        });
+
+        let init = if is_fragmented_mp4(&segment.format) {
+            init_segment(&segment, &cache).await?
+        } else {
+            None
+        };
+
+        self.publish(
+            segment.stream_id,
+            segment.sequence,
+            Some((segment, init)),
+            cache,
+        )
+        .await
+    }
+
+    async fn fail(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        cache.invalidate(&segment).await?;
+        self.publish(segment.stream_id, segment.sequence, None, cache)
+            .await?;
+        self.segments.push(ConcatSegment {
+            segment,
+            success: false,
+        });
+        Ok(())
+    }
+
+    async fn finish(&mut self, cache: impl CacheSource) -> IoriResult<Self::Result> {
+        let mut report = MergeReport::new(&self.segments);
+        report.failure_policy = FailurePolicy::MergeWithGaps;
+        report.failure_policy.should_merge(&report)?;
+
+        {
+            let mut state = self.state.lock().unwrap();
+            for stream in state.streams.values_mut() {
+                stream.flush();
+            }
+            state.ended = true;
+        }
+        tracing::info!("Stream ended at http://{}/index.m3u8", self.local_addr);
+        self.linger().await;
+
+        if !self.keep_segments {
+            cache.clear().await?;
+            report.cache_cleared = true;
+        }
+        Ok(report)
+    }
+}
+
+impl ServeMerger {
+    /// Wait until clients have stopped requesting, or the linger timeout passes.
+    async fn linger(&self) {
+        let deadline = Instant::now() + self.linger;
+        loop {
+            let Some(last_request) = self.state.lock().unwrap().last_request else {
+                return;
+            };
+            let until = (last_request + DRAIN_TIMEOUT).min(deadline);
+            if Instant::now() >= until {
+                return;
+            }
+            tracing::debug!("Waiting for clients to finish playing...");
+            tokio::time::sleep_until(until).await;
+        }
+    }
+}
+
+/// Initialization segment of a fragmented MP4 segment.
+///
+/// Segments decrypted sample by sample are cached with a rewritten initialization segment,
+/// so it is read from the cache instead.
+async fn init_segment(
+    segment: &SegmentInfo,
+    cache: &impl CacheSource,
+) -> IoriResult<Option<Arc<Vec<u8>>>> {
+    let decrypted = segment
+        .key
+        .as_ref()
+        .is_some_and(|key| key.is_sample_encryption());
+    match &segment.initial_segment {
+        InitialSegment::None => Ok(None),
+        InitialSegment::Clear(init) if !decrypted => Ok(Some(init.clone())),
+        InitialSegment::Encrypted(init) if segment.key.is_none() => Ok(Some(init.clone())),
+        _ => {
+            let mut data = Vec::new();
+            cache
+                .open_reader(segment)
+                .await?
+                .read_to_end(&mut data)
+                .await?;
+            let (init, _) = split_init_segment(&data);
+            Ok(Some(Arc::new(init.to_vec())).filter(|init| !init.is_empty()))
+        }
+    }
+}
+
+impl ServeStream {
+    fn new(r#type: SegmentType) -> Self {
+        Self {
+            r#type,
+            pending: BTreeMap::new(),
+            next_sequence: 0,
+            published: VecDeque::new(),
+            media_sequence: 0,
+            discontinuity_sequence: 0,
+            gap: false,
+            last_init: None,
+            inits: Vec::new(),
+        }
+    }
+
+    /// Add an initialization segment, returning its index.
+    fn add_init(&mut self, init: Arc<Vec<u8>>) -> usize {
+        match self.inits.iter().position(|i| *i == init) {
+            Some(index) => index,
+            None => {
+                self.inits.push(init);
+                self.inits.len() - 1
+            }
+        }
+    }
+
+    /// Add a segment, and publish all segments which are in order.
+    ///
+    /// Returns segments removed from the playlist because of the window.
+    fn push(
+        &mut self,
+        sequence: u64,
+        segment: Option<ServeSegment>,
+        window: Option<usize>,
+    ) -> Vec<SegmentInfo> {
+        self.pending.insert(sequence, segment);
+        while let Some(segment) = self.pending.remove(&self.next_sequence) {
+            self.next_sequence += 1;
+            let Some(mut segment) = segment else {
+                self.gap = true;
+                continue;
+            };
+
+            // a new initialization segment usually starts a new period with different timestamps
+            let init_changed = self.last_init.is_some() && segment.init != self.last_init;
+            segment.discontinuity = (self.gap || init_changed) && !self.published.is_empty();
+            self.gap = false;
+            self.last_init = segment.init;
+            self.published.push_back(segment);
+        }
+
+        let mut evicted = Vec::new();
+        if let Some(window) = window {
+            while self.published.len() > window {
+                let segment = self.published.pop_front().unwrap();
+                self.media_sequence += 1;
+                // the next segment should start with its own EXT-X-DISCONTINUITY
+                if self.published.front().is_some_and(|s| s.discontinuity) {
+                    self.discontinuity_sequence += 1;
+                }
+                evicted.push(segment.segment);
+            }
+        }
+        evicted
+    }
+
+    /// Publish remaining segments, skipping sequences which were never received.
+    fn flush(&mut self) {
+        while let Some(&sequence) = self.pending.keys().next() {
+            if sequence != self.next_sequence {
+                self.gap = true;
+                self.next_sequence = sequence;
+            }
+            self.push(sequence, self.pending.remove(&sequence).unwrap(), None);
+        }
+    }
+}
+
+/// Generate the media playlist of a stream.
+fn media_playlist(stream_id: u64, stream: &ServeStream, window: bool, ended: bool) -> String {
+    let segments = stream.published.iter().map(|s| PlaylistSegment {
+        uri: format!(
+            "{stream_id}/{}.{}",
+            s.segment.sequence,
+            s.segment.format.as_ext()
+        ),
+        duration: s.segment.duration,
+        byte_range: None,
+        map: s
+            .init
+            .map(|init| format!("URI=\"{stream_id}/init-{init}.mp4\"")),
+        discontinuity: s.discontinuity,
+    });
+
+    MediaPlaylist {
+        segments: segments.collect(),
+        media_sequence: stream.media_sequence,
+        discontinuity_sequence: stream.discontinuity_sequence,
+        playlist_type: (!window).then_some("EVENT"),
+        ended,
+    }
+    .to_string()
+}
+
+/// Generate the master playlist, or `None` if there is only one stream.
+fn serve_master_playlist(streams: &BTreeMap<u64, ServeStream>) -> Option<String> {
+    let streams: Vec<_> = streams
+        .iter()
+        .map(|(stream_id, stream)| PlaylistStream {
+            stream_id: *stream_id,
+            r#type: stream.r#type,
+            uri: format!("{stream_id}.m3u8"),
+        })
+        .collect();
+    master_playlist(&streams)
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: status.as_bytes().to_vec(),
+        }
+    }
+
+    async fn write_to(&self, stream: &mut TcpStream, with_body: bool) -> IoriResult<()> {
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        if with_body {
+            stream.write_all(&self.body).await?;
+        }
+        stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+fn content_type(format: &SegmentFormat) -> &'static str {
+    match format {
+        SegmentFormat::Mpeg2TS => "video/mp2t",
+        SegmentFormat::Mp4 | SegmentFormat::Cmfv => "video/mp4",
+        SegmentFormat::M4a | SegmentFormat::Cmfa => "audio/mp4",
+        SegmentFormat::Aac => "audio/aac",
+        SegmentFormat::Raw(ext) | SegmentFormat::Other(ext) if ext == "vtt" => "text/vtt",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn serve(listener: TcpListener, state: Arc<Mutex<ServeState>>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::warn!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, state).await {
+                tracing::debug!("Failed to handle connection: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<ServeState>>) -> IoriResult<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Response::error("431 Request Header Fields Too Large")
+                .write_to(&mut stream, true)
+                .await;
+        }
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+
+    let response = match method {
+        "GET" | "HEAD" => route(path, &state).await,
+        _ => Response::error("405 Method Not Allowed"),
+    };
+    response.write_to(&mut stream, method != "HEAD").await
+}
+
+async fn route(path: &str, state: &Mutex<ServeState>) -> Response {
+    const PLAYLIST: &str = "application/vnd.apple.mpegurl";
+
+    state.lock().unwrap().last_request = Some(Instant::now());
+    let path = path.trim_start_matches('/');
+    if path.is_empty() || path == "index.m3u8" {
+        let state = state.lock().unwrap();
+        let master = serve_master_playlist(&state.streams);
+        return match (master, state.streams.iter().next()) {
+            (Some(master), _) => Response::ok(PLAYLIST, master),
+            (None, Some((stream_id, stream))) => Response::ok(
+                PLAYLIST,
+                media_playlist(*stream_id, stream, state.window.is_some(), state.ended),
+            ),
+            (None, None) => Response::error("503 Service Unavailable"),
+        };
+    }
+
+    if let Some(stream_id) = path.strip_suffix(".m3u8") {
+        let state = state.lock().unwrap();
+        let stream = stream_id
+            .parse()
+            .ok()
+            .and_then(|id| state.streams.get(&id).map(|s| (id, s)));
+        return match stream {
+            Some((stream_id, stream)) => Response::ok(
+                PLAYLIST,
+                media_playlist(stream_id, stream, state.window.is_some(), state.ended),
+            ),
+            None => Response::error("404 Not Found"),
+        };
+    }
+
+    let Some((stream_id, file_name)) = path.split_once('/') else {
+        return Response::error("404 Not Found");
+    };
+    let Ok(stream_id) = stream_id.parse::<u64>() else {
+        return Response::error("404 Not Found");
+    };
+
+    if let Some(init) = file_name
+        .strip_prefix("init-")
+        .and_then(|f| f.strip_suffix(".mp4"))
+    {
+        let state = state.lock().unwrap();
+        let init = init
+            .parse::<usize>()
+            .ok()
+            .and_then(|init| state.streams.get(&stream_id)?.inits.get(init));
+        return match init {
+            Some(init) => Response::ok("video/mp4", init.as_slice()),
+            None => Response::error("404 Not Found"),
+        };
+    }
+
+    // only segments in the playlist are served, as others may have been removed
+    let found = {
+        let state = state.lock().unwrap();
+        let sequence = file_name
+            .split_once('.')
+            .and_then(|(sequence, _)| sequence.parse::<u64>().ok());
+        let segment = state.streams.get(&stream_id).and_then(|stream| {
+            stream
+                .published
+                .iter()
+                .find(|s| Some(s.segment.sequence) == sequence)
+        });
+        segment
+            .zip(state.cache.clone())
+            .map(|(segment, cache)| (segment.segment.clone(), cache))
+    };
+    let Some((segment, cache)) = found else {
+        return Response::error("404 Not Found");
+    };
+
+    let mut data = Vec::new();
+    let read = async {
+        cache
+            .open_reader(&segment)
+            .await?
+            .read_to_end(&mut data)
+            .await?;
+        IoriResult::Ok(())
+    };
+    if let Err(e) = read.await {
+        tracing::debug!("Failed to read segment {}: {e}", segment.file_name);
+        return Response::error("404 Not Found");
+    }
+
+    if is_fragmented_mp4(&segment.format) {
+        let (_, media) = split_init_segment(&data);
+        data = media.to_vec();
+    }
+    Response::ok(content_type(&segment.format), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::memory::MemoryCacheSource;
+
+    fn segment(sequence: u64, init: Option<usize>) -> Option<ServeSegment> {
+        Some(ServeSegment {
+            segment: SegmentInfo {
+                sequence,
+                format: SegmentFormat::Mp4,
+                duration: Some(2.),
+                ..Default::default()
+            },
+            init,
+            discontinuity: false,
+        })
+    }
+
+    #[test]
+    fn test_media_playlist() {
+        let mut stream = ServeStream::new(SegmentType::Video);
+        assert!(stream.push(1, segment(1, Some(0)), Some(2)).is_empty());
+        assert!(stream.push(0, segment(0, Some(0)), Some(2)).is_empty());
+        assert!(stream.push(2, None, Some(2)).is_empty());
+        let evicted = stream.push(3, segment(3, Some(0)), Some(2));
+        assert_eq!(evicted.iter().map(|s| s.sequence).collect::<Vec<_>>(), [0]);
+
+        assert_eq!(
+            media_playlist(1, &stream, true, false),
+            "#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:1
+#EXT-X-DISCONTINUITY-SEQUENCE:0
+#EXT-X-MAP:URI=\"1/init-0.mp4\"
+#EXTINF:2.000,
+1/1.mp4
+#EXT-X-DISCONTINUITY
+#EXTINF:2.000,
+1/3.mp4
+"
+        );
+
+        // the discontinuity leaves the window
+        stream.push(4, segment(4, Some(0)), Some(2));
+        stream.flush();
+        assert_eq!(
+            media_playlist(1, &stream, true, true),
+            "#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:2
+#EXT-X-DISCONTINUITY-SEQUENCE:1
+#EXT-X-MAP:URI=\"1/init-0.mp4\"
+#EXTINF:2.000,
+1/3.mp4
+#EXTINF:2.000,
+1/4.mp4
+#EXT-X-ENDLIST
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_merger() -> IoriResult<()> {
+        let cache = Arc::new(MemoryCacheSource::new());
+        let mut merger =
+            ServeMerger::new("127.0.0.1:0".parse().unwrap(), true)?.with_linger(Duration::ZERO);
+        let url = format!("http://{}", merger.local_addr());
+
+        for sequence in 0..2 {
+            let segment = SegmentInfo {
+                stream_id: 1,
+                sequence,
+                file_name: format!("{sequence}.ts"),
+                duration: Some(2.),
+                ..Default::default()
+            };
+            let mut writer = CacheSource::open_writer(&cache, &segment).await?.unwrap();
+            writer
+                .write_all(format!("Segment {sequence}").as_bytes())
+                .await?;
+            writer.shutdown().await?;
+            drop(writer);
+            merger.update(segment, cache.clone()).await?;
+        }
+
+        let playlist = reqwest::get(format!("{url}/index.m3u8"))
+            .await?
+            .text()
+            .await?;
+        assert!(playlist.contains("1/1.ts"));
+        assert!(!playlist.contains("#EXT-X-ENDLIST"));
+
+        let segment = reqwest::get(format!("{url}/1/1.ts")).await?.text().await?;
+        assert_eq!(segment, "Segment 1");
+        let missing = reqwest::get(format!("{url}/1/2.ts")).await?;
+        assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+
+        merger.finish(cache.clone()).await?;
+        let playlist = reqwest::get(format!("{url}/1.m3u8")).await?.text().await?;
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_merger_linger() -> IoriResult<()> {
+        let cache = Arc::new(MemoryCacheSource::new());
+        let mut merger = ServeMerger::new("127.0.0.1:0".parse().unwrap(), false)?
+            .with_linger(Duration::from_millis(500));
+        let url = format!("http://{}", merger.local_addr());
+
+        let segment = SegmentInfo {
+            stream_id: 1,
+            file_name: "0.ts".to_string(),
+            duration: Some(2.),
+            ..Default::default()
+        };
+        let mut writer = CacheSource::open_writer(&cache, &segment).await?.unwrap();
+        writer.write_all(b"Segment 0").await?;
+        writer.shutdown().await?;
+        drop(writer);
+        merger.update(segment, cache.clone()).await?;
+        reqwest::get(format!("{url}/index.m3u8")).await?;
+
+        // segments are still served while the merger lingers after the download
+        let started = Instant::now();
+        let (report, segment) = tokio::join!(merger.finish(cache.clone()), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            reqwest::get(format!("{url}/1/0.ts")).await?.text().await
+        });
+        assert_eq!(segment?, "Segment 0");
+        assert!(report?.cache_cleared);
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert!(cache.into_inner().lock().unwrap().is_empty());
+
+        Ok(())
+    }
+}
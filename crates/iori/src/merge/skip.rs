@@ -1,22 +1,45 @@
-use super::Merger;
+use super::{concat::ConcatSegment, MergeReport, Merger};
 use crate::{cache::CacheSource, error::IoriResult, SegmentInfo};
 
-pub struct SkipMerger;
+/// SkipMerger keeps downloaded segments in the cache without merging them.
+///
+/// The [MergeReport] tells merging was skipped, with the segments which were downloaded or
+/// failed.
+#[derive(Default)]
+pub struct SkipMerger {
+    segments: Vec<ConcatSegment>,
+}
+
+impl SkipMerger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
 
 impl Merger for SkipMerger {
-    type Result = ();
+    type Result = MergeReport;
 
-    async fn update(&mut self, _segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
+    async fn update(&mut self, segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
+        self.segments.push(ConcatSegment {
+            segment,
+            success: true,
+        });
         Ok(())
     }
 
-    async fn fail(&mut self, _segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
+    async fn fail(&mut self, segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
+        self.segments.push(ConcatSegment {
+            segment,
+            success: false,
+        });
         Ok(())
     }
 
     async fn finish(&mut self, cache: impl CacheSource) -> IoriResult<Self::Result> {
         tracing::info!("Skip merging. Please merge video chunks manually.");
         tracing::info!("Temporary files are located at {:?}", cache.location_hint());
-        Ok(())
+        let mut report = MergeReport::new(&self.segments);
+        report.skipped = true;
+        Ok(report)
     }
 }
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+
+use super::{IoriMerger, MergeReport, Merger};
+use crate::{
+    IoriResult, SegmentInfo,
+    cache::{CacheSource, CacheSourceReader, CacheSourceWriter, DynCacheSource},
+    metadata::Metadata,
+};
+
+/// TeeMerger fans out segments to several mergers, for example piping to a player with
+/// [PipeMerger](super::PipeMerger) while archiving with [AutoMerger](super::AutoMerger).
+///
+/// The cache is shared by all mergers, and segments are kept after they are read. A segment
+/// is only invalidated after every merger has invalidated it, and the cache is only cleared
+/// after every merger has cleared it. A merger which keeps its segments until it finishes,
+/// like a [PipeMerger](super::PipeMerger) without `recycle`, keeps them in the cache for all
+/// mergers. Failed segments are invalidated at once.
+///
+/// The report of the primary merger is returned, which is the first one unless set with
+/// [TeeMerger::with_primary].
+pub struct TeeMerger {
+    mergers: Vec<IoriMerger>,
+    /// Index of the merger whose report is returned.
+    primary: usize,
+    state: Arc<Mutex<TeeState>>,
+}
+
+#[derive(Default)]
+struct TeeState {
+    /// Number of mergers which have invalidated each segment, by stream id and sequence.
+    ///
+    /// Only counted if every merger invalidates segments while downloading, so that entries
+    /// are removed once all mergers have invalidated a segment.
+    invalidated: HashMap<(u64, u64), usize>,
+    /// Number of mergers which have cleared the cache.
+    cleared: usize,
+}
+
+impl TeeMerger {
+    pub fn new(mergers: Vec<IoriMerger>) -> Self {
+        Self {
+            mergers,
+            primary: 0,
+            state: Default::default(),
+        }
+    }
+
+    /// Return the report of the merger at `index`, like the merger writing the output file.
+    pub fn with_primary(mut self, index: usize) -> Self {
+        self.primary = index;
+        self
+    }
+
+    /// Set metadata of all mergers which support it.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.mergers = self
+            .mergers
+            .into_iter()
+            .map(|merger| merger.with_metadata(metadata.clone()))
+            .collect();
+        self
+    }
+
+    /// Whether every merger removes segments from the cache while downloading.
+    pub(crate) fn invalidates_segments(&self) -> bool {
+        self.mergers.iter().all(IoriMerger::invalidates_segments)
+    }
+
+    fn cache(&self, cache: impl CacheSource) -> TeeCache {
+        // a segment might be read by several mergers
+        cache.retain_segments();
+        TeeCache {
+            inner: Arc::new(cache),
+            state: self.state.clone(),
+            consumers: self.mergers.len(),
+            invalidation: if self.invalidates_segments() {
+                Invalidation::Counted
+            } else {
+                Invalidation::Deferred
+            },
+        }
+    }
+}
+
+impl Merger for TeeMerger {
+    type Result = MergeReport;
+
+    async fn update(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        let cache = self.cache(cache);
+        for merger in self.mergers.iter_mut() {
+            let update: BoxFuture<'_, _> = Box::pin(merger.update(segment.clone(), cache.clone()));
+            update.await?;
+        }
+        Ok(())
+    }
+
+    async fn fail(&mut self, segment: SegmentInfo, cache: impl CacheSource) -> IoriResult<()> {
+        let cache = TeeCache {
+            invalidation: Invalidation::Ignored,
+            ..self.cache(cache)
+        };
+        for merger in self.mergers.iter_mut() {
+            let fail: BoxFuture<'_, _> = Box::pin(merger.fail(segment.clone(), cache.clone()));
+            fail.await?;
+        }
+
+        // no merger needs a failed segment
+        cache.inner.invalidate(&segment).await
+    }
+
+    async fn finish(&mut self, cache: impl CacheSource) -> IoriResult<Self::Result> {
+        let cache = self.cache(cache);
+        let mut primary = MergeReport::default();
+        for (index, merger) in self.mergers.iter_mut().enumerate() {
+            let finish: BoxFuture<'_, _> = Box::pin(merger.finish(cache.clone()));
+            let report = finish.await?;
+            if index == self.primary {
+                primary = report;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.invalidated.clear();
+        primary.cache_cleared = state.cleared >= self.mergers.len();
+        Ok(primary)
+    }
+}
+
+/// Cache shared by mergers of a [TeeMerger], which forwards invalidation and clearing
+/// only after all mergers have done so.
+#[derive(Clone)]
+struct TeeCache {
+    inner: Arc<dyn DynCacheSource>,
+    state: Arc<Mutex<TeeState>>,
+    /// Number of mergers sharing the cache.
+    consumers: usize,
+    invalidation: Invalidation,
+}
+
+/// How [TeeCache] handles invalidation of a segment by a merger.
+#[derive(Clone, Copy)]
+enum Invalidation {
+    /// Invalidate the segment after every merger has invalidated it.
+    Counted,
+    /// Keep the segment until the cache is cleared, as some merger keeps segments until it
+    /// finishes.
+    Deferred,
+    /// Leave invalidation to the [TeeMerger], for failed segments.
+    Ignored,
+}
+
+impl CacheSource for TeeCache {
+    fn open_writer(
+        &self,
+        segment: &SegmentInfo,
+    ) -> impl Future<Output = IoriResult<Option<CacheSourceWriter>>> + Send {
+        self.inner.open_writer(segment)
+    }
+
+    fn open_reader(
+        &self,
+        segment: &SegmentInfo,
+    ) -> impl Future<Output = IoriResult<CacheSourceReader>> + Send {
+        self.inner.open_reader(segment)
+    }
+
+    fn segment_path(&self, segment: &SegmentInfo) -> impl Future<Output = Option<PathBuf>> + Send {
+        self.inner.segment_path(segment)
+    }
+
+    async fn invalidate(&self, segment: &SegmentInfo) -> IoriResult<()> {
+        if !matches!(self.invalidation, Invalidation::Counted) {
+            return Ok(());
+        }
+
+        let consumed = {
+            let mut state = self.state.lock().unwrap();
+            let key = (segment.stream_id, segment.sequence);
+            let count = state.invalidated.entry(key).or_default();
+            *count += 1;
+            let consumed = *count >= self.consumers;
+            if consumed {
+                state.invalidated.remove(&key);
+            }
+            consumed
+        };
+
+        if consumed {
+            self.inner.invalidate(segment).await?;
+        }
+        Ok(())
+    }
+
+    async fn clear(&self) -> IoriResult<()> {
+        let consumed = {
+            let mut state = self.state.lock().unwrap();
+            state.cleared += 1;
+            state.cleared == self.consumers
+        };
+
+        if consumed {
+            self.inner.clear().await?;
+        }
+        Ok(())
+    }
+
+    fn retain_segments(&self) {
+        self.inner.retain_segments()
+    }
+
+    fn record_segment(&self, segment: &SegmentInfo) -> impl Future<Output = IoriResult<()>> + Send {
+        self.inner.record_segment(segment)
+    }
+
+    fn location_hint(&self) -> Option<String> {
+        self.inner.location_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::cache::memory::MemoryCacheSource;
+
+    #[tokio::test]
+    async fn test_tee_cache() -> IoriResult<()> {
+        let inner = Arc::new(MemoryCacheSource::new());
+        let entries = inner.clone().into_inner();
+        let merger = TeeMerger::new(vec![IoriMerger::pipe(true), IoriMerger::pipe(true)]);
+        let cache = merger.cache(inner);
+
+        let segment = SegmentInfo::default();
+        let mut writer = CacheSource::open_writer(&cache, &segment).await?.unwrap();
+        writer.write_all(b"data").await?;
+        writer.shutdown().await?;
+        drop(writer);
+
+        // the segment is kept until both mergers have invalidated it
+        CacheSource::invalidate(&cache, &segment).await?;
+        assert_eq!(entries.lock().unwrap().len(), 1);
+        CacheSource::invalidate(&cache, &segment).await?;
+        assert_eq!(entries.lock().unwrap().len(), 0);
+        assert!(merger.state.lock().unwrap().invalidated.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tee_cache_deferred() -> IoriResult<()> {
+        let inner = Arc::new(MemoryCacheSource::new());
+        let entries = inner.clone().into_inner();
+        let merger = TeeMerger::new(vec![IoriMerger::pipe(true), IoriMerger::skip()]);
+        let cache = merger.cache(inner);
+
+        let segment = SegmentInfo::default();
+        let mut writer = CacheSource::open_writer(&cache, &segment).await?.unwrap();
+        writer.write_all(b"data").await?;
+        writer.shutdown().await?;
+        drop(writer);
+
+        // the skip merger keeps segments, so invalidations are not counted
+        CacheSource::invalidate(&cache, &segment).await?;
+        assert_eq!(entries.lock().unwrap().len(), 1);
+        assert!(merger.state.lock().unwrap().invalidated.is_empty());
+
+        CacheSource::clear(&cache).await?;
+        CacheSource::clear(&cache).await?;
+        assert_eq!(entries.lock().unwrap().len(), 0);
+
+        Ok(())
+    }
+}
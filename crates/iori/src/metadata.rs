@@ -0,0 +1,293 @@
+//! Metadata of the downloaded resource.
+//!
+//! [Metadata] is provided by inspectors and written into the merged output: as MP4 `ilst`
+//! items, Matroska tags or ffmpeg format metadata. Outputs which can not carry tags, like
+//! MPEG-TS, get a sidecar `.json` file instead. [Chapter]s are written as Matroska chapters
+//! or a Nero `chpl` box in MP4.
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    IoriResult,
+    chapter::{Chapter, to_mp4_chpl},
+    util::mp4::write_box,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    /// Title of the program or video.
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Channel, room or provider of the program.
+    pub channel: Option<String>,
+    /// When the program started.
+    pub start_time: Option<DateTime<Utc>>,
+    /// URL of the page the resource was inspected from.
+    pub source_url: Option<String>,
+    /// Identifier of the program on its platform, for example `lv123456789`.
+    pub platform_id: Option<String>,
+    /// Chapters built from timed events of the stream.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chapters: Vec<Chapter>,
+}
+
+/// A tag written into the output.
+struct Tag {
+    /// Key of ffmpeg format metadata
+    ffmpeg: &'static str,
+    /// Name of Matroska simple tag
+    matroska: &'static str,
+    /// Type of MP4 `ilst` item
+    mp4: &'static [u8; 4],
+    value: String,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Fill missing fields with fields of `other`.
+    pub fn merge(&mut self, other: Metadata) {
+        self.title = self.title.take().or(other.title);
+        self.description = self.description.take().or(other.description);
+        self.channel = self.channel.take().or(other.channel);
+        self.start_time = self.start_time.take().or(other.start_time);
+        self.source_url = self.source_url.take().or(other.source_url);
+        self.platform_id = self.platform_id.take().or(other.platform_id);
+        if self.chapters.is_empty() {
+            self.chapters = other.chapters;
+        }
+    }
+
+    /// Whether there is any field written as a tag.
+    pub(crate) fn has_tags(&self) -> bool {
+        !self.tags().is_empty()
+    }
+
+    fn tags(&self) -> Vec<Tag> {
+        let fields = [
+            ("title", "TITLE", b"\xa9nam", self.title.clone()),
+            (
+                "description",
+                "DESCRIPTION",
+                b"desc",
+                self.description.clone(),
+            ),
+            ("artist", "ARTIST", b"\xa9ART", self.channel.clone()),
+            (
+                "date",
+                "DATE_RECORDED",
+                b"\xa9day",
+                self.start_time.map(|t| t.to_rfc3339()),
+            ),
+            ("comment", "URL", b"\xa9cmt", self.source_url.clone()),
+            (
+                "episode_id",
+                "CATALOG_NUMBER",
+                b"tven",
+                self.platform_id.clone(),
+            ),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(ffmpeg, matroska, mp4, value)| {
+                Some(Tag {
+                    ffmpeg,
+                    matroska,
+                    mp4,
+                    value: value?,
+                })
+            })
+            .collect()
+    }
+
+    /// Key-value pairs for ffmpeg format metadata.
+    pub(crate) fn ffmpeg_tags(&self) -> Vec<(&'static str, String)> {
+        self.tags()
+            .into_iter()
+            .map(|tag| (tag.ffmpeg, tag.value))
+            .collect()
+    }
+
+    /// Global tags in the XML format of `mkvmerge --global-tags`.
+    pub(crate) fn to_matroska_tags(&self) -> String {
+        let mut xml = String::new();
+        writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        writeln!(xml, "<Tags>").unwrap();
+        writeln!(xml, "  <Tag>").unwrap();
+        writeln!(xml, "    <Targets />").unwrap();
+        for tag in self.tags() {
+            writeln!(xml, "    <Simple>").unwrap();
+            writeln!(xml, "      <Name>{}</Name>", tag.matroska).unwrap();
+            writeln!(
+                xml,
+                "      <String>{}</String>",
+                quick_xml::escape::escape(tag.value.as_str())
+            )
+            .unwrap();
+            writeln!(xml, "    </Simple>").unwrap();
+        }
+        writeln!(xml, "  </Tag>").unwrap();
+        writeln!(xml, "</Tags>").unwrap();
+        xml
+    }
+
+    /// An MP4 `udta` box with iTunes-style `ilst` items and chapters, or an empty vector if
+    /// there is no metadata.
+    pub(crate) fn to_mp4_udta(&self) -> Vec<u8> {
+        let tags = self.tags();
+        if tags.is_empty() && self.chapters.is_empty() {
+            return Vec::new();
+        }
+
+        let mut udta = Vec::new();
+        if !tags.is_empty() {
+            Self::write_mp4_meta(&mut udta, tags);
+        }
+        if !self.chapters.is_empty() {
+            udta.extend_from_slice(&to_mp4_chpl(&self.chapters));
+        }
+
+        let mut output = Vec::new();
+        write_box(&mut output, b"udta", &udta);
+        output
+    }
+
+    /// Write a `meta` box with `ilst` items of `tags`.
+    fn write_mp4_meta(udta: &mut Vec<u8>, tags: Vec<Tag>) {
+        let mut ilst = Vec::new();
+        for tag in tags {
+            // type indicator 1 for UTF-8 text, and locale 0
+            let mut data = 1u32.to_be_bytes().to_vec();
+            data.extend_from_slice(&0u32.to_be_bytes());
+            data.extend_from_slice(tag.value.as_bytes());
+
+            let mut item = Vec::new();
+            write_box(&mut item, b"data", &data);
+            write_box(&mut ilst, tag.mp4, &item);
+        }
+
+        // version and flags of the full box, followed by the handler
+        let mut meta = 0u32.to_be_bytes().to_vec();
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(b"mdir");
+        hdlr.extend_from_slice(b"appl");
+        hdlr.extend_from_slice(&[0; 9]);
+        write_box(&mut meta, b"hdlr", &hdlr);
+        write_box(&mut meta, b"ilst", &ilst);
+        write_box(udta, b"meta", &meta);
+    }
+
+    /// Write the metadata to a `.json` file next to `output`, returning the file path.
+    ///
+    /// Nothing is written if there is no metadata, or if `output` is a `.json` file itself.
+    pub async fn write_sidecar(&self, output: &Path) -> IoriResult<Option<PathBuf>> {
+        let path = output.with_extension("json");
+        if self.is_empty() || path == output {
+            return Ok(None);
+        }
+
+        tokio::fs::write(&path, serde_json::to_vec_pretty(self)?).await?;
+        tracing::info!("Metadata saved to {}", path.display());
+        Ok(Some(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::mp4::find_box;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            title: Some("Live & Talk".to_string()),
+            channel: Some("Room".to_string()),
+            start_time: DateTime::from_timestamp(1_700_000_000, 0),
+            platform_id: Some("lv1".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_matroska_tags() {
+        let xml = metadata().to_matroska_tags();
+        assert!(xml.contains("<Name>TITLE</Name>\n      <String>Live &amp; Talk</String>"));
+        assert!(xml.contains("<String>2023-11-14T22:13:20+00:00</String>"));
+        assert!(!xml.contains("DESCRIPTION"));
+    }
+
+    #[test]
+    fn test_mp4_udta() {
+        assert!(Metadata::default().to_mp4_udta().is_empty());
+
+        let udta = metadata().to_mp4_udta();
+        let udta = find_box(&udta, b"udta").unwrap();
+        let meta = udta.find_child(b"meta").unwrap();
+        let hdlr = meta.children(4).find(|b| &b.r#type == b"hdlr").unwrap();
+        assert_eq!(&hdlr.data[8..12], b"mdir");
+        let ilst = meta.children(4).find(|b| &b.r#type == b"ilst").unwrap();
+        let items: Vec<_> = ilst.children(0).map(|b| b.r#type).collect();
+        assert_eq!(items, [*b"\xa9nam", *b"\xa9ART", *b"\xa9day", *b"tven"]);
+
+        let title = ilst
+            .children(0)
+            .next()
+            .unwrap()
+            .find_child(b"data")
+            .unwrap();
+        assert_eq!(&title.data[8..], "Live & Talk".as_bytes());
+        assert!(udta.find_child(b"chpl").is_none());
+
+        let metadata = Metadata {
+            chapters: vec![Chapter {
+                start: 0.,
+                end: 10.,
+                title: "Program".to_string(),
+            }],
+            ..Default::default()
+        };
+        let udta = metadata.to_mp4_udta();
+        let udta = find_box(&udta, b"udta").unwrap();
+        assert!(udta.find_child(b"meta").is_none());
+        assert!(udta.find_child(b"chpl").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_write_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.ts");
+        let path = metadata().write_sidecar(&output).await.unwrap().unwrap();
+        assert_eq!(path, dir.path().join("output.json"));
+        let saved: Metadata = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!(saved, metadata());
+
+        let output = dir.path().join("danmaku.json");
+        assert!(metadata().write_sidecar(&output).await.unwrap().is_none());
+        assert!(
+            Metadata::default()
+                .write_sidecar(&dir.path().join("empty.ts"))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut metadata = metadata();
+        metadata.merge(Metadata {
+            title: Some("Other".to_string()),
+            source_url: Some("https://example.com".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(metadata.title.as_deref(), Some("Live & Talk"));
+        assert_eq!(metadata.source_url.as_deref(), Some("https://example.com"));
+    }
+}
@@ -0,0 +1,205 @@
+//! Processing of segment data between fetching and caching.
+//!
+//! A source fetches a segment, prepends its initialization segment and decrypts it. The
+//! downloader then passes the data through a chain of [SegmentProcessor]s, in the order
+//! they are added, before writing it into the cache. Processors can fix, validate or
+//! inspect segments without changing the source, for example to strip junk data added by
+//! a site. An error from a processor fails the attempt, and the segment is fetched again.
+mod timed_metadata;
+mod validate;
+pub use timed_metadata::TimedMetadataProcessor;
+pub use validate::SegmentValidator;
+
+use std::{future::Future, sync::Arc};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{IoriResult, SegmentInfo, StreamingSource, util::id3::id3_tags_size};
+
+pub trait SegmentProcessor: Send + Sync + 'static {
+    /// Process data of a segment, returning the data to pass to the next processor.
+    fn process(
+        &self,
+        segment: &SegmentInfo,
+        data: Vec<u8>,
+    ) -> impl Future<Output = IoriResult<Vec<u8>>> + Send;
+
+    /// Whether the processor handles the segment, usually decided by its type and format.
+    ///
+    /// Segments which no processor handles are written into the cache as they are fetched,
+    /// without buffering them in memory.
+    fn applies_to(&self, _segment: &SegmentInfo) -> bool {
+        true
+    }
+}
+
+/// Synchronous processors can be written as closures.
+impl<F> SegmentProcessor for F
+where
+    F: Fn(&SegmentInfo, Vec<u8>) -> IoriResult<Vec<u8>> + Send + Sync + 'static,
+{
+    async fn process(&self, segment: &SegmentInfo, data: Vec<u8>) -> IoriResult<Vec<u8>> {
+        self(segment, data)
+    }
+}
+
+/// Object-safe version of [SegmentProcessor], for chains of processors of any type.
+trait DynSegmentProcessor: Send + Sync {
+    fn process<'a>(
+        &'a self,
+        segment: &'a SegmentInfo,
+        data: Vec<u8>,
+    ) -> BoxFuture<'a, IoriResult<Vec<u8>>>;
+
+    fn applies_to(&self, segment: &SegmentInfo) -> bool;
+}
+
+impl<P> DynSegmentProcessor for P
+where
+    P: SegmentProcessor,
+{
+    fn process<'a>(
+        &'a self,
+        segment: &'a SegmentInfo,
+        data: Vec<u8>,
+    ) -> BoxFuture<'a, IoriResult<Vec<u8>>> {
+        Box::pin(SegmentProcessor::process(self, segment, data))
+    }
+
+    fn applies_to(&self, segment: &SegmentInfo) -> bool {
+        SegmentProcessor::applies_to(self, segment)
+    }
+}
+
+/// A chain of [SegmentProcessor]s, applied in the order they are added.
+#[derive(Clone, Default)]
+pub struct SegmentProcessors(Vec<Arc<dyn DynSegmentProcessor>>);
+
+impl SegmentProcessors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, processor: impl SegmentProcessor) {
+        self.0.push(Arc::new(processor));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether any processor handles the segment.
+    pub fn applies_to(&self, segment: &SegmentInfo) -> bool {
+        self.0.iter().any(|processor| processor.applies_to(segment))
+    }
+
+    /// Pass data of a segment through all processors which handle it.
+    pub async fn process(&self, segment: &SegmentInfo, mut data: Vec<u8>) -> IoriResult<Vec<u8>> {
+        for processor in self.0.iter() {
+            if processor.applies_to(segment) {
+                data = processor.process(segment, data).await?;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Fetch a segment from the source, and write the processed data into the writer.
+    ///
+    /// If no processor handles the segment, it is written into the writer directly.
+    pub(crate) async fn fetch_segment<S, W>(
+        &self,
+        source: &S,
+        segment: &S::Segment,
+        info: &SegmentInfo,
+        writer: &mut W,
+    ) -> IoriResult<()>
+    where
+        S: StreamingSource,
+        W: AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        if !self.applies_to(info) {
+            return source.fetch_segment(segment, writer).await;
+        }
+
+        let mut data = Vec::new();
+        source.fetch_segment(segment, &mut data).await?;
+        let data = self.process(info, data).await?;
+        writer.write_all(&data).await?;
+        Ok(())
+    }
+}
+
+/// Strip ID3 tags prepended to MPEG-TS segments, which some sites add before the first
+/// TS packet. Other segments are not changed.
+pub struct StripId3Processor;
+
+impl SegmentProcessor for StripId3Processor {
+    async fn process(&self, _segment: &SegmentInfo, mut data: Vec<u8>) -> IoriResult<Vec<u8>> {
+        let offset = id3_tags_size(&data);
+        if offset > 0 && data.get(offset) == Some(&0x47) {
+            data.drain(..offset);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IoriError;
+
+    #[tokio::test]
+    async fn test_processors() -> IoriResult<()> {
+        let mut processors = SegmentProcessors::new();
+        processors.push(StripId3Processor);
+        processors.push(
+            |segment: &SegmentInfo, mut data: Vec<u8>| -> IoriResult<Vec<u8>> {
+                data.push(segment.sequence as u8);
+                Ok(data)
+            },
+        );
+
+        let segment = SegmentInfo {
+            sequence: 3,
+            ..Default::default()
+        };
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+        data.extend_from_slice(&[0x47, 0x40]);
+        assert_eq!(processors.process(&segment, data).await?, [0x47, 0x40, 3]);
+
+        // not MPEG-TS after the tag
+        let data = b"ID3\x04\x00\x00\x00\x00\x00\x00\xff\xf1".to_vec();
+        assert_eq!(
+            processors.process(&segment, data.clone()).await?,
+            [data, vec![3]].concat()
+        );
+
+        processors.push(|_: &SegmentInfo, _: Vec<u8>| -> IoriResult<Vec<u8>> {
+            Err(IoriError::SegmentProcessing("invalid".to_string()))
+        });
+        assert!(processors.process(&segment, Vec::new()).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processors_applies_to() -> IoriResult<()> {
+        let mut processors = SegmentProcessors::new();
+        processors.push(SegmentValidator::new());
+
+        // subtitles are not validated, so they are not buffered either
+        let subtitle = SegmentInfo {
+            r#type: crate::SegmentType::Subtitle,
+            ..Default::default()
+        };
+        assert!(!processors.applies_to(&subtitle));
+        assert!(processors.process(&subtitle, Vec::new()).await.is_ok());
+
+        let video = SegmentInfo::default();
+        assert!(processors.applies_to(&video));
+        assert!(processors.process(&video, Vec::new()).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::PathBuf;
+
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+
+use super::SegmentProcessor;
+use crate::{IoriResult, SegmentInfo, SegmentType, timed_metadata::read_timed_metadata};
+
+/// TimedMetadataProcessor extracts timed metadata of fetched segments into a JSON-lines
+/// file, one [TimedMetadata](crate::timed_metadata::TimedMetadata) per line.
+///
+/// Segment data is passed on unmodified. Entries are written as soon as each segment is
+/// fetched, in the order of fetching. The file is only created if any entry is found.
+///
+/// It should be added after processors which may reject a segment, like
+/// [SegmentValidator](super::SegmentValidator), so that entries of a rejected attempt are
+/// not written twice.
+pub struct TimedMetadataProcessor {
+    output_file: PathBuf,
+    writer: Mutex<Option<File>>,
+}
+
+impl TimedMetadataProcessor {
+    pub fn new(output_file: PathBuf) -> Self {
+        Self {
+            output_file,
+            writer: Mutex::new(None),
+        }
+    }
+
+    async fn extract(&self, segment: &SegmentInfo, data: &[u8]) -> IoriResult<()> {
+        let entries = read_timed_metadata(data);
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut lines = Vec::new();
+        for mut entry in entries {
+            entry.stream_id = segment.stream_id;
+            entry.sequence = segment.sequence;
+            serde_json::to_writer(&mut lines, &entry)?;
+            lines.push(b'\n');
+        }
+
+        let mut writer = self.writer.lock().await;
+        if writer.is_none() {
+            tracing::info!(
+                "Extracting timed metadata to {}",
+                self.output_file.display()
+            );
+            *writer = Some(File::create(&self.output_file).await?);
+        }
+        // lines of a segment are written at once, and the file is readable while downloading
+        let file = writer.as_mut().unwrap();
+        file.write_all(&lines).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+impl SegmentProcessor for TimedMetadataProcessor {
+    async fn process(&self, segment: &SegmentInfo, data: Vec<u8>) -> IoriResult<Vec<u8>> {
+        // failing to read metadata should not fail the segment
+        if let Err(e) = self.extract(segment, &data).await {
+            tracing::warn!(
+                "Failed to extract timed metadata from {}: {e}",
+                segment.file_name
+            );
+        }
+        Ok(data)
+    }
+
+    fn applies_to(&self, segment: &SegmentInfo) -> bool {
+        segment.r#type != SegmentType::Subtitle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timed_metadata_processor() -> IoriResult<()> {
+        let dir = tempfile::tempdir()?;
+        let output_file = dir.path().join("metadata.jsonl");
+        let processor = TimedMetadataProcessor::new(output_file.clone());
+
+        // packed audio with an ID3 tag of a single TXXX frame
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x17TXXX\x00\x00\x00\x0d\x00\x00".to_vec();
+        data.extend_from_slice(b"\x03lyrics\0hello");
+        data.extend_from_slice(&[0xff, 0xf1, 0x50, 0x80]);
+
+        // segments without metadata do not create the file
+        let segment = SegmentInfo::default();
+        processor.process(&segment, vec![0xff, 0xf1]).await?;
+        assert!(!output_file.exists());
+
+        let segment = SegmentInfo {
+            stream_id: 1,
+            sequence: 2,
+            ..Default::default()
+        };
+        let processed = processor.process(&segment, data.clone()).await?;
+        assert_eq!(processed, data);
+
+        let lines = tokio::fs::read_to_string(&output_file).await?;
+        let lines: Vec<serde_json::Value> = lines
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["stream_id"], 1);
+        assert_eq!(lines[0]["sequence"], 2);
+        assert_eq!(lines[0]["type"], "id3");
+
+        Ok(())
+    }
+}
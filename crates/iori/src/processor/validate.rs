@@ -0,0 +1,187 @@
+use super::SegmentProcessor;
+use crate::{
+    IoriError, IoriResult, SegmentFormat, SegmentInfo, SegmentType,
+    util::{
+        id3::id3_tags_size,
+        mp4::{Mp4BoxIter, read_u32},
+    },
+};
+
+const TS_PACKET_SIZE: usize = 188;
+
+/// SegmentValidator rejects segments which are obviously broken, so that they are fetched
+/// again instead of being cached.
+///
+/// Some CDNs respond with truncated bodies, HTML error pages or empty files with a success
+/// status. Segments are checked by their format:
+///
+/// - MPEG-TS segments must consist of whole 188-byte packets starting with the `0x47` sync
+///   byte, after optional leading ID3 tags.
+/// - Fragmented MP4 segments must be a sequence of well-formed boxes covering all data.
+/// - Other media segments must not be empty or an HTML page.
+///
+/// Subtitles and raw files are not checked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SegmentValidator;
+
+impl SegmentValidator {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Check data of a segment after it is fetched and decrypted.
+    pub fn validate(&self, segment: &SegmentInfo, data: &[u8]) -> IoriResult<()> {
+        if !self.applies_to(segment) {
+            return Ok(());
+        }
+
+        let invalid = |reason: String| {
+            Err(IoriError::InvalidSegment {
+                file_name: segment.file_name.clone(),
+                reason,
+            })
+        };
+
+        if data.is_empty() {
+            return invalid("empty data".to_string());
+        }
+        if is_html(data) {
+            return invalid("got an HTML page".to_string());
+        }
+
+        // the format hint may come from a misleading URL extension
+        match segment.format.clone().refine(data) {
+            SegmentFormat::Mpeg2TS => {
+                let packets = &data[id3_tags_size(data)..];
+                if !packets.len().is_multiple_of(TS_PACKET_SIZE) {
+                    return invalid(format!(
+                        "{} bytes are not aligned to TS packets",
+                        packets.len()
+                    ));
+                }
+                if let Some(index) = packets
+                    .chunks_exact(TS_PACKET_SIZE)
+                    .position(|packet| packet[0] != 0x47)
+                {
+                    return invalid(format!("missing sync byte in TS packet {index}"));
+                }
+            }
+            SegmentFormat::Mp4 | SegmentFormat::M4a | SegmentFormat::Cmfv | SegmentFormat::Cmfa => {
+                let mut end = 0;
+                for mp4_box in Mp4BoxIter::new(data) {
+                    if !mp4_box
+                        .r#type
+                        .iter()
+                        .all(|b| b.is_ascii_alphanumeric() || *b == b' ')
+                    {
+                        return invalid(format!("invalid box type at offset {}", mp4_box.offset));
+                    }
+                    end = mp4_box.end();
+                }
+                if end != data.len() {
+                    // report the declared size of the broken box
+                    let size = read_u32(data, end).unwrap_or_default();
+                    return invalid(format!(
+                        "box at offset {end} with size {size} exceeds {} bytes of data",
+                        data.len()
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl SegmentProcessor for SegmentValidator {
+    async fn process(&self, segment: &SegmentInfo, data: Vec<u8>) -> IoriResult<Vec<u8>> {
+        self.validate(segment, &data)?;
+        Ok(data)
+    }
+
+    fn applies_to(&self, segment: &SegmentInfo) -> bool {
+        segment.r#type != SegmentType::Subtitle && !matches!(segment.format, SegmentFormat::Raw(_))
+    }
+}
+
+/// Whether the data looks like an HTML page, like an error page of a CDN.
+fn is_html(data: &[u8]) -> bool {
+    let start = data
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(0);
+    let head = data[start..data.len().min(start + 14)].to_ascii_lowercase();
+    head.starts_with(b"<!doctype html") || head.starts_with(b"<html")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::mp4::write_box;
+
+    #[test]
+    fn test_validate_ts() {
+        let validator = SegmentValidator::new();
+        let segment = SegmentInfo::default();
+
+        let mut packet = vec![0x47];
+        packet.resize(TS_PACKET_SIZE, 0xff);
+        let data = packet.repeat(3);
+        assert!(validator.validate(&segment, &data).is_ok());
+
+        // leading ID3 tag
+        let mut tagged = b"ID3\x04\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+        tagged.extend_from_slice(&data);
+        assert!(validator.validate(&segment, &tagged).is_ok());
+
+        // truncated
+        assert!(validator.validate(&segment, &data[..400]).is_err());
+        // out of sync
+        let mut broken = data.clone();
+        broken[TS_PACKET_SIZE] = 0x00;
+        assert!(validator.validate(&segment, &broken).is_err());
+
+        // format detected from data of tokenized urls
+        let tokenized = SegmentInfo {
+            format: SegmentFormat::Other("php".to_string()),
+            ..Default::default()
+        };
+        assert!(validator.validate(&tokenized, &data[..400]).is_err());
+
+        assert!(validator.validate(&segment, &[]).is_err());
+        assert!(
+            validator
+                .validate(&segment, b"\n<!DOCTYPE html><html></html>")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_mp4() {
+        let validator = SegmentValidator::new();
+        let segment = SegmentInfo {
+            format: SegmentFormat::Mp4,
+            ..Default::default()
+        };
+
+        let mut data = Vec::new();
+        write_box(&mut data, b"moof", &[0; 16]);
+        write_box(&mut data, b"mdat", &[0; 32]);
+        assert!(validator.validate(&segment, &data).is_ok());
+
+        // truncated
+        assert!(validator.validate(&segment, &data[..40]).is_err());
+        // garbage after boxes
+        let mut broken = data.clone();
+        broken.extend_from_slice(&[0x00, 0x01]);
+        assert!(validator.validate(&segment, &broken).is_err());
+
+        // subtitles are not checked
+        let segment = SegmentInfo {
+            r#type: SegmentType::Subtitle,
+            ..segment
+        };
+        assert!(validator.validate(&segment, b"WEBVTT").is_ok());
+    }
+}
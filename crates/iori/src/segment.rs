@@ -1,6 +1,12 @@
 use std::str::FromStr;
 
-use crate::{decrypt::IoriKey, ByteRange, HttpClient, IoriResult, StreamingSegment};
+use crate::{
+    chapter::SegmentEvent, decrypt::IoriKey, util::id3::id3_tags_size, ByteRange, HttpClient,
+    IoriResult, StreamingSegment,
+};
+
+/// Number of bytes at the start of a segment needed by [SegmentFormat::sniff].
+pub const SNIFF_SIZE: usize = 1024;
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub enum InitialSegment {
@@ -50,10 +56,66 @@ impl SegmentFormat {
             "cmfv" => Self::Cmfv,
             "cmfa" => Self::Cmfa,
             "aac" => Self::Aac,
-            "txt" | "ass" | "srt" | "vtt" | "json" => Self::Raw(ext.to_string()),
+            "txt" | "ass" | "srt" | "vtt" | "ttml" | "json" => Self::Raw(ext.to_string()),
             _ => Self::Other(ext.to_string()),
         }
     }
+
+    /// Detect the format from the first bytes of a segment.
+    ///
+    /// MPEG-TS, ISO-BMFF, ADTS, packed audio with ID3 tags, WebVTT and TTML are detected.
+    /// Returns `None` if the data is not recognized.
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        let text = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+        if text.starts_with(b"WEBVTT") {
+            return Some(Self::Raw("vtt".to_string()));
+        }
+        let text = &text[..text.len().min(SNIFF_SIZE)];
+        let text = text.trim_ascii_start();
+        if (text.starts_with(b"<?xml") || text.starts_with(b"<tt"))
+            && text.windows(3).any(|w| w == b"<tt")
+        {
+            return Some(Self::Raw("ttml".to_string()));
+        }
+
+        // MPEG-TS and ADTS, optionally after ID3 tags
+        let body = &data[id3_tags_size(data)..];
+        if body.first() == Some(&0x47) && body.get(188).is_none_or(|b| *b == 0x47) {
+            return Some(Self::Mpeg2TS);
+        }
+        if body.len() >= 2 && body[0] == 0xff && body[1] & 0xf6 == 0xf0 {
+            return Some(Self::Aac);
+        }
+
+        // ISO-BMFF
+        match data.get(4..8)? {
+            b"ftyp" if data.get(8..12) == Some(&b"M4A "[..]) => Some(Self::M4a),
+            b"ftyp" | b"styp" | b"moof" | b"moov" | b"sidx" | b"emsg" | b"prft" => Some(Self::Mp4),
+            _ => None,
+        }
+    }
+
+    /// Correct the format with the data of the segment.
+    ///
+    /// The format is only replaced if the data is in another container, so that the
+    /// distinction between `mp4`, `m4a`, `cmfv` and `cmfa` given by the source is kept.
+    pub fn refine(self, data: &[u8]) -> Self {
+        match Self::sniff(data) {
+            Some(sniffed) if !self.is_same_container(&sniffed) => sniffed,
+            _ => self,
+        }
+    }
+
+    fn is_same_container(&self, other: &Self) -> bool {
+        use SegmentFormat::*;
+
+        match (self, other) {
+            (Mpeg2TS, Mpeg2TS) | (Aac, Aac) => true,
+            (Mp4 | M4a | Cmfv | Cmfa, Mp4 | M4a | Cmfv | Cmfa) => true,
+            (Raw(a) | Other(a), Raw(b) | Other(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
 impl FromStr for SegmentFormat {
@@ -73,6 +135,10 @@ pub struct SegmentInfo {
     pub key: Option<std::sync::Arc<IoriKey>>,
     pub r#type: SegmentType,
     pub format: SegmentFormat,
+    /// Duration of the segment in seconds, if known
+    pub duration: Option<f64>,
+    /// Timed events starting in the segment
+    pub events: Vec<SegmentEvent>,
 }
 
 impl<T> From<&T> for SegmentInfo
@@ -88,6 +154,8 @@ where
             key: segment.key(),
             r#type: segment.r#type(),
             format: segment.format(),
+            duration: segment.duration(),
+            events: segment.events(),
         }
     }
 }
@@ -120,6 +188,14 @@ impl StreamingSegment for Box<dyn StreamingSegment + Send + Sync + '_> {
     fn format(&self) -> SegmentFormat {
         self.as_ref().format()
     }
+
+    fn duration(&self) -> Option<f64> {
+        self.as_ref().duration()
+    }
+
+    fn events(&self) -> Vec<SegmentEvent> {
+        self.as_ref().events()
+    }
 }
 
 impl StreamingSegment for &Box<dyn StreamingSegment + Send + Sync + '_> {
@@ -150,9 +226,18 @@ impl StreamingSegment for &Box<dyn StreamingSegment + Send + Sync + '_> {
     fn format(&self) -> SegmentFormat {
         self.as_ref().format()
     }
+
+    fn duration(&self) -> Option<f64> {
+        self.as_ref().duration()
+    }
+
+    fn events(&self) -> Vec<SegmentEvent> {
+        self.as_ref().events()
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum SegmentType {
     #[default]
@@ -227,6 +312,58 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_segment_format_sniff() {
+        let mut ts = vec![0x47];
+        ts.resize(188 * 2, 0xff);
+        ts[188] = 0x47;
+        assert_eq!(SegmentFormat::sniff(&ts), Some(SegmentFormat::Mpeg2TS));
+
+        let mut packed_audio = b"ID3\x04\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+        packed_audio.extend_from_slice(&[0xff, 0xf1, 0x50, 0x80]);
+        assert_eq!(
+            SegmentFormat::sniff(&packed_audio),
+            Some(SegmentFormat::Aac)
+        );
+
+        assert_eq!(
+            SegmentFormat::sniff(b"\0\0\0\x18ftypiso6"),
+            Some(SegmentFormat::Mp4)
+        );
+        assert_eq!(
+            SegmentFormat::sniff(b"\0\0\0\x18ftypM4A \0\0\0\0"),
+            Some(SegmentFormat::M4a)
+        );
+        assert_eq!(
+            SegmentFormat::sniff(b"\0\0\0\x10moof"),
+            Some(SegmentFormat::Mp4)
+        );
+        assert_eq!(
+            SegmentFormat::sniff(b"\xEF\xBB\xBFWEBVTT\n\n"),
+            Some(SegmentFormat::Raw("vtt".to_string()))
+        );
+        assert_eq!(
+            SegmentFormat::sniff(b"<?xml version=\"1.0\"?>\n<tt xmlns=\"\">"),
+            Some(SegmentFormat::Raw("ttml".to_string()))
+        );
+        assert_eq!(SegmentFormat::sniff(b"<html></html>"), None);
+
+        // extension-less or tokenized urls
+        assert_eq!(
+            SegmentFormat::from_filename("segment.php").refine(&ts),
+            SegmentFormat::Mpeg2TS
+        );
+        // keep the hint for the same container
+        assert_eq!(
+            SegmentFormat::Cmfv.refine(b"\0\0\0\x10moof"),
+            SegmentFormat::Cmfv
+        );
+        assert_eq!(
+            SegmentFormat::Mp4.refine(b"<html></html>"),
+            SegmentFormat::Mp4
+        );
+    }
+
     #[test]
     fn test_segment_format_as_ext() {
         assert_eq!(SegmentFormat::Mpeg2TS.as_ext(), "ts");
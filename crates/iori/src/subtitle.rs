@@ -0,0 +1,391 @@
+//! Subtitle extraction and conversion.
+//!
+//! Text tracks in fragmented MP4 (`wvtt` and `stpp`) and HLS WebVTT segments can not
+//! be concatenated like other segments. They are parsed into [SubtitleCue]s instead, and
+//! written out as standalone `vtt`, `srt` or `ass` files.
+mod mp4;
+mod ttml;
+mod webvtt;
+
+pub use webvtt::TimestampMap;
+
+use std::{fmt::Write, str::FromStr};
+
+use crate::{IoriError, IoriResult};
+
+/// A single subtitle cue. Times are in milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubtitleCue {
+    pub start: u64,
+    pub end: u64,
+    /// Cue identifier. Only preserved by WebVTT.
+    pub id: Option<String>,
+    /// WebVTT cue settings, for example `line:0 align:start`. Only preserved by WebVTT.
+    pub settings: Option<String>,
+    /// Cue payload with WebVTT markup. Lines are separated by `\n`.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubtitleFormat {
+    #[default]
+    WebVTT,
+    Srt,
+    Ass,
+}
+
+impl SubtitleFormat {
+    pub fn as_ext(&self) -> &'static str {
+        match self {
+            Self::WebVTT => "vtt",
+            Self::Srt => "srt",
+            Self::Ass => "ass",
+        }
+    }
+}
+
+impl FromStr for SubtitleFormat {
+    type Err = IoriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "vtt" | "webvtt" => Ok(Self::WebVTT),
+            "srt" => Ok(Self::Srt),
+            "ass" | "ssa" => Ok(Self::Ass),
+            _ => Err(IoriError::SubtitleParsing(format!(
+                "Unknown subtitle format: {s}"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Subtitle {
+    pub cues: Vec<SubtitleCue>,
+}
+
+impl Subtitle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Extract cues from a fragmented MP4 text track (`wvtt` or `stpp`).
+    ///
+    /// The data should contain the initialization segment followed by one or more media segments.
+    pub fn from_fmp4(data: &[u8]) -> IoriResult<Self> {
+        Ok(Self {
+            cues: mp4::extract_cues(data)?,
+        })
+    }
+
+    /// Merge WebVTT segments of a HLS subtitle playlist.
+    ///
+    /// Cue times of each segment are mapped to the MPEG-2 TS timeline with its `X-TIMESTAMP-MAP`
+    /// header, and then shifted to be relative to `base_pts`, which should be the first video PTS
+    /// in 90kHz units. If `base_pts` is not provided, the timestamp of the first segment is used.
+    pub fn from_hls_webvtt<'a, I>(segments: I, mut base_pts: Option<u64>) -> IoriResult<Self>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut cues = Vec::new();
+        for segment in segments {
+            let document = webvtt::parse_webvtt(segment)?;
+            let offset = match document.timestamp_map {
+                Some(map) => {
+                    let base_pts = *base_pts.get_or_insert(map.mpegts);
+                    pts_diff(map.mpegts, base_pts) / 90 - map.local as i64
+                }
+                None => 0,
+            };
+
+            cues.extend(document.cues.into_iter().map(|mut cue| {
+                cue.start = cue.start.saturating_add_signed(offset);
+                cue.end = cue.end.saturating_add_signed(offset);
+                cue
+            }));
+        }
+
+        Ok(Self { cues })
+    }
+
+    pub fn extend(&mut self, other: Subtitle) {
+        self.cues.extend(other.cues);
+    }
+
+    /// Sort cues by time and join cues split across segment boundaries.
+    ///
+    /// A cue spanning multiple media segments is repeated in each of them, so
+    /// adjacent cues with the same content are merged into one.
+    pub fn normalize(&mut self) {
+        self.cues.sort_by_key(|c| (c.start, c.end));
+
+        let mut cues: Vec<SubtitleCue> = Vec::with_capacity(self.cues.len());
+        for cue in self.cues.drain(..) {
+            if let Some(last) = cues.iter_mut().rev().find(|last| {
+                last.text == cue.text && last.settings == cue.settings && last.end >= cue.start
+            }) {
+                last.end = last.end.max(cue.end);
+                continue;
+            }
+            cues.push(cue);
+        }
+        self.cues = cues;
+    }
+
+    pub fn to_format(&self, format: SubtitleFormat) -> String {
+        match format {
+            SubtitleFormat::WebVTT => self.to_webvtt(),
+            SubtitleFormat::Srt => self.to_srt(),
+            SubtitleFormat::Ass => self.to_ass(),
+        }
+    }
+
+    pub fn to_webvtt(&self) -> String {
+        let mut output = String::from("WEBVTT\n\n");
+        for cue in self.cues.iter() {
+            if let Some(id) = &cue.id {
+                writeln!(output, "{id}").unwrap();
+            }
+            write!(
+                output,
+                "{} --> {}",
+                format_time(cue.start, '.', 3),
+                format_time(cue.end, '.', 3)
+            )
+            .unwrap();
+            if let Some(settings) = &cue.settings {
+                write!(output, " {settings}").unwrap();
+            }
+            writeln!(output, "\n{}\n", cue.text).unwrap();
+        }
+        output
+    }
+
+    pub fn to_srt(&self) -> String {
+        let mut output = String::new();
+        for (i, cue) in self.cues.iter().enumerate() {
+            writeln!(
+                output,
+                "{}\n{} --> {}\n{}\n",
+                i + 1,
+                format_time(cue.start, ',', 3),
+                format_time(cue.end, ',', 3),
+                convert_markup(&cue.text, srt_tag)
+            )
+            .unwrap();
+        }
+        output
+    }
+
+    pub fn to_ass(&self) -> String {
+        let mut output = String::from(ASS_HEADER);
+        for cue in self.cues.iter() {
+            let text = convert_markup(&cue.text, ass_tag).replace('\n', "\\N");
+            writeln!(
+                output,
+                "Dialogue: 0,{},{},Default,,0,0,0,,{text}",
+                format_ass_time(cue.start),
+                format_ass_time(cue.end),
+            )
+            .unwrap();
+        }
+        output
+    }
+}
+
+/// Difference between two 33-bit MPEG-2 TS timestamps, handling wrap-around.
+fn pts_diff(pts: u64, base: u64) -> i64 {
+    const WRAP: i64 = 1 << 33;
+    let diff = pts as i64 - base as i64;
+    if diff < -WRAP / 2 {
+        diff + WRAP
+    } else if diff > WRAP / 2 {
+        diff - WRAP
+    } else {
+        diff
+    }
+}
+
+const ASS_HEADER: &str = "[Script Info]
+ScriptType: v4.00+
+PlayResX: 1920
+PlayResY: 1080
+ScaledBorderAndShadow: yes
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Default,Arial,60,&H00FFFFFF,&H000000FF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,3,0,2,60,60,50,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+";
+
+/// Format milliseconds as `HH:MM:SS.mmm`.
+fn format_time(time: u64, separator: char, precision: usize) -> String {
+    let hours = time / 3_600_000;
+    let minutes = time / 60_000 % 60;
+    let seconds = time / 1000 % 60;
+    let fraction = match precision {
+        2 => time % 1000 / 10,
+        _ => time % 1000,
+    };
+    format!("{hours:02}:{minutes:02}:{seconds:02}{separator}{fraction:0precision$}")
+}
+
+/// Format milliseconds as `H:MM:SS.cc`.
+fn format_ass_time(time: u64) -> String {
+    let formatted = format_time(time, '.', 2);
+    // ASS uses a single digit hour
+    formatted
+        .strip_prefix('0')
+        .unwrap_or(&formatted)
+        .to_string()
+}
+
+/// Convert WebVTT markup with the given tag mapper.
+///
+/// Tags not handled by the mapper are removed, and character references are unescaped.
+fn convert_markup(text: &str, map_tag: fn(&str, bool) -> Option<&'static str>) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        output.push_str(&unescape_entities(&rest[..start]));
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+
+        let tag = &rest[start + 1..start + end];
+        let (is_closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        // <c.class>, <v Speaker>
+        let name = tag
+            .split(|c: char| c == '.' || c.is_whitespace())
+            .next()
+            .unwrap_or_default();
+        if let Some(replacement) = map_tag(name, is_closing) {
+            output.push_str(replacement);
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(&unescape_entities(rest));
+    output
+}
+
+fn srt_tag(name: &str, is_closing: bool) -> Option<&'static str> {
+    Some(match (name, is_closing) {
+        ("i", false) => "<i>",
+        ("i", true) => "</i>",
+        ("b", false) => "<b>",
+        ("b", true) => "</b>",
+        ("u", false) => "<u>",
+        ("u", true) => "</u>",
+        _ => return None,
+    })
+}
+
+fn ass_tag(name: &str, is_closing: bool) -> Option<&'static str> {
+    Some(match (name, is_closing) {
+        ("i", false) => "{\\i1}",
+        ("i", true) => "{\\i0}",
+        ("b", false) => "{\\b1}",
+        ("b", true) => "{\\b0}",
+        ("u", false) => "{\\u1}",
+        ("u", true) => "{\\u0}",
+        _ => return None,
+    })
+}
+
+fn unescape_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", "\u{a0}")
+        .replace("&lrm;", "\u{200e}")
+        .replace("&rlm;", "\u{200f}")
+        .replace("&amp;", "&")
+}
+
+/// Escape text for WebVTT cue payloads.
+pub(crate) fn escape_webvtt(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(start: u64, end: u64, text: &str) -> SubtitleCue {
+        SubtitleCue {
+            start,
+            end,
+            id: None,
+            settings: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_normalize_joins_split_cues() {
+        let mut subtitle = Subtitle {
+            cues: vec![
+                cue(2000, 4000, "world"),
+                cue(0, 2000, "hello"),
+                cue(2000, 3000, "hello"),
+            ],
+        };
+        subtitle.normalize();
+        assert_eq!(
+            subtitle.cues,
+            vec![cue(0, 3000, "hello"), cue(2000, 4000, "world")]
+        );
+    }
+
+    #[test]
+    fn test_merge_hls_webvtt() {
+        let segments = [
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:8589844592,LOCAL:00:00:00.000\n\n00:00:01.000 --> 00:00:02.000\nbefore wrap\n",
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\n00:00:09.000 --> 00:00:12.000\nfirst\n",
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\n00:00:09.000 --> 00:00:12.000\nfirst\n\n00:00:15.000 --> 00:00:16.000\nsecond\n",
+        ];
+        // one second before the 33-bit timestamp wraps around
+        let base_pts = (1 << 33) - 90000;
+
+        let mut subtitle = Subtitle::from_hls_webvtt(segments, Some(base_pts)).unwrap();
+        subtitle.normalize();
+        assert_eq!(
+            subtitle.cues,
+            vec![
+                cue(1000, 2000, "before wrap"),
+                cue(20000, 23000, "first"),
+                cue(26000, 27000, "second"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subtitle_output() {
+        let subtitle = Subtitle {
+            cues: vec![cue(
+                3_723_456,
+                3_725_000,
+                "<i>a &amp; b</i>\n<c.yellow>c</c>",
+            )],
+        };
+        assert_eq!(
+            subtitle.to_webvtt(),
+            "WEBVTT\n\n01:02:03.456 --> 01:02:05.000\n<i>a &amp; b</i>\n<c.yellow>c</c>\n\n"
+        );
+        assert_eq!(
+            subtitle.to_srt(),
+            "1\n01:02:03,456 --> 01:02:05,000\n<i>a & b</i>\nc\n\n"
+        );
+        assert!(subtitle.to_ass().ends_with(
+            "Dialogue: 0,1:02:03.45,1:02:05.00,Default,,0,0,0,,{\\i1}a & b{\\i0}\\Nc\n"
+        ));
+    }
+}
@@ -0,0 +1,226 @@
+//! Text tracks in fragmented MP4, defined in ISO/IEC 14496-30.
+use std::collections::HashMap;
+
+use super::{SubtitleCue, ttml};
+use crate::{
+    IoriError, IoriResult,
+    util::mp4::{Mp4Box, Mp4BoxIter, parse_moof, parse_trex, read_timescale, read_track_id},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextCodec {
+    /// WebVTT, `wvtt` sample entry
+    WebVTT,
+    /// TTML, `stpp` sample entry
+    Ttml,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TrackInfo {
+    timescale: u64,
+    codec: TextCodec,
+}
+
+pub(crate) fn extract_cues(data: &[u8]) -> IoriResult<Vec<SubtitleCue>> {
+    let mut tracks = HashMap::new();
+    let mut trex = HashMap::new();
+    let mut cues = Vec::new();
+
+    for mp4_box in Mp4BoxIter::new(data) {
+        match &mp4_box.r#type {
+            b"moov" => {
+                parse_moov(&mp4_box, &mut tracks);
+                trex = parse_trex(&mp4_box);
+            }
+            b"moof" => {
+                for fragment in parse_moof(&mp4_box, &trex)? {
+                    let Some(track) = tracks.get(&fragment.track_id) else {
+                        continue;
+                    };
+                    for sample in fragment.samples {
+                        let sample_data = data
+                            .get(sample.offset..sample.offset + sample.size as usize)
+                            .ok_or_else(|| {
+                                IoriError::SubtitleParsing("Invalid moof box".to_string())
+                            })?;
+                        let time = sample
+                            .decode_time
+                            .saturating_add_signed(sample.composition_offset as i64);
+                        let start = to_millis(time, track.timescale);
+                        let end = to_millis(time + sample.duration as u64, track.timescale);
+                        match track.codec {
+                            TextCodec::WebVTT => {
+                                cues.extend(parse_wvtt_sample(sample_data, start, end))
+                            }
+                            TextCodec::Ttml => {
+                                let document = String::from_utf8_lossy(sample_data);
+                                let mut sample_cues = ttml::parse_ttml(&document)?;
+                                // TTML documents in MP4 usually use the track timeline, but some
+                                // packagers write times relative to the sample instead.
+                                if start > 0 && sample_cues.iter().all(|c| c.start < start) {
+                                    for cue in sample_cues.iter_mut() {
+                                        cue.start += start;
+                                        cue.end += start;
+                                    }
+                                }
+                                cues.extend(sample_cues);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if tracks.is_empty() {
+        return Err(IoriError::SubtitleParsing(
+            "No wvtt or stpp track found".to_string(),
+        ));
+    }
+    Ok(cues)
+}
+
+fn to_millis(time: u64, timescale: u64) -> u64 {
+    (time as u128 * 1000 / timescale.max(1) as u128) as u64
+}
+
+fn parse_moov(moov: &Mp4Box, tracks: &mut HashMap<u32, TrackInfo>) {
+    for trak in moov.children(0).filter(|b| &b.r#type == b"trak") {
+        let (Some(track_id), Some(timescale)) = (read_track_id(&trak), read_timescale(&trak))
+        else {
+            continue;
+        };
+        let codec = trak
+            .find_child(b"mdia")
+            .and_then(|mdia| mdia.find_child(b"minf"))
+            .and_then(|minf| minf.find_child(b"stbl"))
+            .and_then(|stbl| stbl.find_child(b"stsd"))
+            // version(1) + flags(3) + entry_count(4)
+            .and_then(|stsd| stsd.children(8).next())
+            .and_then(|entry| match &entry.r#type {
+                b"wvtt" => Some(TextCodec::WebVTT),
+                b"stpp" => Some(TextCodec::Ttml),
+                _ => None,
+            });
+        let Some(codec) = codec else {
+            continue;
+        };
+
+        tracks.insert(
+            track_id,
+            TrackInfo {
+                timescale: timescale as u64,
+                codec,
+            },
+        );
+    }
+}
+
+/// Parse a WebVTT sample, which contains zero or more `vttc` boxes for cues active in
+/// the sample, or a single `vtte` box for an empty sample.
+fn parse_wvtt_sample(data: &[u8], start: u64, end: u64) -> Vec<SubtitleCue> {
+    let mut cues = Vec::new();
+    for vttc in Mp4BoxIter::new(data).filter(|b| &b.r#type == b"vttc") {
+        let mut cue = SubtitleCue {
+            start,
+            end,
+            id: None,
+            settings: None,
+            text: String::new(),
+        };
+        for child in vttc.children(0) {
+            let value = String::from_utf8_lossy(child.data).trim_end().to_string();
+            match &child.r#type {
+                b"iden" => cue.id = Some(value).filter(|v| !v.is_empty()),
+                b"sttg" => cue.settings = Some(value).filter(|v| !v.is_empty()),
+                b"payl" => cue.text = value,
+                _ => {}
+            }
+        }
+        cues.push(cue);
+    }
+    cues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_box(r#type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(r#type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn full_box(r#type: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = (flags | ((version as u32) << 24)).to_be_bytes().to_vec();
+        data.extend_from_slice(payload);
+        make_box(r#type, &data)
+    }
+
+    fn wvtt_init() -> Vec<u8> {
+        let mut tkhd = vec![0; 8];
+        tkhd.extend_from_slice(&1u32.to_be_bytes()); // track_ID
+        let mut mdhd = vec![0; 8];
+        mdhd.extend_from_slice(&1000u32.to_be_bytes()); // timescale
+
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        stsd.extend(make_box(b"wvtt", &[0; 8]));
+        let stbl = make_box(b"stbl", &full_box(b"stsd", 0, 0, &stsd));
+        let minf = make_box(b"minf", &stbl);
+        let mut mdia = full_box(b"mdhd", 0, 0, &mdhd);
+        mdia.extend(minf);
+        let mut trak = full_box(b"tkhd", 0, 0, &tkhd);
+        trak.extend(make_box(b"mdia", &mdia));
+        make_box(b"moov", &make_box(b"trak", &trak))
+    }
+
+    fn wvtt_fragment(time: u32, samples: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let tfhd = full_box(b"tfhd", 0, 0x020000, &1u32.to_be_bytes());
+        let tfdt = full_box(b"tfdt", 0, 0, &time.to_be_bytes());
+
+        let build = |data_offset: u32| {
+            let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+            trun.extend_from_slice(&data_offset.to_be_bytes());
+            for (duration, data) in samples {
+                trun.extend_from_slice(&duration.to_be_bytes());
+                trun.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            }
+            let mut traf = tfhd.clone();
+            traf.extend(tfdt.clone());
+            traf.extend(full_box(b"trun", 0, 0x01 | 0x100 | 0x200, &trun));
+            make_box(b"moof", &make_box(b"traf", &traf))
+        };
+        let moof_size = build(0).len() as u32;
+        let mut fragment = build(moof_size + 8);
+
+        let mdat: Vec<u8> = samples.iter().flat_map(|(_, d)| d.clone()).collect();
+        fragment.extend(make_box(b"mdat", &mdat));
+        fragment
+    }
+
+    #[test]
+    fn test_extract_wvtt() {
+        let mut vttc = make_box(b"sttg", b"line:0");
+        vttc.extend(make_box(b"payl", b"Hello\nworld"));
+        let cue = make_box(b"vttc", &vttc);
+        let empty = make_box(b"vtte", &[]);
+
+        let mut data = wvtt_init();
+        data.extend(wvtt_fragment(10000, &[(2000, cue), (500, empty)]));
+
+        let cues = extract_cues(&data).unwrap();
+        assert_eq!(
+            cues,
+            vec![SubtitleCue {
+                start: 10000,
+                end: 12000,
+                id: None,
+                settings: Some("line:0".to_string()),
+                text: "Hello\nworld".to_string(),
+            }]
+        );
+    }
+}
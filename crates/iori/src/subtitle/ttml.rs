@@ -0,0 +1,279 @@
+//! A minimal TTML parser for `stpp` samples.
+//!
+//! Only the subset needed for text profiles like IMSC-1 is supported: timed `p` elements,
+//! `br` line breaks and italic `span`s. Styling and layout are discarded.
+use quick_xml::{
+    Reader,
+    events::{BytesStart, Event},
+};
+
+use super::{SubtitleCue, escape_webvtt};
+use crate::IoriResult;
+
+struct TimingParameters {
+    tick_rate: f64,
+    frame_rate: f64,
+}
+
+impl Default for TimingParameters {
+    fn default() -> Self {
+        Self {
+            tick_rate: 1.0,
+            frame_rate: 30.0,
+        }
+    }
+}
+
+/// An open element in the document.
+struct Frame {
+    /// Absolute begin time in milliseconds
+    begin: u64,
+    /// Absolute end time in milliseconds
+    end: Option<u64>,
+    /// Markup to append to the cue text when this element is closed
+    closing: Option<&'static str>,
+}
+
+pub(crate) fn parse_ttml(document: &str) -> IoriResult<Vec<SubtitleCue>> {
+    let mut reader = Reader::from_str(document);
+    let mut params = TimingParameters::default();
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut current: Option<SubtitleCue> = None;
+    let mut cues = Vec::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                if element.local_name().as_ref() == b"tt" {
+                    params = read_timing_parameters(&element)?;
+                }
+
+                let parent = frames.last();
+                let parent_begin = parent.map(|f| f.begin).unwrap_or_default();
+                let parent_end = parent.and_then(|f| f.end);
+                let begin = read_time(&element, b"begin", &params)?
+                    .map_or(parent_begin, |begin| parent_begin + begin);
+                let end = match read_time(&element, b"end", &params)? {
+                    Some(end) => Some(parent_begin + end),
+                    None => read_time(&element, b"dur", &params)?
+                        .map(|dur| begin + dur)
+                        .or(parent_end),
+                };
+
+                let mut closing = None;
+                match element.local_name().as_ref() {
+                    b"p" => {
+                        current = Some(SubtitleCue {
+                            start: begin,
+                            end: end.unwrap_or(begin),
+                            id: None,
+                            settings: None,
+                            text: String::new(),
+                        });
+                    }
+                    b"span" if is_italic(&element)? => {
+                        if let Some(cue) = current.as_mut() {
+                            cue.text.push_str("<i>");
+                            closing = Some("</i>");
+                        }
+                    }
+                    _ => {}
+                }
+                frames.push(Frame {
+                    begin,
+                    end,
+                    closing,
+                });
+            }
+            Event::Empty(element) => {
+                if element.local_name().as_ref() == b"br" {
+                    if let Some(cue) = current.as_mut() {
+                        cue.text.push('\n');
+                    }
+                }
+            }
+            Event::Text(text) => {
+                if let Some(cue) = current.as_mut() {
+                    push_text(&mut cue.text, &text.unescape()?);
+                }
+            }
+            Event::End(element) => {
+                let frame = frames.pop();
+                if let (Some(cue), Some(closing)) =
+                    (current.as_mut(), frame.and_then(|f| f.closing))
+                {
+                    cue.text.push_str(closing);
+                }
+
+                if element.local_name().as_ref() == b"p" {
+                    if let Some(mut cue) = current.take() {
+                        cue.text = cue
+                            .text
+                            .lines()
+                            .map(str::trim)
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                            .trim()
+                            .to_string();
+                        if !cue.text.is_empty() && cue.end > cue.start {
+                            cues.push(cue);
+                        }
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(cues)
+}
+
+/// Append text content, collapsing whitespaces like `xml:space="default"`.
+fn push_text(output: &mut String, text: &str) {
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !output.is_empty() && !output.ends_with([' ', '\n']) {
+                output.push(' ');
+            }
+        } else {
+            output.push_str(&escape_webvtt(c.encode_utf8(&mut [0; 4])));
+        }
+    }
+}
+
+fn read_attribute(element: &BytesStart, name: &[u8]) -> IoriResult<Option<String>> {
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(quick_xml::Error::from)?;
+        if attribute.key.local_name().as_ref() == name {
+            return Ok(Some(attribute.unescape_value()?.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn is_italic(element: &BytesStart) -> IoriResult<bool> {
+    Ok(read_attribute(element, b"fontStyle")?.as_deref() == Some("italic"))
+}
+
+fn read_timing_parameters(element: &BytesStart) -> IoriResult<TimingParameters> {
+    let mut params = TimingParameters::default();
+    if let Some(frame_rate) = read_attribute(element, b"frameRate")?.and_then(|r| r.parse().ok()) {
+        params.frame_rate = frame_rate;
+    }
+    if let Some(multiplier) = read_attribute(element, b"frameRateMultiplier")? {
+        if let Some((numerator, denominator)) = multiplier.split_once(' ') {
+            if let (Ok(numerator), Ok(denominator)) =
+                (numerator.parse::<f64>(), denominator.parse::<f64>())
+            {
+                params.frame_rate = params.frame_rate * numerator / denominator;
+            }
+        }
+    }
+    if let Some(tick_rate) = read_attribute(element, b"tickRate")?.and_then(|r| r.parse().ok()) {
+        params.tick_rate = tick_rate;
+    }
+    Ok(params)
+}
+
+fn read_time(
+    element: &BytesStart,
+    name: &[u8],
+    params: &TimingParameters,
+) -> IoriResult<Option<u64>> {
+    Ok(read_attribute(element, name)?.and_then(|value| parse_time(value.trim(), params)))
+}
+
+/// Parse a TTML time expression into milliseconds.
+///
+/// Both clock time (`hh:mm:ss.fraction`, `hh:mm:ss:frames`) and offset time
+/// (`12.3s`, `500ms`, `90000t`) are supported.
+fn parse_time(value: &str, params: &TimingParameters) -> Option<u64> {
+    let seconds = if value.contains(':') {
+        let parts: Vec<&str> = value.split(':').collect();
+        let (hours, minutes, seconds) = match parts.as_slice() {
+            [hours, minutes, seconds] | [hours, minutes, seconds, _] => (
+                hours.parse::<f64>().ok()?,
+                minutes.parse::<f64>().ok()?,
+                seconds.parse::<f64>().ok()?,
+            ),
+            _ => return None,
+        };
+        let frames = match parts.get(3) {
+            Some(frames) => frames.parse::<f64>().ok()? / params.frame_rate,
+            None => 0.0,
+        };
+        hours * 3600.0 + minutes * 60.0 + seconds + frames
+    } else {
+        let split = value.find(|c: char| c.is_ascii_alphabetic())?;
+        let (number, metric) = value.split_at(split);
+        let number = number.parse::<f64>().ok()?;
+        match metric {
+            "h" => number * 3600.0,
+            "m" => number * 60.0,
+            "s" => number,
+            "ms" => number / 1000.0,
+            "f" => number / params.frame_rate,
+            "t" => number / params.tick_rate,
+            _ => return None,
+        }
+    };
+
+    Some((seconds * 1000.0).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        let params = TimingParameters {
+            tick_rate: 10_000_000.0,
+            frame_rate: 25.0,
+        };
+        assert_eq!(parse_time("00:01:02.500", &params), Some(62_500));
+        assert_eq!(parse_time("00:00:01:05", &params), Some(1200));
+        assert_eq!(parse_time("1.5s", &params), Some(1500));
+        assert_eq!(parse_time("250ms", &params), Some(250));
+        assert_eq!(parse_time("20000000t", &params), Some(2000));
+        assert_eq!(parse_time("invalid", &params), None);
+    }
+
+    #[test]
+    fn test_parse_ttml() {
+        let document = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tt xmlns="http://www.w3.org/ns/ttml" xmlns:tts="http://www.w3.org/ns/ttml#styling"
+    xmlns:ttp="http://www.w3.org/ns/ttml#parameter" ttp:tickRate="1000">
+  <body>
+    <div begin="10s">
+      <p begin="1000t" end="3000t">Hello
+        <br/>
+        <span tts:fontStyle="italic">world</span> &amp; more</p>
+      <p begin="00:00:05.000" dur="2s">Second</p>
+    </div>
+  </body>
+</tt>"#;
+
+        let cues = parse_ttml(document).unwrap();
+        assert_eq!(
+            cues,
+            vec![
+                SubtitleCue {
+                    start: 11000,
+                    end: 13000,
+                    id: None,
+                    settings: None,
+                    text: "Hello\n<i>world</i> &amp; more".to_string(),
+                },
+                SubtitleCue {
+                    start: 15000,
+                    end: 17000,
+                    id: None,
+                    settings: None,
+                    text: "Second".to_string(),
+                },
+            ]
+        );
+    }
+}
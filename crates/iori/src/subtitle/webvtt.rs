@@ -0,0 +1,146 @@
+//! WebVTT parser for HLS subtitle segments.
+use super::SubtitleCue;
+use crate::{IoriError, IoriResult};
+
+/// `X-TIMESTAMP-MAP` header of a HLS WebVTT segment, which maps the local cue time
+/// to the MPEG-2 TS presentation time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimestampMap {
+    /// MPEG-2 TS timestamp in 90kHz units
+    pub mpegts: u64,
+    /// Local cue time in milliseconds
+    pub local: u64,
+}
+
+pub(crate) struct WebVttDocument {
+    pub cues: Vec<SubtitleCue>,
+    pub timestamp_map: Option<TimestampMap>,
+}
+
+pub(crate) fn parse_webvtt(text: &str) -> IoriResult<WebVttDocument> {
+    let text = text.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    let mut blocks = text.split("\n\n").filter(|b| !b.trim().is_empty());
+
+    let header = blocks.next().unwrap_or_default();
+    if !header.starts_with("WEBVTT") {
+        return Err(IoriError::SubtitleParsing(
+            "Missing WEBVTT header".to_string(),
+        ));
+    }
+    let timestamp_map = header
+        .lines()
+        .find_map(|line| line.strip_prefix("X-TIMESTAMP-MAP="))
+        .map(parse_timestamp_map)
+        .transpose()?;
+
+    let mut cues = Vec::new();
+    for block in blocks {
+        let block = block.trim_matches('\n');
+        if block.starts_with("NOTE") || block.starts_with("STYLE") || block.starts_with("REGION") {
+            continue;
+        }
+
+        let mut lines = block.lines();
+        let Some(mut timing) = lines.next() else {
+            continue;
+        };
+        let mut id = None;
+        if !timing.contains("-->") {
+            id = Some(timing.to_string());
+            let Some(line) = lines.next() else {
+                continue;
+            };
+            timing = line;
+        }
+
+        let Some((start, rest)) = timing.split_once("-->") else {
+            continue;
+        };
+        let rest = rest.trim();
+        let (end, settings) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let settings = settings.trim();
+
+        cues.push(SubtitleCue {
+            start: parse_timestamp(start.trim())?,
+            end: parse_timestamp(end)?,
+            id,
+            settings: (!settings.is_empty()).then(|| settings.to_string()),
+            text: lines.collect::<Vec<_>>().join("\n"),
+        });
+    }
+
+    Ok(WebVttDocument {
+        cues,
+        timestamp_map,
+    })
+}
+
+/// Parse `MPEGTS:900000,LOCAL:00:00:00.000`.
+fn parse_timestamp_map(value: &str) -> IoriResult<TimestampMap> {
+    let mut mpegts = None;
+    let mut local = None;
+    for item in value.split(',') {
+        match item.trim().split_once(':') {
+            Some(("MPEGTS", value)) => mpegts = value.parse().ok(),
+            Some(("LOCAL", value)) => local = Some(parse_timestamp(value)?),
+            _ => {}
+        }
+    }
+
+    match (mpegts, local) {
+        (Some(mpegts), Some(local)) => Ok(TimestampMap { mpegts, local }),
+        _ => Err(IoriError::SubtitleParsing(format!(
+            "Invalid X-TIMESTAMP-MAP: {value}"
+        ))),
+    }
+}
+
+/// Parse a WebVTT timestamp (`hh:mm:ss.ttt` or `mm:ss.ttt`) into milliseconds.
+fn parse_timestamp(value: &str) -> IoriResult<u64> {
+    let invalid = || IoriError::SubtitleParsing(format!("Invalid timestamp: {value}"));
+
+    let (time, fraction) = value.split_once('.').ok_or_else(invalid)?;
+    let mut seconds = 0;
+    for part in time.split(':') {
+        seconds = seconds * 60 + part.parse::<u64>().map_err(|_| invalid())?;
+    }
+    let millis = fraction.parse::<u64>().map_err(|_| invalid())?;
+    Ok(seconds * 1000 + millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_webvtt() {
+        let text = "WEBVTT\r\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\r\n\r\nNOTE comment\r\n\r\n1\r\n00:00:01.000 --> 00:00:02.500 line:0\r\nHello\r\nworld\r\n\r\n01:02.000 --> 01:03.000\r\nSecond\r\n";
+        let document = parse_webvtt(text).unwrap();
+        assert_eq!(
+            document.timestamp_map,
+            Some(TimestampMap {
+                mpegts: 900000,
+                local: 0,
+            })
+        );
+        assert_eq!(
+            document.cues,
+            vec![
+                SubtitleCue {
+                    start: 1000,
+                    end: 2500,
+                    id: Some("1".to_string()),
+                    settings: Some("line:0".to_string()),
+                    text: "Hello\nworld".to_string(),
+                },
+                SubtitleCue {
+                    start: 62000,
+                    end: 63000,
+                    id: None,
+                    settings: None,
+                    text: "Second".to_string(),
+                },
+            ]
+        );
+    }
+}
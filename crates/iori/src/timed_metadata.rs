@@ -0,0 +1,298 @@
+//! Timed metadata carried in media segments.
+//!
+//! Live streams carry ID3 tags in MPEG-TS metadata streams or at the start of packed audio
+//! segments, and `emsg` boxes in fragmented MP4 segments. They are used for lyrics, program
+//! information or timing. [read_timed_metadata] extracts them with their presentation time,
+//! without modifying the media data.
+use std::io::{Cursor, Read};
+
+use serde::Serialize;
+
+use crate::util::{
+    mp4::{Mp4BoxIter, parse_emsg, segment_start_time},
+    mpegts::{StreamType, TsDemuxer},
+};
+
+/// Stream type of ID3 timed metadata carried in PES packets, defined in ISO/IEC 13818-1.
+const METADATA_STREAM_TYPE: u8 = 0x15;
+
+/// Owner of the ID3 `PRIV` frame with the MPEG-TS timestamp of packed audio.
+const TRANSPORT_STREAM_TIMESTAMP_OWNER: &str = "com.apple.streaming.transportStreamTimestamp";
+
+/// A timed metadata entry of a segment.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimedMetadata {
+    pub stream_id: u64,
+    pub sequence: u64,
+    /// Presentation time in seconds on the media timeline, if known.
+    pub time: Option<f64>,
+    #[serde(flatten)]
+    pub payload: TimedMetadataPayload,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TimedMetadataPayload {
+    /// An ID3 tag in MPEG-TS or packed audio.
+    Id3 { frames: Vec<Id3Frame> },
+    /// An `emsg` box in fragmented MP4.
+    Emsg {
+        scheme_id_uri: String,
+        value: String,
+        id: u32,
+        /// Duration in seconds, if known.
+        duration: Option<f64>,
+        /// Frames of the message, if it is an ID3 tag.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        frames: Vec<Id3Frame>,
+        /// Hex encoded message data, if it is not an ID3 tag.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+    },
+}
+
+/// A frame of an ID3 tag.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Id3Frame {
+    /// Frame id, like `PRIV` or `TXXX`
+    pub id: String,
+    /// Owner identifier of `PRIV` frames, or description of `TXXX` frames.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Hex encoded data of `PRIV` and unknown frames.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+impl From<&id3::Frame> for Id3Frame {
+    fn from(frame: &id3::Frame) -> Self {
+        let mut result = Self {
+            id: frame.id().to_string(),
+            description: None,
+            text: None,
+            data: None,
+        };
+        match frame.content() {
+            id3::Content::ExtendedText(text) => {
+                result.description = Some(text.description.clone());
+                result.text = Some(text.value.clone());
+            }
+            id3::Content::Private(private) => {
+                result.description = Some(private.owner_identifier.clone());
+                result.data = Some(hex::encode(&private.private_data));
+            }
+            id3::Content::Unknown(unknown) => result.data = Some(hex::encode(&unknown.data)),
+            content => result.text = Some(content.to_string()),
+        }
+        result
+    }
+}
+
+/// Read frames of an ID3 tag at the start of `reader`.
+fn read_id3_frames(reader: impl Read) -> Option<Vec<Id3Frame>> {
+    #[allow(deprecated)]
+    let tag = id3::Tag::read_from(reader).ok()?;
+    Some(tag.frames().map(Id3Frame::from).collect())
+}
+
+/// MPEG-TS timestamp in seconds from the `PRIV` frame of packed audio.
+fn transport_stream_timestamp(frames: &[Id3Frame]) -> Option<f64> {
+    let frame = frames.iter().find(|frame| {
+        frame.id == "PRIV" && frame.description.as_deref() == Some(TRANSPORT_STREAM_TIMESTAMP_OWNER)
+    })?;
+    let data = hex::decode(frame.data.as_deref()?).ok()?;
+    let timestamp = u64::from_be_bytes(data.get(..8)?.try_into().ok()?) & 0x1_ffff_ffff;
+    Some(timestamp as f64 / 90000.)
+}
+
+/// Whether an `emsg` scheme carries ID3 tags, like `https://aomedia.org/emsg/ID3` or
+/// `https://developer.apple.com/streaming/emsg-id3`.
+fn is_id3_scheme(scheme_id_uri: &str) -> bool {
+    scheme_id_uri.to_ascii_lowercase().contains("id3")
+}
+
+/// Extract timed metadata from data of a segment.
+///
+/// MPEG-TS, packed audio and fragmented MP4 are detected from the data. The returned
+/// entries have no stream id or sequence set.
+pub fn read_timed_metadata(data: &[u8]) -> Vec<TimedMetadata> {
+    let entry = |time, payload| TimedMetadata {
+        stream_id: 0,
+        sequence: 0,
+        time,
+        payload,
+    };
+
+    // MPEG-TS
+    if data.first() == Some(&0x47) {
+        let mut demuxer = TsDemuxer::new();
+        let mut packets = demuxer.push(data);
+        packets.extend(demuxer.flush());
+        return packets
+            .into_iter()
+            .filter(|packet| packet.stream_type == StreamType::Other(METADATA_STREAM_TYPE))
+            .filter_map(|packet| {
+                let frames = read_id3_frames(packet.data.as_slice())?;
+                let time = packet.pts.map(|pts| pts as f64 / 90000.);
+                Some(entry(time, TimedMetadataPayload::Id3 { frames }))
+            })
+            .collect();
+    }
+
+    // packed audio, which starts with ID3 tags
+    if data.starts_with(b"ID3") {
+        let mut reader = Cursor::new(data);
+        let mut entries = Vec::new();
+        let mut time = None;
+        while data
+            .get(reader.position() as usize..)
+            .is_some_and(|rest| rest.starts_with(b"ID3"))
+        {
+            let Some(frames) = read_id3_frames(&mut reader) else {
+                break;
+            };
+            time = transport_stream_timestamp(&frames).or(time);
+            entries.push(entry(time, TimedMetadataPayload::Id3 { frames }));
+        }
+        return entries;
+    }
+
+    // fragmented MP4
+    let segment_start = segment_start_time(data);
+    Mp4BoxIter::new(data)
+        .filter(|b| &b.r#type == b"emsg")
+        .filter_map(|emsg| {
+            let message = parse_emsg(emsg.data)?;
+            let duration = (message.event_duration != u32::MAX)
+                .then(|| message.event_duration as f64 / message.timescale.max(1) as f64);
+            let frames = if is_id3_scheme(&message.scheme_id_uri) {
+                read_id3_frames(message.message_data).unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            let data = frames.is_empty().then(|| hex::encode(message.message_data));
+
+            Some(entry(
+                message.time(segment_start),
+                TimedMetadataPayload::Emsg {
+                    id: message.id,
+                    duration,
+                    frames,
+                    data,
+                    scheme_id_uri: message.scheme_id_uri,
+                    value: message.value,
+                },
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::mp4::write_box;
+
+    /// An ID3v2.4 tag with a single frame.
+    fn id3_tag(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let size = |size: usize| {
+            [
+                (size >> 21) as u8 & 0x7f,
+                (size >> 14) as u8 & 0x7f,
+                (size >> 7) as u8 & 0x7f,
+                size as u8 & 0x7f,
+            ]
+        };
+
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&size(content.len()));
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(content);
+
+        let mut tag = b"ID3\x04\x00\x00".to_vec();
+        tag.extend_from_slice(&size(frame.len()));
+        tag.extend(frame);
+        tag
+    }
+
+    #[test]
+    fn test_packed_audio() {
+        let mut timestamp = b"com.apple.streaming.transportStreamTimestamp\0".to_vec();
+        timestamp.extend_from_slice(&900_000u64.to_be_bytes());
+        let mut data = id3_tag(b"PRIV", &timestamp);
+        data.extend(id3_tag(b"TXXX", b"\x03lyrics\0hello"));
+        data.extend_from_slice(&[0xff, 0xf1, 0x50, 0x80]);
+
+        let entries = read_timed_metadata(&data);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].time, Some(10.));
+        assert_eq!(entries[1].time, Some(10.));
+        assert_eq!(
+            entries[1].payload,
+            TimedMetadataPayload::Id3 {
+                frames: vec![Id3Frame {
+                    id: "TXXX".to_string(),
+                    description: Some("lyrics".to_string()),
+                    text: Some("hello".to_string()),
+                    data: None,
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_emsg() {
+        let mut data = Vec::new();
+
+        // version 1 ID3 message at 12s
+        let mut emsg = vec![1, 0, 0, 0];
+        emsg.extend_from_slice(&1000u32.to_be_bytes());
+        emsg.extend_from_slice(&12_000u64.to_be_bytes());
+        emsg.extend_from_slice(&u32::MAX.to_be_bytes());
+        emsg.extend_from_slice(&7u32.to_be_bytes());
+        emsg.extend_from_slice(b"https://aomedia.org/emsg/ID3\0\0");
+        emsg.extend(id3_tag(b"TXXX", b"\x03program\0news"));
+        write_box(&mut data, b"emsg", &emsg);
+
+        // version 0 message of another scheme
+        let mut emsg = vec![0; 4];
+        emsg.extend_from_slice(b"urn:example\01\0");
+        for value in [1000u32, 500, 2000, 8] {
+            emsg.extend_from_slice(&value.to_be_bytes());
+        }
+        emsg.extend_from_slice(&[0xde, 0xad]);
+        write_box(&mut data, b"emsg", &emsg);
+
+        let entries = read_timed_metadata(&data);
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].time, Some(12.));
+        let TimedMetadataPayload::Emsg {
+            id,
+            duration,
+            frames,
+            data,
+            ..
+        } = &entries[0].payload
+        else {
+            panic!("expected emsg");
+        };
+        assert_eq!((*id, *duration, data), (7, None, &None));
+        assert_eq!(frames[0].text.as_deref(), Some("news"));
+
+        // the start of the segment is unknown without tfdt
+        assert_eq!(entries[1].time, None);
+        assert_eq!(
+            entries[1].payload,
+            TimedMetadataPayload::Emsg {
+                scheme_id_uri: "urn:example".to_string(),
+                value: "1".to_string(),
+                id: 8,
+                duration: Some(2.),
+                frames: Vec::new(),
+                data: Some("dead".to_string()),
+            }
+        );
+    }
+}
@@ -0,0 +1,18 @@
+//! Helpers for ID3v2 tags (https://id3.org/id3v2.4.0-structure).
+
+/// Total size of ID3 tags at the start of the data.
+pub fn id3_tags_size(data: &[u8]) -> usize {
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + 10) {
+        if &header[0..3] != b"ID3" {
+            break;
+        }
+        // syncsafe integer, excluding the header and footer
+        let size = header[6..10]
+            .iter()
+            .fold(0, |size, b| (size << 7) | (*b & 0x7f) as usize);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        offset += 10 + size + footer;
+    }
+    offset.min(data.len())
+}
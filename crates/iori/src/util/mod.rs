@@ -3,7 +3,10 @@ use http::HttpClient;
 use crate::IoriResult;
 
 pub mod http;
+pub mod id3;
 pub mod mix;
+pub mod mp4;
+pub mod mpegts;
 pub mod ordered_stream;
 pub mod path;
 pub mod range;
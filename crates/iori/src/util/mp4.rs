@@ -0,0 +1,503 @@
+//! Minimal helpers for reading ISO base media file format (ISO/IEC 14496-12) boxes.
+use std::collections::HashMap;
+
+use crate::{IoriError, IoriResult};
+
+/// A box parsed from a slice of ISO-BMFF data.
+pub struct Mp4Box<'a> {
+    /// Four character code of the box
+    pub r#type: [u8; 4],
+    /// Offset of the box in the parsed data
+    pub offset: usize,
+    /// Size of the whole box, including the header
+    pub size: usize,
+    /// Payload of the box, excluding the header
+    pub data: &'a [u8],
+    /// The whole box, including the header
+    pub raw: &'a [u8],
+}
+
+impl<'a> Mp4Box<'a> {
+    /// Offset of the first byte after this box in the parsed data.
+    pub fn end(&self) -> usize {
+        self.offset + self.size
+    }
+
+    /// Size of the box header, including the 64-bit largesize if present.
+    pub fn header_size(&self) -> usize {
+        self.size - self.data.len()
+    }
+
+    /// Iterate over child boxes of a container box.
+    ///
+    /// `skip` is the number of payload bytes before the first child, for example 4 for the
+    /// version and flags of a full box.
+    pub fn children(&self, skip: usize) -> Mp4BoxIter<'a> {
+        Mp4BoxIter::new(self.data.get(skip..).unwrap_or_default())
+    }
+
+    /// Find the first child box with the given type.
+    pub fn find_child(&self, r#type: &[u8; 4]) -> Option<Mp4Box<'a>> {
+        self.children(0).find(|b| &b.r#type == r#type)
+    }
+}
+
+/// Iterate over top-level boxes in the given data.
+///
+/// Iteration stops at the first malformed or truncated box.
+pub struct Mp4BoxIter<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Mp4BoxIter<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for Mp4BoxIter<'a> {
+    type Item = Mp4Box<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let input = &self.data[self.offset..];
+        if input.len() < 8 {
+            return None;
+        }
+
+        let size = u32::from_be_bytes(input[0..4].try_into().unwrap()) as usize;
+        let r#type: [u8; 4] = input[4..8].try_into().unwrap();
+        let (size, header_size) = match size {
+            // box extends to the end of the data
+            0 => (input.len(), 8),
+            // 64-bit largesize follows the type
+            1 => {
+                if input.len() < 16 {
+                    return None;
+                }
+                let size = u64::from_be_bytes(input[8..16].try_into().unwrap()) as usize;
+                (size, 16)
+            }
+            size => (size, 8),
+        };
+        if size < header_size || size > input.len() {
+            return None;
+        }
+
+        let mp4_box = Mp4Box {
+            r#type,
+            offset: self.offset,
+            size,
+            data: &input[header_size..size],
+            raw: &input[..size],
+        };
+        self.offset += size;
+        Some(mp4_box)
+    }
+}
+
+/// Find the first top-level box with the given type.
+pub fn find_box<'a>(data: &'a [u8], r#type: &[u8; 4]) -> Option<Mp4Box<'a>> {
+    Mp4BoxIter::new(data).find(|b| &b.r#type == r#type)
+}
+
+/// Split a fragmented MP4 segment into the initialization segment at its start, which
+/// consists of `ftyp` and `moov` boxes, and the remaining fragments.
+pub fn split_init_segment(data: &[u8]) -> (&[u8], &[u8]) {
+    let offset = Mp4BoxIter::new(data)
+        .take_while(|b| matches!(&b.r#type, b"ftyp" | b"moov"))
+        .last()
+        .map_or(0, |b| b.end());
+    data.split_at(offset)
+}
+
+/// Default values of samples in a track, defined in `trex` and overridden by `tfhd`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SampleDefaults {
+    pub duration: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+/// A sample described by a track run (`trun`) box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FragmentSample {
+    /// Decode time in timescale units
+    pub decode_time: u64,
+    pub duration: u32,
+    pub size: u32,
+    pub flags: u32,
+    pub composition_offset: i32,
+    /// Offset of the sample data in the data the `moof` box was parsed from
+    pub offset: usize,
+}
+
+impl FragmentSample {
+    /// Whether the sample is a sync sample, which is `sample_is_non_sync_sample` not set.
+    pub fn is_sync(&self) -> bool {
+        self.flags & 0x0001_0000 == 0
+    }
+}
+
+/// Samples of a track fragment (`traf`) box.
+#[derive(Debug, Clone)]
+pub struct TrackFragment {
+    pub track_id: u32,
+    pub samples: Vec<FragmentSample>,
+}
+
+/// Read the track_ID in `tkhd` of a `trak` box.
+pub fn read_track_id(trak: &Mp4Box) -> Option<u32> {
+    let tkhd = trak.find_child(b"tkhd")?;
+    let version = *tkhd.data.first()?;
+    // creation_time and modification_time are 64-bit in version 1
+    read_u32(tkhd.data, if version == 1 { 20 } else { 12 })
+}
+
+/// Read the timescale in `mdia/mdhd` of a `trak` box.
+pub fn read_timescale(trak: &Mp4Box) -> Option<u32> {
+    let mdhd = trak.find_child(b"mdia")?.find_child(b"mdhd")?;
+    let version = *mdhd.data.first()?;
+    read_u32(mdhd.data, if version == 1 { 20 } else { 12 })
+}
+
+/// Read default sample values of each track from `mvex/trex` boxes in a `moov` box.
+pub fn parse_trex(moov: &Mp4Box) -> HashMap<u32, SampleDefaults> {
+    let mut defaults = HashMap::new();
+    let Some(mvex) = moov.find_child(b"mvex") else {
+        return defaults;
+    };
+
+    for trex in mvex.children(0).filter(|b| &b.r#type == b"trex") {
+        // version(1) + flags(3) + track_ID(4) + default_sample_description_index(4)
+        if let (Some(track_id), Some(duration), Some(size), Some(flags)) = (
+            read_u32(trex.data, 4),
+            read_u32(trex.data, 12),
+            read_u32(trex.data, 16),
+            read_u32(trex.data, 20),
+        ) {
+            defaults.insert(
+                track_id,
+                SampleDefaults {
+                    duration,
+                    size,
+                    flags,
+                },
+            );
+        }
+    }
+    defaults
+}
+
+/// Parse samples of all track fragments in a top-level `moof` box.
+///
+/// One [TrackFragment] is returned for each `traf` box, in order.
+///
+/// Segments are cached with the initialization segment prepended, so an explicit
+/// base-data-offset can not be resolved. Data offsets are always treated as relative
+/// to the moof box, which is required by CMAF anyway.
+pub fn parse_moof(
+    moof: &Mp4Box,
+    trex: &HashMap<u32, SampleDefaults>,
+) -> IoriResult<Vec<TrackFragment>> {
+    let invalid = || IoriError::Mp4Parsing("Invalid moof box".to_string());
+    let mut fragments = Vec::new();
+
+    for traf in moof.children(0).filter(|b| &b.r#type == b"traf") {
+        let tfhd = traf.find_child(b"tfhd").ok_or_else(invalid)?;
+        let tfhd_flags = read_u32(tfhd.data, 0).ok_or_else(invalid)? & 0x00ff_ffff;
+        let track_id = read_u32(tfhd.data, 4).ok_or_else(invalid)?;
+        let mut defaults = trex.get(&track_id).copied().unwrap_or_default();
+
+        let mut pos = 8;
+        if tfhd_flags & 0x01 != 0 {
+            // base-data-offset
+            pos += 8;
+        }
+        if tfhd_flags & 0x02 != 0 {
+            // sample-description-index
+            pos += 4;
+        }
+        if tfhd_flags & 0x08 != 0 {
+            defaults.duration = read_u32(tfhd.data, pos).ok_or_else(invalid)?;
+            pos += 4;
+        }
+        if tfhd_flags & 0x10 != 0 {
+            defaults.size = read_u32(tfhd.data, pos).ok_or_else(invalid)?;
+            pos += 4;
+        }
+        if tfhd_flags & 0x20 != 0 {
+            defaults.flags = read_u32(tfhd.data, pos).ok_or_else(invalid)?;
+        }
+
+        let mut decode_time = match traf.find_child(b"tfdt") {
+            Some(tfdt) => match tfdt.data.first() {
+                Some(1) => read_u64(tfdt.data, 4).ok_or_else(invalid)?,
+                _ => read_u32(tfdt.data, 4).ok_or_else(invalid)? as u64,
+            },
+            None => 0,
+        };
+
+        let mut samples = Vec::new();
+        // runs without data_offset continue right after the previous run
+        let mut data_offset = moof.offset as u64;
+        for trun in traf.children(0).filter(|b| &b.r#type == b"trun") {
+            let trun_flags = read_u32(trun.data, 0).ok_or_else(invalid)? & 0x00ff_ffff;
+            let sample_count = read_u32(trun.data, 4).ok_or_else(invalid)?;
+
+            let mut pos = 8;
+            if trun_flags & 0x01 != 0 {
+                let offset = read_u32(trun.data, pos).ok_or_else(invalid)? as i32;
+                data_offset = (moof.offset as u64).saturating_add_signed(offset as i64);
+                pos += 4;
+            }
+            let mut first_sample_flags = None;
+            if trun_flags & 0x04 != 0 {
+                first_sample_flags = Some(read_u32(trun.data, pos).ok_or_else(invalid)?);
+                pos += 4;
+            }
+
+            for index in 0..sample_count {
+                let mut duration = defaults.duration;
+                let mut size = defaults.size;
+                let mut flags = match first_sample_flags {
+                    Some(flags) if index == 0 => flags,
+                    _ => defaults.flags,
+                };
+                let mut composition_offset = 0;
+                if trun_flags & 0x100 != 0 {
+                    duration = read_u32(trun.data, pos).ok_or_else(invalid)?;
+                    pos += 4;
+                }
+                if trun_flags & 0x200 != 0 {
+                    size = read_u32(trun.data, pos).ok_or_else(invalid)?;
+                    pos += 4;
+                }
+                if trun_flags & 0x400 != 0 {
+                    flags = read_u32(trun.data, pos).ok_or_else(invalid)?;
+                    pos += 4;
+                }
+                if trun_flags & 0x800 != 0 {
+                    // unsigned in version 0 and signed in version 1, but offsets larger
+                    // than i32::MAX never appear in practice
+                    composition_offset = read_u32(trun.data, pos).ok_or_else(invalid)? as i32;
+                    pos += 4;
+                }
+
+                samples.push(FragmentSample {
+                    decode_time,
+                    duration,
+                    size,
+                    flags,
+                    composition_offset,
+                    offset: data_offset as usize,
+                });
+                data_offset += size as u64;
+                decode_time += duration as u64;
+            }
+        }
+
+        fragments.push(TrackFragment { track_id, samples });
+    }
+
+    Ok(fragments)
+}
+
+/// An event message box (`emsg`), defined in ISO/IEC 23009-1 5.10.3.3.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventMessage<'a> {
+    pub scheme_id_uri: String,
+    pub value: String,
+    pub timescale: u32,
+    /// Presentation time relative to the start of the segment, in version 0 boxes
+    pub presentation_time_delta: Option<u32>,
+    /// Presentation time on the media timeline, in version 1 boxes
+    pub presentation_time: Option<u64>,
+    /// Duration in timescale units. `0xFFFFFFFF` means unknown.
+    pub event_duration: u32,
+    pub id: u32,
+    pub message_data: &'a [u8],
+}
+
+impl EventMessage<'_> {
+    /// Offset of the event in seconds, relative to the start of the segment.
+    ///
+    /// `segment_start` is the start of the segment in seconds on the media timeline,
+    /// which is required by version 1 boxes.
+    pub fn offset(&self, segment_start: Option<f64>) -> f64 {
+        let timescale = self.timescale.max(1) as f64;
+        match (self.presentation_time_delta, self.presentation_time) {
+            (Some(delta), _) => delta as f64 / timescale,
+            (_, Some(time)) => (time as f64 / timescale - segment_start.unwrap_or(0.)).max(0.),
+            _ => 0.,
+        }
+    }
+
+    /// Presentation time of the event in seconds on the media timeline, if known.
+    pub fn time(&self, segment_start: Option<f64>) -> Option<f64> {
+        let timescale = self.timescale.max(1) as f64;
+        match (self.presentation_time_delta, self.presentation_time) {
+            (_, Some(time)) => Some(time as f64 / timescale),
+            (Some(delta), _) => Some(segment_start? + delta as f64 / timescale),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the payload of an `emsg` box.
+pub fn parse_emsg(data: &[u8]) -> Option<EventMessage<'_>> {
+    // read a null-terminated string, returning the string and the offset after it
+    let read_string = |offset: usize| {
+        let input = data.get(offset..)?;
+        let length = input.iter().position(|b| *b == 0)?;
+        Some((
+            String::from_utf8_lossy(&input[..length]).into_owned(),
+            offset + length + 1,
+        ))
+    };
+
+    match data.first()? {
+        0 => {
+            let (scheme_id_uri, offset) = read_string(4)?;
+            let (value, offset) = read_string(offset)?;
+            Some(EventMessage {
+                scheme_id_uri,
+                value,
+                timescale: read_u32(data, offset)?,
+                presentation_time_delta: Some(read_u32(data, offset + 4)?),
+                presentation_time: None,
+                event_duration: read_u32(data, offset + 8)?,
+                id: read_u32(data, offset + 12)?,
+                message_data: data.get(offset + 16..)?,
+            })
+        }
+        1 => {
+            let (scheme_id_uri, offset) = read_string(24)?;
+            let (value, offset) = read_string(offset)?;
+            Some(EventMessage {
+                scheme_id_uri,
+                value,
+                timescale: read_u32(data, 4)?,
+                presentation_time_delta: None,
+                presentation_time: Some(read_u64(data, 8)?),
+                event_duration: read_u32(data, 16)?,
+                id: read_u32(data, 20)?,
+                message_data: data.get(offset..)?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Start of a media segment in seconds, from `tfdt` of its first track fragment and the
+/// timescale of the first track in the initialization segment.
+pub fn segment_start_time(data: &[u8]) -> Option<f64> {
+    let timescale = find_box(data, b"moov")?
+        .find_child(b"trak")
+        .and_then(|trak| read_timescale(&trak))?;
+    let tfdt = find_box(data, b"moof")?
+        .find_child(b"traf")?
+        .find_child(b"tfdt")?;
+    let decode_time = match tfdt.data.first() {
+        Some(1) => read_u64(tfdt.data, 4)?,
+        _ => read_u32(tfdt.data, 4)? as u64,
+    };
+    Some(decode_time as f64 / timescale.max(1) as f64)
+}
+
+/// Write a box with the given payload.
+pub fn write_box(output: &mut Vec<u8>, r#type: &[u8; 4], payload: &[u8]) {
+    let size = payload.len() + 8;
+    if size > u32::MAX as usize {
+        output.extend_from_slice(&1u32.to_be_bytes());
+        output.extend_from_slice(r#type);
+        output.extend_from_slice(&(size as u64 + 8).to_be_bytes());
+    } else {
+        output.extend_from_slice(&(size as u32).to_be_bytes());
+        output.extend_from_slice(r#type);
+    }
+    output.extend_from_slice(payload);
+}
+
+/// Write a full box with the given version, flags and payload.
+pub fn write_full_box(
+    output: &mut Vec<u8>,
+    r#type: &[u8; 4],
+    version: u8,
+    flags: u32,
+    payload: &[u8],
+) {
+    let mut data = Vec::with_capacity(payload.len() + 4);
+    data.extend_from_slice(&(((version as u32) << 24) | (flags & 0x00ff_ffff)).to_be_bytes());
+    data.extend_from_slice(payload);
+    write_box(output, r#type, &data);
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes(b.try_into().unwrap()))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+}
+
+pub(crate) fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_init_segment() {
+        let mut fragment = Vec::new();
+        write_box(&mut fragment, b"moof", &[1, 2, 3]);
+        write_box(&mut fragment, b"mdat", &[4, 5]);
+
+        let mut init = Vec::new();
+        write_box(&mut init, b"ftyp", b"isom");
+        write_box(&mut init, b"moov", &[0; 8]);
+        let segment = [init.as_slice(), fragment.as_slice()].concat();
+
+        assert_eq!(
+            split_init_segment(&segment),
+            (init.as_slice(), fragment.as_slice())
+        );
+        assert_eq!(
+            split_init_segment(&fragment),
+            (&[][..], fragment.as_slice())
+        );
+    }
+
+    #[test]
+    fn test_iterate_boxes() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0, 0, 12]);
+        data.extend_from_slice(b"ftyp");
+        data.extend_from_slice(b"iso6");
+        data.extend_from_slice(&[0, 0, 0, 8]);
+        data.extend_from_slice(b"moof");
+
+        let boxes: Vec<_> = Mp4BoxIter::new(&data).collect();
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(&boxes[0].r#type, b"ftyp");
+        assert_eq!(boxes[0].data, b"iso6");
+        assert_eq!(&boxes[1].r#type, b"moof");
+        assert_eq!(boxes[1].offset, 12);
+        assert_eq!(boxes[1].end(), 20);
+    }
+
+    #[test]
+    fn test_truncated_box() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0, 0, 16]);
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(&[0, 0]);
+
+        assert!(find_box(&data, b"mdat").is_none());
+    }
+}
@@ -0,0 +1,436 @@
+//! Minimal helpers for reading MPEG-2 transport streams (ISO/IEC 13818-1).
+use std::collections::HashMap;
+
+const TS_PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+
+/// Find the first presentation timestamp of video PES packets in the given data.
+///
+/// If no video stream is found, the first PTS of any stream is returned instead.
+/// The timestamp is in 90kHz units.
+pub fn first_pts(data: &[u8]) -> Option<u64> {
+    let mut fallback = None;
+
+    for packet in data.chunks_exact(TS_PACKET_SIZE) {
+        if packet[0] != SYNC_BYTE {
+            continue;
+        }
+        // payload_unit_start_indicator
+        if packet[1] & 0x40 == 0 {
+            continue;
+        }
+
+        let Some(pes) = packet_payload(packet) else {
+            continue;
+        };
+        let Some(PesHeader {
+            stream_id,
+            pts: Some(pts),
+            ..
+        }) = parse_pes_header(pes)
+        else {
+            continue;
+        };
+
+        if (0xe0..=0xef).contains(&stream_id) {
+            return Some(pts);
+        }
+        fallback.get_or_insert(pts);
+    }
+
+    fallback
+}
+
+/// Elementary stream types in PMT, defined in ISO/IEC 13818-1 Table 2-34.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamType {
+    H264,
+    H265,
+    /// AAC in ADTS
+    Aac,
+    Ac3,
+    Eac3,
+    Other(u8),
+}
+
+impl StreamType {
+    fn from_pmt(stream_type: u8, descriptors: &[u8]) -> Self {
+        match stream_type {
+            0x1b => Self::H264,
+            0x24 => Self::H265,
+            0x0f => Self::Aac,
+            0x81 => Self::Ac3,
+            0x87 => Self::Eac3,
+            // private data, identified by AC-3 or enhanced AC-3 descriptors in DVB
+            0x06 => {
+                let mut descriptors = descriptors;
+                while let [tag, length, rest @ ..] = descriptors {
+                    match tag {
+                        0x6a => return Self::Ac3,
+                        0x7a => return Self::Eac3,
+                        _ => {}
+                    }
+                    descriptors = rest.get(*length as usize..).unwrap_or_default();
+                }
+                Self::Other(stream_type)
+            }
+            _ => Self::Other(stream_type),
+        }
+    }
+}
+
+/// A PES packet of an elementary stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PesPacket {
+    pub pid: u16,
+    pub stream_type: StreamType,
+    /// Presentation timestamp in 90kHz units
+    pub pts: Option<u64>,
+    /// Decoding timestamp in 90kHz units
+    pub dts: Option<u64>,
+    pub data: Vec<u8>,
+}
+
+/// A demuxer which reads PES packets of elementary streams listed in PMT.
+///
+/// Data can be pushed in arbitrary pieces, for example segment by segment.
+#[derive(Default)]
+pub struct TsDemuxer {
+    pmt_pids: Vec<u16>,
+    /// Elementary streams in the order of PMT
+    streams: Vec<(u16, StreamType)>,
+    /// Incomplete PES packet of each PID
+    buffers: HashMap<u16, Vec<u8>>,
+    /// Incomplete TS packet at the end of the pushed data
+    remaining: Vec<u8>,
+}
+
+impl TsDemuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Elementary streams found in PMT, with their PIDs.
+    pub fn streams(&self) -> &[(u16, StreamType)] {
+        &self.streams
+    }
+
+    /// Push data into the demuxer, returning all completed PES packets.
+    pub fn push(&mut self, data: &[u8]) -> Vec<PesPacket> {
+        let mut input = std::mem::take(&mut self.remaining);
+        input.extend_from_slice(data);
+
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        while offset + TS_PACKET_SIZE <= input.len() {
+            if input[offset] != SYNC_BYTE {
+                // resync to the next sync byte
+                offset += 1;
+                continue;
+            }
+            self.read_packet(&input[offset..offset + TS_PACKET_SIZE], &mut packets);
+            offset += TS_PACKET_SIZE;
+        }
+        self.remaining = input.split_off(offset);
+        packets
+    }
+
+    /// Return all buffered PES packets. This should be called at the end of the stream.
+    pub fn flush(&mut self) -> Vec<PesPacket> {
+        self.remaining.clear();
+        let mut packets = Vec::new();
+        for (pid, stream_type) in self.streams.clone() {
+            if let Some(buffer) = self.buffers.remove(&pid) {
+                packets.extend(parse_pes(pid, stream_type, &buffer));
+            }
+        }
+        packets
+    }
+
+    fn read_packet(&mut self, packet: &[u8], packets: &mut Vec<PesPacket>) {
+        let pid = (((packet[1] & 0x1f) as u16) << 8) | packet[2] as u16;
+        let payload_unit_start = packet[1] & 0x40 != 0;
+        let Some(payload) = packet_payload(packet) else {
+            return;
+        };
+
+        if pid == 0 {
+            if payload_unit_start {
+                self.read_pat(payload);
+            }
+        } else if self.pmt_pids.contains(&pid) {
+            if payload_unit_start {
+                self.read_pmt(payload);
+            }
+        } else if let Some(&(_, stream_type)) = self.streams.iter().find(|(p, _)| *p == pid) {
+            if payload_unit_start {
+                if let Some(buffer) = self.buffers.insert(pid, payload.to_vec()) {
+                    packets.extend(parse_pes(pid, stream_type, &buffer));
+                }
+            } else if let Some(buffer) = self.buffers.get_mut(&pid) {
+                buffer.extend_from_slice(payload);
+            }
+        }
+    }
+
+    fn read_pat(&mut self, payload: &[u8]) {
+        let Some(section) = psi_section(payload, 0x00) else {
+            return;
+        };
+        // transport_stream_id(2) + version(1) + section_number(1) + last_section_number(1)
+        for program in section.get(5..).unwrap_or_default().chunks_exact(4) {
+            let program_number = u16::from_be_bytes([program[0], program[1]]);
+            let pid = (((program[2] & 0x1f) as u16) << 8) | program[3] as u16;
+            // program 0 is the network PID
+            if program_number != 0 && !self.pmt_pids.contains(&pid) {
+                self.pmt_pids.push(pid);
+            }
+        }
+    }
+
+    fn read_pmt(&mut self, payload: &[u8]) {
+        let Some(section) = psi_section(payload, 0x02) else {
+            return;
+        };
+        if section.len() < 9 {
+            return;
+        }
+        // program_number(2) + version(1) + section_number(1) + last_section_number(1) +
+        // PCR_PID(2) + program_info_length(2)
+        let program_info_length =
+            ((((section[7] & 0x0f) as usize) << 8) | section[8] as usize).min(section.len() - 9);
+        let mut entries = &section[9 + program_info_length..];
+        while entries.len() >= 5 {
+            let stream_type = entries[0];
+            let pid = (((entries[1] & 0x1f) as u16) << 8) | entries[2] as u16;
+            let info_length = ((((entries[3] & 0x0f) as usize) << 8) | entries[4] as usize)
+                .min(entries.len() - 5);
+            let descriptors = &entries[5..5 + info_length];
+            if !self.streams.iter().any(|(p, _)| *p == pid) {
+                self.streams
+                    .push((pid, StreamType::from_pmt(stream_type, descriptors)));
+            }
+            entries = &entries[5 + info_length..];
+        }
+    }
+}
+
+/// Payload of a TS packet, after the adaptation field.
+fn packet_payload(packet: &[u8]) -> Option<&[u8]> {
+    let adaptation_field_control = (packet[3] >> 4) & 0x03;
+    let payload_offset = match adaptation_field_control {
+        // payload only
+        0b01 => 4,
+        // adaptation field followed by payload
+        0b11 => 5 + packet[4] as usize,
+        _ => return None,
+    };
+    packet.get(payload_offset..)
+}
+
+/// Data of a PSI section with the given table_id, between the section header and CRC_32.
+fn psi_section(payload: &[u8], table_id: u8) -> Option<&[u8]> {
+    let pointer_field = *payload.first()? as usize;
+    let section = payload.get(1 + pointer_field..)?;
+    if *section.first()? != table_id || section.len() < 3 {
+        return None;
+    }
+    let section_length = (((section[1] & 0x0f) as usize) << 8) | section[2] as usize;
+    section.get(3..(3 + section_length).checked_sub(4)?)
+}
+
+struct PesHeader {
+    stream_id: u8,
+    pts: Option<u64>,
+    dts: Option<u64>,
+    /// Offset of the payload in the PES packet
+    payload_offset: usize,
+    /// PES_packet_length, or 0 if unbounded
+    packet_length: usize,
+}
+
+/// Parse the header of a PES packet.
+fn parse_pes_header(pes: &[u8]) -> Option<PesHeader> {
+    if pes.len() < 9 || pes[0..3] != [0x00, 0x00, 0x01] {
+        return None;
+    }
+    let stream_id = pes[3];
+    let packet_length = u16::from_be_bytes([pes[4], pes[5]]) as usize;
+    let pts_dts_flags = pes[7] >> 6;
+    let header_data_length = pes[8] as usize;
+
+    let pts = if pts_dts_flags & 0b10 != 0 {
+        Some(read_timestamp(pes.get(9..14)?))
+    } else {
+        None
+    };
+    let dts = if pts_dts_flags == 0b11 {
+        Some(read_timestamp(pes.get(14..19)?))
+    } else {
+        None
+    };
+
+    Some(PesHeader {
+        stream_id,
+        pts,
+        dts,
+        payload_offset: 9 + header_data_length,
+        packet_length,
+    })
+}
+
+fn read_timestamp(data: &[u8]) -> u64 {
+    (((data[0] as u64 >> 1) & 0x07) << 30)
+        | ((data[1] as u64) << 22)
+        | (((data[2] as u64 >> 1) & 0x7f) << 15)
+        | ((data[3] as u64) << 7)
+        | (data[4] as u64 >> 1)
+}
+
+fn parse_pes(pid: u16, stream_type: StreamType, pes: &[u8]) -> Option<PesPacket> {
+    let header = parse_pes_header(pes)?;
+    let mut data = pes.get(header.payload_offset..)?;
+    if header.packet_length != 0 {
+        // PES_packet_length counts bytes after the length field
+        let end = (6 + header.packet_length).saturating_sub(header.payload_offset);
+        data = &data[..end.min(data.len())];
+    }
+
+    Some(PesPacket {
+        pid,
+        stream_type,
+        pts: header.pts,
+        dts: header.dts,
+        data: data.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pes_packet(pid: u16, stream_id: u8, pts: u64) -> Vec<u8> {
+        let mut packet = vec![
+            SYNC_BYTE,
+            0x40 | (pid >> 8) as u8,
+            pid as u8,
+            0x10,
+            0x00,
+            0x00,
+            0x01,
+            stream_id,
+            0x00,
+            0x00,
+            0x80,
+            0x80,
+            0x05,
+        ];
+        packet.extend_from_slice(&timestamp(pts));
+        packet.resize(TS_PACKET_SIZE, 0xff);
+        packet
+    }
+
+    fn timestamp(pts: u64) -> [u8; 5] {
+        [
+            0x21 | ((pts >> 29) as u8 & 0x0e),
+            (pts >> 22) as u8,
+            0x01 | ((pts >> 14) as u8 & 0xfe),
+            (pts >> 7) as u8,
+            0x01 | ((pts << 1) as u8 & 0xfe),
+        ]
+    }
+
+    /// Build a TS packet, stuffed with an adaptation field.
+    fn ts_packet(pid: u16, start: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            SYNC_BYTE,
+            ((start as u8) << 6) | (pid >> 8) as u8,
+            pid as u8,
+            0x30,
+        ];
+        let stuffing = TS_PACKET_SIZE - 5 - payload.len();
+        packet.push(stuffing as u8);
+        if stuffing > 0 {
+            packet.push(0x00);
+            packet.resize(5 + stuffing, 0xff);
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_first_video_pts() {
+        let mut data = pes_packet(0x101, 0xc0, 1_000);
+        data.extend(pes_packet(0x100, 0xe0, 8_589_934_000));
+
+        assert_eq!(first_pts(&data), Some(8_589_934_000));
+        assert_eq!(first_pts(&data[..TS_PACKET_SIZE]), Some(1_000));
+        assert_eq!(first_pts(&[]), None);
+    }
+
+    #[test]
+    fn test_demuxer() {
+        let pat = [
+            0x00, 0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00, 0x00,
+            0x00, 0x00, 0x00,
+        ];
+        let pmt = [
+            0x00, 0x02, 0xb0, 0x17, 0x00, 0x01, 0xc1, 0x00, 0x00, 0xe1, 0x00, 0xf0, 0x00, 0x1b,
+            0xe1, 0x00, 0xf0, 0x00, 0x0f, 0xe1, 0x01, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let mut video = vec![0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x80, 0x80, 0x05];
+        video.extend_from_slice(&timestamp(3000));
+        video.extend_from_slice(&[0xaa; 250]);
+        let mut audio = vec![0x00, 0x00, 0x01, 0xc0, 0x00, 0x0a, 0x80, 0x80, 0x05];
+        audio.extend_from_slice(&timestamp(2000));
+        audio.extend_from_slice(&[0x11, 0x22]);
+        let mut next_video = vec![0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x80, 0x80, 0x05];
+        next_video.extend_from_slice(&timestamp(6000));
+        next_video.push(0xbb);
+
+        let mut data = ts_packet(0, true, &pat);
+        data.extend(ts_packet(0x1000, true, &pmt));
+        data.extend(ts_packet(0x100, true, &video[..183]));
+        data.extend(ts_packet(0x100, false, &video[183..]));
+        data.extend(ts_packet(0x101, true, &audio));
+        data.extend(ts_packet(0x100, true, &next_video));
+
+        let mut demuxer = TsDemuxer::new();
+        let (first, second) = data.split_at(300);
+        assert!(demuxer.push(first).is_empty());
+        assert_eq!(
+            demuxer.push(second),
+            vec![PesPacket {
+                pid: 0x100,
+                stream_type: StreamType::H264,
+                pts: Some(3000),
+                dts: None,
+                data: vec![0xaa; 250],
+            }]
+        );
+        assert_eq!(
+            demuxer.streams(),
+            &[(0x100, StreamType::H264), (0x101, StreamType::Aac)]
+        );
+        assert_eq!(
+            demuxer.flush(),
+            vec![
+                PesPacket {
+                    pid: 0x100,
+                    stream_type: StreamType::H264,
+                    pts: Some(6000),
+                    dts: None,
+                    data: vec![0xbb],
+                },
+                PesPacket {
+                    pid: 0x101,
+                    stream_type: StreamType::Aac,
+                    pts: Some(2000),
+                    dts: None,
+                    data: vec![0x11, 0x22],
+                },
+            ]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(deprecated)]
 use iori::{
     dash::{archive::CommonDashArchiveSource, live::CommonDashLiveSource},
     HttpClient, StreamingSource,
@@ -20,19 +21,18 @@ async fn test_static_a2d_tv() -> anyhow::Result<()> {
     // no further segments
     info.recv().await.assert_error();
 
+    // The deprecated archive source shares the same timeline engine
+    #[allow(deprecated)]
     let playlist = CommonDashArchiveSource::new(client, playlist_uri.parse()?, None, None)?;
     let mut info = playlist.fetch_info().await?;
 
-    let mut segments_archive = Vec::new();
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 644);
-    segments_archive.extend(segments);
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 636);
-    segments_archive.extend(segments);
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 616);
-    segments_archive.extend(segments);
+    let segments_archive = info.recv().await.assert_success()?;
+    // segments of each adaptation set in each period
+    let counts: Vec<_> = segments_archive
+        .chunk_by(|a, b| a.stream_id == b.stream_id)
+        .map(|segments| segments.len())
+        .collect();
+    assert_eq!(counts, [644, 636, 616]);
     // no further segments
     info.recv().await.assert_error();
 
@@ -60,34 +60,19 @@ async fn test_dash_testcases_5b_1_thomson() -> anyhow::Result<()> {
     // no further segments
     info.recv().await.assert_error();
 
+    // The deprecated archive source shares the same timeline engine
+    #[allow(deprecated)]
     let playlist = CommonDashArchiveSource::new(client, playlist_uri.parse()?, None, None)?;
     let mut info = playlist.fetch_info().await?;
 
-    let mut segments_archive = Vec::new();
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 45);
-    segments_archive.extend(segments);
-
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 45);
-    segments_archive.extend(segments);
-
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 30);
-    segments_archive.extend(segments);
-
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 30);
-    segments_archive.extend(segments);
-
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 49);
-    segments_archive.extend(segments);
-
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 49);
-    segments_archive.extend(segments);
-
+    let segments_archive = info.recv().await.assert_success()?;
+    // segments of each adaptation set in each period
+    let counts: Vec<_> = segments_archive
+        .chunk_by(|a, b| a.stream_id == b.stream_id)
+        .map(|segments| segments.len())
+        .collect();
+    assert_eq!(counts, [45, 45, 30, 30, 49, 49]);
+    // no further segments
     info.recv().await.assert_error();
 
     for (i, segment) in segments_archive.iter().enumerate() {
@@ -1,6 +1,18 @@
+use std::sync::Arc;
+
+#[allow(deprecated)]
 use iori::{
+    cache::memory::MemoryCacheSource,
+    chapter::SegmentEventKind,
     dash::{archive::CommonDashArchiveSource, live::CommonDashLiveSource},
-    HttpClient, StreamingSource,
+    download::ParallelDownloader,
+    merge::ConcatAfterMerger,
+    metadata::Metadata,
+    ByteRange, HttpClient, InitialSegment, StreamingSource,
+};
+use wiremock::{
+    matchers::{header, method, path, path_regex},
+    Mock, ResponseTemplate,
 };
 
 use crate::{dash::setup_mock_server, AssertWrapper};
@@ -21,16 +33,18 @@ async fn test_lemino_sokosaku_235() -> anyhow::Result<()> {
     // no further segments
     info.recv().await.assert_error();
 
+    // The deprecated archive source shares the same timeline engine
+    #[allow(deprecated)]
     let playlist = CommonDashArchiveSource::new(client, playlist_uri.parse()?, None, None)?;
     let mut info = playlist.fetch_info().await?;
 
-    let mut segments_archive = Vec::new();
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 253);
-    segments_archive.extend(segments);
-    let segments = info.recv().await.assert_success()?;
-    assert_eq!(segments.len(), 253);
-    segments_archive.extend(segments);
+    let segments_archive = info.recv().await.assert_success()?;
+    // segments of each adaptation set in each period
+    let counts: Vec<_> = segments_archive
+        .chunk_by(|a, b| a.stream_id == b.stream_id)
+        .map(|segments| segments.len())
+        .collect();
+    assert_eq!(counts, [253, 253]);
     // no further segments
     info.recv().await.assert_error();
 
@@ -323,3 +337,181 @@ async fn test_lemino_sokosaku_235() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_event_stream_events() -> anyhow::Result<()> {
+    let data = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT20S" minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="0" start="PT0S">
+    <EventStream schemeIdUri="urn:scte:scte35:2013:xml" timescale="10">
+      <Event id="1" presentationTime="45" duration="50" />
+    </EventStream>
+    <EventStream schemeIdUri="urn:example:chapter" timescale="1">
+      <Event id="2" presentationTime="12" messageData="Talk" />
+    </EventStream>
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <SegmentTemplate media="video_$Number$.m4s" startNumber="1" duration="4" timescale="1" />
+      <Representation id="v" bandwidth="1000" />
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    let (playlist_uri, _server) = setup_mock_server(data).await;
+
+    let playlist = CommonDashLiveSource::new(HttpClient::default(), playlist_uri.parse()?, None)?;
+    let mut info = playlist.fetch_info().await?;
+    let segments = info.recv().await.assert_success()?;
+    assert_eq!(segments.len(), 5);
+    // @duration in the timescale of the template
+    assert!(segments.iter().all(|s| s.duration == Some(4.)));
+
+    let events: Vec<_> = segments
+        .iter()
+        .map(|s| {
+            s.events
+                .iter()
+                .map(|e| (e.kind, e.title.clone(), e.offset, e.duration))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    assert_eq!(
+        events,
+        [
+            vec![],
+            vec![(SegmentEventKind::AdStart, None, 0.5, Some(5.))],
+            vec![],
+            vec![(SegmentEventKind::Marker, Some("Talk".to_string()), 0., None)],
+            vec![],
+        ]
+    );
+
+    Ok(())
+}
+
+// EventStream events end up as chapters of the merged output
+#[tokio::test]
+async fn test_event_stream_chapters() -> anyhow::Result<()> {
+    let data = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT20S" minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="0" start="PT0S">
+    <EventStream schemeIdUri="urn:scte:scte35:2013:xml" timescale="10">
+      <Event id="1" presentationTime="45" duration="50" />
+    </EventStream>
+    <EventStream schemeIdUri="urn:example:chapter" timescale="1">
+      <Event id="2" presentationTime="12" messageData="Talk" />
+    </EventStream>
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <SegmentTemplate media="video_$Number$.m4s" startNumber="1" duration="4" timescale="1" />
+      <Representation id="v" bandwidth="1000" />
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    let (playlist_uri, server) = setup_mock_server(data).await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/video_\d+\.m4s$"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(b"data".to_vec()))
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir()?;
+    let output_file = output.path().join("output.mp4");
+    let source = CommonDashLiveSource::new(HttpClient::default(), playlist_uri.parse()?, None)?;
+    ParallelDownloader::builder()
+        .merger(ConcatAfterMerger::new(output_file.clone(), false))
+        .cache(Arc::new(MemoryCacheSource::new()))
+        .download(source)
+        .await?;
+
+    let metadata: Metadata =
+        serde_json::from_slice(&tokio::fs::read(output.path().join("output.json")).await?)?;
+    let chapters: Vec<_> = metadata
+        .chapters
+        .iter()
+        .map(|c| (c.start, c.end, c.title.as_str()))
+        .collect();
+    assert_eq!(
+        chapters,
+        [
+            (0., 4.5, "Program"),
+            (4.5, 9.5, "Ad break"),
+            (9.5, 12., "Program"),
+            (12., 20., "Talk"),
+        ]
+    );
+
+    Ok(())
+}
+
+// SegmentBase with an Initialization element without sourceURL and range
+#[tokio::test]
+async fn test_segment_base_self_initializing() -> anyhow::Result<()> {
+    let data = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT4S" minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-on-demand:2011">
+  <Period id="0" start="PT0S">
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <Representation id="v" bandwidth="1000">
+        <BaseURL>video.mp4</BaseURL>
+        <SegmentBase indexRange="100-155" timescale="1000">
+          <Initialization />
+        </SegmentBase>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    let (playlist_uri, server) = setup_mock_server(data).await;
+
+    // sidx with two references of 100 and 200 bytes
+    let mut sidx = 56u32.to_be_bytes().to_vec();
+    sidx.extend_from_slice(b"sidx");
+    sidx.extend_from_slice(&[0, 0, 0, 0]); // version and flags
+    sidx.extend_from_slice(&1u32.to_be_bytes()); // reference_ID
+    sidx.extend_from_slice(&1000u32.to_be_bytes()); // timescale
+    sidx.extend_from_slice(&0u32.to_be_bytes()); // earliest_presentation_time
+    sidx.extend_from_slice(&0u32.to_be_bytes()); // first_offset
+    sidx.extend_from_slice(&[0, 0]); // reserved
+    sidx.extend_from_slice(&2u16.to_be_bytes()); // reference_count
+    for (size, duration) in [(100u32, 2000u32), (200, 2000)] {
+        sidx.extend_from_slice(&size.to_be_bytes());
+        sidx.extend_from_slice(&duration.to_be_bytes());
+        sidx.extend_from_slice(&0x9000_0000u32.to_be_bytes());
+    }
+    assert_eq!(sidx.len(), 56);
+
+    let init = vec![0xaa; 100];
+    Mock::given(method("GET"))
+        .and(path("/video.mp4"))
+        .and(header("range", "bytes=0-99"))
+        .respond_with(ResponseTemplate::new(206).set_body_bytes(init.clone()))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/video.mp4"))
+        .and(header("range", "bytes=100-155"))
+        .respond_with(ResponseTemplate::new(206).set_body_bytes(sidx))
+        .mount(&server)
+        .await;
+
+    let playlist = CommonDashLiveSource::new(HttpClient::default(), playlist_uri.parse()?, None)?;
+    let mut info = playlist.fetch_info().await?;
+    let segments = info.recv().await.assert_success()?;
+
+    // the initialization segment is the data before the index, not the whole file
+    let segments: Vec<_> = segments
+        .into_iter()
+        .map(|s| (s.initial_segment, s.byte_range))
+        .collect();
+    assert_eq!(
+        segments,
+        [
+            (
+                InitialSegment::Encrypted(init.clone().into()),
+                Some(ByteRange::new(156, Some(100)))
+            ),
+            (
+                InitialSegment::Encrypted(init.into()),
+                Some(ByteRange::new(256, Some(200)))
+            ),
+        ]
+    );
+
+    Ok(())
+}
@@ -1,6 +1,20 @@
-use std::sync::{atomic::AtomicU8, Arc};
+use std::{
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
 
-use iori::{cache::memory::MemoryCacheSource, download::ParallelDownloader, merge::SkipMerger};
+use iori::{
+    cache::{memory::MemoryCacheSource, CacheSource},
+    download::ParallelDownloader,
+    merge::{
+        AutoMerger, ConcatAfterMerger, FailurePolicy, IncrementalMerger, IoriMerger, MergeReport,
+        Merger, SkipMerger, TeeMerger,
+    },
+    IoriError, IoriResult, SegmentFormat, SegmentInfo,
+};
 
 use crate::source::{TestSegment, TestSource};
 
@@ -16,7 +30,7 @@ async fn test_parallel_downloader_with_failed_retry() -> anyhow::Result<()> {
     let cache = Arc::new(MemoryCacheSource::new());
 
     ParallelDownloader::builder()
-        .merger(SkipMerger)
+        .merger(SkipMerger::new())
         .cache(cache.clone())
         .retries(1)
         .download(source)
@@ -41,7 +55,7 @@ async fn test_parallel_downloader_with_success_retry() -> anyhow::Result<()> {
     let cache = Arc::new(MemoryCacheSource::new());
 
     ParallelDownloader::builder()
-        .merger(SkipMerger)
+        .merger(SkipMerger::new())
         .cache(cache.clone())
         .retries(3)
         .download(source)
@@ -53,3 +67,326 @@ async fn test_parallel_downloader_with_success_retry() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_parallel_downloader_merge_report() -> anyhow::Result<()> {
+    let source = TestSource::new(vec![
+        TestSegment::new(1, 0, 0),
+        TestSegment::new(1, 1, 0),
+        TestSegment::new(1, 2, 5),
+    ]);
+
+    let output = tempfile::tempdir()?;
+    let output_file = output.path().join("output.ts");
+    let report = ParallelDownloader::builder()
+        .merger(ConcatAfterMerger::new(output_file.clone(), false))
+        .cache(Arc::new(MemoryCacheSource::new()))
+        .concurrency(NonZeroU32::new(1).unwrap())
+        .retries(1)
+        .download(source)
+        .await?;
+
+    assert_eq!(report.output, Some(output_file.clone()));
+    assert_eq!(report.downloaded_segments, 2);
+    assert_eq!(report.failed_segments.len(), 1);
+    assert_eq!(report.failed_segments[0].file_name, "test1_2.ts");
+    assert_eq!(report.bytes_written, 46);
+    assert!(report.cache_cleared);
+    assert_eq!(
+        tokio::fs::read_to_string(output_file).await?,
+        "Segment 0 from stream 1Segment 1 from stream 1"
+    );
+
+    Ok(())
+}
+
+async fn download_with_gap<M>(merger: M) -> iori::IoriResult<MergeReport>
+where
+    M: Merger<Result = MergeReport> + Send + Sync + 'static,
+{
+    let source = TestSource::new(vec![
+        TestSegment::new(1, 0, 0),
+        TestSegment::new(1, 1, 5),
+        TestSegment::new(1, 2, 0),
+    ]);
+
+    ParallelDownloader::builder()
+        .merger(merger)
+        .cache(Arc::new(MemoryCacheSource::new()))
+        .concurrency(NonZeroU32::new(1).unwrap())
+        .retries(1)
+        .download(source)
+        .await
+}
+
+#[tokio::test]
+async fn test_skip_merger_report() -> anyhow::Result<()> {
+    let report = download_with_gap(SkipMerger::new()).await?;
+
+    assert!(report.skipped);
+    assert!(report.output.is_none());
+    assert_eq!(report.downloaded_segments, 2);
+    assert_eq!(report.failed_segments.len(), 1);
+    assert_eq!(report.gaps.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concat_failure_policy() -> anyhow::Result<()> {
+    let output = tempfile::tempdir()?;
+
+    // split into parts by default
+    let output_file = output.path().join("split.ts");
+    let report = download_with_gap(ConcatAfterMerger::new(output_file, false)).await?;
+    assert_eq!(report.failure_policy, FailurePolicy::Split);
+    assert_eq!(report.gaps.len(), 1);
+    assert_eq!(report.gaps[0].sequence, 1);
+    assert_eq!(
+        report.parts,
+        [
+            output.path().join("split.1.ts"),
+            output.path().join("split.2.ts")
+        ]
+    );
+    assert_eq!(
+        tokio::fs::read_to_string(&report.parts[1]).await?,
+        "Segment 2 from stream 1"
+    );
+
+    let output_file = output.path().join("gaps.ts");
+    let report = download_with_gap(
+        ConcatAfterMerger::new(output_file.clone(), false)
+            .with_failure_policy(FailurePolicy::MergeWithGaps),
+    )
+    .await?;
+    assert_eq!(report.output, Some(output_file.clone()));
+    assert!(report.parts.is_empty());
+    assert_eq!(
+        tokio::fs::read_to_string(output_file).await?,
+        "Segment 0 from stream 1Segment 2 from stream 1"
+    );
+
+    let report = download_with_gap(
+        ConcatAfterMerger::new(output.path().join("skip.ts"), false)
+            .with_failure_policy(FailurePolicy::Skip),
+    )
+    .await?;
+    assert!(report.skipped);
+    assert!(report.output.is_none());
+
+    let result = download_with_gap(
+        ConcatAfterMerger::new(output.path().join("max.ts"), false)
+            .with_failure_policy(FailurePolicy::MaxMissing(10.)),
+    )
+    .await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auto_merger_split() -> anyhow::Result<()> {
+    let output = tempfile::tempdir()?;
+    let report = download_with_gap(
+        AutoMerger::new(output.path().join("auto.ts"), false)
+            .with_failure_policy(FailurePolicy::Split),
+    )
+    .await?;
+
+    // each part is written to its own file
+    let parts = [
+        output.path().join("auto_part1.ts"),
+        output.path().join("auto_part2.ts"),
+    ];
+    assert_eq!(report.parts, parts);
+    assert_eq!(
+        tokio::fs::read_to_string(&parts[0]).await?,
+        "Segment 0 from stream 1"
+    );
+    assert_eq!(
+        tokio::fs::read_to_string(&parts[1]).await?,
+        "Segment 2 from stream 1"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_incremental_merger() -> anyhow::Result<()> {
+    let output = tempfile::tempdir()?;
+    let output_file = output.path().join("incremental.ts");
+    let report = download_with_gap(IncrementalMerger::new(output_file.clone(), false)).await?;
+
+    assert_eq!(report.output, Some(output_file.clone()));
+    assert_eq!(report.failure_policy, FailurePolicy::MergeWithGaps);
+    assert_eq!(report.gaps.len(), 1);
+    assert!(report.cache_cleared);
+    assert_eq!(
+        tokio::fs::read_to_string(output_file).await?,
+        "Segment 0 from stream 1Segment 2 from stream 1"
+    );
+    assert!(!output.path().join("incremental_01.ts").exists());
+
+    // track files are kept without muxing when skipping
+    let output_file = output.path().join("skip.ts");
+    let report = download_with_gap(
+        IncrementalMerger::new(output_file.clone(), false).with_failure_policy(FailurePolicy::Skip),
+    )
+    .await?;
+    assert!(report.skipped);
+    assert!(!output_file.exists());
+    assert!(output.path().join("skip_01.ts").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concat_multiple_streams() -> anyhow::Result<()> {
+    let source = TestSource::new(vec![
+        TestSegment::new(1, 0, 0),
+        TestSegment::new(2, 0, 5),
+        TestSegment::new(1, 1, 0),
+        TestSegment::new(2, 1, 5),
+    ]);
+
+    let output = tempfile::tempdir()?;
+    let output_file = output.path().join("output.ts");
+    let report = ParallelDownloader::builder()
+        .merger(ConcatAfterMerger::new(output_file.clone(), false))
+        .cache(Arc::new(MemoryCacheSource::new()))
+        .retries(1)
+        .download(source)
+        .await?;
+
+    // segments of different streams are not interleaved
+    assert_eq!(report.output, Some(output_file.clone()));
+    assert_eq!(report.tracks.len(), 2);
+    assert_eq!(
+        tokio::fs::read_to_string(output_file).await?,
+        "Segment 0 from stream 1Segment 1 from stream 1"
+    );
+    assert!(!output.path().join("output_01.ts").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tee_merger() -> anyhow::Result<()> {
+    let source = TestSource::new(vec![TestSegment::new(1, 0, 0), TestSegment::new(1, 1, 0)]);
+
+    let output = tempfile::tempdir()?;
+    let pipe_file = output.path().join("pipe.ts");
+    let archive_file = output.path().join("archive.ts");
+    let cache = Arc::new(MemoryCacheSource::new());
+    let report = ParallelDownloader::builder()
+        .merger(IoriMerger::Tee(
+            TeeMerger::new(vec![
+                IoriMerger::pipe_to_file(true, pipe_file.clone()),
+                IoriMerger::concat(archive_file.clone(), true),
+            ])
+            .with_primary(1),
+        ))
+        .cache(cache.clone())
+        .download(source)
+        .await?;
+
+    // the report of the primary merger is returned
+    assert_eq!(report.output, Some(archive_file.clone()));
+    for file in [pipe_file, archive_file] {
+        assert_eq!(
+            tokio::fs::read_to_string(file).await?,
+            "Segment 0 from stream 1Segment 1 from stream 1"
+        );
+    }
+
+    // segments are kept for the archive, though the pipe recycles them
+    assert!(!report.cache_cleared);
+    assert_eq!(cache.into_inner().lock().unwrap().len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_segment_processors() -> anyhow::Result<()> {
+    let source = TestSource::new(vec![TestSegment::new(1, 0, 0), TestSegment::new(1, 1, 0)]);
+
+    // the second processor rejects segment 1 once, which is fetched again
+    let rejected = Arc::new(AtomicU8::new(1));
+    let output = tempfile::tempdir()?;
+    let output_file = output.path().join("output.ts");
+    let report = ParallelDownloader::builder()
+        .merger(ConcatAfterMerger::new(output_file.clone(), false))
+        .cache(Arc::new(MemoryCacheSource::new()))
+        .concurrency(NonZeroU32::new(1).unwrap())
+        .processor(|_: &SegmentInfo, data: Vec<u8>| -> IoriResult<Vec<u8>> {
+            Ok(data.to_ascii_uppercase())
+        })
+        .processor(
+            move |segment: &SegmentInfo, data: Vec<u8>| -> IoriResult<Vec<u8>> {
+                if segment.sequence == 1 && rejected.swap(0, Ordering::Relaxed) > 0 {
+                    return Err(IoriError::SegmentProcessing("rejected".to_string()));
+                }
+                Ok(data)
+            },
+        )
+        .download(source)
+        .await?;
+
+    assert_eq!(report.downloaded_segments, 2);
+    assert_eq!(
+        tokio::fs::read_to_string(output_file).await?,
+        "SEGMENT 0 FROM STREAM 1SEGMENT 1 FROM STREAM 1"
+    );
+
+    Ok(())
+}
+
+/// Records formats of the segments passed to the merger.
+struct FormatRecorder(Vec<SegmentFormat>);
+
+impl Merger for FormatRecorder {
+    type Result = Vec<SegmentFormat>;
+
+    async fn update(&mut self, segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
+        self.0.push(segment.format);
+        Ok(())
+    }
+
+    async fn fail(&mut self, _segment: SegmentInfo, _cache: impl CacheSource) -> IoriResult<()> {
+        Ok(())
+    }
+
+    async fn finish(&mut self, _cache: impl CacheSource) -> IoriResult<Self::Result> {
+        Ok(self.0.clone())
+    }
+}
+
+#[tokio::test]
+async fn test_segment_format_detection() -> anyhow::Result<()> {
+    let source = TestSource::new(vec![TestSegment::new(1, 0, 0)]);
+    // the source says MPEG-TS, but responds with a fragment of fragmented MP4
+    let to_mp4 = |_: &SegmentInfo, _: Vec<u8>| -> IoriResult<Vec<u8>> {
+        Ok(b"\0\0\0\x10moof\0\0\0\0\0\0\0\0".to_vec())
+    };
+
+    let formats = ParallelDownloader::builder()
+        .merger(FormatRecorder(Vec::new()))
+        .cache(Arc::new(MemoryCacheSource::new()))
+        .processor(to_mp4)
+        .download(source.clone())
+        .await?;
+    assert_eq!(formats, [SegmentFormat::Mp4]);
+
+    // explicit format overrides the detection
+    let formats = ParallelDownloader::builder()
+        .merger(FormatRecorder(Vec::new()))
+        .cache(Arc::new(MemoryCacheSource::new()))
+        .processor(to_mp4)
+        .segment_format(SegmentFormat::Aac)
+        .download(source)
+        .await?;
+    assert_eq!(formats, [SegmentFormat::Aac]);
+
+    Ok(())
+}
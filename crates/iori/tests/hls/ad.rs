@@ -0,0 +1,72 @@
+use crate::hls::setup_mock_server;
+use iori::{HttpClient, ad::AdFilter, hls::HlsPlaylistSource};
+use regex::Regex;
+
+const PLAYLIST: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-VERSION:3
+#EXTINF:6,
+https://cdn.example.com/0.ts
+#EXT-X-CUE-OUT:12
+#EXT-X-DISCONTINUITY
+#EXTINF:6,
+https://cdn.example.com/ad0.ts
+#EXTINF:6,
+https://cdn.example.com/ad1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:6,
+https://cdn.example.com/1.ts
+#EXT-X-DATERANGE:ID="ad",START-DATE="2024-01-01T00:00:24Z",SCTE35-OUT=0xFC
+#EXT-X-DISCONTINUITY
+#EXTINF:6,
+https://cdn.example.com/ad2.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:6,
+https://cdn.example.com/2.ts
+#EXTINF:6,
+https://ads.example.com/3.ts
+#EXT-X-ENDLIST
+"#;
+
+async fn load_urls(ad_filter: AdFilter) -> anyhow::Result<Vec<String>> {
+    let (uri, _server) = setup_mock_server(PLAYLIST).await;
+    let mut playlist = HlsPlaylistSource::new(HttpClient::default(), uri.parse()?, None);
+    playlist.set_ad_filter(ad_filter);
+
+    let latest_media_sequences = playlist.load_streams(1).await?;
+    let (streams, _) = playlist.load_segments(&latest_media_sequences, 1).await?;
+    Ok(streams[0].iter().map(|s| s.url.to_string()).collect())
+}
+
+#[tokio::test]
+async fn test_skip_ads() -> anyhow::Result<()> {
+    assert_eq!(load_urls(AdFilter::default()).await?.len(), 7);
+
+    let urls = load_urls(AdFilter::default().with_cue_tags(true)).await?;
+    assert_eq!(
+        urls,
+        [
+            "https://cdn.example.com/0.ts",
+            "https://cdn.example.com/1.ts",
+            "https://cdn.example.com/2.ts",
+            "https://ads.example.com/3.ts",
+        ]
+    );
+
+    let urls =
+        load_urls(AdFilter::default().with_url_patterns(vec![Regex::new(r"//ads\.|/ad\d")?]))
+            .await?;
+    assert_eq!(
+        urls,
+        [
+            "https://cdn.example.com/0.ts",
+            "https://cdn.example.com/1.ts",
+            "https://cdn.example.com/2.ts",
+        ]
+    );
+
+    let urls = load_urls(AdFilter::default().with_cue_tags(true).with_dry_run(true)).await?;
+    assert_eq!(urls.len(), 7);
+
+    Ok(())
+}
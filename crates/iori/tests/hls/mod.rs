@@ -1,3 +1,4 @@
+mod ad;
 mod m3u8_rs;
 mod rfc8216;
 
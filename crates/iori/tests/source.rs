@@ -17,6 +17,16 @@ pub struct TestSegment {
 }
 
 impl TestSegment {
+    /// Segment `sequence` of stream `stream_id`, which fails to download `fail_count` times.
+    pub fn new(stream_id: u64, sequence: u64, fail_count: u8) -> Self {
+        Self {
+            stream_id,
+            sequence,
+            file_name: format!("test{stream_id}_{sequence}.ts"),
+            fail_count: Arc::new(AtomicU8::new(fail_count)),
+        }
+    }
+
     async fn write_data<W>(&self, writer: &mut W) -> IoriResult<()>
     where
         W: tokio::io::AsyncWrite + Unpin + Send + Sync + 'static,
@@ -1,7 +1,6 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use iori_ssa::decrypt;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use iori_ssa::{decrypt, Decryptor};
+use std::io::{BufWriter, Cursor};
 
 fn decrypt_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("decrypt");
@@ -15,13 +14,15 @@ fn decrypt_benchmark(c: &mut Criterion) {
         0x76,
     ]; // EB1F93270D5922B591DB0EFF854BFD76
 
-    // 测试小文件
+    // 测试小文件，读入内存以排除文件 IO 的影响
+    let input = std::fs::read("test/small.ts").unwrap();
+    group.throughput(Throughput::Bytes(input.len() as u64));
+
     group.bench_function("decrypt", |b| {
         b.iter(|| {
-            let input = Box::new(BufReader::new(File::open("test/small.ts").unwrap()));
             let output = BufWriter::new(Vec::new());
             decrypt(
-                black_box(input),
+                black_box(Cursor::new(&input)),
                 black_box(output),
                 black_box(key),
                 black_box(iv),
@@ -30,6 +31,19 @@ fn decrypt_benchmark(c: &mut Criterion) {
         });
     });
 
+    // 以 16 KiB 为单位增量解密
+    group.bench_function("decrypt_incremental", |b| {
+        b.iter(|| {
+            let mut decryptor = Decryptor::new(black_box(key), black_box(iv));
+            let mut output = Vec::new();
+            for chunk in input.chunks(16 * 1024) {
+                output.extend(decryptor.update(black_box(chunk)).unwrap());
+            }
+            output.extend(decryptor.finish().unwrap());
+            black_box(output);
+        });
+    });
+
     group.finish();
 }
 
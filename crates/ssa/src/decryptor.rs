@@ -0,0 +1,328 @@
+use std::io::{self, Read};
+
+use mpeg2ts::ts::{ReadTsPacket, TsPacketReader};
+
+use crate::{AudioSetupType, Error, Result, TsDecryptor};
+
+/// Size of chunks read by [decrypt](crate::decrypt) and [decrypt_async].
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+const TS_PACKET_SIZE: usize = 188;
+
+/// Incremental SAMPLE-AES decryptor, which accepts input in chunks of any size and returns
+/// the output that can be decrypted so far.
+///
+/// ```ignore
+/// let mut decryptor = Decryptor::new(key, iv);
+/// for chunk in chunks {
+///     output.write_all(&decryptor.update(chunk)?)?;
+/// }
+/// output.write_all(&decryptor.finish()?)?;
+/// ```
+pub struct Decryptor {
+    key: [u8; 16],
+    iv: [u8; 16],
+    state: State,
+    /// Input of packed audio which is not processed yet
+    pending: Vec<u8>,
+}
+
+enum State {
+    /// Waiting for the first bytes to detect the container
+    Detecting,
+    MpegTs(Box<MpegTsState>),
+    /// Reading ID3 tags at the start of packed audio
+    Id3(Option<AudioSetupType>),
+    /// Reading packed audio frames, which are written as is without audio setup
+    /// information
+    Audio(Option<AudioSetupType>),
+}
+
+impl Decryptor {
+    pub fn new(key: [u8; 16], iv: [u8; 16]) -> Self {
+        Self {
+            key,
+            iv,
+            state: State::Detecting,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Decrypt the next chunk of input, and return decrypted output which is complete.
+    pub fn update(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        if let State::MpegTs(ts) = &mut self.state {
+            ts.update(input, &mut output)?;
+            return Ok(output);
+        }
+
+        self.pending.extend_from_slice(input);
+        self.process(&mut output, false)?;
+        Ok(output)
+    }
+
+    /// Decrypt the remaining input, and return the rest of the output.
+    ///
+    /// Incomplete frames at the end of packed audio are written as is.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.process(&mut output, true)?;
+
+        match self.state {
+            State::MpegTs(ts) => ts.finish(&mut output)?,
+            _ => output.append(&mut self.pending),
+        }
+        Ok(output)
+    }
+
+    fn process(&mut self, output: &mut Vec<u8>, finished: bool) -> Result<()> {
+        // processed input is removed once at the end
+        let mut position = 0;
+        let result = self.process_pending(&mut position, output, finished);
+        self.pending.drain(..position);
+        result
+    }
+
+    fn process_pending(
+        &mut self,
+        position: &mut usize,
+        output: &mut Vec<u8>,
+        finished: bool,
+    ) -> Result<()> {
+        loop {
+            let input = &mut self.pending[*position..];
+            match &mut self.state {
+                State::Detecting => {
+                    let Some(magic) = input.first() else {
+                        return Ok(());
+                    };
+
+                    // MPEG-TS
+                    if *magic == 0x47 {
+                        let mut ts = Box::new(MpegTsState::new(self.key, self.iv));
+                        ts.update(input, output)?;
+                        *position = self.pending.len();
+                        self.state = State::MpegTs(ts);
+                        return Ok(());
+                    }
+                    self.state = State::Id3(None);
+                }
+                State::MpegTs(_) => return Ok(()),
+                State::Id3(audio_format) => {
+                    // wait for the whole header of a possible tag
+                    let is_header_incomplete =
+                        input.len() < 10 && b"ID3".starts_with(&input[..input.len().min(3)]);
+                    if is_header_incomplete && !finished {
+                        return Ok(());
+                    }
+                    if is_header_incomplete || !input.starts_with(b"ID3") {
+                        self.state = State::Audio(audio_format.take());
+                        continue;
+                    }
+
+                    let size = id3_tag_size(input);
+                    if input.len() < size {
+                        if finished {
+                            self.state = State::Audio(None);
+                            continue;
+                        }
+                        return Ok(());
+                    }
+
+                    #[allow(deprecated)]
+                    let tag = id3::Tag::read_from(&input[..size])?;
+                    if let Some(format) = AudioSetupType::from_id3_tag(&tag) {
+                        *audio_format = Some(format);
+                    }
+                    output.extend_from_slice(&input[..size]);
+                    *position += size;
+                }
+                State::Audio(audio_format) => {
+                    let Some(format) = *audio_format else {
+                        output.extend_from_slice(input);
+                        *position += input.len();
+                        return Ok(());
+                    };
+                    let Some(length) = format.frame_length(input) else {
+                        return Ok(());
+                    };
+                    if length == 0 {
+                        log::warn!("Invalid audio frame header, writing the rest as is.");
+                        *audio_format = None;
+                        continue;
+                    }
+                    if input.len() < length {
+                        return Ok(());
+                    }
+
+                    let size = format.decrypt_frame(&mut input[..length], self.key, self.iv);
+                    output.extend_from_slice(&input[..size]);
+                    *position += size;
+                }
+            }
+        }
+    }
+}
+
+/// Size of the ID3v2 tag at the start of `data`, including its header and footer.
+fn id3_tag_size(data: &[u8]) -> usize {
+    // "ID3", version(2), flags(1), synchsafe size(4)
+    let size = data[6..10]
+        .iter()
+        .fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7f));
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+struct MpegTsState {
+    reader: TsPacketReader<PacketBuffer>,
+    decryptor: TsDecryptor<Vec<u8>>,
+}
+
+impl MpegTsState {
+    fn new(key: [u8; 16], iv: [u8; 16]) -> Self {
+        Self {
+            reader: TsPacketReader::new(PacketBuffer::default()),
+            decryptor: TsDecryptor::new(Vec::new(), key, iv),
+        }
+    }
+
+    /// Decrypt complete packets of the input. A broken packet fails the segment, instead of
+    /// silently dropping the rest of it.
+    fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<()> {
+        self.reader.stream_mut().extend(input);
+        // only read complete packets, which are consumed as a whole
+        while self.reader.stream().remaining() >= TS_PACKET_SIZE {
+            match self.reader.read_ts_packet()? {
+                Some(packet) => self.decryptor.process(packet)?,
+                None => break,
+            }
+        }
+
+        output.append(self.decryptor.output_mut());
+        Ok(())
+    }
+
+    fn finish(mut self, output: &mut Vec<u8>) -> Result<()> {
+        let remaining = self.reader.stream().remaining();
+        if remaining > 0 {
+            return Err(Error::IncompletePacket(remaining));
+        }
+
+        self.decryptor.finish()?;
+        output.append(self.decryptor.output_mut());
+        Ok(())
+    }
+}
+
+/// Input chunks which are not read by the MPEG-TS packet reader yet.
+#[derive(Default)]
+struct PacketBuffer {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl PacketBuffer {
+    fn extend(&mut self, input: &[u8]) {
+        self.data.drain(..self.position);
+        self.position = 0;
+        self.data.extend_from_slice(input);
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+}
+
+impl Read for PacketBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = (&self.data[self.position..]).read(buf)?;
+        self.position += size;
+        Ok(size)
+    }
+}
+
+/// Decrypt a SAMPLE-AES encrypted MPEG-TS segment or packed audio segment from an async
+/// reader into an async writer, in chunks.
+#[cfg(feature = "tokio")]
+pub async fn decrypt_async<R, W>(
+    mut input: R,
+    mut output: W,
+    key: [u8; 16],
+    iv: [u8; 16],
+) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut decryptor = Decryptor::new(key, iv);
+    let mut buf = vec![0; CHUNK_SIZE];
+
+    loop {
+        let size = input.read(&mut buf).await?;
+        if size == 0 {
+            break;
+        }
+        output.write_all(&decryptor.update(&buf[..size])?).await?;
+    }
+    output.write_all(&decryptor.finish()?).await?;
+    output.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::TagLike;
+
+    use super::*;
+
+    #[test]
+    fn test_chunked_packed_audio() -> Result<()> {
+        let key = [1; 16];
+        let iv = [2; 16];
+
+        // an ID3 tag without audio setup information, followed by data written as is
+        let mut tag = id3::Tag::new();
+        tag.set_title("title");
+        let mut input = Vec::new();
+        tag.write_to(&mut input, id3::Version::Id3v24)?;
+        let tag_size = input.len();
+        input.extend((0..100).map(|i| i as u8));
+
+        let mut decryptor = Decryptor::new(key, iv);
+        let mut output = Vec::new();
+        for chunk in input.chunks(7) {
+            output.extend(decryptor.update(chunk)?);
+        }
+        output.extend(decryptor.finish()?);
+
+        assert_eq!(id3_tag_size(&input), tag_size);
+        assert_eq!(output, input);
+        Ok(())
+    }
+
+    #[test]
+    fn test_broken_mpegts() {
+        let key = [1; 16];
+        let iv = [2; 16];
+
+        // null packets, the second of which has no sync byte
+        let mut packet = vec![0x47, 0x1f, 0xff, 0x10];
+        packet.resize(TS_PACKET_SIZE, 0xff);
+        let mut input = packet.clone();
+        input.push(0);
+        input.extend_from_slice(&packet[1..]);
+        assert!(Decryptor::new(key, iv).update(&input).is_err());
+
+        // truncated at the end
+        let mut decryptor = Decryptor::new(key, iv);
+        decryptor.update(&packet[..100]).unwrap();
+        assert!(matches!(
+            decryptor.finish(),
+            Err(Error::IncompletePacket(100))
+        ));
+    }
+}
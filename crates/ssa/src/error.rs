@@ -6,6 +6,9 @@ pub enum Error {
     #[error("Invalid NAL unit start code")]
     InvalidStartCode,
 
+    #[error("Incomplete MPEG-TS packet of {0} bytes at the end")]
+    IncompletePacket(usize),
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
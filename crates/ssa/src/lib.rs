@@ -1,18 +1,50 @@
 mod constant;
+mod decryptor;
 mod error;
+pub use decryptor::*;
 pub use error::*;
 
+use decryptor::CHUNK_SIZE;
+
 use aes::cipher::{BlockDecryptMut, KeyIvInit};
 use memchr::memmem;
 use mpeg2ts::es::StreamType;
 use mpeg2ts::pes::PesHeader;
 use mpeg2ts::ts::{
     payload::{Bytes, Pes},
-    ContinuityCounter, ReadTsPacket, TransportScramblingControl, TsHeader, TsPacket,
+    ContinuityCounter, Pid, ReadTsPacket, TransportScramblingControl, TsHeader, TsPacket,
     TsPacketReader, TsPacketWriter, TsPayload, WriteTsPacket,
 };
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{BufWriter, Read, Write};
+
+/// Stream type of HEVC video.
+///
+/// There is no dedicated stream type for SAMPLE-AES encrypted HEVC, so encrypted streams
+/// keep it and it is not rewritten in the PMT.
+const HEVC_STREAM_TYPE: u8 = 0x24;
+
+fn is_hevc(stream_type: StreamType) -> bool {
+    stream_type as u8 == HEVC_STREAM_TYPE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VideoCodec {
+    H264,
+    Hevc,
+}
+
+impl VideoCodec {
+    /// Whether NAL units of the type are encrypted, which are coded slices only.
+    fn is_encrypted(&self, nal_unit: &NALUnit) -> bool {
+        match self {
+            // non-IDR and IDR slices
+            VideoCodec::H264 => nal_unit.r#type == 5 || nal_unit.r#type == 1,
+            // VCL NAL units from TRAIL_N to RASL_R and from BLA_W_LP to CRA_NUT
+            VideoCodec::Hevc => matches!(nal_unit.hevc_type(), 0..=9 | 16..=21),
+        }
+    }
+}
 
 pub struct NALUnit {
     data: Vec<u8>,
@@ -56,6 +88,11 @@ impl NALUnit {
         ))
     }
 
+    /// nal_unit_type in the 2-byte NAL unit header of HEVC.
+    pub fn hevc_type(&self) -> u8 {
+        (self.data[0] >> 1) & 0x3f
+    }
+
     fn remove_scep_3_bytes(&mut self) {
         let mut i = 0;
         let mut j = 0;
@@ -78,8 +115,8 @@ impl NALUnit {
     }
 
     /// Encrypted_nal_unit () {
-    ///     nal_unit_type_byte                // 1 byte
-    ///     unencrypted_leader                // 31 bytes
+    ///     nal_unit_type_byte                // 1 byte, or 2 bytes of nal_unit_header for HEVC
+    ///     unencrypted_leader                // 31 bytes, or 30 bytes for HEVC
     ///     while (bytes_remaining() > 0) {
     ///         if (bytes_remaining() > 16) {
     ///             encrypted_block           // 16 bytes
@@ -286,7 +323,13 @@ impl PESSegment {
         // do decrypt first
         match self.stream_type {
             // avc
-            StreamType::H264 | StreamType::H264WithAes128Cbc => self.decrypt_video(key, iv)?,
+            StreamType::H264 | StreamType::H264WithAes128Cbc => {
+                self.decrypt_video(VideoCodec::H264, key, iv)?
+            }
+            // hevc
+            stream_type if is_hevc(stream_type) => {
+                self.decrypt_video(VideoCodec::Hevc, key, iv)?
+            }
             // adts
             StreamType::AdtsAac
             | StreamType::AdtsAacWithAes128Cbc
@@ -346,7 +389,7 @@ impl PESSegment {
         Ok(())
     }
 
-    fn decrypt_video(&mut self, key: [u8; 16], iv: [u8; 16]) -> Result<()> {
+    fn decrypt_video(&mut self, codec: VideoCodec, key: [u8; 16], iv: [u8; 16]) -> Result<()> {
         let mut input = self.data.as_slice();
         let output = Vec::with_capacity(self.data.len() * 2);
         let mut output = BufWriter::new(output);
@@ -355,7 +398,7 @@ impl PESSegment {
             let (mut nal_unit, data_new) = NALUnit::get_next(input)?;
             input = data_new;
 
-            if nal_unit.r#type == 5 || nal_unit.r#type == 1 {
+            if codec.is_encrypted(&nal_unit) {
                 nal_unit.decrypt(&key, &iv);
             }
 
@@ -450,122 +493,162 @@ fn should_decrypt_stream(id_map: &HashMap<u16, StreamType>, pid: u16) -> bool {
             | StreamType::DolbyDigitalPlusUpToSixChannelAudioWithAes128Cbc
             | StreamType::DolbyDigitalPlusUpTo16ChannelAudio,
         ) => true,
+        // hevc
+        Some(stream_type) => is_hevc(*stream_type),
         _ => false,
     }
 }
 
-pub fn decrypt_mpegts<R, W>(input: R, output: W, key: [u8; 16], iv: [u8; 16]) -> Result<()>
-where
-    R: Read,
-    W: Write,
-{
-    let mut reader = TsPacketReader::new(input);
-    let mut writer = IoriTsPacketWriter::new(output);
-
-    let mut streams = HashMap::new();
-    let mut pid_map = HashMap::new();
-
-    while let Ok(Some(TsPacket {
-        header,
-        adaptation_field,
-        payload,
-    })) = reader.read_ts_packet()
-    {
-        if let Some(payload) = payload {
-            // do not flush after receiving the following payloads
-            let flush = if matches!(
-                payload,
-                // PES is the start of a new stream
-                TsPayload::Pes(_) |
-                // RAW is part of the current stream
-                TsPayload::Raw(_) |
-                // NULL is just placeholder, no need to flush
-                TsPayload::Null(_)
-            ) {
-                None
-            } else {
-                Some(header.pid)
-            };
+/// Decrypts MPEG-TS packets one by one, buffering PES packets of encrypted streams until
+/// they are complete.
+struct TsDecryptor<W> {
+    writer: IoriTsPacketWriter<W>,
+    key: [u8; 16],
+    iv: [u8; 16],
 
-            match payload {
-                TsPayload::Pmt(mut pmt) => {
-                    // modify from encrypted to clear stream
-                    for es in pmt.es_info.iter_mut() {
-                        // save stream type before modify
-                        pid_map.insert(es.elementary_pid.as_u16(), es.stream_type);
-
-                        // map stream types to its unencrypted version
-                        es.stream_type = match es.stream_type {
-                            StreamType::H264WithAes128Cbc => StreamType::H264,
-                            StreamType::AdtsAacWithAes128Cbc => StreamType::AdtsAac,
-                            StreamType::DolbyDigitalUpToSixChannelAudioWithAes128Cbc => {
-                                StreamType::DolbyDigitalUpToSixChannelAudio
-                            }
-                            StreamType::DolbyDigitalPlusUpToSixChannelAudioWithAes128Cbc => {
-                                StreamType::DolbyDigitalPlusUpTo16ChannelAudio
-                            }
-                            _ => es.stream_type,
-                        };
-                    }
-                    writer.write_packet(&mut TsPacket {
-                        header,
-                        adaptation_field,
-                        payload: Some(TsPayload::Pmt(pmt)),
-                    })?;
-                }
-                // only decrypt stream that should be decrypted
-                TsPayload::Pes(pes) if should_decrypt_stream(&pid_map, header.pid.as_u16()) => {
-                    let stream_type = pid_map.get(&header.pid.as_u16());
-
-                    let prev_pes = streams.insert(
-                        header.pid,
-                        PESSegment {
-                            // SAFETY: we know the stream type is valid
-                            stream_type: *stream_type.unwrap(),
-
-                            pes_ts_header: header,
-                            pes_header: pes.header,
-                            pes_packet_len: pes.pes_packet_len,
-                            initial_size: pes.data.len(),
-                            data: pes.data.to_vec(),
-                            data_packet_num: 0,
-                        },
-                    );
-
-                    if let Some(pes) = prev_pes {
-                        pes.decrypt_and_write(key, iv, &mut writer)?;
-                    }
-                }
-                TsPayload::Raw(bytes) if streams.contains_key(&header.pid) => {
-                    // SAFETY: We've validated the stream exist in streams
-                    let pes = streams.get_mut(&header.pid).unwrap();
-                    pes.data_packet_num += 1;
-                    pes.data.extend_from_slice(&bytes);
+    streams: HashMap<Pid, PESSegment>,
+    pid_map: HashMap<u16, StreamType>,
+}
+
+impl<W: Write> TsDecryptor<W> {
+    fn new(output: W, key: [u8; 16], iv: [u8; 16]) -> Self {
+        Self {
+            writer: IoriTsPacketWriter::new(output),
+            key,
+            iv,
+            streams: HashMap::new(),
+            pid_map: HashMap::new(),
+        }
+    }
+
+    fn output_mut(&mut self) -> &mut W {
+        self.writer.inner.stream_mut()
+    }
+
+    fn process(
+        &mut self,
+        TsPacket {
+            header,
+            adaptation_field,
+            payload,
+        }: TsPacket,
+    ) -> Result<()> {
+        let Some(payload) = payload else {
+            return Ok(());
+        };
+
+        // do not flush after receiving the following payloads
+        let flush = if matches!(
+            payload,
+            // PES is the start of a new stream
+            TsPayload::Pes(_) |
+            // RAW is part of the current stream
+            TsPayload::Raw(_) |
+            // NULL is just placeholder, no need to flush
+            TsPayload::Null(_)
+        ) {
+            None
+        } else {
+            Some(header.pid)
+        };
+
+        match payload {
+            TsPayload::Pmt(mut pmt) => {
+                // modify from encrypted to clear stream
+                for es in pmt.es_info.iter_mut() {
+                    // save stream type before modify
+                    self.pid_map
+                        .insert(es.elementary_pid.as_u16(), es.stream_type);
+
+                    // map stream types to its unencrypted version
+                    es.stream_type = match es.stream_type {
+                        StreamType::H264WithAes128Cbc => StreamType::H264,
+                        StreamType::AdtsAacWithAes128Cbc => StreamType::AdtsAac,
+                        StreamType::DolbyDigitalUpToSixChannelAudioWithAes128Cbc => {
+                            StreamType::DolbyDigitalUpToSixChannelAudio
+                        }
+                        StreamType::DolbyDigitalPlusUpToSixChannelAudioWithAes128Cbc => {
+                            StreamType::DolbyDigitalPlusUpTo16ChannelAudio
+                        }
+                        _ => es.stream_type,
+                    };
                 }
-                // for other payload, just write it without modification
-                _ => writer.write_packet(&mut TsPacket {
+                self.writer.write_packet(&mut TsPacket {
                     header,
                     adaptation_field,
-                    payload: Some(payload),
-                })?,
+                    payload: Some(TsPayload::Pmt(pmt)),
+                })?;
             }
-
-            if let Some(flush) = flush {
-                if let Some(pes) = streams.remove(&flush) {
-                    pes.decrypt_and_write(key, iv, &mut writer)?;
-                };
+            // only decrypt stream that should be decrypted
+            TsPayload::Pes(pes) if should_decrypt_stream(&self.pid_map, header.pid.as_u16()) => {
+                let stream_type = self.pid_map.get(&header.pid.as_u16());
+
+                let prev_pes = self.streams.insert(
+                    header.pid,
+                    PESSegment {
+                        // SAFETY: we know the stream type is valid
+                        stream_type: *stream_type.unwrap(),
+
+                        pes_ts_header: header,
+                        pes_header: pes.header,
+                        pes_packet_len: pes.pes_packet_len,
+                        initial_size: pes.data.len(),
+                        data: pes.data.to_vec(),
+                        data_packet_num: 0,
+                    },
+                );
+
+                if let Some(pes) = prev_pes {
+                    pes.decrypt_and_write(self.key, self.iv, &mut self.writer)?;
+                }
+            }
+            TsPayload::Raw(bytes) if self.streams.contains_key(&header.pid) => {
+                // SAFETY: We've validated the stream exist in streams
+                let pes = self.streams.get_mut(&header.pid).unwrap();
+                pes.data_packet_num += 1;
+                pes.data.extend_from_slice(&bytes);
             }
+            // for other payload, just write it without modification
+            _ => self.writer.write_packet(&mut TsPacket {
+                header,
+                adaptation_field,
+                payload: Some(payload),
+            })?,
         }
+
+        if let Some(pes) = flush.and_then(|flush| self.streams.remove(&flush)) {
+            pes.decrypt_and_write(self.key, self.iv, &mut self.writer)?;
+        }
+
+        Ok(())
     }
 
-    // handle remaining streams
-    for pes in streams.into_values() {
-        pes.decrypt_and_write(key, iv, &mut writer)?;
+    /// Decrypt and write remaining streams.
+    fn finish(&mut self) -> Result<()> {
+        for (_, pes) in self.streams.drain() {
+            pes.decrypt_and_write(self.key, self.iv, &mut self.writer)?;
+        }
+        Ok(())
     }
+}
 
-    Ok(())
+pub fn decrypt_mpegts<R, W>(input: R, output: W, key: [u8; 16], iv: [u8; 16]) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut reader = TsPacketReader::new(input);
+    let mut decryptor = TsDecryptor::new(output, key, iv);
+
+    while let Ok(Some(packet)) = reader.read_ts_packet() {
+        decryptor.process(packet)?;
+    }
+
+    // handle remaining streams
+    decryptor.finish()
 }
 
+#[derive(Debug, Clone, Copy)]
 enum AudioSetupType {
     /// AAC-LC
     AacLc,
@@ -579,33 +662,11 @@ enum AudioSetupType {
     EnhancedAc3,
 }
 
-pub fn decrypt<R, W>(input: R, mut output: W, key: [u8; 16], iv: [u8; 16]) -> Result<()>
-where
-    R: Read,
-    W: Write,
-{
-    let mut input = BufReader::new(input);
-    let magic = input.fill_buf()?;
-
-    if magic.is_empty() {
-        return Ok(());
-    }
-
-    // MPEG-TS
-    if magic[0] == 0x47 {
-        return decrypt_mpegts(input, output, key, iv);
-    }
-
-    let mut audio_format = None;
-    let mut is_id3 = &magic[0..3] == b"ID3";
-    while is_id3 {
-        #[allow(deprecated)]
-        let tag = id3::Tag::read_from(&mut input)?;
-        tag.write_to(&mut output, tag.version())?;
-
-        // In elementary streams the audio setup information is carried inside an ID3 Private Frame, as defined in ID3 tag version 2.4.0.
-        // The owner identifier is com.apple.streaming.audioDescription.
-        let format = tag.frames().find(|f| f.id() == "PRIV").and_then(|p| {
+impl AudioSetupType {
+    /// In elementary streams the audio setup information is carried inside an ID3 Private Frame, as defined in ID3 tag version 2.4.0.
+    /// The owner identifier is com.apple.streaming.audioDescription.
+    fn from_id3_tag(tag: &id3::Tag) -> Option<Self> {
+        tag.frames().find(|f| f.id() == "PRIV").and_then(|p| {
             if let id3::Content::Private(p) = p.content() {
                 if p.owner_identifier == "com.apple.streaming.audioDescription" {
                     // audio_setup_information() {
@@ -631,42 +692,76 @@ where
             }
 
             None
-        });
-
-        if let Some(format) = format {
-            audio_format = Some(format);
-        }
-
-        let magic = input.fill_buf()?;
-        is_id3 = magic.len() >= 3 && &magic[0..3] == b"ID3";
+        })
     }
 
-    let Some(audio_format) = audio_format else {
-        return Ok(());
-    };
-
-    let mut buf = Vec::new();
-    input.read_to_end(&mut buf)?;
-
-    let mut data = &mut buf[..];
-    loop {
-        if data.is_empty() {
-            break;
+    /// Length of the frame starting at `header`, or `None` if the header is incomplete.
+    ///
+    /// Returns 0 for invalid headers.
+    fn frame_length(&self, header: &[u8]) -> Option<usize> {
+        match self {
+            AudioSetupType::AacLc | AudioSetupType::AacHeV1 | AudioSetupType::AacHeV2 => {
+                (header.len() >= 6).then(|| {
+                    let length = AdtsHeader::read_adts_frame_length(header);
+                    // the frame must contain the header
+                    let header_length = if header[1] & 0x01 == 0 { 9 } else { 7 };
+                    if length < header_length {
+                        0
+                    } else {
+                        length
+                    }
+                })
+            }
+            AudioSetupType::Ac3 => (header.len() >= 5).then(|| {
+                let fscod = (header[4] >> 6) as usize;
+                let frmsizcod = (header[4] & 0b111111) as usize;
+                if fscod < 3 && frmsizcod < constant::AC3_FRAME_SIZE_CODE_TABLE.len() {
+                    Ac3Header::read_ac3_frame_length(header)
+                } else {
+                    0
+                }
+            }),
+            AudioSetupType::EnhancedAc3 => {
+                (header.len() >= 4).then(|| Eac3Header::read_eac3_frame_length(header))
+            }
         }
+    }
 
-        let size = match audio_format {
+    /// Decrypt a whole frame, and return its length.
+    fn decrypt_frame(&self, input: &mut [u8], key: [u8; 16], iv: [u8; 16]) -> usize {
+        match self {
             AudioSetupType::AacLc | AudioSetupType::AacHeV1 | AudioSetupType::AacHeV2 => {
-                decrypt_aac_frame(data, key, iv)
+                decrypt_aac_frame(input, key, iv)
             }
-            AudioSetupType::Ac3 => decrypt_ac3_frame(data, key, iv),
-            AudioSetupType::EnhancedAc3 => decrypt_eac3_frame(data, key, iv),
-        };
+            AudioSetupType::Ac3 => decrypt_ac3_frame(input, key, iv),
+            AudioSetupType::EnhancedAc3 => decrypt_eac3_frame(input, key, iv),
+        }
+    }
+}
 
-        let decrypted = &data[..size];
-        output.write_all(decrypted)?;
+/// Decrypt a SAMPLE-AES encrypted MPEG-TS segment or packed audio segment.
+///
+/// Input is read and decrypted in chunks with [Decryptor]. Packed audio without audio
+/// setup information is written as is.
+pub fn decrypt<R, W>(mut input: R, mut output: W, key: [u8; 16], iv: [u8; 16]) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut decryptor = Decryptor::new(key, iv);
+    let mut buf = vec![0; CHUNK_SIZE];
 
-        data = &mut data[size..];
+    loop {
+        let size = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        output.write_all(&decryptor.update(&buf[..size])?)?;
     }
+    output.write_all(&decryptor.finish()?)?;
+    output.flush()?;
 
     Ok(())
 }
@@ -1,6 +1,7 @@
 use std::io::Cursor;
 
-use iori_ssa::decrypt;
+use iori_ssa::{Decryptor, decrypt};
+use mpeg2ts::ts::{ReadTsPacket, TsPacketReader, TsPayload};
 
 const KEY: [u8; 16] = u128::to_be_bytes(0xa8cda0ee5390b716298ffad0a1f1a021);
 const IV: [u8; 16] = u128::to_be_bytes(0xE60C79C314E3C9B471E7E51ABAA0B24A);
@@ -24,3 +25,47 @@ fn decrypt_eac3() {
     decrypt(&mut encrypted, &mut decrypted, KEY, IV).unwrap();
     assert_eq!(decrypted, expected_decrypted);
 }
+
+#[test]
+fn decrypt_chunked() {
+    let encrypted = include_bytes!("fixtures/eac3/segment-0.ts");
+    let expected_decrypted = include_bytes!("fixtures/eac3/segment-0.ts.dec");
+
+    // chunks are not aligned to MPEG-TS packets
+    let mut decryptor = Decryptor::new(KEY, IV);
+    let mut decrypted = Vec::new();
+    for chunk in encrypted.chunks(1000) {
+        decrypted.extend(decryptor.update(chunk).unwrap());
+    }
+    decrypted.extend(decryptor.finish().unwrap());
+    assert_eq!(decrypted, expected_decrypted);
+}
+
+/// Collect the elementary stream of a PID in an MPEG-TS segment.
+fn elementary_stream(data: &[u8], pid: u16) -> Vec<u8> {
+    let mut reader = TsPacketReader::new(data);
+    let mut output = Vec::new();
+    while let Some(packet) = reader.read_ts_packet().unwrap() {
+        if packet.header.pid.as_u16() != pid {
+            continue;
+        }
+        match packet.payload {
+            Some(TsPayload::Pes(pes)) => output.extend_from_slice(&pes.data),
+            Some(TsPayload::Raw(bytes)) => output.extend_from_slice(&bytes),
+            _ => {}
+        }
+    }
+    output
+}
+
+#[test]
+fn decrypt_hevc() {
+    // generated by fixtures/hevc/script.py
+    let mut encrypted = Cursor::new(include_bytes!("fixtures/hevc/segment-0.ts"));
+    let mut decrypted = Vec::new();
+    let expected_stream = include_bytes!("fixtures/hevc/segment-0.h265");
+
+    decrypt(&mut encrypted, &mut decrypted, KEY, IV).unwrap();
+    assert_eq!(decrypted.len() % 188, 0);
+    assert_eq!(elementary_stream(&decrypted, 0x100), expected_stream);
+}
@@ -0,0 +1,227 @@
+#!/usr/bin/env python3
+# Generates a SAMPLE-AES encrypted HEVC MPEG-TS segment, as no common packager produces
+# one, along with the decrypted elementary stream.
+#
+# segment-0.ts    encrypted MPEG-TS with a single HEVC stream
+# segment-0.h265  expected elementary stream after decryption
+#
+# Requires the `cryptography` package.
+import random
+import struct
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+
+KEY = bytes.fromhex("a8cda0ee5390b716298ffad0a1f1a021")
+IV = bytes.fromhex("E60C79C314E3C9B471E7E51ABAA0B24A")
+
+PMT_PID = 0x1000
+VIDEO_PID = 0x100
+
+rng = random.Random(17)
+
+
+def aes_encrypt_block(block):
+    encryptor = Cipher(algorithms.AES(KEY), modes.ECB()).encryptor()
+    return encryptor.update(block) + encryptor.finalize()
+
+
+def aes_decrypt_block(block):
+    decryptor = Cipher(algorithms.AES(KEY), modes.ECB()).decryptor()
+    return decryptor.update(block) + decryptor.finalize()
+
+
+def xor(a, b):
+    return bytes(x ^ y for x, y in zip(a, b))
+
+
+def random_bytes(size):
+    # no zero bytes, so that plain data never contains start codes or emulation prevention
+    return bytes(rng.randint(1, 255) for _ in range(size))
+
+
+def has_zero_pair(data):
+    return b"\x00\x00" in data
+
+
+def encrypt_nal_unit(plain, force_emulation_prevention=False):
+    """Encrypts a NAL unit without emulation prevention bytes, and returns it with
+    emulation prevention applied, along with the (possibly adjusted) plain NAL unit.
+
+    With `force_emulation_prevention`, the first encrypted block is chosen to end with two
+    zero bytes, followed by a clear byte of 0x01, so that an emulation prevention byte has
+    to be inserted after encryption.
+    """
+    plain = bytearray(plain)
+    encrypted = bytearray(plain)
+    chain = IV
+    pos = 32
+    first = True
+    while pos < len(plain):
+        if len(plain) - pos > 16:
+            if first and force_emulation_prevention:
+                while True:
+                    block = random_bytes(14) + b"\x00\x00"
+                    decrypted = xor(aes_decrypt_block(block), chain)
+                    if not has_zero_pair(decrypted) and decrypted[-1] != 0:
+                        break
+                plain[pos : pos + 16] = decrypted
+                if pos + 16 < len(plain):
+                    plain[pos + 16] = 0x01
+                    encrypted[pos + 16] = 0x01
+            else:
+                block = aes_encrypt_block(xor(plain[pos : pos + 16], chain))
+            encrypted[pos : pos + 16] = block
+            chain = block
+            first = False
+            pos += 16
+        pos += min(144, len(plain) - pos)
+
+    return add_emulation_prevention(bytes(encrypted)), bytes(plain)
+
+
+def add_emulation_prevention(data):
+    output = bytearray()
+    zeros = 0
+    for byte in data:
+        if zeros >= 2 and byte <= 3:
+            output.append(0x03)
+            zeros = 0
+        output.append(byte)
+        zeros = zeros + 1 if byte == 0 else 0
+    return bytes(output)
+
+
+def nal_unit(nal_type, size):
+    header = bytes([nal_type << 1, 0x01])
+    return header + random_bytes(size - 2)
+
+
+def access_units():
+    """Returns (encrypted, plain) elementary stream of each access unit."""
+    units = []
+
+    # AUD, VPS, SPS, PPS and SEI are clear, IDR_W_RADL is encrypted
+    first = [
+        (nal_unit(35, 3), False, False),
+        (nal_unit(32, 24), False, False),
+        (nal_unit(33, 60), False, False),
+        (nal_unit(34, 10), False, False),
+        (nal_unit(39, 80), False, False),
+        (nal_unit(19, 700), True, True),
+    ]
+    # TRAIL_R is encrypted, and a short TRAIL_N is left clear for its size
+    second = [
+        (nal_unit(35, 3), False, False),
+        (nal_unit(1, 500), True, False),
+        (nal_unit(0, 40), True, False),
+    ]
+    # CRA_NUT is encrypted
+    third = [
+        (nal_unit(35, 3), False, False),
+        (nal_unit(21, 333), True, False),
+    ]
+
+    for nal_units in (first, second, third):
+        encrypted = bytearray()
+        plain = bytearray()
+        for data, is_encrypted, force in nal_units:
+            if is_encrypted and len(data) > 48:
+                data_encrypted, data = encrypt_nal_unit(data, force)
+            else:
+                data_encrypted = data
+            encrypted += b"\x00\x00\x00\x01" + data_encrypted
+            plain += b"\x00\x00\x00\x01" + data
+        units.append((bytes(encrypted), bytes(plain)))
+
+    return units
+
+
+def crc32_mpeg(data):
+    crc = 0xFFFFFFFF
+    for byte in data:
+        crc ^= byte << 24
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x04C11DB7) if crc & 0x80000000 else crc << 1
+            crc &= 0xFFFFFFFF
+    return crc
+
+
+class TsWriter:
+    def __init__(self):
+        self.counters = {}
+        self.output = bytearray()
+
+    def packet(self, pid, payload, start=False):
+        assert len(payload) <= 184
+        counter = self.counters.get(pid, 0)
+        self.counters[pid] = (counter + 1) % 16
+
+        stuffing = 184 - len(payload)
+        header = struct.pack(
+            ">BHB",
+            0x47,
+            (0x4000 if start else 0) | pid,
+            (0x30 if stuffing else 0x10) | counter,
+        )
+        adaptation = b""
+        if stuffing:
+            # stuffing of a single byte is only the adaptation field length
+            assert stuffing >= 2, stuffing
+            adaptation = bytes([stuffing - 1, 0x00]) + b"\xff" * (stuffing - 2)
+        packet = header + adaptation + payload
+        assert len(packet) == 188
+        self.output += packet
+
+    def section(self, pid, table_id, table_id_extension, body):
+        section = struct.pack(">BHHBBB", table_id, 0, table_id_extension, 0xC1, 0, 0) + body
+        length = len(section) - 3 + 4
+        section = bytes([table_id]) + struct.pack(">H", 0xB000 | length) + section[3:]
+        section += struct.pack(">I", crc32_mpeg(section))
+        payload = b"\x00" + section
+        self.packet(pid, payload + b"\xff" * (184 - len(payload)), start=True)
+
+    def pes(self, pid, data, pts):
+        pts_bytes = bytes(
+            [
+                0x21 | ((pts >> 29) & 0x0E),
+                (pts >> 22) & 0xFF,
+                0x01 | ((pts >> 14) & 0xFE),
+                (pts >> 7) & 0xFF,
+                0x01 | ((pts << 1) & 0xFE),
+            ]
+        )
+        pes = b"\x00\x00\x01\xe0" + struct.pack(">H", 0) + b"\x80\x80\x05" + pts_bytes + data
+
+        start = True
+        while pes:
+            size = min(184, len(pes))
+            # avoid a single byte of stuffing
+            if len(pes) == 183:
+                size = 182
+            self.packet(pid, pes[:size], start=start)
+            pes = pes[size:]
+            start = False
+
+
+def main():
+    writer = TsWriter()
+    # PAT: program 1 -> PMT_PID
+    writer.section(0, 0x00, 1, struct.pack(">HH", 1, 0xE000 | PMT_PID))
+    # PMT: no PCR, HEVC on VIDEO_PID
+    pmt = struct.pack(">HH", 0xE000 | 0x1FFF, 0xF000)
+    pmt += struct.pack(">BHH", 0x24, 0xE000 | VIDEO_PID, 0xF000)
+    writer.section(PMT_PID, 0x02, 1, pmt)
+
+    expected = bytearray()
+    for index, (encrypted, plain) in enumerate(access_units()):
+        writer.pes(VIDEO_PID, encrypted, 90000 + index * 3000)
+        expected += plain
+
+    with open("segment-0.ts", "wb") as f:
+        f.write(writer.output)
+    with open("segment-0.h265", "wb") as f:
+        f.write(expected)
+
+
+if __name__ == "__main__":
+    main()
@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
 use chrono::{DateTime, Utc};
+use iori::metadata::Metadata;
 use shiori_plugin::*;
 
 use crate::{
@@ -181,12 +182,23 @@ impl Inspect for NicoLiveInspectorImpl {
 
                         let mut result = vec![];
                         if !this.danmaku_only {
+                            let metadata = Metadata {
+                                title: Some(data.program_title()),
+                                description: Some(data.program_description()),
+                                channel: data.program_provider(),
+                                start_time: data
+                                    .program_begin_time()
+                                    .and_then(|time| DateTime::from_timestamp(time as i64, 0)),
+                                platform_id: data.program_id(),
+                                ..Default::default()
+                            };
                             result.push(InspectPlaylist {
                                 title: Some(data.program_title()),
                                 playlist_url: stream.uri,
                                 playlist_type: PlaylistType::HLS,
                                 cookies: stream.cookies.into_cookies(),
                                 streams_hint: Some(2),
+                                metadata,
                                 ..Default::default()
                             });
                         }
@@ -275,11 +287,23 @@ impl Inspect for NicoVideoInspectorImpl {
                     Box::pin(async move {
                         let data = NivoServerResponse::new(url, user_session.as_deref()).await?;
                         let (playlist_url, cookies) = data.playlist_url().await?;
+                        let metadata = Metadata {
+                            title: data.program_title(),
+                            description: data.program_description(),
+                            channel: data.program_owner(),
+                            start_time: data
+                                .program_registered_at()
+                                .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+                                .map(|time| time.to_utc()),
+                            platform_id: data.video_id(),
+                            ..Default::default()
+                        };
                         Ok(InspectResult::Playlists(vec![InspectPlaylist {
                             title: data.program_title(),
                             playlist_url,
                             playlist_type: PlaylistType::HLS,
                             headers: vec![format!("Cookie: {cookies}")],
+                            metadata,
                             ..Default::default()
                         }]))
                     })
@@ -121,6 +121,36 @@ impl NicoEmbeddedData {
             .unwrap()
     }
 
+    pub fn program_begin_time(&self) -> Option<u64> {
+        self.data
+            .get("program")
+            .and_then(|program| program.get("beginTime"))
+            .and_then(|begin_at| begin_at.as_u64())
+    }
+
+    pub fn program_id(&self) -> Option<String> {
+        self.data
+            .get("program")
+            .and_then(|program| program.get("nicoliveProgramId"))
+            .and_then(|id| id.as_str())
+            .map(|id| id.to_string())
+    }
+
+    /// Name of the user or channel who provides the program.
+    pub fn program_provider(&self) -> Option<String> {
+        self.data
+            .get("program")
+            .and_then(|program| program.get("supplier"))
+            .and_then(|supplier| supplier.get("name"))
+            .or_else(|| {
+                self.data
+                    .get("socialGroup")
+                    .and_then(|group| group.get("name"))
+            })
+            .and_then(|name| name.as_str())
+            .map(|name| name.to_string())
+    }
+
     pub fn audience_token(&self) -> anyhow::Result<String> {
         let wss_url = self
             .websocket_url()
@@ -276,7 +306,36 @@ impl NivoServerResponse {
             .map(|title| title.to_string())
     }
 
-    fn video_id(&self) -> Option<String> {
+    pub fn program_description(&self) -> Option<String> {
+        self.response()
+            .and_then(|r| r.get("video"))
+            .and_then(|video| video.get("description"))
+            .and_then(|description| description.as_str())
+            .map(|description| description.to_string())
+    }
+
+    /// Registered time of the video in RFC 3339.
+    pub fn program_registered_at(&self) -> Option<String> {
+        self.response()
+            .and_then(|r| r.get("video"))
+            .and_then(|video| video.get("registeredAt"))
+            .and_then(|time| time.as_str())
+            .map(|time| time.to_string())
+    }
+
+    /// Nickname of the uploader, or name of the channel.
+    pub fn program_owner(&self) -> Option<String> {
+        self.response()
+            .and_then(|r| {
+                r.get("owner")
+                    .and_then(|owner| owner.get("nickname"))
+                    .or_else(|| r.get("channel").and_then(|channel| channel.get("name")))
+            })
+            .and_then(|name| name.as_str())
+            .map(|name| name.to_string())
+    }
+
+    pub fn video_id(&self) -> Option<String> {
         self.response()
             .and_then(|r| r.get("video"))
             .and_then(|video| video.get("id"))
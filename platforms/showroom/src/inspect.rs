@@ -1,4 +1,5 @@
-use shiori_plugin::*;
+use chrono::DateTime;
+use shiori_plugin::{iori::metadata::Metadata, *};
 
 use crate::ShowRoomClient;
 
@@ -73,10 +74,22 @@ impl Inspect for ShowroomInspectorImpl {
                             return Ok(InspectResult::None);
                         };
 
+                        // lives have no title, so the room is recorded as the channel only
+                        let profile = client.room_profile(room_id).await.ok();
+                        let metadata = Metadata {
+                            channel: Some(info.room_name.clone()),
+                            start_time: profile
+                                .map(|profile| profile.current_live_started_at)
+                                .filter(|started_at| *started_at != 0)
+                                .and_then(|started_at| DateTime::from_timestamp(started_at, 0)),
+                            platform_id: Some(info.live_id.to_string()),
+                            ..Default::default()
+                        };
                         Ok(InspectResult::Playlist(InspectPlaylist {
                             title: Some(info.room_name),
                             playlist_url: stream.url.clone(),
                             playlist_type: PlaylistType::HLS,
+                            metadata,
                             ..Default::default()
                         }))
                     })
@@ -104,10 +117,21 @@ impl Inspect for ShowroomInspectorImpl {
                             )
                             .await?;
                         let stream = timeshift_streaming_url.best();
+
+                        let timeshift = timeshift_info.timeshift;
+                        let profile = client.room_profile(timeshift.room_id).await.ok();
+                        let metadata = Metadata {
+                            title: Some(timeshift.title.clone()),
+                            description: Some(timeshift.description),
+                            channel: profile.map(|profile| profile.room_name),
+                            platform_id: Some(timeshift.live_id.to_string()),
+                            ..Default::default()
+                        };
                         Ok(InspectResult::Playlist(InspectPlaylist {
-                            title: Some(timeshift_info.timeshift.title),
+                            title: Some(timeshift.title),
                             playlist_url: stream.url().to_string(),
                             playlist_type: PlaylistType::HLS,
+                            metadata,
                             ..Default::default()
                         }))
                     })
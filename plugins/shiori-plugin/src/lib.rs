@@ -118,6 +118,10 @@ pub struct InspectPlaylist {
 
     /// Hints how many streams does this playlist contains.
     pub streams_hint: Option<u32>,
+
+    /// Metadata written into the output file
+    #[serde(default)]
+    pub metadata: iori::metadata::Metadata,
 }
 
 pub trait InspectorApp {